@@ -36,5 +36,7 @@ EOF
 
 cat secrets.json | socat - VSOCK-CONNECT:$ENCLAVE_CID:7777
 socat TCP4-LISTEN:3000,reuseaddr,fork VSOCK-CONNECT:$ENCLAVE_CID:3000 &
+# perma-ws: Expose port 3001 for localhost-only access to admin endpoints
+socat TCP4-LISTEN:3001,bind=127.0.0.1,reuseaddr,fork VSOCK-CONNECT:$ENCLAVE_CID:3001 &
 
 # Additional port configurations will be added here by configure_enclave.sh if needed
@@ -20,6 +20,22 @@ public struct PermaNFT has key, store {
     reference_id: String,
     screenshot_blob_id: String,
     screenshot_byte_size: u64,
+    full_page_max_height: Option<u32>,
+    screenshot_provider: String,
+    tls_cert_fingerprint: Option<String>,
+    tls_cert_chain: vector<String>,
+    final_url: Option<String>,
+    redirect_chain_hash: Option<String>,
+    responsive_captures: vector<ResponsiveCapture>,
+    color_scheme: Option<String>,
+    canonical_url: Option<String>,
+    phash: Option<String>,
+    text_blob_id: Option<String>,
+    text_sha256: Option<String>,
+    data_content_type: Option<String>,
+    data_sha256: Option<String>,
+    wacz_blob_id: String,
+    nonce: Option<String>,
     timestamp_ms: u64,
 }
 
@@ -29,6 +45,34 @@ public struct PermaResponse has copy, drop {
     reference_id: String,
     screenshot_blob_id: String,
     screenshot_byte_size: u64,
+    full_page_max_height: Option<u32>,
+    screenshot_provider: String,
+    tls_cert_fingerprint: Option<String>,
+    tls_cert_chain: vector<String>,
+    final_url: Option<String>,
+    redirect_chain_hash: Option<String>,
+    responsive_captures: vector<ResponsiveCapture>,
+    color_scheme: Option<String>,
+    canonical_url: Option<String>,
+    phash: Option<String>,
+    text_blob_id: Option<String>,
+    text_sha256: Option<String>,
+    data_content_type: Option<String>,
+    data_sha256: Option<String>,
+    wacz_blob_id: String,
+    nonce: Option<String>,
+}
+
+/// Capture at one of the requested responsive widths.
+/// Should match the ResponsiveCapture struct in Rust.
+public struct ResponsiveCapture has copy, drop, store {
+    width: u32,
+    blob_id: String,
+    byte_size: u64,
+}
+
+public fun new_responsive_capture(width: u32, blob_id: String, byte_size: u64): ResponsiveCapture {
+    ResponsiveCapture { width, blob_id, byte_size }
 }
 
 public struct PERMA has drop {}
@@ -52,6 +96,22 @@ public fun update_perma<T>(
     reference_id: String,
     screenshot_blob_id: String,
     screenshot_byte_size: u64,
+    full_page_max_height: Option<u32>,
+    screenshot_provider: String,
+    tls_cert_fingerprint: Option<String>,
+    tls_cert_chain: vector<String>,
+    final_url: Option<String>,
+    redirect_chain_hash: Option<String>,
+    responsive_captures: vector<ResponsiveCapture>,
+    color_scheme: Option<String>,
+    canonical_url: Option<String>,
+    phash: Option<String>,
+    text_blob_id: Option<String>,
+    text_sha256: Option<String>,
+    data_content_type: Option<String>,
+    data_sha256: Option<String>,
+    wacz_blob_id: String,
+    nonce: Option<String>,
     timestamp_ms: u64,
     sig: &vector<u8>,
     enclave: &Enclave<T>,
@@ -60,7 +120,28 @@ public fun update_perma<T>(
     let res = enclave.verify_signature(
         PERMA_INTENT,
         timestamp_ms,
-        PermaResponse { url, reference_id, screenshot_blob_id, screenshot_byte_size },
+        PermaResponse {
+            url,
+            reference_id,
+            screenshot_blob_id,
+            screenshot_byte_size,
+            full_page_max_height,
+            screenshot_provider,
+            tls_cert_fingerprint,
+            tls_cert_chain,
+            final_url,
+            redirect_chain_hash,
+            responsive_captures,
+            color_scheme,
+            canonical_url,
+            phash,
+            text_blob_id,
+            text_sha256,
+            data_content_type,
+            data_sha256,
+            wacz_blob_id,
+            nonce,
+        },
         sig,
     );
     assert!(res, EInvalidSignature);
@@ -71,6 +152,22 @@ public fun update_perma<T>(
         reference_id,
         screenshot_blob_id,
         screenshot_byte_size,
+        full_page_max_height,
+        screenshot_provider,
+        tls_cert_fingerprint,
+        tls_cert_chain,
+        final_url,
+        redirect_chain_hash,
+        responsive_captures,
+        color_scheme,
+        canonical_url,
+        phash,
+        text_blob_id,
+        text_sha256,
+        data_content_type,
+        data_sha256,
+        wacz_blob_id,
+        nonce,
         timestamp_ms,
     }
 }
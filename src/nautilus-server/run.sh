@@ -57,5 +57,7 @@ python3 /traffic_forwarder.py 127.0.0.70 443 3 8107 &
 
 # Listens on Local VSOCK Port 3000 and forwards to localhost 3000
 socat VSOCK-LISTEN:3000,reuseaddr,fork TCP:localhost:3000 &
+# Listens on Local VSOCK Port 3001 and forwards to the host-only admin server
+socat VSOCK-LISTEN:3001,reuseaddr,fork TCP:localhost:3001 &
 
 /nautilus-server
@@ -0,0 +1,217 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::EnclaveError;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// How long a key that answered 429 is skipped.
+pub const DEFAULT_RATE_LIMIT_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// API keys of one provider, handed out round-robin so requests spread over
+/// the keys' rate limits. A key that got rate limited is skipped until its
+/// cooldown ends.
+#[derive(Debug)]
+pub struct ApiKeyPool {
+    keys: Vec<String>,
+    cooldown: Duration,
+    state: Mutex<PoolState>,
+}
+
+#[derive(Debug, Default)]
+struct PoolState {
+    next: usize,
+    /// When each key may be used again, by index in `keys`.
+    limited_until: Vec<Option<Instant>>,
+}
+
+impl ApiKeyPool {
+    pub fn new(keys: Vec<String>, cooldown: Duration) -> Self {
+        let state = PoolState {
+            next: 0,
+            limited_until: vec![None; keys.len()],
+        };
+        Self {
+            keys,
+            cooldown,
+            state: Mutex::new(state),
+        }
+    }
+
+    /// Pool of the comma-separated keys in `value`, e.g. `API_KEY=key1,key2`.
+    /// A single key gives a pool of one.
+    pub fn parse(value: &str) -> Self {
+        let keys = value
+            .split(',')
+            .map(str::trim)
+            .filter(|k| !k.is_empty())
+            .map(str::to_string)
+            .collect();
+        Self::new(keys, DEFAULT_RATE_LIMIT_COOLDOWN)
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// First key, for apps that don't rotate.
+    pub fn first(&self) -> Option<&str> {
+        self.keys.first().map(String::as_str)
+    }
+
+    /// Next key in turn that isn't cooling down.
+    pub fn select(&self) -> Result<String, EnclaveError> {
+        self.select_at(Instant::now())
+    }
+
+    /// Skip `key` for the pool's cooldown after the provider rate limited it.
+    pub fn mark_rate_limited(&self, key: &str) {
+        self.mark_rate_limited_at(key, Instant::now());
+    }
+
+    fn select_at(&self, now: Instant) -> Result<String, EnclaveError> {
+        if self.keys.is_empty() {
+            return Err(EnclaveError::Internal("no API key configured".to_string()));
+        }
+        let mut state = self.state.lock().expect("lock poisoned");
+        for offset in 0..self.keys.len() {
+            let index = (state.next + offset) % self.keys.len();
+            if state.limited_until[index].is_none_or(|until| until <= now) {
+                state.limited_until[index] = None;
+                state.next = index + 1;
+                return Ok(self.keys[index].clone());
+            }
+        }
+        let retry_after = state
+            .limited_until
+            .iter()
+            .flatten()
+            .min()
+            .map_or(self.cooldown, |until| until.duration_since(now));
+        Err(EnclaveError::Unavailable {
+            reason: "every API key is rate limited".to_string(),
+            retry_after_secs: retry_after.as_secs().max(1),
+        })
+    }
+
+    fn mark_rate_limited_at(&self, key: &str, now: Instant) {
+        let Some(index) = self.keys.iter().position(|k| k == key) else {
+            return;
+        };
+        info!(
+            "API key #{} was rate limited, skipping it for {:?}",
+            index, self.cooldown
+        );
+        let mut state = self.state.lock().expect("lock poisoned");
+        state.limited_until[index] = Some(now + self.cooldown);
+    }
+}
+
+/// Send a request with the next key of `pool`, moving on to the following
+/// key while the provider answers 429, up to once per key.
+pub async fn send_with_key_rotation<F, Fut>(
+    pool: &ApiKeyPool,
+    context: &str,
+    send: F,
+) -> Result<reqwest::Response, EnclaveError>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = reqwest::Result<reqwest::Response>>,
+{
+    for _ in 0..pool.len() {
+        let key = pool.select()?;
+        let response = send(key.clone())
+            .await
+            .map_err(|e| EnclaveError::from_reqwest(context, e))?;
+        if response.status() != reqwest::StatusCode::TOO_MANY_REQUESTS {
+            return Ok(response);
+        }
+        pool.mark_rate_limited(&key);
+    }
+    // Every key answered 429 and is cooling down.
+    Err(pool.select().err().unwrap_or_else(|| {
+        EnclaveError::Upstream(format!("{context}: every API key is rate limited"))
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use wiremock::matchers::{method, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn pool(keys: &[&str]) -> ApiKeyPool {
+        ApiKeyPool::new(
+            keys.iter().map(|k| k.to_string()).collect(),
+            DEFAULT_RATE_LIMIT_COOLDOWN,
+        )
+    }
+
+    #[test]
+    fn test_keys_rotate_round_robin() {
+        let pool = pool(&["a", "b", "c"]);
+        let now = Instant::now();
+        let picked: Vec<String> = (0..4).map(|_| pool.select_at(now).unwrap()).collect();
+        assert_eq!(picked, vec!["a", "b", "c", "a"]);
+        assert_eq!(ApiKeyPool::parse(" a, b ,,").len(), 2);
+    }
+
+    #[test]
+    fn test_rate_limited_key_is_skipped() {
+        let pool = pool(&["a", "b"]);
+        let now = Instant::now();
+        pool.mark_rate_limited_at("a", now);
+        for _ in 0..3 {
+            assert_eq!(pool.select_at(now).unwrap(), "b");
+        }
+
+        // Both cooling down: unavailable until the first one is usable.
+        pool.mark_rate_limited_at("b", now + Duration::from_secs(30));
+        assert!(matches!(
+            pool.select_at(now + Duration::from_secs(40)),
+            Err(EnclaveError::Unavailable {
+                retry_after_secs: 20,
+                ..
+            })
+        ));
+
+        // Back in rotation once the cooldown ends.
+        let later = now + DEFAULT_RATE_LIMIT_COOLDOWN;
+        assert_eq!(pool.select_at(later).unwrap(), "a");
+    }
+
+    #[tokio::test]
+    async fn test_send_rotates_past_rate_limited_key() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(query_param("key", "a"))
+            .respond_with(ResponseTemplate::new(429))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(query_param("key", "b"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let pool = pool(&["a", "b"]);
+        let http = reqwest::Client::new();
+        for _ in 0..2 {
+            let response = send_with_key_rotation(&pool, "Weather API", |key| {
+                http.get(server.uri()).query(&[("key", key)]).send()
+            })
+            .await
+            .unwrap();
+            assert_eq!(response.status(), 200);
+        }
+        server.verify().await;
+    }
+}
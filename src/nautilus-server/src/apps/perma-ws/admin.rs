@@ -0,0 +1,133 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::attestation::{
+    retry_unpersisted as retry_all, SignedPermaResponse, UnpersistedAttestation,
+};
+use super::capture::STORAGE_ENDPOINT;
+use super::config::required;
+use super::deletion::{delete_archive as delete_archive_inner, SignedArchiveTombstone};
+use super::recent_captures::recent_captures;
+use super::validation::{first_error, storage_target};
+use crate::common::{listen_addr, DEFAULT_HOST_INIT_PORT};
+use crate::AppState;
+use crate::EnclaveError;
+use axum::extract::{Path, Query, State};
+use axum::http::HeaderMap;
+use axum::routing::{delete, get, post};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tracing::info;
+
+/// Response for /admin/retry_unpersisted
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RetryUnpersistedResponse {
+    pub saved: usize,
+    pub remaining: usize,
+}
+
+/// List signed responses whose attestation save failed, for reconciliation.
+pub async fn list_unpersisted(
+    State(state): State<Arc<AppState>>,
+) -> Json<Vec<UnpersistedAttestation>> {
+    let unpersisted = state.perma.unpersisted.lock().expect("lock poisoned");
+    Json(unpersisted.iter().cloned().collect())
+}
+
+/// Re-attempt saving all unpersisted attestations with the frontend.
+pub async fn retry_unpersisted(
+    State(state): State<Arc<AppState>>,
+) -> Json<RetryUnpersistedResponse> {
+    let saved = retry_all(&state.perma, &state.http).await;
+    let remaining = state.perma.unpersisted.lock().expect("lock poisoned").len();
+    info!(
+        "Retried unpersisted attestations: {} saved, {} remaining",
+        saved, remaining
+    );
+    Json(RetryUnpersistedResponse { saved, remaining })
+}
+
+/// Signed response recorded in the attestation store for `reference_id`,
+/// whether or not the frontend saved it.
+pub async fn stored_attestation(
+    State(state): State<Arc<AppState>>,
+    Path(reference_id): Path<String>,
+) -> Result<Json<SignedPermaResponse>, EnclaveError> {
+    state
+        .perma
+        .attestation_store
+        .get(&reference_id)
+        .map(Json)
+        .ok_or_else(|| EnclaveError::NotFound(format!("no stored attestation for {reference_id}")))
+}
+
+/// Header carrying `ADMIN_SECRET` for destructive admin endpoints.
+pub const ADMIN_SECRET_HEADER: &str = "x-admin-secret";
+
+/// Where the archive to delete was stored: the `bucket` and `path_prefix` its
+/// request picked, if any.
+#[derive(Debug, Default, Deserialize)]
+pub struct DeleteArchiveQuery {
+    pub bucket: Option<String>,
+    pub path_prefix: Option<String>,
+}
+
+/// Delete an archive's stored screenshot and tombstone its attestation, e.g.
+/// for an erasure request. Requires the `x-admin-secret` header.
+pub async fn delete_archive(
+    State(state): State<Arc<AppState>>,
+    Path(reference_id): Path<String>,
+    Query(query): Query<DeleteArchiveQuery>,
+    headers: HeaderMap,
+) -> Result<Json<SignedArchiveTombstone>, EnclaveError> {
+    let admin_secret = required(&state.perma.config.admin_secret, "ADMIN_SECRET")?;
+    let provided = headers
+        .get(ADMIN_SECRET_HEADER)
+        .and_then(|v| v.to_str().ok());
+    if provided != Some(admin_secret) {
+        return Err(EnclaveError::Unauthorized(
+            "missing or invalid admin secret".to_string(),
+        ));
+    }
+    let target = storage_target(
+        query.bucket.as_deref(),
+        query.path_prefix.as_deref(),
+        &state.perma.config,
+    )
+    .map_err(first_error)?;
+    let tombstone = delete_archive_inner(&state, STORAGE_ENDPOINT, &reference_id, &target).await?;
+    Ok(Json(tombstone))
+}
+
+/// Spawn a separate server on port 3001 (or `HOST_INIT_PORT`) for host-only
+/// admin access. Like the seal bootstrap server, the port is not forwarded
+/// outside the host.
+pub async fn spawn_host_admin_server(state: Arc<AppState>) -> Result<SocketAddr, EnclaveError> {
+    let host_app = Router::new()
+        .route("/admin/unpersisted", get(list_unpersisted))
+        .route("/admin/retry_unpersisted", post(retry_unpersisted))
+        .route("/admin/attestation/:reference_id", get(stored_attestation))
+        .route("/admin/captures", get(recent_captures))
+        .route("/archive/:reference_id", delete(delete_archive))
+        .with_state(state);
+
+    let addr = listen_addr("HOST_INIT_PORT", DEFAULT_HOST_INIT_PORT)?;
+    let host_listener = TcpListener::bind(addr).await.map_err(|e| {
+        EnclaveError::GenericError(format!("Failed to bind host admin server: {e}"))
+    })?;
+    let host_addr = host_listener.local_addr().map_err(|e| {
+        EnclaveError::GenericError(format!("Failed to get host admin address: {e}"))
+    })?;
+    info!("Host-only admin server listening on {}", host_addr);
+
+    tokio::spawn(async move {
+        axum::serve(host_listener, host_app.into_make_service())
+            .await
+            .expect("Host admin server failed");
+    });
+
+    Ok(host_addr)
+}
@@ -0,0 +1,147 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::config::PermaConfig;
+use crate::EnclaveError;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::info;
+
+/// What to do with an archive request when all permits are taken
+/// (`SATURATION_POLICY`). `Queue`, the default, waits up to
+/// `SATURATION_QUEUE_TIMEOUT_MS` for a permit, trading latency for fewer
+/// rejections, `Reject` sheds load immediately. Either way a request that gets no permit is answered with 503
+/// and `Retry-After`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SaturationPolicy {
+    Reject,
+    #[default]
+    Queue,
+}
+
+impl FromStr for SaturationPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "reject" => Ok(SaturationPolicy::Reject),
+            "queue" => Ok(SaturationPolicy::Queue),
+            other => Err(format!("unknown policy {other}, expected reject or queue")),
+        }
+    }
+}
+
+impl std::fmt::Display for SaturationPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SaturationPolicy::Reject => write!(f, "reject"),
+            SaturationPolicy::Queue => write!(f, "queue"),
+        }
+    }
+}
+
+/// Bounds the number of archives in flight (`MAX_CONCURRENT_ARCHIVES`).
+#[derive(Debug)]
+pub struct ArchiveLimiter {
+    permits: Arc<Semaphore>,
+    policy: SaturationPolicy,
+    queue_timeout: Duration,
+    retry_after_secs: u64,
+}
+
+impl ArchiveLimiter {
+    pub fn new(config: &PermaConfig) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(config.max_concurrent_archives)),
+            policy: config.saturation_policy,
+            queue_timeout: Duration::from_millis(config.saturation_queue_timeout_ms),
+            retry_after_secs: config.retry_after_secs,
+        }
+    }
+
+    /// Take a permit for one archive, held until the returned guard is
+    /// dropped. The guard is owned so it can move into a spawned task.
+    pub async fn acquire(&self) -> Result<OwnedSemaphorePermit, EnclaveError> {
+        if let Ok(permit) = self.permits.clone().try_acquire_owned() {
+            return Ok(permit);
+        }
+        if self.policy == SaturationPolicy::Queue {
+            info!(
+                "Archive permits exhausted, queueing for up to {:?}",
+                self.queue_timeout
+            );
+            if let Ok(Ok(permit)) =
+                tokio::time::timeout(self.queue_timeout, self.permits.clone().acquire_owned()).await
+            {
+                return Ok(permit);
+            }
+        }
+        Err(EnclaveError::Unavailable {
+            reason: "too many archives in progress".to_string(),
+            retry_after_secs: self.retry_after_secs,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::http::{header, StatusCode};
+    use axum::response::IntoResponse;
+
+    fn limiter(policy: SaturationPolicy) -> ArchiveLimiter {
+        ArchiveLimiter::new(&PermaConfig {
+            max_concurrent_archives: 1,
+            saturation_policy: policy,
+            saturation_queue_timeout_ms: 200,
+            retry_after_secs: 7,
+            ..Default::default()
+        })
+    }
+
+    fn assert_unavailable(err: EnclaveError) {
+        let response = err.into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "7");
+    }
+
+    #[test]
+    fn test_parse_policy() {
+        assert_eq!("Queue".parse(), Ok(SaturationPolicy::Queue));
+        assert_eq!(" reject ".parse(), Ok(SaturationPolicy::Reject));
+        assert!("drop".parse::<SaturationPolicy>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reject_fails_fast_when_saturated() {
+        let limiter = limiter(SaturationPolicy::Reject);
+        let _held = limiter.acquire().await.unwrap();
+        let started = std::time::Instant::now();
+        let err = limiter.acquire().await.unwrap_err();
+        assert!(started.elapsed() < Duration::from_millis(100));
+        assert_unavailable(err);
+    }
+
+    #[tokio::test]
+    async fn test_queue_waits_for_released_permit() {
+        let limiter = limiter(SaturationPolicy::Queue);
+        let held = limiter.acquire().await.unwrap();
+        let (permit, _) = tokio::join!(limiter.acquire(), async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(held);
+        });
+        assert!(permit.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_queue_rejects_after_timeout() {
+        let limiter = limiter(SaturationPolicy::Queue);
+        let _held = limiter.acquire().await.unwrap();
+        let started = std::time::Instant::now();
+        let err = limiter.acquire().await.unwrap_err();
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert_unavailable(err);
+    }
+}
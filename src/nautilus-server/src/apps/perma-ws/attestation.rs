@@ -0,0 +1,579 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::config::{frontend_endpoint, required, PermaConfig};
+use super::outbound_log::log_outbound;
+use super::state::PermaState;
+use super::PermaResponse;
+use crate::common::{IntentMessage, ProcessedDataResponse};
+use crate::AppState;
+use crate::EnclaveError;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::info;
+
+/// Signed response as returned by process_data and stored by the frontend.
+pub type SignedPermaResponse = ProcessedDataResponse<IntentMessage<PermaResponse>>;
+
+/// A signed response the frontend failed to store, kept to be saved again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnpersistedAttestation {
+    pub reference_id: String,
+    pub attestation: SignedPermaResponse,
+    /// Unsigned client metadata to save with the attestation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_metadata: Option<Value>,
+    /// Last save error.
+    pub error: String,
+    pub failed_at_ms: u64,
+}
+
+/// Save the signed response with the frontend so it can be looked up by
+/// reference id, recording it in the attestation store first. Skipped when
+/// the client asked for an ephemeral response (`persist: false`). A failed
+/// save is kept in the unpersisted list for the background retry, and only
+/// fails the request without `ATTESTATION_FAIL_OPEN`. Returns whether the
+/// attestation was saved.
+pub async fn persist_attestation(
+    perma: &PermaState,
+    http: &reqwest::Client,
+    persist: bool,
+    reference_id: &str,
+    signed_response: &SignedPermaResponse,
+    client_metadata: Option<&Value>,
+) -> Result<bool, EnclaveError> {
+    if !persist {
+        info!(
+            "Skipping attestation save for {} (persist=false)",
+            reference_id
+        );
+        return Ok(false);
+    }
+    perma.attestation_store.put(reference_id, signed_response);
+
+    match save_attestation(
+        http,
+        &perma.config,
+        reference_id,
+        signed_response,
+        client_metadata,
+    )
+    .await
+    {
+        Ok(()) => Ok(true),
+        Err(e) => {
+            info!(
+                "Failed to save attestation for {}, keeping it as unpersisted: {}",
+                reference_id, e
+            );
+            perma.record_unpersisted(UnpersistedAttestation {
+                reference_id: reference_id.to_string(),
+                attestation: signed_response.clone(),
+                client_metadata: client_metadata.cloned(),
+                error: e.to_string(),
+                failed_at_ms: now_ms(),
+            });
+            if perma.config.attestation_fail_open {
+                Ok(false)
+            } else {
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Re-attempt saving every unpersisted attestation. The ones that fail again
+/// go back on the list. Returns the number of attestations saved.
+pub async fn retry_unpersisted(perma: &PermaState, http: &reqwest::Client) -> usize {
+    let mut saved = 0;
+    for mut pending in perma.take_unpersisted() {
+        match save_attestation(
+            http,
+            &perma.config,
+            &pending.reference_id,
+            &pending.attestation,
+            pending.client_metadata.as_ref(),
+        )
+        .await
+        {
+            Ok(()) => saved += 1,
+            Err(e) => {
+                pending.error = e.to_string();
+                perma.record_unpersisted(pending);
+            }
+        }
+    }
+    saved
+}
+
+/// Retry the unpersisted attestations every `period` in the background, so
+/// a frontend outage heals without calling `/admin/retry_unpersisted`.
+pub fn spawn_attestation_retry(state: Arc<AppState>, period: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(period);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            let pending = state.perma.unpersisted.lock().expect("lock poisoned").len();
+            if pending == 0 {
+                continue;
+            }
+            let saved = retry_unpersisted(&state.perma, &state.http).await;
+            info!(
+                "Background retry saved {} of {} unpersisted attestations",
+                saved, pending
+            );
+        }
+    })
+}
+
+/// Outcome of [`flush_unpersisted`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FlushOutcome {
+    pub flushed: usize,
+    pub dropped: usize,
+}
+
+/// Last attempt at saving every unpersisted attestation before the server
+/// exits, within `timeout` overall. The list only lives in memory, so the
+/// ones that fail again or are still pending at the deadline are dropped.
+pub async fn flush_unpersisted(
+    perma: &PermaState,
+    http: &reqwest::Client,
+    timeout: Duration,
+) -> FlushOutcome {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut outcome = FlushOutcome::default();
+    for pending in perma.take_unpersisted() {
+        let save = save_attestation(
+            http,
+            &perma.config,
+            &pending.reference_id,
+            &pending.attestation,
+            pending.client_metadata.as_ref(),
+        );
+        match tokio::time::timeout_at(deadline, save).await {
+            Ok(Ok(())) => outcome.flushed += 1,
+            Ok(Err(e)) => {
+                info!(
+                    "Dropping unpersisted attestation {}: {}",
+                    pending.reference_id, e
+                );
+                outcome.dropped += 1;
+            }
+            Err(_) => {
+                info!(
+                    "Dropping unpersisted attestation {}: flush timed out after {:?}",
+                    pending.reference_id, timeout
+                );
+                outcome.dropped += 1;
+            }
+        }
+    }
+    info!(
+        "Flushed unpersisted attestations on shutdown: {} flushed, {} dropped",
+        outcome.flushed, outcome.dropped
+    );
+    outcome
+}
+
+/// POST the signed response to the frontend's attestation API, with the
+/// client's metadata next to it. The metadata is not part of the signed
+/// response.
+pub async fn save_attestation(
+    http: &reqwest::Client,
+    config: &PermaConfig,
+    reference_id: &str,
+    signed_response: &SignedPermaResponse,
+    client_metadata: Option<&Value>,
+) -> Result<(), EnclaveError> {
+    let frontend_url = required(&config.frontend_url, "FRONTEND_URL")?;
+    let admin_secret = required(&config.admin_secret, "ADMIN_SECRET")?;
+
+    // save attestation - http://localhost:3001/api/attestation
+    let attestation_url = frontend_endpoint(frontend_url, "api/attestation")?;
+    let mut attestation_body = json!({
+        "admin_secret": admin_secret,
+        "reference_id": reference_id,
+        "attestation": signed_response
+    });
+    if let Some(client_metadata) = client_metadata {
+        attestation_body["client_metadata"] = client_metadata.clone();
+    }
+
+    info!("Saving attestation to: {}", attestation_url);
+
+    let send = http
+        .post(attestation_url.as_str())
+        .json(&attestation_body)
+        .send();
+    let attestation_res = log_outbound(
+        config.outbound_log_sample,
+        "attestation",
+        "POST",
+        attestation_url.as_str(),
+        send,
+    )
+    .await
+    .map_err(|e| EnclaveError::from_reqwest("Failed to save attestation", e))?;
+
+    if attestation_res.status() != reqwest::StatusCode::CREATED
+        && attestation_res.status() != reqwest::StatusCode::OK
+    {
+        return Err(EnclaveError::Upstream(format!(
+            "Failed to save attestation, status: {}",
+            attestation_res.status()
+        )));
+    }
+    Ok(())
+}
+
+pub(crate) fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// A response for `reference_id` signed with the test key, for the tests
+/// that only need some signed response.
+#[cfg(test)]
+pub(crate) fn test_signed_response(reference_id: &str) -> SignedPermaResponse {
+    crate::common::to_signed_response(
+        &AppState::for_test().eph_kp,
+        PermaResponse {
+            url: "https://example.com".to_string(),
+            reference_id: reference_id.to_string(),
+            screenshot_blob_id: "blob".to_string(),
+            screenshot_byte_size: 1,
+            full_page_max_height: None,
+            screenshot_provider: "screenshotone".to_string(),
+            tls_cert_fingerprint: None,
+            tls_cert_chain: vec![],
+            final_url: None,
+            redirect_chain_hash: None,
+            responsive_captures: vec![],
+            color_scheme: None,
+            canonical_url: None,
+            phash: None,
+            text_blob_id: None,
+            text_sha256: None,
+            data_content_type: None,
+            data_sha256: None,
+            wacz_blob_id: "wacz".to_string(),
+            nonce: None,
+        },
+        1744038900000,
+        crate::common::IntentScope::ProcessData,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::attestation_store::{AttestationStore, InMemoryAttestationStore};
+    use wiremock::matchers::{body_partial_json, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    async fn mount_frontend(server: &MockServer, status: u16, expected_posts: u64) {
+        Mock::given(method("POST"))
+            .and(path("/api/attestation"))
+            .respond_with(ResponseTemplate::new(status))
+            .expect(expected_posts)
+            .mount(server)
+            .await;
+    }
+
+    fn perma_state(server: &MockServer, fail_open: bool) -> PermaState {
+        PermaState::with_config(PermaConfig {
+            frontend_url: Some(server.uri()),
+            admin_secret: Some("admin".to_string()),
+            attestation_fail_open: fail_open,
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_persist_false_skips_attestation_post() {
+        let server = MockServer::start().await;
+        mount_frontend(&server, 201, 0).await;
+        let perma = perma_state(&server, false);
+        let persisted = persist_attestation(
+            &perma,
+            &reqwest::Client::new(),
+            false,
+            "ABCDEF-GH12",
+            &test_signed_response("ABCDEF-GH12"),
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(!persisted);
+        server.verify().await;
+    }
+
+    #[tokio::test]
+    async fn test_persist_posts_attestation() {
+        let server = MockServer::start().await;
+        mount_frontend(&server, 201, 1).await;
+        let perma = perma_state(&server, false);
+        let persisted = persist_attestation(
+            &perma,
+            &reqwest::Client::new(),
+            true,
+            "ABCDEF-GH12",
+            &test_signed_response("ABCDEF-GH12"),
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(persisted);
+        server.verify().await;
+    }
+
+    #[tokio::test]
+    async fn test_attestation_stored_before_frontend_save() {
+        let server = MockServer::start().await;
+        mount_frontend(&server, 500, 2).await;
+        let perma = perma_state(&server, false);
+        let http = reqwest::Client::new();
+        let signed = test_signed_response("ABCDEF-GH12");
+
+        // Recorded even though the frontend save failed.
+        assert!(
+            persist_attestation(&perma, &http, true, "ABCDEF-GH12", &signed, None)
+                .await
+                .is_err()
+        );
+        let stored = perma.attestation_store.get("ABCDEF-GH12").unwrap();
+        assert_eq!(stored.signature, signed.signature);
+
+        // Ephemeral responses are not.
+        persist_attestation(&perma, &http, false, "ZZZZZZ-ZZ12", &signed, None)
+            .await
+            .unwrap();
+        assert!(perma.attestation_store.get("ZZZZZZ-ZZ12").is_none());
+
+        // A custom store is used in place of the in-memory one.
+        let custom = InMemoryAttestationStore::new(1);
+        custom.put("OTHER-0001", &signed);
+        let perma = perma_state(&server, true).with_attestation_store(custom);
+        persist_attestation(&perma, &http, true, "ABCDEF-GH12", &signed, None)
+            .await
+            .unwrap();
+        assert_eq!(perma.attestation_store.len(), 1);
+        assert!(perma.attestation_store.get("OTHER-0001").is_none());
+        server.verify().await;
+    }
+
+    #[tokio::test]
+    async fn test_background_retry_drains_unpersisted() {
+        let server = MockServer::start().await;
+        mount_frontend(&server, 500, 1).await;
+        let state = Arc::new(AppState {
+            perma: perma_state(&server, true),
+            ..AppState::for_test()
+        });
+        persist_attestation(
+            &state.perma,
+            &state.http,
+            true,
+            "ABCDEF-GH12",
+            &test_signed_response("ABCDEF-GH12"),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(state.perma.unpersisted.lock().unwrap().len(), 1);
+
+        // The frontend comes back, the next tick saves the attestation.
+        server.reset().await;
+        mount_frontend(&server, 201, 1).await;
+        let task = spawn_attestation_retry(state.clone(), Duration::from_millis(50));
+        for _ in 0..50 {
+            if state.perma.unpersisted.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        task.abort();
+        assert!(state.perma.unpersisted.lock().unwrap().is_empty());
+        server.verify().await;
+    }
+
+    #[tokio::test]
+    async fn test_client_metadata_saved_unsigned() {
+        let server = MockServer::start().await;
+        let metadata = json!({ "job_id": "job-42", "user_id": 7 });
+        let signed = test_signed_response("ABCDEF-GH12");
+        Mock::given(method("POST"))
+            .and(path("/api/attestation"))
+            .and(body_partial_json(json!({
+                "client_metadata": metadata,
+                "attestation": { "signature": signed.signature },
+            })))
+            .respond_with(ResponseTemplate::new(201))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let perma = perma_state(&server, false);
+        let persisted = persist_attestation(
+            &perma,
+            &reqwest::Client::new(),
+            true,
+            "ABCDEF-GH12",
+            &signed,
+            Some(&metadata),
+        )
+        .await
+        .unwrap();
+        assert!(persisted);
+        server.verify().await;
+
+        // The metadata is outside the signed payload.
+        assert_eq!(
+            signed.signature,
+            test_signed_response("ABCDEF-GH12").signature
+        );
+        assert!(!serde_json::to_string(&signed).unwrap().contains("job-42"));
+    }
+
+    #[tokio::test]
+    async fn test_failed_save_without_fail_open_errors() {
+        let server = MockServer::start().await;
+        mount_frontend(&server, 500, 1).await;
+        let perma = perma_state(&server, false);
+        assert!(persist_attestation(
+            &perma,
+            &reqwest::Client::new(),
+            true,
+            "ABCDEF-GH12",
+            &test_signed_response("ABCDEF-GH12"),
+            None,
+        )
+        .await
+        .is_err());
+        // Still queued for the background retry.
+        assert_eq!(perma.unpersisted.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_unpersisted_recorded_and_retried() {
+        let server = MockServer::start().await;
+        mount_frontend(&server, 500, 1).await;
+        let perma = perma_state(&server, true);
+
+        let persisted = persist_attestation(
+            &perma,
+            &reqwest::Client::new(),
+            true,
+            "ABCDEF-GH12",
+            &test_signed_response("ABCDEF-GH12"),
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(!persisted);
+        {
+            let unpersisted = perma.unpersisted.lock().unwrap();
+            assert_eq!(unpersisted.len(), 1);
+            assert_eq!(unpersisted[0].reference_id, "ABCDEF-GH12");
+        }
+
+        server.reset().await;
+        mount_frontend(&server, 201, 1).await;
+        assert_eq!(retry_unpersisted(&perma, &reqwest::Client::new()).await, 1);
+        assert!(perma.unpersisted.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_flushes_unpersisted() {
+        let server = MockServer::start().await;
+        mount_frontend(&server, 500, 1).await;
+        let perma = perma_state(&server, true);
+        let http = reqwest::Client::new();
+        let persisted = persist_attestation(
+            &perma,
+            &http,
+            true,
+            "ABCDEF-GH12",
+            &test_signed_response("ABCDEF-GH12"),
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(!persisted);
+
+        // The frontend is back by the time the server shuts down.
+        server.reset().await;
+        mount_frontend(&server, 201, 1).await;
+        let outcome = flush_unpersisted(&perma, &http, Duration::from_secs(5)).await;
+        assert_eq!(
+            outcome,
+            FlushOutcome {
+                flushed: 1,
+                dropped: 0
+            }
+        );
+        assert!(perma.unpersisted.lock().unwrap().is_empty());
+        server.verify().await;
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_flush_drops_after_deadline() {
+        let server = MockServer::start().await;
+        mount_frontend(&server, 500, 1).await;
+        let perma = perma_state(&server, true);
+        let http = reqwest::Client::new();
+        persist_attestation(
+            &perma,
+            &http,
+            true,
+            "ABCDEF-GH12",
+            &test_signed_response("ABCDEF-GH12"),
+            None,
+        )
+        .await
+        .unwrap();
+
+        server.reset().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(201).set_delay(Duration::from_secs(2)))
+            .mount(&server)
+            .await;
+        let outcome = flush_unpersisted(&perma, &http, Duration::from_millis(100)).await;
+        assert_eq!(
+            outcome,
+            FlushOutcome {
+                flushed: 0,
+                dropped: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_unpersisted_capacity_evicts_oldest() {
+        let perma = PermaState::with_config(PermaConfig {
+            unpersisted_capacity: 2,
+            ..Default::default()
+        });
+        for reference_id in ["A-0001", "A-0002", "A-0003"] {
+            perma.record_unpersisted(UnpersistedAttestation {
+                reference_id: reference_id.to_string(),
+                attestation: test_signed_response("ABCDEF-GH12"),
+                client_metadata: None,
+                error: "down".to_string(),
+                failed_at_ms: 0,
+            });
+        }
+        let ids: Vec<String> = perma
+            .take_unpersisted()
+            .into_iter()
+            .map(|u| u.reference_id)
+            .collect();
+        assert_eq!(ids, vec!["A-0002", "A-0003"]);
+    }
+}
@@ -0,0 +1,96 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::attestation::SignedPermaResponse;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use tracing::info;
+
+/// Signed attestations by reference id, recorded before and independently of
+/// the frontend save so a capture isn't lost when the frontend is down.
+/// Implement it to keep them somewhere durable, see
+/// [`PermaState::with_attestation_store`](super::PermaState::with_attestation_store).
+pub trait AttestationStore: Send + Sync {
+    /// Record the attestation of `reference_id`, replacing any previous one.
+    fn put(&self, reference_id: &str, attestation: &SignedPermaResponse);
+
+    fn get(&self, reference_id: &str) -> Option<SignedPermaResponse>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Default store: the latest `capacity` attestations in memory, oldest
+/// evicted first. Lost on restart.
+#[derive(Debug)]
+pub struct InMemoryAttestationStore {
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    by_reference_id: HashMap<String, SignedPermaResponse>,
+    /// Reference ids, oldest first.
+    order: VecDeque<String>,
+}
+
+impl InMemoryAttestationStore {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::default(),
+        }
+    }
+}
+
+impl AttestationStore for InMemoryAttestationStore {
+    fn put(&self, reference_id: &str, attestation: &SignedPermaResponse) {
+        let mut entries = self.entries.lock().expect("lock poisoned");
+        let previous = entries
+            .by_reference_id
+            .insert(reference_id.to_string(), attestation.clone());
+        if previous.is_none() {
+            entries.order.push_back(reference_id.to_string());
+        }
+        while entries.order.len() > self.capacity {
+            if let Some(evicted) = entries.order.pop_front() {
+                info!("Evicting stored attestation {} over capacity", evicted);
+                entries.by_reference_id.remove(&evicted);
+            }
+        }
+    }
+
+    fn get(&self, reference_id: &str) -> Option<SignedPermaResponse> {
+        let entries = self.entries.lock().expect("lock poisoned");
+        entries.by_reference_id.get(reference_id).cloned()
+    }
+
+    fn len(&self) -> usize {
+        self.entries.lock().expect("lock poisoned").order.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::attestation::test_signed_response;
+
+    #[test]
+    fn test_in_memory_store_evicts_oldest() {
+        let store = InMemoryAttestationStore::new(2);
+        for reference_id in ["A-0001", "A-0002", "A-0001", "A-0003"] {
+            store.put(reference_id, &test_signed_response(reference_id));
+        }
+        assert_eq!(store.len(), 2);
+        assert!(store.get("A-0001").is_none());
+        assert_eq!(
+            store.get("A-0003").unwrap().response.data.reference_id,
+            "A-0003"
+        );
+        assert!(store.get("A-0002").is_some());
+    }
+}
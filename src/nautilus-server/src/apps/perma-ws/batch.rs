@@ -0,0 +1,157 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::attestation::SignedPermaResponse;
+use super::progress::Progress;
+use super::{admit, archive, PermaRequest};
+use crate::AppState;
+use crate::EnclaveError;
+use axum::extract::State;
+use axum::response::IntoResponse;
+use axum::Json;
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+/// Body of `/process_batch`.
+#[derive(Debug, Deserialize)]
+pub struct BatchRequest {
+    /// Pages to archive, at most `MAX_BATCH_SIZE`.
+    pub urls: Vec<String>,
+}
+
+/// Outcome of one URL of a batch, in the order of the request.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum BatchResult {
+    Signed(SignedPermaResponse),
+    Failed(BatchError),
+}
+
+/// Why one URL of a batch wasn't archived.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct BatchError {
+    pub url: String,
+    /// HTTP status `/process_data` would have answered with.
+    pub status: u16,
+    pub error: String,
+}
+
+impl BatchError {
+    fn new(url: String, error: EnclaveError) -> Self {
+        Self {
+            url,
+            error: error.to_string(),
+            status: error.into_response().status().as_u16(),
+        }
+    }
+}
+
+/// Archive every URL of the batch like `/process_data` with default
+/// options, `BATCH_CONCURRENCY` at a time, each still taking an archive
+/// permit. A failing URL doesn't fail the batch, its entry carries the error
+/// instead. Batches over `MAX_BATCH_SIZE` are refused with 400.
+pub async fn process_batch(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<BatchRequest>,
+) -> Result<Json<Vec<BatchResult>>, EnclaveError> {
+    let config = &state.perma.config;
+    if request.urls.is_empty() {
+        return Err(EnclaveError::BadRequest(
+            "urls must not be empty".to_string(),
+        ));
+    }
+    if request.urls.len() > config.max_batch_size {
+        return Err(EnclaveError::BadRequest(format!(
+            "batch of {} urls is over the limit of {}",
+            request.urls.len(),
+            config.max_batch_size
+        )));
+    }
+
+    let slots = Semaphore::new(config.batch_concurrency.max(1));
+    let results = join_all(request.urls.into_iter().map(|url| {
+        let state = &state;
+        let slots = &slots;
+        async move {
+            match archive_url(state, slots, url.clone()).await {
+                Ok(signed) => BatchResult::Signed(signed),
+                Err(e) => BatchResult::Failed(BatchError::new(url, e)),
+            }
+        }
+    }))
+    .await;
+    Ok(Json(results))
+}
+
+async fn archive_url(
+    state: &AppState,
+    slots: &Semaphore,
+    url: String,
+) -> Result<SignedPermaResponse, EnclaveError> {
+    let _slot = slots
+        .acquire()
+        .await
+        .map_err(|e| EnclaveError::Internal(format!("batch semaphore closed: {e}")))?;
+    let request = PermaRequest {
+        url,
+        ..PermaRequest::default()
+    };
+    let _permit = admit(state, &request).await?;
+    archive(state, request, &Progress::default()).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::{PermaConfig, PermaState};
+    use axum::http::StatusCode;
+
+    fn state(max_batch_size: usize) -> Arc<AppState> {
+        Arc::new(AppState {
+            perma: PermaState::with_config(PermaConfig {
+                max_batch_size,
+                ..PermaConfig::default()
+            }),
+            ..AppState::for_test()
+        })
+    }
+
+    fn batch(urls: &[&str]) -> Json<BatchRequest> {
+        Json(BatchRequest {
+            urls: urls.iter().map(|u| u.to_string()).collect(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_failed_urls_do_not_fail_the_batch() {
+        let Json(results) = process_batch(State(state(3)), batch(&["ftp://a", "not a url"]))
+            .await
+            .unwrap();
+        let urls: Vec<_> = results
+            .iter()
+            .map(|result| match result {
+                BatchResult::Failed(e) => {
+                    assert_eq!(e.status, StatusCode::BAD_REQUEST.as_u16());
+                    e.url.as_str()
+                }
+                BatchResult::Signed(_) => panic!("invalid url was archived"),
+            })
+            .collect();
+        assert_eq!(urls, ["ftp://a", "not a url"]);
+    }
+
+    #[tokio::test]
+    async fn test_batch_size_is_bounded() {
+        let urls = ["https://example.com"; 3];
+        assert!(matches!(
+            process_batch(State(state(2)), batch(&urls)).await,
+            Err(EnclaveError::BadRequest(_))
+        ));
+        assert!(matches!(
+            process_batch(State(state(2)), batch(&[])).await,
+            Err(EnclaveError::BadRequest(_))
+        ));
+    }
+}
@@ -0,0 +1,238 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::attestation::{now_ms, SignedPermaResponse};
+use super::retry::backoff_delay;
+use super::target_http::TargetHttp;
+use crate::EnclaveError;
+use fastcrypto::encoding::{Encoding, Hex};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::info;
+
+/// Header carrying the HMAC of a callback, see [`callback_signature`].
+pub const CALLBACK_SIGNATURE_HEADER: &str = "X-Perma-Signature";
+
+/// Header carrying the time a callback was signed at, in milliseconds.
+pub const CALLBACK_TIMESTAMP_HEADER: &str = "X-Perma-Timestamp";
+
+/// Attempts per callback before giving up.
+pub const CALLBACK_MAX_ATTEMPTS: u32 = 3;
+
+/// Timeout of each callback attempt.
+const CALLBACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Hex HMAC-SHA256, keyed with `CALLBACK_SECRET`, of `{timestamp}\n{body}`.
+/// Integrators recompute it from the raw body and the timestamp header, and
+/// check the timestamp is recent to refuse replays.
+pub fn callback_signature(secret: &str, timestamp_ms: u64, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(format!("{timestamp_ms}\n").as_bytes());
+    mac.update(body);
+    Hex::encode(mac.finalize().into_bytes())
+}
+
+/// POST the signed response as JSON to the request's `callback_url`, signed
+/// with [`callback_signature`]. Connection errors, 429 and 5xx answers are
+/// retried up to `max_attempts` times with backoff, other answers are final.
+/// Redirects are not followed, and a URL whose host resolves to a private
+/// address is refused, see [`TargetHttp::check`], so a callback can't be
+/// replayed against the enclave's own admin API.
+pub async fn deliver_callback(
+    http: &TargetHttp,
+    callback_url: &str,
+    secret: &str,
+    signed: &SignedPermaResponse,
+    max_attempts: u32,
+) -> Result<(), EnclaveError> {
+    let url = reqwest::Url::parse(callback_url).map_err(|e| {
+        EnclaveError::BadRequest(format!("invalid callback url {callback_url}: {e}"))
+    })?;
+    http.check(&url).await?;
+    let body = serde_json::to_vec(signed)
+        .map_err(|e| EnclaveError::Internal(format!("Failed to encode the callback: {e}")))?;
+    let mut attempt = 1;
+    loop {
+        let timestamp_ms = now_ms();
+        let result = http
+            .client()
+            .post(url.clone())
+            .timeout(CALLBACK_TIMEOUT)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(CALLBACK_TIMESTAMP_HEADER, timestamp_ms.to_string())
+            .header(
+                CALLBACK_SIGNATURE_HEADER,
+                callback_signature(secret, timestamp_ms, &body),
+            )
+            .body(body.clone())
+            .send()
+            .await;
+        let error = match result {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response) => {
+                let status = response.status();
+                let error = EnclaveError::Upstream(format!("Callback answered {status}"));
+                if !(status.is_server_error() || status.as_u16() == 429) {
+                    return Err(error);
+                }
+                error
+            }
+            Err(e) => EnclaveError::from_reqwest("Failed to deliver the callback", e.without_url()),
+        };
+        if attempt >= max_attempts {
+            return Err(error);
+        }
+        let delay = backoff_delay(attempt);
+        info!(
+            "Retrying callback after attempt {} failed in {:?}: {}",
+            attempt, delay, error
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Deliver the callback in the background, so the client gets its response
+/// without waiting on the integrator's endpoint. A failure is only logged.
+pub fn spawn_callback(
+    http: TargetHttp,
+    callback_url: String,
+    secret: String,
+    signed: SignedPermaResponse,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let reference_id = &signed.response.data.reference_id;
+        match deliver_callback(
+            &http,
+            &callback_url,
+            &secret,
+            &signed,
+            CALLBACK_MAX_ATTEMPTS,
+        )
+        .await
+        {
+            Ok(()) => info!("Delivered callback for {}", reference_id),
+            Err(e) => info!("Failed to deliver callback for {}: {}", reference_id, e),
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::attestation::test_signed_response;
+    use wiremock::matchers::{header_exists, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn http(server: &MockServer) -> TargetHttp {
+        TargetHttp::allowing(&[&server.address().to_string()])
+    }
+
+    #[test]
+    fn test_callback_signature() {
+        let signature = callback_signature("callback", 1744038900000, b"{}");
+        assert_eq!(signature.len(), 64);
+        assert_ne!(signature, callback_signature("other", 1744038900000, b"{}"));
+        assert_ne!(
+            signature,
+            callback_signature("callback", 1744038900001, b"{}")
+        );
+        assert_ne!(
+            signature,
+            callback_signature("callback", 1744038900000, b"[]")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_callback_is_signed_and_retried() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(header_exists(CALLBACK_SIGNATURE_HEADER))
+            .respond_with(ResponseTemplate::new(204))
+            .mount(&server)
+            .await;
+
+        let signed = test_signed_response("ABCDEF-GH12");
+        deliver_callback(&http(&server), &server.uri(), "callback", &signed, 3)
+            .await
+            .unwrap();
+
+        let requests = server.received_requests().await.unwrap();
+        assert_eq!(requests.len(), 2);
+        let request = &requests[1];
+        let timestamp: u64 = request.headers[CALLBACK_TIMESTAMP_HEADER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(
+            request.headers[CALLBACK_SIGNATURE_HEADER].to_str().unwrap(),
+            callback_signature("callback", timestamp, &request.body)
+        );
+        let delivered: SignedPermaResponse = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(delivered.signature, signed.signature);
+    }
+
+    #[tokio::test]
+    async fn test_callback_client_error_is_final() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&server)
+            .await;
+
+        let result = deliver_callback(
+            &http(&server),
+            &server.uri(),
+            "callback",
+            &test_signed_response("ABCDEF-GH12"),
+            3,
+        )
+        .await;
+        assert!(matches!(result, Err(EnclaveError::Upstream(_))));
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_callback_redirect_is_not_followed() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(307)
+                    .insert_header("location", "http://127.0.0.1:3001/admin/retry_unpersisted"),
+            )
+            .mount(&server)
+            .await;
+
+        let result = deliver_callback(
+            &http(&server),
+            &server.uri(),
+            "callback",
+            &test_signed_response("ABCDEF-GH12"),
+            3,
+        )
+        .await;
+        assert!(matches!(result, Err(EnclaveError::Upstream(_))));
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+
+        // Without the mock server's exemption its loopback host is refused.
+        let result = deliver_callback(
+            &TargetHttp::new(),
+            &server.uri(),
+            "callback",
+            &test_signed_response("ABCDEF-GH12"),
+            3,
+        )
+        .await;
+        assert!(matches!(result, Err(EnclaveError::BadRequest(_))));
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,260 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::target_http::TargetHttp;
+use crate::EnclaveError;
+use reqwest::Url;
+use std::net::IpAddr;
+use tracing::info;
+
+/// Bytes of the page read looking for the canonical link, which belongs in
+/// the `<head>`.
+const MAX_CANONICAL_SCAN_BYTES: usize = 512 * 1024;
+
+/// Fetch `url` and return the page's `<link rel="canonical">` when it differs
+/// from `url`. The canonical URL must be same-origin with `url`, and neither
+/// `url`, its redirects nor the canonical URL may be a private host, so the
+/// preflight can't be pointed at the enclave's surroundings.
+pub async fn resolve_canonical_url(
+    http: &TargetHttp,
+    url: &str,
+) -> Result<Option<Url>, EnclaveError> {
+    let requested =
+        Url::parse(url).map_err(|e| EnclaveError::BadRequest(format!("invalid url {url}: {e}")))?;
+    let mut response = http.get(requested.as_str()).await?;
+    let mut html = Vec::new();
+    while html.len() < MAX_CANONICAL_SCAN_BYTES {
+        match response.chunk().await {
+            Ok(Some(chunk)) => html.extend_from_slice(&chunk),
+            Ok(None) => break,
+            Err(e) => {
+                return Err(EnclaveError::Upstream(format!(
+                    "Failed to read {url} for its canonical link: {e}"
+                )));
+            }
+        }
+    }
+
+    let Some(href) = find_canonical_link(&String::from_utf8_lossy(&html)) else {
+        return Ok(None);
+    };
+    let canonical = requested.join(&href).map_err(|e| {
+        EnclaveError::Upstream(format!(
+            "{url} declares an invalid canonical link {href}: {e}"
+        ))
+    })?;
+    check_canonical(&requested, &canonical, http.is_exempt(&requested))?;
+    if canonical == requested {
+        return Ok(None);
+    }
+    info!("Canonical URL of {} is {}", url, canonical);
+    Ok(Some(canonical))
+}
+
+/// Reject a canonical URL on another origin than the requested one, or on a
+/// private host.
+pub fn check_canonical(
+    requested: &Url,
+    canonical: &Url,
+    allow_private_hosts: bool,
+) -> Result<(), EnclaveError> {
+    if canonical.origin() != requested.origin() {
+        return Err(EnclaveError::BadRequest(format!(
+            "canonical url {canonical} is not same-origin with {requested}"
+        )));
+    }
+    if !allow_private_hosts && is_private_host(canonical) {
+        return Err(EnclaveError::BadRequest(format!(
+            "canonical url {canonical} points to a private host"
+        )));
+    }
+    Ok(())
+}
+
+/// Whether the URL's host is a loopback, private, link-local or unspecified
+/// address, or a name reserved for local use.
+pub fn is_private_host(url: &Url) -> bool {
+    let Some(host) = url.host_str() else {
+        return true;
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    match host.parse::<IpAddr>() {
+        Ok(ip) => is_private_ip(ip),
+        Err(_) => {
+            let host = host.trim_end_matches('.').to_ascii_lowercase();
+            host == "localhost"
+                || [".localhost", ".local", ".internal"]
+                    .iter()
+                    .any(|suffix| host.ends_with(suffix))
+        }
+    }
+}
+
+/// Whether `ip` is a loopback, private, link-local, unspecified or broadcast
+/// address, including IPv4 ones mapped into IPv6.
+pub fn is_private_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+        }
+        IpAddr::V6(ip) => {
+            let segment = ip.segments()[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || (segment & 0xfe00) == 0xfc00 // unique local
+                || (segment & 0xffc0) == 0xfe80 // link-local
+                || ip
+                    .to_ipv4_mapped()
+                    .is_some_and(|v4| is_private_ip(IpAddr::V4(v4)))
+        }
+    }
+}
+
+/// The `href` of the first `<link>` whose `rel` includes `canonical`.
+pub fn find_canonical_link(html: &str) -> Option<String> {
+    let lower = html.to_ascii_lowercase();
+    let mut from = 0;
+    while let Some(start) = lower[from..].find("<link").map(|i| from + i) {
+        let end = lower[start..].find('>').map_or(lower.len(), |i| start + i);
+        let attributes = tag_attributes(&html[start + "<link".len()..end]);
+        let is_canonical = attributes.iter().any(|(name, value)| {
+            name == "rel"
+                && value
+                    .split_ascii_whitespace()
+                    .any(|rel| rel.eq_ignore_ascii_case("canonical"))
+        });
+        if is_canonical {
+            if let Some((_, href)) = attributes.into_iter().find(|(name, _)| name == "href") {
+                let href = href.trim();
+                if !href.is_empty() {
+                    return Some(href.to_string());
+                }
+            }
+        }
+        from = end;
+    }
+    None
+}
+
+/// Attributes of a tag body, names lowercased, values unquoted.
+fn tag_attributes(tag: &str) -> Vec<(String, String)> {
+    let mut attributes = Vec::new();
+    let mut rest = tag.trim_start();
+    while !rest.is_empty() {
+        let name_end = rest
+            .find(|c: char| c.is_whitespace() || c == '=' || c == '/')
+            .unwrap_or(rest.len());
+        let name = rest[..name_end].to_ascii_lowercase();
+        rest = rest[name_end..].trim_start();
+        let mut value = String::new();
+        if let Some(after_eq) = rest.strip_prefix('=') {
+            let after_eq = after_eq.trim_start();
+            let (parsed, remaining) = match after_eq.chars().next() {
+                Some(quote @ ('"' | '\'')) => {
+                    let body = &after_eq[1..];
+                    let close = body.find(quote).unwrap_or(body.len());
+                    (&body[..close], body.get(close + 1..).unwrap_or_default())
+                }
+                _ => {
+                    let close = after_eq.find(char::is_whitespace).unwrap_or(after_eq.len());
+                    (&after_eq[..close], &after_eq[close..])
+                }
+            };
+            value = parsed.to_string();
+            rest = remaining;
+        }
+        if !name.is_empty() {
+            attributes.push((name, value));
+        }
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == '/');
+    }
+    attributes
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_canonical_link_is_used() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/amp/article"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r#"<html><head><link rel="amphtml" href="/amp/article">
+                <LINK REL='Canonical' HREF='/article'/></head></html>"#,
+            ))
+            .mount(&server)
+            .await;
+
+        let http = TargetHttp::allowing(&[&server.address().to_string()]);
+        let requested = format!("{}/amp/article?utm_source=x", server.uri());
+        let canonical = resolve_canonical_url(&http, &requested).await.unwrap();
+        assert_eq!(canonical, Some(url(&format!("{}/article", server.uri()))));
+
+        // Not exempted, the loopback mock server is refused.
+        assert!(resolve_canonical_url(&TargetHttp::new(), &requested)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_page_without_canonical() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("<html></html>"))
+            .mount(&server)
+            .await;
+        let http = TargetHttp::allowing(&[&server.address().to_string()]);
+        let canonical = resolve_canonical_url(&http, &server.uri()).await.unwrap();
+        assert_eq!(canonical, None);
+    }
+
+    #[test]
+    fn test_check_canonical() {
+        let requested = url("https://example.com/amp/a");
+        assert!(check_canonical(&requested, &url("https://example.com/a"), false).is_ok());
+        for rejected in [
+            "https://evil.example/a",
+            "http://example.com/a",
+            "https://example.com:8443/a",
+        ] {
+            assert!(check_canonical(&requested, &url(rejected), false).is_err());
+        }
+        assert!(is_private_host(&url("http://127.0.0.1/")));
+        assert!(is_private_host(&url("http://169.254.169.254/latest")));
+        assert!(is_private_host(&url("http://[::1]/")));
+        assert!(is_private_host(&url("http://metadata.internal/")));
+        assert!(!is_private_host(&url("https://example.com/")));
+    }
+
+    #[test]
+    fn test_find_canonical_link() {
+        assert_eq!(
+            find_canonical_link(r#"<link href="https://a.com/x" rel="alternate canonical">"#),
+            Some("https://a.com/x".to_string())
+        );
+        assert_eq!(
+            find_canonical_link("<link rel=canonical href=/x>"),
+            Some("/x".to_string())
+        );
+        assert_eq!(
+            find_canonical_link(r#"<link rel="stylesheet" href="/s.css">"#),
+            None
+        );
+        assert_eq!(
+            find_canonical_link(r#"<link rel="canonical" href="">"#),
+            None
+        );
+    }
+}
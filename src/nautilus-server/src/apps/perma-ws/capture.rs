@@ -0,0 +1,1302 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::config::PermaConfig;
+use super::outbound_log::log_outbound;
+use super::progress::{ArchiveStage, Progress};
+use super::reference_id::storage_key;
+use super::retry::{backoff_delay, send_with_retry, Stage};
+use super::storage::blob_id_from_etag;
+use super::validation::{first_error, FieldError, FieldErrors};
+use crate::common::read_body_limited;
+use crate::http_util::{parse_content_range_total, parse_retry_after};
+use crate::EnclaveError;
+use fastcrypto::encoding::{Encoding, Hex};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::ops::RangeInclusive;
+use std::time::Duration;
+use tracing::info;
+
+/// ScreenshotOne capture endpoint.
+pub const SCREENSHOTONE_API_URL: &str = "https://api.screenshotone.com/take";
+/// ScreenshotOne usage endpoint, probed by the deep health check as it
+/// checks the access key without taking a capture.
+pub const SCREENSHOTONE_USAGE_URL: &str = "https://api.screenshotone.com/usage";
+/// S3-compatible storage ScreenshotOne uploads captures to.
+pub const STORAGE_ENDPOINT: &str = "https://storage.nami.cloud";
+pub const STORAGE_BUCKET: &str = "perma-ws";
+
+/// Prefix of a screenshot blob id derived from the image bytes, for stores
+/// that expose no ETag, so it can't be mistaken for a Walrus blob id.
+pub const SHA256_BLOB_ID_PREFIX: &str = "sha256:";
+
+/// Largest stored screenshot downloaded to derive its blob id from.
+pub const MAX_CHECKSUM_BYTES: usize = 20 * 1024 * 1024;
+
+/// Storage key of an archive's screenshot. ScreenshotOne stores it under
+/// `{reference_id}/{reference_id}` and appends the format extension.
+pub fn screenshot_storage_key(reference_id: &str, format: ScreenshotFormat) -> String {
+    format!("{}.{}", storage_key(reference_id), format.extension())
+}
+
+/// Most widths a request may ask to capture in `responsive_widths`.
+pub const MAX_RESPONSIVE_WIDTHS: usize = 4;
+
+/// Output format of a capture, as named by ScreenshotOne.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScreenshotFormat {
+    #[default]
+    Png,
+    Jpeg,
+    Webp,
+    Pdf,
+}
+
+impl ScreenshotFormat {
+    pub const ALL: [ScreenshotFormat; 4] = [
+        ScreenshotFormat::Png,
+        ScreenshotFormat::Jpeg,
+        ScreenshotFormat::Webp,
+        ScreenshotFormat::Pdf,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScreenshotFormat::Png => "png",
+            ScreenshotFormat::Jpeg => "jpeg",
+            ScreenshotFormat::Webp => "webp",
+            ScreenshotFormat::Pdf => "pdf",
+        }
+    }
+
+    /// File extension ScreenshotOne gives stored captures.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ScreenshotFormat::Jpeg => "jpg",
+            other => other.as_str(),
+        }
+    }
+}
+
+impl std::str::FromStr for ScreenshotFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_ascii_lowercase();
+        Self::ALL
+            .into_iter()
+            .find(|f| f.as_str() == s || (s == "jpg" && *f == ScreenshotFormat::Jpeg))
+            .ok_or_else(|| format!("unknown screenshot format {s}"))
+    }
+}
+
+/// Preferred color scheme the page is rendered with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorScheme {
+    Light,
+    Dark,
+    NoPreference,
+}
+
+impl ColorScheme {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ColorScheme::Light => "light",
+            ColorScheme::Dark => "dark",
+            ColorScheme::NoPreference => "no_preference",
+        }
+    }
+
+    /// ScreenshotOne `dark_mode` value, `None` leaving the provider default.
+    pub fn dark_mode(&self) -> Option<bool> {
+        match self {
+            ColorScheme::Light => Some(false),
+            ColorScheme::Dark => Some(true),
+            ColorScheme::NoPreference => None,
+        }
+    }
+}
+
+/// Parse a comma-separated list of formats, e.g. `ALLOWED_FORMATS`.
+pub fn parse_formats(value: &str) -> Result<Vec<ScreenshotFormat>, EnclaveError> {
+    let formats = value
+        .split(',')
+        .filter(|f| !f.trim().is_empty())
+        .map(|f| f.parse())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| EnclaveError::GenericError(format!("Invalid ALLOWED_FORMATS: {e}")))?;
+    if formats.is_empty() {
+        return Err(EnclaveError::GenericError(
+            "ALLOWED_FORMATS must name at least one format".to_string(),
+        ));
+    }
+    Ok(formats)
+}
+
+/// Optional per-request capture settings. Flattened into PermaRequest, so
+/// the fields sit next to `url` in the request JSON.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct CaptureOptions {
+    /// Maximum height in pixels of the full-page capture.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub full_page_max_height: Option<u32>,
+    /// Viewport size in pixels, bounded by the server's [`ViewportBounds`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub viewport_width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub viewport_height: Option<u32>,
+    /// Output format, restricted by `ALLOWED_FORMATS`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<ScreenshotFormat>,
+    /// Viewport widths to also capture the page at, one extra capture each,
+    /// e.g. `[375, 768, 1440]` for phone, tablet and desktop.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub responsive_widths: Option<Vec<u32>>,
+    /// Compression quality of jpeg and webp captures, 1 to 100. Defaults to
+    /// [`DEFAULT_IMAGE_QUALITY`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_quality: Option<u32>,
+    /// Color scheme to render the page in. Leaves the provider default when
+    /// omitted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color_scheme: Option<ColorScheme>,
+    /// Capture the whole scrolling page, defaults to true. False captures
+    /// the viewport only, and `full_page_max_height` doesn't apply.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub full_page: Option<bool>,
+    /// Wait before capturing, e.g. for pages that load content lazily, at
+    /// most [`MAX_DELAY_MS`]. Rounded up to whole seconds. Defaults to 0.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delay_ms: Option<u32>,
+    /// Hide ads on the captured page, defaults to true.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block_ads: Option<bool>,
+}
+
+/// Image quality used when the request doesn't set one.
+pub const DEFAULT_IMAGE_QUALITY: u32 = 80;
+
+/// Longest `delay_ms` a request may ask for, well within the 60s
+/// ScreenshotOne timeout.
+pub const MAX_DELAY_MS: u32 = 30_000;
+
+/// Accepted viewport dimensions in pixels (`VIEWPORT_MIN_WIDTH`,
+/// `VIEWPORT_MAX_WIDTH`, `VIEWPORT_MIN_HEIGHT`, `VIEWPORT_MAX_HEIGHT`).
+#[derive(Debug, Clone)]
+pub struct ViewportBounds {
+    pub width: RangeInclusive<u32>,
+    pub height: RangeInclusive<u32>,
+}
+
+impl Default for ViewportBounds {
+    fn default() -> Self {
+        Self {
+            width: 320..=3840,
+            height: 240..=4320,
+        }
+    }
+}
+
+impl CaptureOptions {
+    /// Validate the options against the server configuration and fill in the
+    /// server defaults, giving the options the capture is taken with.
+    pub fn resolve(&self, config: &PermaConfig) -> Result<CaptureOptions, EnclaveError> {
+        self.resolve_fields(config).map_err(first_error)
+    }
+
+    /// Same as [`CaptureOptions::resolve`], reporting every invalid field
+    /// instead of the first one.
+    pub fn resolve_fields(&self, config: &PermaConfig) -> Result<CaptureOptions, Vec<FieldError>> {
+        let bounds = &config.viewport_bounds;
+        let mut errors = FieldErrors::default();
+        errors.check(
+            "viewport_width",
+            validate_dimension("viewport_width", self.viewport_width, &bounds.width),
+        );
+        errors.check(
+            "viewport_height",
+            validate_dimension("viewport_height", self.viewport_height, &bounds.height),
+        );
+        let full_page_max_height = errors.check(
+            "full_page_max_height",
+            self.effective_full_page_max_height(config.max_full_page_height),
+        );
+        errors.check("delay_ms", self.validate_delay());
+        let format = errors.check(
+            "format",
+            self.effective_format(config.allowed_formats.as_deref()),
+        );
+        let responsive_widths = errors.check(
+            "responsive_widths",
+            self.validated_responsive_widths(bounds),
+        );
+        errors.check("image_quality", self.validate_image_quality());
+        errors.into_result()?;
+
+        Ok(CaptureOptions {
+            full_page_max_height: full_page_max_height
+                .flatten()
+                .filter(|_| self.is_full_page()),
+            format,
+            responsive_widths: responsive_widths.flatten(),
+            ..self.clone()
+        })
+    }
+
+    /// Reject an image quality outside 1 to 100.
+    pub fn validate_image_quality(&self) -> Result<(), EnclaveError> {
+        match self.image_quality {
+            Some(quality) if !(1..=100).contains(&quality) => Err(EnclaveError::BadRequest(
+                format!("image_quality {quality} is outside the allowed range 1..=100"),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Reject a delay over [`MAX_DELAY_MS`].
+    pub fn validate_delay(&self) -> Result<(), EnclaveError> {
+        match self.delay_ms {
+            Some(delay) if delay > MAX_DELAY_MS => Err(EnclaveError::BadRequest(format!(
+                "delay_ms {delay} is outside the allowed range 0..={MAX_DELAY_MS}"
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the whole page is captured rather than the viewport.
+    pub fn is_full_page(&self) -> bool {
+        self.full_page.unwrap_or(true)
+    }
+
+    /// Check the responsive widths against the viewport bounds and
+    /// [`MAX_RESPONSIVE_WIDTHS`], returning them sorted.
+    pub fn validated_responsive_widths(
+        &self,
+        bounds: &ViewportBounds,
+    ) -> Result<Option<Vec<u32>>, EnclaveError> {
+        let Some(widths) = &self.responsive_widths else {
+            return Ok(None);
+        };
+        if widths.is_empty() || widths.len() > MAX_RESPONSIVE_WIDTHS {
+            return Err(EnclaveError::BadRequest(format!(
+                "responsive_widths must list 1 to {MAX_RESPONSIVE_WIDTHS} widths"
+            )));
+        }
+        let mut sorted = widths.clone();
+        sorted.sort_unstable();
+        sorted.dedup();
+        if sorted.len() != widths.len() {
+            return Err(EnclaveError::BadRequest(
+                "responsive_widths must not repeat a width".to_string(),
+            ));
+        }
+        if let Some(width) = sorted.iter().find(|w| !bounds.width.contains(w)) {
+            return Err(EnclaveError::BadRequest(format!(
+                "responsive width {width} is outside the allowed range {}..={}",
+                bounds.width.start(),
+                bounds.width.end()
+            )));
+        }
+        Ok(Some(sorted))
+    }
+
+    /// Check the requested format against the allowed ones. Without a request
+    /// the default format is used, or the first allowed one if the default
+    /// isn't allowed. `None` allows every format.
+    pub fn effective_format(
+        &self,
+        allowed: Option<&[ScreenshotFormat]>,
+    ) -> Result<ScreenshotFormat, EnclaveError> {
+        let Some(allowed) = allowed else {
+            return Ok(self.format.unwrap_or_default());
+        };
+        match self.format {
+            Some(format) if allowed.contains(&format) => Ok(format),
+            Some(format) => Err(EnclaveError::BadRequest(format!(
+                "format {} is not allowed, allowed formats: {}",
+                format.as_str(),
+                allowed
+                    .iter()
+                    .map(|f| f.as_str())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ))),
+            None if allowed.contains(&ScreenshotFormat::default()) => {
+                Ok(ScreenshotFormat::default())
+            }
+            None => allowed.first().copied().ok_or_else(|| {
+                EnclaveError::GenericError("no screenshot format is allowed".to_string())
+            }),
+        }
+    }
+
+    /// Reject viewport dimensions outside the configured bounds.
+    pub fn validate_viewport(&self, bounds: &ViewportBounds) -> Result<(), EnclaveError> {
+        validate_dimension("viewport_width", self.viewport_width, &bounds.width)?;
+        validate_dimension("viewport_height", self.viewport_height, &bounds.height)
+    }
+
+    /// Resolve the full-page max height against the server ceiling. A value
+    /// above the ceiling is rejected, a missing value falls back to the ceiling.
+    pub fn effective_full_page_max_height(
+        &self,
+        ceiling: Option<u32>,
+    ) -> Result<Option<u32>, EnclaveError> {
+        if !self.is_full_page() && self.full_page_max_height.is_some() {
+            return Err(EnclaveError::BadRequest(
+                "full_page_max_height only applies to full_page captures".to_string(),
+            ));
+        }
+        match (self.full_page_max_height, ceiling) {
+            (Some(0), _) => Err(EnclaveError::BadRequest(
+                "full_page_max_height must be greater than 0".to_string(),
+            )),
+            (Some(height), Some(ceiling)) if height > ceiling => Err(EnclaveError::BadRequest(
+                format!("full_page_max_height {height} exceeds the server maximum of {ceiling}"),
+            )),
+            (Some(height), _) => Ok(Some(height)),
+            (None, ceiling) => Ok(ceiling),
+        }
+    }
+}
+
+/// Reject a dimension outside `range`, `name` being the request field.
+fn validate_dimension(
+    name: &str,
+    value: Option<u32>,
+    range: &RangeInclusive<u32>,
+) -> Result<(), EnclaveError> {
+    match value {
+        Some(value) if !range.contains(&value) => Err(EnclaveError::BadRequest(format!(
+            "{name} {value} is outside the allowed range {}..={}",
+            range.start(),
+            range.end()
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Everything needed to build a ScreenshotOne capture URL.
+pub struct ScreenshotOneParams<'a> {
+    pub http: &'a reqwest::Client,
+    /// Capture endpoint, `SCREENSHOTONE_URL`.
+    pub api_url: &'a str,
+    pub access_key: &'a str,
+    pub url: &'a str,
+    /// Bucket the image is stored in, see [`StorageTarget`].
+    ///
+    /// [`StorageTarget`]: super::storage::StorageTarget
+    pub storage_bucket: &'a str,
+    /// Already percent-encoded storage key.
+    pub storage_path: &'a str,
+    pub storage_access_key_id: &'a str,
+    pub storage_secret_access_key: &'a str,
+    /// Options resolved against the server configuration.
+    pub options: &'a CaptureOptions,
+    /// Attempts per upstream call, see [`send_with_retry`].
+    pub upstream_max_attempts: u32,
+    /// Retries of the ScreenshotOne call, see
+    /// [`call_screenshotone_with_retry`].
+    pub screenshotone_max_retries: u32,
+    /// See [`log_outbound`].
+    pub outbound_log_sample: u64,
+    /// Largest ScreenshotOne answer read, see [`read_body_limited`].
+    pub max_response_bytes: usize,
+}
+
+/// Build the ScreenshotOne `take` URL that captures the page and stores the
+/// image at `storage_path` in `storage_bucket`.
+pub fn build_screenshotone_url(params: &ScreenshotOneParams) -> String {
+    let mut screenshotone_url = format!(
+        "{}?\
+        access_key={}&\
+        url={}&\
+        format={}&\
+        block_ads={}&\
+        block_cookie_banners=true&\
+        block_banners_by_heuristics=true&\
+        block_trackers=true&\
+        block_chats=true&\
+        delay={}&\
+        timeout=60&\
+        storage_acl=public-read&\
+        store=true&\
+        storage_bucket={}&\
+        storage_path={}&\
+        storage_endpoint={}&\
+        storage_return_location=true&\
+        storage_access_key_id={}&\
+        storage_secret_access_key={}&\
+        capture_beyond_viewport=true&\
+        response_type=json&\
+        full_page={}&\
+        image_quality={}",
+        params.api_url,
+        params.access_key,
+        urlencoding::encode(params.url),
+        params.options.format.unwrap_or_default().as_str(),
+        params.options.block_ads.unwrap_or(true),
+        params.options.delay_ms.unwrap_or(0).div_ceil(1000),
+        urlencoding::encode(params.storage_bucket),
+        params.storage_path,
+        urlencoding::encode(STORAGE_ENDPOINT),
+        params.storage_access_key_id,
+        params.storage_secret_access_key,
+        params.options.is_full_page(),
+        params
+            .options
+            .image_quality
+            .unwrap_or(DEFAULT_IMAGE_QUALITY),
+    );
+    let options = params.options;
+    if options.is_full_page() {
+        screenshotone_url.push_str("&full_page_scroll=true&full_page_scroll_delay=500");
+    }
+    if let Some(height) = options.full_page_max_height {
+        screenshotone_url.push_str(&format!("&full_page_max_height={height}"));
+    }
+    if let Some(width) = options.viewport_width {
+        screenshotone_url.push_str(&format!("&viewport_width={width}"));
+    }
+    if let Some(height) = options.viewport_height {
+        screenshotone_url.push_str(&format!("&viewport_height={height}"));
+    }
+    if let Some(dark_mode) = options.color_scheme.and_then(|s| s.dark_mode()) {
+        screenshotone_url.push_str(&format!("&dark_mode={dark_mode}"));
+    }
+    screenshotone_url
+}
+
+/// A stored screenshot as referenced by the signed response.
+#[derive(Debug, Clone)]
+pub struct Screenshot {
+    /// Storage ETag of the uploaded image, which is its Walrus blob id, or
+    /// [`SHA256_BLOB_ID_PREFIX`] and its SHA-256 when the store exposes no
+    /// ETag, see [`get_blob_id`].
+    pub blob_id: String,
+    pub byte_size: usize,
+    /// Where the stored image can be downloaded.
+    pub url: String,
+}
+
+/// Capture the page with ScreenshotOne, which uploads the image to storage,
+/// then read back the blob id and byte size of the stored object.
+pub async fn capture_with_screenshotone(
+    params: &ScreenshotOneParams<'_>,
+    progress: &Progress,
+) -> Result<Screenshot, EnclaveError> {
+    let screenshotone_url = build_screenshotone_url(params);
+
+    info!("Calling ScreenshotOne API for: {}", params.url);
+    let screenshotone_json = call_screenshotone_with_retry(
+        params.http,
+        &screenshotone_url,
+        params.screenshotone_max_retries,
+        params.outbound_log_sample,
+        params.max_response_bytes,
+    )
+    .await?;
+
+    info!(
+        "ScreenshotOne response: {}",
+        serde_json::to_string_pretty(&screenshotone_json).unwrap_or_default()
+    );
+    progress.report(ArchiveStage::ScreenshotDone);
+
+    // Get the blob_id (ETag) from the screenshotone response URL
+    let screenshot_blob_url = screenshotone_json["store"]["location"]
+        .as_str()
+        .ok_or_else(|| {
+            EnclaveError::Upstream("store.location not found in ScreenshotOne response".to_string())
+        })?;
+    let (blob_id, source) = get_blob_id(
+        params.http,
+        screenshot_blob_url,
+        params.upstream_max_attempts,
+        params.outbound_log_sample,
+    )
+    .await?;
+    info!("Screenshot blob id {} from {:?}", blob_id, source);
+    progress.report(ArchiveStage::EtagFetched);
+
+    // Get byte size of screenshot_url
+    let screenshot_url = screenshotone_json["screenshot_url"].as_str().unwrap_or("");
+    let byte_size =
+        get_byte_size(params.http, screenshot_url, params.upstream_max_attempts).await?;
+
+    Ok(Screenshot {
+        blob_id,
+        byte_size,
+        url: screenshot_url.to_string(),
+    })
+}
+
+/// A failed ScreenshotOne call, and whether calling again may succeed.
+struct ScreenshotOneFailure {
+    error: EnclaveError,
+    retryable: bool,
+    /// Delay the answer asked for with `Retry-After`, e.g. on a 429.
+    retry_after: Option<Duration>,
+}
+
+/// Longest a ScreenshotOne call waits in total between its retries. A
+/// `Retry-After` asking for more than what is left fails the call at once.
+pub const MAX_SCREENSHOTONE_RETRY_WAIT: Duration = Duration::from_secs(30);
+
+/// Call ScreenshotOne, retrying up to `max_retries` times on network errors,
+/// 429 and 5xx answers, and answers without `store.location`, after the
+/// delay of the answer's `Retry-After` if any, else with exponential backoff.
+/// The waits add up to at most [`MAX_SCREENSHOTONE_RETRY_WAIT`]. Other 4xx
+/// fail at once. Returns the JSON answer, which
+/// has a `store.location`. Each attempt is logged as sampled by
+/// `outbound_log_sample`, see [`log_outbound`]. An answer over
+/// `max_response_bytes` fails at once.
+///
+/// The scoop was already submitted by now, so a transient provider failure
+/// shouldn't throw the whole archive away. A retry may be billed twice.
+pub async fn call_screenshotone_with_retry(
+    client: &reqwest::Client,
+    screenshotone_url: &str,
+    max_retries: u32,
+    outbound_log_sample: u64,
+    max_response_bytes: usize,
+) -> Result<Value, EnclaveError> {
+    let mut retry = 0;
+    let mut waited = Duration::ZERO;
+    loop {
+        let call = call_screenshotone(
+            client,
+            screenshotone_url,
+            outbound_log_sample,
+            max_response_bytes,
+        );
+        match call.await {
+            Ok(json) => return Ok(json),
+            Err(failure) if failure.retryable && retry < max_retries => {
+                retry += 1;
+                let delay = failure.retry_after.unwrap_or_else(|| backoff_delay(retry));
+                if waited + delay > MAX_SCREENSHOTONE_RETRY_WAIT {
+                    info!(
+                        "Not retrying ScreenshotOne in {:?}, over the {:?} budget",
+                        delay, MAX_SCREENSHOTONE_RETRY_WAIT
+                    );
+                    return Err(failure.error);
+                }
+                waited += delay;
+                info!(
+                    "Retrying ScreenshotOne ({}/{}) in {:?}: {}",
+                    retry, max_retries, delay, failure.error
+                );
+                tokio::time::sleep(delay).await;
+            }
+            Err(failure) => return Err(failure.error),
+        }
+    }
+}
+
+/// Client-side ceiling of a ScreenshotOne capture, above the `timeout=60`
+/// ScreenshotOne is asked to give up after, so its own error comes first.
+pub const SCREENSHOTONE_REQUEST_TIMEOUT: Duration = Duration::from_secs(90);
+
+async fn call_screenshotone(
+    client: &reqwest::Client,
+    screenshotone_url: &str,
+    outbound_log_sample: u64,
+    max_response_bytes: usize,
+) -> Result<Value, ScreenshotOneFailure> {
+    let retryable = |error| ScreenshotOneFailure {
+        error,
+        retryable: true,
+        retry_after: None,
+    };
+    let send = client
+        .get(screenshotone_url)
+        .timeout(SCREENSHOTONE_REQUEST_TIMEOUT)
+        .send();
+    let response = match log_outbound(
+        outbound_log_sample,
+        "screenshotone",
+        "GET",
+        screenshotone_url,
+        send,
+    )
+    .await
+    {
+        Ok(response) => response,
+        Err(e) => {
+            let error = EnclaveError::from_reqwest("Failed to call ScreenshotOne", e);
+            return Err(retryable(error));
+        }
+    };
+
+    // Read as text first: ScreenshotOne explains failures (blocked URL,
+    // exhausted quota) in the body, which may not even be JSON.
+    let status = response.status();
+    let retry_after = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|v| parse_retry_after(v, chrono::Utc::now()));
+    let body = read_body_limited("ScreenshotOne", response, max_response_bytes).await;
+    if !status.is_success() {
+        let message = match &body {
+            Ok(body) => format!(
+                "ScreenshotOne returned status {status}: {}",
+                String::from_utf8_lossy(body).trim()
+            ),
+            Err(_) => format!("ScreenshotOne returned status {status}"),
+        };
+        return Err(ScreenshotOneFailure {
+            error: EnclaveError::Upstream(message),
+            retryable: status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error(),
+            retry_after,
+        });
+    }
+
+    let body = match body {
+        Ok(body) => body,
+        Err(error @ EnclaveError::ResponseTooLarge { .. }) => {
+            return Err(ScreenshotOneFailure {
+                error,
+                retryable: false,
+                retry_after: None,
+            })
+        }
+        Err(error) => return Err(retryable(error)),
+    };
+    let json: Value = match serde_json::from_slice(&body) {
+        Ok(json) => json,
+        Err(e) => {
+            return Err(retryable(EnclaveError::Upstream(format!(
+                "Failed to parse ScreenshotOne response: {e}: {}",
+                String::from_utf8_lossy(&body).trim()
+            ))))
+        }
+    };
+    if json["store"]["location"].as_str().is_none() {
+        return Err(retryable(EnclaveError::Upstream(
+            "store.location not found in ScreenshotOne response".to_string(),
+        )));
+    }
+    Ok(json)
+}
+
+/// Byte size of a stored object. Reads the total from the Content-Range of a
+/// one byte Range request, and falls back to the Content-Length of a HEAD
+/// request when the server doesn't report it (`bytes 0-0/*`).
+async fn get_byte_size(
+    client: &reqwest::Client,
+    url: &str,
+    max_attempts: u32,
+) -> Result<usize, EnclaveError> {
+    // Use Range request to get only headers (1 byte) instead of downloading the whole file
+    let response = send_with_retry(Stage::ByteSize, max_attempts, || {
+        client.get(url).header("Range", "bytes=0-0").send()
+    })
+    .await
+    .map_err(|e| EnclaveError::from_reqwest("Failed to get screenshot", e))?;
+
+    if let Some(total) = response
+        .headers()
+        .get("content-range")
+        .and_then(parse_content_range_total)
+    {
+        return Ok(total);
+    }
+
+    info!("No Content-Range total for {}, falling back to HEAD", url);
+    let response = send_with_retry(Stage::ByteSize, max_attempts, || client.head(url).send())
+        .await
+        .map_err(|e| EnclaveError::from_reqwest("Failed to get screenshot", e))?;
+    response
+        .status()
+        .is_success()
+        .then(|| response.headers().get("content-length"))
+        .flatten()
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.trim().parse::<usize>().ok())
+        .ok_or_else(|| {
+            EnclaveError::Upstream(format!("Could not determine the byte size of {url}"))
+        })
+}
+
+/// Where the blob id of a stored screenshot came from, see [`get_blob_id`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlobIdSource {
+    /// ETag of a one byte Range request.
+    RangeEtag,
+    /// ETag of a HEAD request, for stores that only expose it there.
+    HeadEtag,
+    /// SHA-256 of the downloaded image, for stores exposing no ETag at all.
+    Sha256,
+}
+
+/// Blob id of the stored screenshot at `url`, from the first of:
+/// - the ETag of a Range request (only downloads 1 byte),
+/// - the ETag of a HEAD request, as some S3-compatible stores only expose it
+///   there, or not at all for some ACLs,
+/// - [`SHA256_BLOB_ID_PREFIX`] and the hex SHA-256 of the image, at most
+///   [`MAX_CHECKSUM_BYTES`], so the id stays deterministic.
+///
+/// An ETag that isn't a Walrus blob id still fails, see
+/// [`blob_id_from_etag`].
+async fn get_blob_id(
+    client: &reqwest::Client,
+    url: &str,
+    max_attempts: u32,
+    outbound_log_sample: u64,
+) -> Result<(String, BlobIdSource), EnclaveError> {
+    let response = send_with_retry(Stage::Etag, max_attempts, || {
+        let send = client.get(url).header("Range", "bytes=0-0").send();
+        log_outbound(outbound_log_sample, "etag", "GET", url, send)
+    })
+    .await
+    .map_err(|e| EnclaveError::from_reqwest("Failed to fetch URL", e))?;
+    if let Some(blob_id) = etag_blob_id(&response)? {
+        return Ok((blob_id, BlobIdSource::RangeEtag));
+    }
+
+    info!(
+        "No ETag for {} on a Range request, falling back to HEAD",
+        url
+    );
+    let response = send_with_retry(Stage::Etag, max_attempts, || {
+        let send = client.head(url).send();
+        log_outbound(outbound_log_sample, "etag", "HEAD", url, send)
+    })
+    .await
+    .map_err(|e| EnclaveError::from_reqwest("Failed to fetch URL", e))?;
+    if let Some(blob_id) = etag_blob_id(&response)? {
+        return Ok((blob_id, BlobIdSource::HeadEtag));
+    }
+
+    info!(
+        "No ETag for {} on HEAD either, falling back to the SHA-256 of the image",
+        url
+    );
+    let response = send_with_retry(Stage::Etag, max_attempts, || {
+        let send = client.get(url).send();
+        log_outbound(outbound_log_sample, "etag", "GET", url, send)
+    })
+    .await
+    .map_err(|e| EnclaveError::from_reqwest("Failed to fetch URL", e))?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(EnclaveError::Upstream(format!(
+            "Storage returned status {status} downloading the screenshot"
+        )));
+    }
+    let image = read_body_limited("screenshot storage", response, MAX_CHECKSUM_BYTES).await?;
+    Ok((
+        format!(
+            "{SHA256_BLOB_ID_PREFIX}{}",
+            Hex::encode(Sha256::digest(&image))
+        ),
+        BlobIdSource::Sha256,
+    ))
+}
+
+/// Blob id carried by the ETag of `response`, `None` without one.
+fn etag_blob_id(response: &reqwest::Response) -> Result<Option<String>, EnclaveError> {
+    response
+        .headers()
+        .get("etag")
+        .map(blob_id_from_etag)
+        .transpose()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::DEFAULT_MAX_RESPONSE_BYTES;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn params<'a>(
+        http: &'a reqwest::Client,
+        options: &'a CaptureOptions,
+    ) -> ScreenshotOneParams<'a> {
+        ScreenshotOneParams {
+            http,
+            api_url: SCREENSHOTONE_API_URL,
+            access_key: "key",
+            url: "https://example.com/a?b=c",
+            storage_bucket: STORAGE_BUCKET,
+            storage_path: "ID%2FID",
+            storage_access_key_id: "id",
+            storage_secret_access_key: "secret",
+            options,
+            upstream_max_attempts: 1,
+            screenshotone_max_retries: 0,
+            outbound_log_sample: 1,
+            max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+        }
+    }
+
+    #[test]
+    fn test_build_url_full_page_max_height() {
+        let options = CaptureOptions {
+            full_page_max_height: Some(8000),
+            ..Default::default()
+        };
+        let url = build_screenshotone_url(&params(&reqwest::Client::new(), &options));
+        assert!(url.starts_with("https://api.screenshotone.com/take?access_key=key&"));
+        assert!(url.contains("url=https%3A%2F%2Fexample.com%2Fa%3Fb%3Dc&"));
+        assert!(url.contains("&storage_bucket=perma-ws&storage_path=ID%2FID&"));
+        assert!(url.contains("&storage_endpoint=https%3A%2F%2Fstorage.nami.cloud&"));
+        assert!(url.ends_with("&full_page_max_height=8000"));
+
+        let url =
+            build_screenshotone_url(&params(&reqwest::Client::new(), &CaptureOptions::default()));
+        assert!(!url.contains("full_page_max_height"));
+        assert!(!url.contains("viewport_"));
+    }
+
+    #[test]
+    fn test_full_page_max_height_ceiling() {
+        let requested = |h| CaptureOptions {
+            full_page_max_height: h,
+            ..Default::default()
+        };
+        assert_eq!(
+            requested(Some(4000))
+                .effective_full_page_max_height(Some(10000))
+                .unwrap(),
+            Some(4000)
+        );
+        assert_eq!(
+            requested(Some(10000))
+                .effective_full_page_max_height(Some(10000))
+                .unwrap(),
+            Some(10000)
+        );
+        assert!(requested(Some(10001))
+            .effective_full_page_max_height(Some(10000))
+            .is_err());
+        assert!(requested(Some(0))
+            .effective_full_page_max_height(None)
+            .is_err());
+        assert_eq!(
+            requested(None)
+                .effective_full_page_max_height(Some(10000))
+                .unwrap(),
+            Some(10000)
+        );
+        assert_eq!(
+            requested(None)
+                .effective_full_page_max_height(None)
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_viewport_bounds() {
+        let bounds = ViewportBounds::default();
+        let viewport = |w, h| CaptureOptions {
+            viewport_width: w,
+            viewport_height: h,
+            ..Default::default()
+        };
+        assert!(viewport(None, None).validate_viewport(&bounds).is_ok());
+        assert!(viewport(Some(320), Some(240))
+            .validate_viewport(&bounds)
+            .is_ok());
+        assert!(viewport(Some(3840), Some(4320))
+            .validate_viewport(&bounds)
+            .is_ok());
+        for (w, h) in [
+            (Some(319), None),
+            (Some(3841), None),
+            (None, Some(239)),
+            (None, Some(4321)),
+        ] {
+            assert!(matches!(
+                viewport(w, h).validate_viewport(&bounds),
+                Err(EnclaveError::BadRequest(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_build_url_viewport() {
+        let options = CaptureOptions {
+            viewport_width: Some(1280),
+            viewport_height: Some(720),
+            ..Default::default()
+        };
+        let url = build_screenshotone_url(&params(&reqwest::Client::new(), &options));
+        assert!(url.ends_with("&viewport_width=1280&viewport_height=720"));
+    }
+
+    #[test]
+    fn test_build_url_color_scheme() {
+        let url = |color_scheme| {
+            build_screenshotone_url(&params(
+                &reqwest::Client::new(),
+                &CaptureOptions {
+                    color_scheme,
+                    ..Default::default()
+                },
+            ))
+        };
+        assert!(url(Some(ColorScheme::Light)).ends_with("&dark_mode=false"));
+        assert!(url(Some(ColorScheme::Dark)).ends_with("&dark_mode=true"));
+        assert!(!url(Some(ColorScheme::NoPreference)).contains("dark_mode"));
+        assert!(!url(None).contains("dark_mode"));
+    }
+
+    #[test]
+    fn test_build_url_overrides() {
+        let url = |options: CaptureOptions| {
+            build_screenshotone_url(&params(&reqwest::Client::new(), &options))
+        };
+        let default = url(CaptureOptions::default());
+        for param in [
+            "&format=png&",
+            "&block_ads=true&",
+            "&delay=0&",
+            "&full_page=true&",
+            "&image_quality=80&",
+            "&full_page_scroll=true&full_page_scroll_delay=500",
+        ] {
+            assert!(default.contains(param), "{param} missing from {default}");
+        }
+
+        let overridden = url(CaptureOptions {
+            format: Some(ScreenshotFormat::Jpeg),
+            full_page: Some(false),
+            delay_ms: Some(2500),
+            image_quality: Some(60),
+            block_ads: Some(false),
+            ..Default::default()
+        });
+        assert!(overridden.contains("&format=jpeg&block_ads=false&"));
+        assert!(overridden.contains("&delay=3&"));
+        assert!(overridden.contains("&full_page=false&image_quality=60"));
+        assert!(!overridden.contains("full_page_scroll"));
+    }
+
+    #[test]
+    fn test_capture_override_validation() {
+        let config = PermaConfig {
+            max_full_page_height: Some(10000),
+            ..Default::default()
+        };
+        let resolve = |options: CaptureOptions| options.resolve(&config);
+
+        let viewport_only = resolve(CaptureOptions {
+            full_page: Some(false),
+            delay_ms: Some(MAX_DELAY_MS),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(viewport_only.full_page_max_height, None);
+        assert_eq!(viewport_only.delay_ms, Some(MAX_DELAY_MS));
+        assert_eq!(
+            resolve(CaptureOptions::default())
+                .unwrap()
+                .full_page_max_height,
+            Some(10000)
+        );
+
+        assert!(resolve(CaptureOptions {
+            delay_ms: Some(MAX_DELAY_MS + 1),
+            ..Default::default()
+        })
+        .is_err());
+        assert!(resolve(CaptureOptions {
+            full_page: Some(false),
+            full_page_max_height: Some(5000),
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn test_responsive_widths_validation() {
+        let bounds = ViewportBounds::default();
+        let widths = |w: &[u32]| CaptureOptions {
+            responsive_widths: Some(w.to_vec()),
+            ..Default::default()
+        };
+        assert_eq!(
+            widths(&[1440, 375])
+                .validated_responsive_widths(&bounds)
+                .unwrap(),
+            Some(vec![375, 1440])
+        );
+        assert_eq!(
+            CaptureOptions::default()
+                .validated_responsive_widths(&bounds)
+                .unwrap(),
+            None
+        );
+        for invalid in [
+            &[][..],
+            &[375, 768, 1024, 1440, 1920],
+            &[375, 375],
+            &[100],
+            &[375, 5000],
+        ] {
+            assert!(matches!(
+                widths(invalid).validated_responsive_widths(&bounds),
+                Err(EnclaveError::BadRequest(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_allowed_formats() {
+        let requested = |format| CaptureOptions {
+            format,
+            ..Default::default()
+        };
+        let allowed = parse_formats("png, jpg").unwrap();
+        assert_eq!(allowed, vec![ScreenshotFormat::Png, ScreenshotFormat::Jpeg]);
+
+        assert_eq!(
+            requested(Some(ScreenshotFormat::Jpeg))
+                .effective_format(Some(&allowed))
+                .unwrap(),
+            ScreenshotFormat::Jpeg
+        );
+        assert!(matches!(
+            requested(Some(ScreenshotFormat::Pdf)).effective_format(Some(&allowed)),
+            Err(EnclaveError::BadRequest(_))
+        ));
+        assert_eq!(
+            requested(Some(ScreenshotFormat::Pdf))
+                .effective_format(None)
+                .unwrap(),
+            ScreenshotFormat::Pdf
+        );
+        // A single allowed format is forced when the request doesn't pick one.
+        assert_eq!(
+            requested(None)
+                .effective_format(Some(&[ScreenshotFormat::Webp]))
+                .unwrap(),
+            ScreenshotFormat::Webp
+        );
+        assert!(parse_formats("png,gif").is_err());
+        assert!(parse_formats(" , ").is_err());
+
+        let options = requested(Some(ScreenshotFormat::Webp));
+        assert!(
+            build_screenshotone_url(&params(&reqwest::Client::new(), &options))
+                .contains("&format=webp&")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_byte_size_falls_back_to_head() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(206).insert_header("content-range", "bytes 0-0/*"))
+            .mount(&server)
+            .await;
+        Mock::given(method("HEAD"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(vec![0u8; 1234]))
+            .mount(&server)
+            .await;
+        assert_eq!(
+            get_byte_size(&reqwest::Client::new(), &server.uri(), 1)
+                .await
+                .unwrap(),
+            1234
+        );
+    }
+
+    #[tokio::test]
+    async fn test_blob_id_falls_back_to_head_then_checksum() {
+        use wiremock::matchers::header;
+        let blob_id = "mC2ePrmW9VnmM_TRlN7zdh2Qn1o7ZH0ahR_q1nwyydE";
+        let client = reqwest::Client::new();
+
+        // ETag only exposed on HEAD.
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(206).set_body_bytes(vec![0u8]))
+            .mount(&server)
+            .await;
+        Mock::given(method("HEAD"))
+            .respond_with(
+                ResponseTemplate::new(200).insert_header("etag", format!("\"{blob_id}\"")),
+            )
+            .mount(&server)
+            .await;
+        assert_eq!(
+            get_blob_id(&client, &server.uri(), 1, 0).await.unwrap(),
+            (blob_id.to_string(), BlobIdSource::HeadEtag)
+        );
+
+        // No ETag at all: the id is the checksum of the whole image.
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(header("range", "bytes=0-0"))
+            .respond_with(ResponseTemplate::new(206).set_body_bytes(b"p".to_vec()))
+            .mount(&server)
+            .await;
+        Mock::given(method("HEAD"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(b"png".to_vec()))
+            .mount(&server)
+            .await;
+        let (id, source) = get_blob_id(&client, &server.uri(), 1, 0).await.unwrap();
+        assert_eq!(source, BlobIdSource::Sha256);
+        assert_eq!(
+            id,
+            format!(
+                "{SHA256_BLOB_ID_PREFIX}{}",
+                Hex::encode(Sha256::digest(b"png"))
+            )
+        );
+        assert_eq!(
+            get_blob_id(&client, &server.uri(), 1, 0).await.unwrap().0,
+            id
+        );
+    }
+
+    async fn call_with_retries(
+        server: &MockServer,
+        max_retries: u32,
+    ) -> Result<Value, EnclaveError> {
+        call_screenshotone_with_retry(
+            &reqwest::Client::new(),
+            &server.uri(),
+            max_retries,
+            1,
+            DEFAULT_MAX_RESPONSE_BYTES,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_screenshotone_transient_failures_are_retried() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({})))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "store": { "location": "https://storage.nami.cloud/perma-ws/ID/ID.png" },
+                "screenshot_url": "https://storage.nami.cloud/perma-ws/ID/ID.png",
+            })))
+            .mount(&server)
+            .await;
+
+        let json = call_with_retries(&server, 2).await.unwrap();
+        assert_eq!(
+            json["store"]["location"],
+            "https://storage.nami.cloud/perma-ws/ID/ID.png"
+        );
+        assert_eq!(server.received_requests().await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_screenshotone_client_error_fails_fast() {
+        let server = MockServer::start().await;
+        let failure = r#"{"is_successful":false,"error_code":"host_returned_error","error_message":"The host returned 403"}"#;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(400).set_body_raw(failure, "application/json"))
+            .mount(&server)
+            .await;
+        let result = call_with_retries(&server, 3).await;
+        match result {
+            Err(EnclaveError::Upstream(msg)) => {
+                assert_eq!(
+                    msg,
+                    format!("ScreenshotOne returned status 400 Bad Request: {failure}")
+                )
+            }
+            other => panic!("expected upstream error, got {other:?}"),
+        }
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+
+        // Retries are bounded.
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(429))
+            .mount(&server)
+            .await;
+        let result = call_with_retries(&server, 2).await;
+        assert!(result.is_err());
+        assert_eq!(server.received_requests().await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_screenshotone_retry_after_is_honored() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "1"))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "store": { "location": "https://storage.nami.cloud/perma-ws/ID/ID.png" },
+            })))
+            .mount(&server)
+            .await;
+        let started = std::time::Instant::now();
+        call_with_retries(&server, 1).await.unwrap();
+        // Backoff alone would have retried after 200ms.
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert_eq!(server.received_requests().await.unwrap().len(), 2);
+
+        // A wait over the budget fails at once.
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "3600"))
+            .mount(&server)
+            .await;
+        let result = call_with_retries(&server, 3).await;
+        assert!(matches!(result, Err(EnclaveError::Upstream(_))));
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_screenshotone_oversized_answer_is_rejected() {
+        let server = MockServer::start().await;
+        let padding = "x".repeat(DEFAULT_MAX_RESPONSE_BYTES);
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(serde_json::json!({ "padding": padding })),
+            )
+            .mount(&server)
+            .await;
+        let result = call_with_retries(&server, 2).await;
+        assert!(matches!(result, Err(EnclaveError::ResponseTooLarge { .. })));
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_byte_size_unknown_is_error() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(206).insert_header("content-range", "garbage"))
+            .mount(&server)
+            .await;
+        Mock::given(method("HEAD"))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&server)
+            .await;
+        assert!(matches!(
+            get_byte_size(&reqwest::Client::new(), &server.uri(), 1).await,
+            Err(EnclaveError::Upstream(_))
+        ));
+    }
+}
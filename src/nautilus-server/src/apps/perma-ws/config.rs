@@ -0,0 +1,877 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::admission::SaturationPolicy;
+use super::capture::{
+    parse_formats, ScreenshotFormat, ViewportBounds, SCREENSHOTONE_API_URL, STORAGE_BUCKET,
+    STORAGE_ENDPOINT,
+};
+use super::provider::{parse_providers, SCREENSHOTONE};
+use super::reference_id::DEFAULT_REFERENCE_ID_EPOCH_SECS;
+use super::DEFAULT_SCOOPER_URL;
+use crate::common::{redact, DEFAULT_MAX_RESPONSE_BYTES};
+use crate::EnclaveError;
+use chrono_tz::Tz;
+use std::fmt;
+use std::time::Duration;
+
+const DEFAULT_UNPERSISTED_CAPACITY: usize = 100;
+const DEFAULT_ATTESTATION_STORE_CAPACITY: usize = 10_000;
+const DEFAULT_RECENT_CAPTURES_CAPACITY: usize = 100;
+const DEFAULT_ATTESTATION_RETRY_INTERVAL_SECS: u64 = 60;
+const DEFAULT_IDEMPOTENCY_TTL_SECS: u64 = 600;
+const DEFAULT_MAX_CONCURRENT_ARCHIVES: usize = 8;
+const DEFAULT_MAX_BATCH_SIZE: usize = 10;
+const DEFAULT_SCOOPER_POLL_INTERVAL_MS: u64 = 2_000;
+const DEFAULT_SCOOPER_POLL_TIMEOUT_SECS: u64 = 300;
+const DEFAULT_BATCH_CONCURRENCY: usize = 4;
+const DEFAULT_SATURATION_QUEUE_TIMEOUT_MS: u64 = 5_000;
+const DEFAULT_RETRY_AFTER_SECS: u64 = 5;
+const DEFAULT_HOST_RATE_BURST: u32 = 1;
+const DEFAULT_UPSTREAM_MAX_ATTEMPTS: u32 = 3;
+const DEFAULT_SCREENSHOTONE_MAX_RETRIES: u32 = 2;
+const DEFAULT_OUTBOUND_LOG_SAMPLE: u64 = 1;
+const DEFAULT_SHUTDOWN_FLUSH_TIMEOUT_MS: u64 = 10_000;
+const DEFAULT_FRESHNESS_MAX_AGE_SECS: u64 = 300;
+/// Timeout of timestamp token requests.
+pub const TSA_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Server-side configuration for perma-ws, read once from the environment on
+/// boot. Secrets are optional here so a missing one only fails the requests
+/// that need it.
+#[derive(Clone)]
+pub struct PermaConfig {
+    /// Ceiling in pixels for full-page captures (`SCREENSHOT_MAX_HEIGHT`). When
+    /// unset, captures are unbounded unless the request asks for a maximum.
+    pub max_full_page_height: Option<u32>,
+    /// Base URL of the frontend that stores attestations (`FRONTEND_URL`),
+    /// without a trailing slash, see [`normalize_frontend_url`].
+    pub frontend_url: Option<String>,
+    /// Secret authenticating attestation saves with the frontend (`ADMIN_SECRET`).
+    pub admin_secret: Option<String>,
+    /// Scooper submit endpoint (`SCOOPER_URL`, defaults to production), see
+    /// [`normalize_scooper_url`]. Job status is polled next to it, see
+    /// [`PermaConfig::scooper_job_status_url`].
+    pub scooper_url: String,
+    /// Shared secret for scooper (`SCOOPER_SECRET`).
+    pub scooper_secret: Option<String>,
+    /// ScreenshotOne capture endpoint (`SCREENSHOTONE_URL`, defaults to
+    /// [`SCREENSHOTONE_API_URL`]), validated like `SCOOPER_URL`, e.g. to
+    /// capture through a proxy or a mock.
+    pub screenshotone_url: String,
+    /// ScreenshotOne access key (`ACCESS_KEY`).
+    pub access_key: Option<String>,
+    /// Storage credentials handed to ScreenshotOne for the upload
+    /// (`STORAGE_ACCESS_KEY_ID`, `STORAGE_SECRET_ACCESS_KEY`).
+    pub storage_access_key_id: Option<String>,
+    pub storage_secret_access_key: Option<String>,
+    /// Screenshot providers in fallback order (`SCREENSHOT_PROVIDERS`,
+    /// comma-separated, defaults to `screenshotone`).
+    pub screenshot_providers: Vec<String>,
+    /// Accepted viewport dimensions for captures.
+    pub viewport_bounds: ViewportBounds,
+    /// Keep serving when the attestation save fails (`ATTESTATION_FAIL_OPEN`).
+    /// The signed response is held for `/admin/retry_unpersisted` either way.
+    pub attestation_fail_open: bool,
+    /// Maximum number of unpersisted attestations kept (`UNPERSISTED_CAPACITY`).
+    pub unpersisted_capacity: usize,
+    /// Maximum number of attestations kept by the in-memory attestation store
+    /// (`ATTESTATION_STORE_CAPACITY`, defaults to 10000).
+    pub attestation_store_capacity: usize,
+    /// Captures `/admin/captures` can list (`RECENT_CAPTURES_CAPACITY`,
+    /// defaults to 100).
+    pub recent_captures_capacity: usize,
+    /// Unix seconds reference ids count their timestamp from
+    /// (`REFERENCE_ID_EPOCH`, defaults to 2025-01-01), see
+    /// [`reference_id_epoch_from_env`].
+    pub reference_id_epoch_secs: u64,
+    /// How often unpersisted attestations are retried in the background
+    /// (`ATTESTATION_RETRY_INTERVAL_SECS`, defaults to 60), 0 disables it.
+    pub attestation_retry_interval_secs: u64,
+    /// How long the signed response of a request with an `idempotency_key`
+    /// is returned for repeats of the key (`IDEMPOTENCY_TTL_SECS`, defaults
+    /// to 600), 0 disables it.
+    pub idempotency_ttl_secs: u64,
+    /// Maximum number of archives processed at once (`MAX_CONCURRENT_ARCHIVES`).
+    pub max_concurrent_archives: usize,
+    /// Maximum number of urls of a `/process_batch` request
+    /// (`MAX_BATCH_SIZE`, defaults to 10).
+    pub max_batch_size: usize,
+    /// Archives of one batch run at once (`BATCH_CONCURRENCY`, defaults to
+    /// 4), still within `max_concurrent_archives`.
+    pub batch_concurrency: usize,
+    /// Behavior when all archive permits are taken (`SATURATION_POLICY`,
+    /// defaults to queue).
+    pub saturation_policy: SaturationPolicy,
+    /// How long a queued request waits for a permit
+    /// (`SATURATION_QUEUE_TIMEOUT_MS`), only used by the `queue` policy.
+    pub saturation_queue_timeout_ms: u64,
+    /// `Retry-After` sent with 503 responses (`RETRY_AFTER_SECS`).
+    pub retry_after_secs: u64,
+    /// Sign a domain-separated hash instead of the raw BCS bytes for
+    /// responses longer than this many bytes (`SIGN_HASH_THRESHOLD_BYTES`).
+    /// Unset keeps raw signing for every response, which the bundled
+    /// `perma.move` verifier requires.
+    pub sign_hash_threshold_bytes: Option<usize>,
+    /// Buckets other than the perma-ws one that requests may store their
+    /// captures in (`ALLOWED_STORAGE_BUCKETS`, comma-separated). The storage
+    /// credentials must be able to write to them.
+    pub allowed_storage_buckets: Vec<String>,
+    /// Key prefixes requests may store their captures under
+    /// (`ALLOWED_PATH_PREFIXES`, comma-separated). Unset allows none.
+    pub allowed_path_prefixes: Vec<String>,
+    /// Formats requests may select (`ALLOWED_FORMATS`, comma-separated).
+    /// Unset allows every format.
+    pub allowed_formats: Option<Vec<ScreenshotFormat>>,
+    /// Archives allowed per day (`DAILY_ARCHIVE_LIMIT`), unlimited when unset.
+    pub daily_archive_limit: Option<u64>,
+    /// IANA timezone whose midnight starts a new quota day
+    /// (`QUOTA_RESET_TZ`, defaults to UTC).
+    pub quota_reset_tz: Tz,
+    /// Archives per minute allowed for one target host
+    /// (`HOST_RATE_LIMIT_PER_MINUTE`), unlimited when unset.
+    pub host_rate_limit_per_minute: Option<u32>,
+    /// Archives of one host that may start back to back before the rate
+    /// applies (`HOST_RATE_BURST`, defaults to 1).
+    pub host_rate_burst: u32,
+    /// RFC 3161 timestamp authority to get a token for each signed response
+    /// from (`TSA_URL`), none when unset.
+    pub tsa_url: Option<String>,
+    /// Return responses without a token when the TSA fails
+    /// (`TSA_FAIL_OPEN`, defaults to true) instead of failing the request.
+    pub tsa_fail_open: bool,
+    /// Attempts per upstream call (`UPSTREAM_MAX_ATTEMPTS`, defaults to 3),
+    /// only repeated when safe for the stage, see [`super::retry::Stage`].
+    pub upstream_max_attempts: u32,
+    /// How often a scoop job is polled for its WACZ blob id when scooper
+    /// didn't return it with the 202 (`SCOOPER_POLL_INTERVAL_MS`, defaults to
+    /// 2000).
+    pub scooper_poll_interval_ms: u64,
+    /// How long an archive waits for the WACZ upload before failing with 504
+    /// (`SCOOPER_POLL_TIMEOUT_SECS`, defaults to 300).
+    pub scooper_poll_timeout_secs: u64,
+    /// Retries of a ScreenshotOne capture answering 429, 5xx or without a
+    /// stored location (`SCREENSHOTONE_MAX_RETRIES`, defaults to 2).
+    pub screenshotone_max_retries: u32,
+    /// Log 1 in N outbound calls to scooper, ScreenshotOne, storage and the
+    /// frontend (`OUTBOUND_LOG_SAMPLE`, defaults to 1, i.e. every call).
+    /// Failed calls are always logged, see [`super::outbound_log`].
+    pub outbound_log_sample: u64,
+    /// Largest scooper or ScreenshotOne answer read before parsing it
+    /// (`MAX_RESPONSE_BYTES`, defaults to 1 MiB), bigger ones fail the call.
+    pub max_response_bytes: usize,
+    /// Receives the request and error of every archive that failed for good
+    /// (`DEAD_LETTER_URL`), for later replay. None when unset.
+    pub dead_letter_url: Option<String>,
+    /// Key of the HMAC signing callbacks to a request's `callback_url`
+    /// (`CALLBACK_SECRET`). Requests with a `callback_url` are refused while
+    /// it is unset.
+    pub callback_secret: Option<String>,
+    /// How long shutdown waits for unpersisted attestations to be saved
+    /// (`SHUTDOWN_FLUSH_TIMEOUT_MS`, defaults to 10s). Whatever isn't saved
+    /// by then is lost with the process.
+    pub shutdown_flush_timeout_ms: u64,
+    /// Refuse targets whose `Date` header is older than
+    /// `freshness_max_age_secs` (`ENFORCE_FRESHNESS`), so stale cached
+    /// content isn't archived as live.
+    pub enforce_freshness: bool,
+    /// Freshness window in seconds (`FRESHNESS_MAX_AGE_SECS`, defaults to 300).
+    pub freshness_max_age_secs: u64,
+    /// Archive targets serving JSON, XML or CSV as data instead of
+    /// screenshotting them (`ALLOW_DATA_ARCHIVE`), see
+    /// [`super::data_archive`].
+    pub allow_data_archive: bool,
+    /// Whether the server runs in a Nitro enclave, detected from the NSM
+    /// device rather than set. The enclave only reaches the hosts of
+    /// `allowed_endpoints.yaml`, so what fetches the target itself or posts
+    /// to a request's callback needs `target_proxy_url` there, see
+    /// [`PermaConfig::check_target_fetch`].
+    pub in_enclave: bool,
+    /// HTTP forward proxy the target and callback fetches go through
+    /// (`TARGET_PROXY_URL`, e.g. `http://egress.perma.ws:443`), see
+    /// [`normalize_target_proxy_url`]. Direct when unset.
+    pub target_proxy_url: Option<String>,
+}
+
+impl Default for PermaConfig {
+    fn default() -> Self {
+        Self {
+            max_full_page_height: None,
+            frontend_url: None,
+            admin_secret: None,
+            scooper_url: DEFAULT_SCOOPER_URL.to_string(),
+            scooper_secret: None,
+            screenshotone_url: SCREENSHOTONE_API_URL.to_string(),
+            access_key: None,
+            storage_access_key_id: None,
+            storage_secret_access_key: None,
+            screenshot_providers: vec![SCREENSHOTONE.to_string()],
+            viewport_bounds: ViewportBounds::default(),
+            attestation_fail_open: false,
+            unpersisted_capacity: DEFAULT_UNPERSISTED_CAPACITY,
+            attestation_store_capacity: DEFAULT_ATTESTATION_STORE_CAPACITY,
+            recent_captures_capacity: DEFAULT_RECENT_CAPTURES_CAPACITY,
+            reference_id_epoch_secs: DEFAULT_REFERENCE_ID_EPOCH_SECS,
+            attestation_retry_interval_secs: DEFAULT_ATTESTATION_RETRY_INTERVAL_SECS,
+            idempotency_ttl_secs: DEFAULT_IDEMPOTENCY_TTL_SECS,
+            max_concurrent_archives: DEFAULT_MAX_CONCURRENT_ARCHIVES,
+            max_batch_size: DEFAULT_MAX_BATCH_SIZE,
+            batch_concurrency: DEFAULT_BATCH_CONCURRENCY,
+            saturation_policy: SaturationPolicy::default(),
+            saturation_queue_timeout_ms: DEFAULT_SATURATION_QUEUE_TIMEOUT_MS,
+            retry_after_secs: DEFAULT_RETRY_AFTER_SECS,
+            sign_hash_threshold_bytes: None,
+            allowed_storage_buckets: Vec::new(),
+            allowed_path_prefixes: Vec::new(),
+            allowed_formats: None,
+            daily_archive_limit: None,
+            quota_reset_tz: Tz::UTC,
+            host_rate_limit_per_minute: None,
+            host_rate_burst: DEFAULT_HOST_RATE_BURST,
+            tsa_url: None,
+            tsa_fail_open: true,
+            upstream_max_attempts: DEFAULT_UPSTREAM_MAX_ATTEMPTS,
+            scooper_poll_interval_ms: DEFAULT_SCOOPER_POLL_INTERVAL_MS,
+            scooper_poll_timeout_secs: DEFAULT_SCOOPER_POLL_TIMEOUT_SECS,
+            screenshotone_max_retries: DEFAULT_SCREENSHOTONE_MAX_RETRIES,
+            outbound_log_sample: DEFAULT_OUTBOUND_LOG_SAMPLE,
+            max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+            dead_letter_url: None,
+            callback_secret: None,
+            shutdown_flush_timeout_ms: DEFAULT_SHUTDOWN_FLUSH_TIMEOUT_MS,
+            enforce_freshness: false,
+            freshness_max_age_secs: DEFAULT_FRESHNESS_MAX_AGE_SECS,
+            allow_data_archive: false,
+            in_enclave: false,
+            target_proxy_url: None,
+        }
+    }
+}
+
+impl PermaConfig {
+    pub fn from_env() -> Result<Self, EnclaveError> {
+        let config = Self {
+            max_full_page_height: parse_env("SCREENSHOT_MAX_HEIGHT")?,
+            frontend_url: match std::env::var("FRONTEND_URL") {
+                Ok(value) if !value.trim().is_empty() => Some(normalize_frontend_url(&value)?),
+                _ => None,
+            },
+            admin_secret: std::env::var("ADMIN_SECRET").ok(),
+            scooper_url: match std::env::var("SCOOPER_URL") {
+                Ok(value) if !value.trim().is_empty() => normalize_scooper_url(&value)?,
+                _ => DEFAULT_SCOOPER_URL.to_string(),
+            },
+            scooper_secret: std::env::var("SCOOPER_SECRET").ok(),
+            screenshotone_url: match std::env::var("SCREENSHOTONE_URL") {
+                Ok(value) if !value.trim().is_empty() => {
+                    normalize_http_url("SCREENSHOTONE_URL", &value)?
+                }
+                _ => SCREENSHOTONE_API_URL.to_string(),
+            },
+            access_key: std::env::var("ACCESS_KEY").ok(),
+            storage_access_key_id: std::env::var("STORAGE_ACCESS_KEY_ID").ok(),
+            storage_secret_access_key: std::env::var("STORAGE_SECRET_ACCESS_KEY").ok(),
+            screenshot_providers: match std::env::var("SCREENSHOT_PROVIDERS") {
+                Ok(value) => parse_providers(&value)?,
+                Err(_) => vec![SCREENSHOTONE.to_string()],
+            },
+            viewport_bounds: viewport_bounds_from_env()?,
+            attestation_fail_open: parse_env("ATTESTATION_FAIL_OPEN")?.unwrap_or(false),
+            unpersisted_capacity: parse_env("UNPERSISTED_CAPACITY")?
+                .unwrap_or(DEFAULT_UNPERSISTED_CAPACITY),
+            attestation_store_capacity: parse_env("ATTESTATION_STORE_CAPACITY")?
+                .unwrap_or(DEFAULT_ATTESTATION_STORE_CAPACITY),
+            recent_captures_capacity: parse_env("RECENT_CAPTURES_CAPACITY")?
+                .unwrap_or(DEFAULT_RECENT_CAPTURES_CAPACITY),
+            reference_id_epoch_secs: reference_id_epoch_from_env()?,
+            attestation_retry_interval_secs: parse_env("ATTESTATION_RETRY_INTERVAL_SECS")?
+                .unwrap_or(DEFAULT_ATTESTATION_RETRY_INTERVAL_SECS),
+            idempotency_ttl_secs: parse_env("IDEMPOTENCY_TTL_SECS")?
+                .unwrap_or(DEFAULT_IDEMPOTENCY_TTL_SECS),
+            max_concurrent_archives: validate_max_concurrent_archives(
+                parse_env("MAX_CONCURRENT_ARCHIVES")?.unwrap_or(DEFAULT_MAX_CONCURRENT_ARCHIVES),
+            )?,
+            max_batch_size: parse_env("MAX_BATCH_SIZE")?.unwrap_or(DEFAULT_MAX_BATCH_SIZE),
+            batch_concurrency: parse_env("BATCH_CONCURRENCY")?.unwrap_or(DEFAULT_BATCH_CONCURRENCY),
+            saturation_policy: parse_env("SATURATION_POLICY")?.unwrap_or_default(),
+            saturation_queue_timeout_ms: parse_env("SATURATION_QUEUE_TIMEOUT_MS")?
+                .unwrap_or(DEFAULT_SATURATION_QUEUE_TIMEOUT_MS),
+            retry_after_secs: parse_env("RETRY_AFTER_SECS")?.unwrap_or(DEFAULT_RETRY_AFTER_SECS),
+            sign_hash_threshold_bytes: parse_env("SIGN_HASH_THRESHOLD_BYTES")?,
+            allowed_storage_buckets: parse_list(
+                &std::env::var("ALLOWED_STORAGE_BUCKETS").unwrap_or_default(),
+            ),
+            allowed_path_prefixes: parse_list(
+                &std::env::var("ALLOWED_PATH_PREFIXES").unwrap_or_default(),
+            )
+            .into_iter()
+            .map(|prefix| prefix.trim_matches('/').to_string())
+            .filter(|prefix| !prefix.is_empty())
+            .collect(),
+            allowed_formats: match std::env::var("ALLOWED_FORMATS") {
+                Ok(value) if !value.trim().is_empty() => Some(parse_formats(&value)?),
+                _ => None,
+            },
+            daily_archive_limit: parse_env("DAILY_ARCHIVE_LIMIT")?,
+            quota_reset_tz: parse_env("QUOTA_RESET_TZ")?.unwrap_or(Tz::UTC),
+            host_rate_limit_per_minute: parse_env("HOST_RATE_LIMIT_PER_MINUTE")?,
+            host_rate_burst: parse_env("HOST_RATE_BURST")?.unwrap_or(DEFAULT_HOST_RATE_BURST),
+            tsa_url: std::env::var("TSA_URL").ok(),
+            tsa_fail_open: parse_env("TSA_FAIL_OPEN")?.unwrap_or(true),
+            upstream_max_attempts: parse_env::<u32>("UPSTREAM_MAX_ATTEMPTS")?
+                .unwrap_or(DEFAULT_UPSTREAM_MAX_ATTEMPTS)
+                .max(1),
+            scooper_poll_interval_ms: parse_env("SCOOPER_POLL_INTERVAL_MS")?
+                .unwrap_or(DEFAULT_SCOOPER_POLL_INTERVAL_MS),
+            scooper_poll_timeout_secs: parse_env("SCOOPER_POLL_TIMEOUT_SECS")?
+                .unwrap_or(DEFAULT_SCOOPER_POLL_TIMEOUT_SECS),
+            screenshotone_max_retries: parse_env("SCREENSHOTONE_MAX_RETRIES")?
+                .unwrap_or(DEFAULT_SCREENSHOTONE_MAX_RETRIES),
+            outbound_log_sample: parse_env::<u64>("OUTBOUND_LOG_SAMPLE")?
+                .unwrap_or(DEFAULT_OUTBOUND_LOG_SAMPLE)
+                .max(1),
+            max_response_bytes: parse_env("MAX_RESPONSE_BYTES")?
+                .unwrap_or(DEFAULT_MAX_RESPONSE_BYTES),
+            dead_letter_url: std::env::var("DEAD_LETTER_URL").ok(),
+            callback_secret: std::env::var("CALLBACK_SECRET").ok(),
+            shutdown_flush_timeout_ms: parse_env("SHUTDOWN_FLUSH_TIMEOUT_MS")?
+                .unwrap_or(DEFAULT_SHUTDOWN_FLUSH_TIMEOUT_MS),
+            enforce_freshness: parse_env("ENFORCE_FRESHNESS")?.unwrap_or(false),
+            freshness_max_age_secs: parse_env("FRESHNESS_MAX_AGE_SECS")?
+                .unwrap_or(DEFAULT_FRESHNESS_MAX_AGE_SECS),
+            allow_data_archive: parse_env("ALLOW_DATA_ARCHIVE")?.unwrap_or(false),
+            in_enclave: crate::common::in_enclave(),
+            target_proxy_url: match std::env::var("TARGET_PROXY_URL") {
+                Ok(value) if !value.trim().is_empty() => Some(normalize_target_proxy_url(&value)?),
+                _ => None,
+            },
+        };
+        for (name, set) in [
+            ("ENFORCE_FRESHNESS", config.enforce_freshness),
+            ("ALLOW_DATA_ARCHIVE", config.allow_data_archive),
+        ] {
+            if set {
+                config
+                    .check_target_fetch(name)
+                    .map_err(|e| EnclaveError::GenericError(e.to_string()))?;
+            }
+        }
+        Ok(config)
+    }
+
+    /// Refuse `option`, which reaches a host the request names (the target
+    /// or a callback), in a Nitro enclave without `TARGET_PROXY_URL`: its
+    /// only egress is the hosts of `allowed_endpoints.yaml`, with no DNS, so
+    /// an arbitrary host is only reached through the proxy.
+    pub fn check_target_fetch(&self, option: &str) -> Result<(), EnclaveError> {
+        if self.in_enclave && self.target_proxy_url.is_none() {
+            return Err(EnclaveError::BadRequest(format!(
+                "{option} reaches an arbitrary host, which inside a Nitro enclave needs TARGET_PROXY_URL"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Env vars required to archive that are unset: the scooper, ScreenshotOne
+    /// and storage credentials, and the frontend settings attestations are
+    /// saved with.
+    pub fn missing_secrets(&self) -> Vec<String> {
+        [
+            ("SCOOPER_SECRET", &self.scooper_secret),
+            ("ACCESS_KEY", &self.access_key),
+            ("STORAGE_ACCESS_KEY_ID", &self.storage_access_key_id),
+            ("STORAGE_SECRET_ACCESS_KEY", &self.storage_secret_access_key),
+            ("FRONTEND_URL", &self.frontend_url),
+            ("ADMIN_SECRET", &self.admin_secret),
+        ]
+        .into_iter()
+        .filter(|(_, value)| value.as_deref().is_none_or(str::is_empty))
+        .map(|(name, _)| name.to_string())
+        .collect()
+    }
+
+    /// Scooper endpoint reporting the state of a scoop job, `GET {url}/{job_id}`:
+    /// `job-status` next to the submit endpoint, e.g.
+    /// `https://host/scoop-async` gives `https://host/job-status`.
+    pub fn scooper_job_status_url(&self) -> Result<String, EnclaveError> {
+        reqwest::Url::parse(&self.scooper_url)
+            .and_then(|url| url.join("job-status"))
+            .map(String::from)
+            .map_err(|e| EnclaveError::Internal(format!("Invalid SCOOPER_URL: {e}")))
+    }
+
+    /// Effective settings for the startup banner, with secrets redacted and
+    /// URLs reduced to their host.
+    pub fn summary(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("scooper_host", host_of(&self.scooper_url)),
+            ("screenshotone_host", host_of(&self.screenshotone_url)),
+            ("storage_host", host_of(STORAGE_ENDPOINT)),
+            ("storage_bucket", STORAGE_BUCKET.to_string()),
+            ("screenshot_providers", self.screenshot_providers.join(",")),
+            (
+                "frontend_host",
+                self.frontend_url
+                    .as_deref()
+                    .map(host_of)
+                    .unwrap_or_else(|| "<unset>".to_string()),
+            ),
+            (
+                "screenshot_max_height",
+                self.max_full_page_height
+                    .map(|h| h.to_string())
+                    .unwrap_or_else(|| "unbounded".to_string()),
+            ),
+            (
+                "viewport_bounds",
+                format!(
+                    "{}..={}x{}..={}",
+                    self.viewport_bounds.width.start(),
+                    self.viewport_bounds.width.end(),
+                    self.viewport_bounds.height.start(),
+                    self.viewport_bounds.height.end()
+                ),
+            ),
+            (
+                "attestation_fail_open",
+                self.attestation_fail_open.to_string(),
+            ),
+            (
+                "max_concurrent_archives",
+                self.max_concurrent_archives.to_string(),
+            ),
+            ("max_batch_size", self.max_batch_size.to_string()),
+            ("batch_concurrency", self.batch_concurrency.to_string()),
+            ("saturation_policy", self.saturation_policy.to_string()),
+            (
+                "allowed_storage_buckets",
+                self.allowed_storage_buckets.join(","),
+            ),
+            (
+                "allowed_path_prefixes",
+                self.allowed_path_prefixes.join(","),
+            ),
+            (
+                "allowed_formats",
+                self.allowed_formats
+                    .as_ref()
+                    .map(|formats| {
+                        formats
+                            .iter()
+                            .map(|f| f.as_str())
+                            .collect::<Vec<_>>()
+                            .join(",")
+                    })
+                    .unwrap_or_else(|| "all".to_string()),
+            ),
+            (
+                "sign_hash_threshold_bytes",
+                self.sign_hash_threshold_bytes
+                    .map(|b| b.to_string())
+                    .unwrap_or_else(|| "never".to_string()),
+            ),
+            (
+                "daily_archive_limit",
+                self.daily_archive_limit
+                    .map(|l| l.to_string())
+                    .unwrap_or_else(|| "unlimited".to_string()),
+            ),
+            ("quota_reset_tz", self.quota_reset_tz.name().to_string()),
+            (
+                "host_rate_limit",
+                self.host_rate_limit_per_minute
+                    .map(|r| format!("{r}/min, burst {}", self.host_rate_burst))
+                    .unwrap_or_else(|| "unlimited".to_string()),
+            ),
+            (
+                "tsa_host",
+                self.tsa_url
+                    .as_deref()
+                    .map(host_of)
+                    .unwrap_or_else(|| "<unset>".to_string()),
+            ),
+            ("tsa_fail_open", self.tsa_fail_open.to_string()),
+            (
+                "upstream_max_attempts",
+                self.upstream_max_attempts.to_string(),
+            ),
+            (
+                "scooper_poll_interval_ms",
+                self.scooper_poll_interval_ms.to_string(),
+            ),
+            (
+                "scooper_poll_timeout_secs",
+                self.scooper_poll_timeout_secs.to_string(),
+            ),
+            (
+                "screenshotone_max_retries",
+                self.screenshotone_max_retries.to_string(),
+            ),
+            ("outbound_log_sample", self.outbound_log_sample.to_string()),
+            ("max_response_bytes", self.max_response_bytes.to_string()),
+            (
+                "dead_letter_host",
+                self.dead_letter_url
+                    .as_deref()
+                    .map(host_of)
+                    .unwrap_or_else(|| "<unset>".to_string()),
+            ),
+            (
+                "shutdown_flush_timeout_ms",
+                self.shutdown_flush_timeout_ms.to_string(),
+            ),
+            ("enforce_freshness", self.enforce_freshness.to_string()),
+            (
+                "freshness_max_age_secs",
+                self.freshness_max_age_secs.to_string(),
+            ),
+            ("allow_data_archive", self.allow_data_archive.to_string()),
+            ("in_enclave", self.in_enclave.to_string()),
+            (
+                "target_proxy_host",
+                self.target_proxy_url
+                    .as_deref()
+                    .map(host_of)
+                    .unwrap_or_else(|| "<unset>".to_string()),
+            ),
+            (
+                "attestation_store_capacity",
+                self.attestation_store_capacity.to_string(),
+            ),
+            (
+                "recent_captures_capacity",
+                self.recent_captures_capacity.to_string(),
+            ),
+            (
+                "reference_id_epoch_secs",
+                self.reference_id_epoch_secs.to_string(),
+            ),
+            (
+                "attestation_retry_interval_secs",
+                self.attestation_retry_interval_secs.to_string(),
+            ),
+            (
+                "idempotency_ttl_secs",
+                self.idempotency_ttl_secs.to_string(),
+            ),
+            ("admin_secret", redact(self.admin_secret.as_deref())),
+            ("scooper_secret", redact(self.scooper_secret.as_deref())),
+            ("callback_secret", redact(self.callback_secret.as_deref())),
+            ("access_key", redact(self.access_key.as_deref())),
+            (
+                "storage_access_key_id",
+                redact(self.storage_access_key_id.as_deref()),
+            ),
+            (
+                "storage_secret_access_key",
+                redact(self.storage_secret_access_key.as_deref()),
+            ),
+        ]
+    }
+}
+
+/// Never print secrets through Debug.
+impl fmt::Debug for PermaConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.summary()).finish()
+    }
+}
+
+/// Viewport bounds from the environment, defaulting each unset end.
+fn viewport_bounds_from_env() -> Result<ViewportBounds, EnclaveError> {
+    let default = ViewportBounds::default();
+    let width = parse_env("VIEWPORT_MIN_WIDTH")?.unwrap_or(*default.width.start())
+        ..=parse_env("VIEWPORT_MAX_WIDTH")?.unwrap_or(*default.width.end());
+    let height = parse_env("VIEWPORT_MIN_HEIGHT")?.unwrap_or(*default.height.start())
+        ..=parse_env("VIEWPORT_MAX_HEIGHT")?.unwrap_or(*default.height.end());
+    if width.is_empty() || height.is_empty() {
+        return Err(EnclaveError::GenericError(format!(
+            "Invalid viewport bounds: width {width:?}, height {height:?}"
+        )));
+    }
+    Ok(ViewportBounds { width, height })
+}
+
+/// Return a configured value or an error naming the missing env var.
+pub(crate) fn required<'a>(value: &'a Option<String>, name: &str) -> Result<&'a str, EnclaveError> {
+    value
+        .as_deref()
+        .ok_or_else(|| EnclaveError::Internal(format!("{name} not set")))
+}
+
+/// Parse an optional env var, treating unset or empty as `None`.
+/// Trimmed, non-empty entries of a comma-separated list.
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// `REFERENCE_ID_EPOCH` in Unix seconds, defaulting to
+/// [`DEFAULT_REFERENCE_ID_EPOCH_SECS`]. It must be in the past, as ids count
+/// the milliseconds since. Ids only decode against the epoch they were
+/// generated with, so changing it misdates the ids generated before.
+fn reference_id_epoch_from_env() -> Result<u64, EnclaveError> {
+    let Some(epoch_secs) = parse_env::<u64>("REFERENCE_ID_EPOCH")? else {
+        return Ok(DEFAULT_REFERENCE_ID_EPOCH_SECS);
+    };
+    validate_reference_id_epoch(epoch_secs, chrono::Utc::now().timestamp())
+}
+
+/// Check `epoch_secs` is before `now_secs`, see
+/// [`reference_id_epoch_from_env`].
+fn validate_reference_id_epoch(epoch_secs: u64, now_secs: i64) -> Result<u64, EnclaveError> {
+    if !i64::try_from(epoch_secs).is_ok_and(|epoch| epoch < now_secs) {
+        return Err(EnclaveError::GenericError(format!(
+            "Invalid REFERENCE_ID_EPOCH {epoch_secs}: must be in the past"
+        )));
+    }
+    Ok(epoch_secs)
+}
+
+/// Check `MAX_CONCURRENT_ARCHIVES` is at least 1, as with no archive permit
+/// every request would wait for one and be answered 503.
+fn validate_max_concurrent_archives(max: usize) -> Result<usize, EnclaveError> {
+    if max == 0 {
+        return Err(EnclaveError::GenericError(
+            "Invalid MAX_CONCURRENT_ARCHIVES 0: must be at least 1".to_string(),
+        ));
+    }
+    Ok(max)
+}
+
+pub(crate) fn parse_env<T: std::str::FromStr>(name: &str) -> Result<Option<T>, EnclaveError>
+where
+    T::Err: std::fmt::Display,
+{
+    match std::env::var(name) {
+        Ok(value) if !value.trim().is_empty() => value
+            .trim()
+            .parse::<T>()
+            .map(Some)
+            .map_err(|e| EnclaveError::GenericError(format!("Invalid {name}: {e}"))),
+        _ => Ok(None),
+    }
+}
+
+/// Check `FRONTEND_URL` is an absolute http(s) URL and strip its trailing
+/// slash, so a typo fails the boot instead of every attestation save.
+pub fn normalize_frontend_url(value: &str) -> Result<String, EnclaveError> {
+    normalize_http_url("FRONTEND_URL", value)
+}
+
+/// Validate `SCOOPER_URL` like [`normalize_frontend_url`], so a malformed
+/// value fails startup instead of every archive.
+pub fn normalize_scooper_url(value: &str) -> Result<String, EnclaveError> {
+    normalize_http_url("SCOOPER_URL", value)
+}
+
+/// Validate `TARGET_PROXY_URL` like [`normalize_frontend_url`], as an http
+/// URL: https targets are tunnelled through it with `CONNECT`, so they stay
+/// encrypted end to end.
+pub fn normalize_target_proxy_url(value: &str) -> Result<String, EnclaveError> {
+    let url = normalize_http_url("TARGET_PROXY_URL", value)?;
+    if !url.starts_with("http://") {
+        return Err(EnclaveError::GenericError(format!(
+            "Invalid TARGET_PROXY_URL {value}: must be an http URL"
+        )));
+    }
+    Ok(url)
+}
+
+/// Absolute http(s) URL of env var `name`, without query, fragment or
+/// trailing slash.
+fn normalize_http_url(name: &str, value: &str) -> Result<String, EnclaveError> {
+    let invalid =
+        |reason: &str| EnclaveError::GenericError(format!("Invalid {name} {value}: {reason}"));
+    let url = reqwest::Url::parse(value.trim()).map_err(|e| invalid(&e.to_string()))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(invalid("must be an http or https URL"));
+    }
+    if url.host_str().is_none() {
+        return Err(invalid("has no host"));
+    }
+    if url.query().is_some() || url.fragment().is_some() {
+        return Err(invalid("must not have a query or fragment"));
+    }
+    Ok(url.as_str().trim_end_matches('/').to_string())
+}
+
+/// `path` resolved under the frontend base URL, keeping the base's own path,
+/// e.g. `https://host/app` and `api/attestation` give
+/// `https://host/app/api/attestation`.
+pub fn frontend_endpoint(frontend_url: &str, path: &str) -> Result<reqwest::Url, EnclaveError> {
+    let mut base = reqwest::Url::parse(frontend_url)
+        .map_err(|e| EnclaveError::Internal(format!("Invalid FRONTEND_URL: {e}")))?;
+    if !base.path().ends_with('/') {
+        base.set_path(&format!("{}/", base.path()));
+    }
+    base.join(path.trim_start_matches('/'))
+        .map_err(|e| EnclaveError::Internal(format!("Invalid frontend path {path}: {e}")))
+}
+
+/// Host part of a URL, or the input itself if it doesn't parse.
+fn host_of(url: &str) -> String {
+    reqwest::Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(str::to_string))
+        .unwrap_or_else(|| url.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::startup_banner;
+
+    #[test]
+    fn test_missing_secrets() {
+        let config = PermaConfig {
+            scooper_secret: Some("scooper".to_string()),
+            access_key: Some(String::new()),
+            storage_access_key_id: Some("id".to_string()),
+            frontend_url: Some("https://www.perma.ws/".to_string()),
+            admin_secret: Some("admin".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.missing_secrets(),
+            vec!["ACCESS_KEY", "STORAGE_SECRET_ACCESS_KEY"]
+        );
+    }
+
+    #[test]
+    fn test_banner_redacts_secrets() {
+        let config = PermaConfig {
+            frontend_url: Some("https://www.perma.ws/".to_string()),
+            admin_secret: Some("admin-s3cret".to_string()),
+            scooper_secret: Some("scooper-s3cret".to_string()),
+            access_key: Some("access-s3cret".to_string()),
+            storage_access_key_id: Some("key-id-s3cret".to_string()),
+            storage_secret_access_key: Some("storage-s3cret".to_string()),
+            ..Default::default()
+        };
+        let banner = startup_banner(&["0.0.0.0:3000".to_string()], &config.summary()).to_string();
+        assert!(!banner.contains("s3cret"));
+        assert!(banner.contains("\"scooper_secret\":\"<redacted>\""));
+        assert!(banner.contains("\"frontend_host\":\"www.perma.ws\""));
+        assert!(banner.contains("scooper-production.up.railway.app"));
+        assert!(!format!("{config:?}").contains("s3cret"));
+    }
+
+    #[test]
+    fn test_normalize_frontend_url() {
+        assert_eq!(
+            normalize_frontend_url("https://www.perma.ws/").unwrap(),
+            "https://www.perma.ws"
+        );
+        assert_eq!(
+            normalize_frontend_url(" http://localhost:3001 ").unwrap(),
+            "http://localhost:3001"
+        );
+        assert_eq!(
+            normalize_frontend_url("https://example.com/app//").unwrap(),
+            "https://example.com/app"
+        );
+        for invalid in [
+            "www.perma.ws",
+            "localhost:3001",
+            "ftp://www.perma.ws",
+            "https://www.perma.ws/?a=b",
+        ] {
+            let err = normalize_frontend_url(invalid).unwrap_err();
+            assert!(err.to_string().contains("FRONTEND_URL"), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_scooper_url() {
+        let config = PermaConfig::default();
+        assert_eq!(config.scooper_url, DEFAULT_SCOOPER_URL);
+        assert_eq!(
+            config.scooper_job_status_url().unwrap(),
+            "https://scooper-production.up.railway.app/job-status"
+        );
+
+        let local = PermaConfig {
+            scooper_url: normalize_scooper_url("http://localhost:8080/scoop-async/").unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(local.scooper_url, "http://localhost:8080/scoop-async");
+        assert_eq!(
+            local.scooper_job_status_url().unwrap(),
+            "http://localhost:8080/job-status"
+        );
+        for invalid in ["scooper.local/scoop-async", "ftp://scooper.local"] {
+            let err = normalize_scooper_url(invalid).unwrap_err();
+            assert!(err.to_string().contains("SCOOPER_URL"), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_target_proxy_url() {
+        assert_eq!(
+            normalize_target_proxy_url(" http://egress.perma.ws:443/ ").unwrap(),
+            "http://egress.perma.ws:443"
+        );
+        for invalid in ["egress.perma.ws:443", "https://egress.perma.ws"] {
+            let err = normalize_target_proxy_url(invalid).unwrap_err();
+            assert!(err.to_string().contains("TARGET_PROXY_URL"), "{invalid}");
+        }
+
+        let enclave = PermaConfig {
+            in_enclave: true,
+            ..Default::default()
+        };
+        assert!(enclave.check_target_fetch("extract_text").is_err());
+        let proxied = PermaConfig {
+            target_proxy_url: Some("http://egress.perma.ws:443".to_string()),
+            ..enclave
+        };
+        assert!(proxied.check_target_fetch("extract_text").is_ok());
+    }
+
+    #[test]
+    fn test_reference_id_epoch() {
+        let now = 1744038900;
+        assert_eq!(
+            PermaConfig::default().reference_id_epoch_secs,
+            DEFAULT_REFERENCE_ID_EPOCH_SECS
+        );
+        assert_eq!(
+            validate_reference_id_epoch(1704067200, now).unwrap(),
+            1704067200
+        );
+        for future in [now as u64, now as u64 + 1, u64::MAX] {
+            let err = validate_reference_id_epoch(future, now).unwrap_err();
+            assert!(err.to_string().contains("REFERENCE_ID_EPOCH"), "{future}");
+        }
+    }
+
+    #[test]
+    fn test_max_concurrent_archives() {
+        assert_eq!(validate_max_concurrent_archives(1).unwrap(), 1);
+        let err = validate_max_concurrent_archives(0).unwrap_err();
+        assert!(err.to_string().contains("MAX_CONCURRENT_ARCHIVES"));
+    }
+
+    #[test]
+    fn test_frontend_endpoint() {
+        for base in ["https://www.perma.ws", "https://www.perma.ws/"] {
+            assert_eq!(
+                frontend_endpoint(base, "/api/attestation")
+                    .unwrap()
+                    .as_str(),
+                "https://www.perma.ws/api/attestation"
+            );
+        }
+        assert_eq!(
+            frontend_endpoint("https://example.com/app", "api/attestation/tombstone")
+                .unwrap()
+                .as_str(),
+            "https://example.com/app/api/attestation/tombstone"
+        );
+    }
+}
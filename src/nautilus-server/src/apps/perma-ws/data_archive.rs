@@ -0,0 +1,299 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::reference_id::storage_key;
+use super::storage::{upload_object, StorageCredentials, StorageTarget};
+use super::target_http::TargetHttp;
+use crate::common::read_body_limited;
+use crate::EnclaveError;
+use fastcrypto::encoding::{Encoding, Hex};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+/// Screenshot provider signed for archives stored as data, see
+/// [`fetch_structured_data`].
+pub const DATA_PROVIDER: &str = "data";
+
+/// Largest data response archived, bigger ones fail the archive.
+pub const MAX_DATA_ARCHIVE_BYTES: usize = 10 * 1024 * 1024;
+
+/// Storage key of an archive's data body, where its screenshot would be.
+pub fn data_storage_key(reference_id: &str) -> String {
+    format!("{}.data", storage_key(reference_id))
+}
+
+/// A structured response fetched from the target.
+#[derive(Debug, Clone)]
+pub struct DataResponse {
+    /// Media type without parameters, lowercased, e.g. `application/json`.
+    pub content_type: String,
+    pub body: Vec<u8>,
+}
+
+/// A data response as stored and signed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchivedData {
+    /// Storage ETag of the stored body, which is its Walrus blob id.
+    pub blob_id: String,
+    pub byte_size: usize,
+    pub content_type: String,
+    /// Hex SHA-256 of the canonical JSON for JSON bodies, of the raw body
+    /// otherwise, see [`data_sha256`].
+    pub sha256: String,
+}
+
+/// Whether a `Content-Type` is data rather than a page: JSON, XML, CSV and
+/// their `+json`/`+xml` variants.
+pub fn is_structured_content_type(content_type: &str) -> bool {
+    let mime = media_type(content_type);
+    is_json(&mime)
+        || mime.ends_with("+xml")
+        || matches!(
+            mime.as_str(),
+            "application/xml" | "text/xml" | "text/csv" | "application/x-ndjson"
+        )
+}
+
+fn is_json(mime: &str) -> bool {
+    mime == "application/json" || mime.ends_with("+json")
+}
+
+fn media_type(content_type: &str) -> String {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+/// Fetch `url` and keep its body when the response is structured data, see
+/// [`is_structured_content_type`]. `None` for anything else, whose body is
+/// not read, so the page is screenshotted as usual. Redirects to private
+/// hosts are refused, see [`TargetHttp::get`], so the enclave can't be made
+/// to archive and sign its own admin API.
+pub async fn fetch_structured_data(
+    http: &TargetHttp,
+    url: &str,
+) -> Result<Option<DataResponse>, EnclaveError> {
+    let response = http
+        .get(url)
+        .await?
+        .error_for_status()
+        .map_err(|e| EnclaveError::from_reqwest("Failed to fetch the target", e))?;
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    if !is_structured_content_type(content_type) {
+        return Ok(None);
+    }
+    let content_type = media_type(content_type);
+    info!("{} is {}, archiving it as data", url, content_type);
+    let body = read_body_limited("Target", response, MAX_DATA_ARCHIVE_BYTES).await?;
+    Ok(Some(DataResponse { content_type, body }))
+}
+
+/// Hex SHA-256 signed for a data response. JSON is hashed in its canonical
+/// form (see [`canonical_json`]), so the hash doesn't depend on the server's
+/// key order or whitespace. Other types are hashed as served.
+pub fn data_sha256(data: &DataResponse) -> Result<String, EnclaveError> {
+    if !is_json(&data.content_type) {
+        return Ok(Hex::encode(Sha256::digest(&data.body)));
+    }
+    let value: Value = serde_json::from_slice(&data.body).map_err(|e| {
+        EnclaveError::Upstream(format!("Target served invalid {}: {e}", data.content_type))
+    })?;
+    Ok(Hex::encode(Sha256::digest(
+        canonical_json(&value).as_bytes(),
+    )))
+}
+
+/// `value` serialized with object keys sorted and no whitespace.
+pub fn canonical_json(value: &Value) -> String {
+    let mut out = String::new();
+    write_canonical_json(value, &mut out);
+    out
+}
+
+fn write_canonical_json(value: &Value, out: &mut String) {
+    match value {
+        Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            out.push('{');
+            for (i, (key, value)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&Value::String(key.clone()).to_string());
+                out.push(':');
+                write_canonical_json(value, out);
+            }
+            out.push('}');
+        }
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical_json(item, out);
+            }
+            out.push(']');
+        }
+        scalar => out.push_str(&scalar.to_string()),
+    }
+}
+
+/// Store the raw body of `data` as the archive's artifact and return what
+/// gets signed.
+pub async fn store_data(
+    http: &reqwest::Client,
+    data: DataResponse,
+    reference_id: &str,
+    storage: &StorageTarget,
+    storage_endpoint: &str,
+    credentials: &StorageCredentials<'_>,
+) -> Result<ArchivedData, EnclaveError> {
+    let sha256 = data_sha256(&data)?;
+    let byte_size = data.body.len();
+    let blob_id = upload_object(
+        http,
+        storage_endpoint,
+        &storage.bucket,
+        &storage.key(&data_storage_key(reference_id)),
+        data.body,
+        credentials,
+    )
+    .await?;
+    Ok(ArchivedData {
+        blob_id,
+        byte_size,
+        content_type: data.content_type,
+        sha256,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[test]
+    fn test_structured_content_types() {
+        assert!(is_structured_content_type(
+            "application/json; charset=utf-8"
+        ));
+        assert!(is_structured_content_type("application/geo+json"));
+        assert!(is_structured_content_type("Text/CSV"));
+        assert!(is_structured_content_type("application/atom+xml"));
+        assert!(!is_structured_content_type("text/html; charset=utf-8"));
+        assert!(!is_structured_content_type("image/png"));
+        assert!(!is_structured_content_type(""));
+    }
+
+    #[test]
+    fn test_canonical_json() {
+        let value: Value =
+            serde_json::from_str(r#"{ "b": [1, {"d": null, "c": "x"}], "a": true }"#).unwrap();
+        assert_eq!(
+            canonical_json(&value),
+            r#"{"a":true,"b":[1,{"c":"x","d":null}]}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_json_endpoint_is_archived_as_data() {
+        let server = MockServer::start().await;
+        let body = "{\n  \"temperature\": 21.5,\n  \"city\": \"Lisbon\"\n}";
+        Mock::given(method("GET"))
+            .and(path("/feed"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(body, "application/json"))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/page"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw("<html></html>", "text/html"))
+            .mount(&server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path("/perma-ws/ABCDEF-GH12/ABCDEF-GH12.data"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("etag", "\"Om6weQ85rIfJTzhWst0sXREOaBFgImGpqSPTuyOtyLc\""),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        let http = reqwest::Client::new();
+        let target_http = TargetHttp::allowing(&[&server.address().to_string()]);
+        let credentials = StorageCredentials {
+            access_key_id: "id",
+            secret_access_key: "secret",
+        };
+
+        let page = fetch_structured_data(&target_http, &format!("{}/page", server.uri()))
+            .await
+            .unwrap();
+        assert!(page.is_none());
+
+        let data = fetch_structured_data(&target_http, &format!("{}/feed", server.uri()))
+            .await
+            .unwrap()
+            .unwrap();
+        let archived = store_data(
+            &http,
+            data,
+            "ABCDEF-GH12",
+            &StorageTarget::default(),
+            &server.uri(),
+            &credentials,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            archived.blob_id,
+            "Om6weQ85rIfJTzhWst0sXREOaBFgImGpqSPTuyOtyLc"
+        );
+        assert_eq!(archived.content_type, "application/json");
+        assert_eq!(archived.byte_size, body.len());
+
+        // The raw body is stored, the hash is over the canonical JSON.
+        let stored = &server.received_requests().await.unwrap()[2];
+        assert_eq!(stored.body, body.as_bytes());
+        let stored_json: Value = serde_json::from_slice(&stored.body).unwrap();
+        assert_eq!(
+            stored_json,
+            json!({ "city": "Lisbon", "temperature": 21.5 })
+        );
+        let canonical = r#"{"city":"Lisbon","temperature":21.5}"#;
+        assert_eq!(archived.sha256, Hex::encode(Sha256::digest(canonical)));
+        server.verify().await;
+    }
+
+    #[tokio::test]
+    async fn test_redirect_to_admin_api_is_refused() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/feed"))
+            .respond_with(
+                ResponseTemplate::new(302)
+                    .insert_header("location", "http://127.0.0.1:3001/admin/unpersisted"),
+            )
+            .mount(&server)
+            .await;
+        let target_http = TargetHttp::allowing(&[&server.address().to_string()]);
+
+        let result = fetch_structured_data(&target_http, &format!("{}/feed", server.uri())).await;
+        assert!(
+            matches!(result, Err(EnclaveError::BadRequest(ref msg)) if msg.contains("private host")),
+            "{result:?}"
+        );
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,170 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::attestation::now_ms;
+use super::config::PermaConfig;
+use super::PermaRequest;
+use crate::EnclaveError;
+use serde::Serialize;
+use std::time::Duration;
+use tracing::info;
+
+/// Timeout of dead-letter posts, which delay the error response.
+const DEAD_LETTER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A permanently failed archive, posted to `DEAD_LETTER_URL` so a batch tool
+/// can replay the original request.
+#[derive(Debug, Serialize)]
+pub struct DeadLetter<'a> {
+    pub request: &'a PermaRequest,
+    /// Terminal error, with configured secrets redacted.
+    pub error: String,
+    pub failed_at_ms: u64,
+}
+
+/// Whether `error` means the archive itself failed, as opposed to the request
+/// being rejected before archiving, which replaying wouldn't fix.
+pub fn is_terminal_failure(error: &EnclaveError) -> bool {
+    !matches!(
+        error,
+        EnclaveError::BadRequest(_)
+            | EnclaveError::Unauthorized(_)
+            | EnclaveError::PayloadTooLarge { .. }
+            | EnclaveError::Stale(_)
+            | EnclaveError::AlreadyRunning { .. }
+            | EnclaveError::Unavailable { .. }
+            | EnclaveError::RateLimited { .. }
+    )
+}
+
+/// Replace every configured secret in `message` with `<redacted>`. Upstream
+/// errors may quote request URLs carrying them, e.g. the ScreenshotOne
+/// access key.
+pub fn redact_secrets(message: &str, config: &PermaConfig) -> String {
+    [
+        &config.admin_secret,
+        &config.scooper_secret,
+        &config.callback_secret,
+        &config.access_key,
+        &config.storage_access_key_id,
+        &config.storage_secret_access_key,
+    ]
+    .into_iter()
+    .flatten()
+    .filter(|secret| !secret.is_empty())
+    .fold(message.to_string(), |message, secret| {
+        message
+            .replace(secret.as_str(), "<redacted>")
+            .replace(urlencoding::encode(secret).as_ref(), "<redacted>")
+    })
+}
+
+/// Post a dead-letter record for a failed archive of `request` when
+/// `DEAD_LETTER_URL` is set and the failure is terminal. A failed post is
+/// only logged, the client gets the archive error either way.
+pub async fn record_dead_letter(
+    http: &reqwest::Client,
+    config: &PermaConfig,
+    request: &PermaRequest,
+    error: &EnclaveError,
+) {
+    let Some(dead_letter_url) = &config.dead_letter_url else {
+        return;
+    };
+    if !is_terminal_failure(error) {
+        return;
+    }
+    let record = DeadLetter {
+        request,
+        error: redact_secrets(&error.to_string(), config),
+        failed_at_ms: now_ms(),
+    };
+    let result = http
+        .post(dead_letter_url)
+        .timeout(DEAD_LETTER_TIMEOUT)
+        .json(&record)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+    match result {
+        Ok(_) => info!("Recorded dead letter for {}", request.url),
+        Err(e) => info!("Failed to record dead letter for {}: {}", request.url, e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::progress::Progress;
+    use crate::app::{archive, PermaState};
+    use crate::AppState;
+    use serde_json::json;
+    use wiremock::matchers::{body_partial_json, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn request(url: &str) -> PermaRequest {
+        serde_json::from_value(json!({ "url": url, "viewport_width": 1280 })).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_terminal_failure_posts_dead_letter() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/dead_letter"))
+            .and(body_partial_json(json!({
+                "request": { "url": "https://example.com/", "viewport_width": 1280 },
+                "error": "internal error: SCOOPER_SECRET not set",
+            })))
+            .respond_with(ResponseTemplate::new(202))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        // Without SCOOPER_SECRET the archive fails after admission.
+        let state = AppState {
+            perma: PermaState::with_config(PermaConfig {
+                dead_letter_url: Some(format!("{}/dead_letter", server.uri())),
+                ..Default::default()
+            }),
+            ..AppState::for_test()
+        };
+        let result = archive(
+            &state,
+            request("https://example.com/"),
+            &Progress::default(),
+        )
+        .await;
+        assert!(result.is_err());
+        server.verify().await;
+    }
+
+    #[tokio::test]
+    async fn test_rejected_request_is_not_dead_lettered() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(202))
+            .expect(0)
+            .mount(&server)
+            .await;
+        let config = PermaConfig {
+            dead_letter_url: Some(server.uri()),
+            ..Default::default()
+        };
+        let error = EnclaveError::BadRequest("invalid url".to_string());
+        let http = reqwest::Client::new();
+        record_dead_letter(&http, &config, &request("ftp://example.com"), &error).await;
+        server.verify().await;
+    }
+
+    #[test]
+    fn test_redact_secrets() {
+        let config = PermaConfig {
+            access_key: Some("s3cret/key".to_string()),
+            ..Default::default()
+        };
+        let message = "error sending request for url (https://api.screenshotone.com/take?access_key=s3cret%2Fkey&url=x): s3cret/key";
+        let redacted = redact_secrets(message, &config);
+        assert!(!redacted.contains("s3cret"));
+        assert!(redacted.contains("access_key=<redacted>&"));
+    }
+}
@@ -0,0 +1,224 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::attestation::now_ms;
+use super::capture::{screenshot_storage_key, ScreenshotFormat};
+use super::config::{frontend_endpoint, required, PermaConfig};
+use super::reference_id::is_valid_reference_id;
+use super::storage::{delete_object, StorageCredentials, StorageTarget};
+use crate::common::{to_signed_response, IntentMessage, IntentScope, ProcessedDataResponse};
+use crate::AppState;
+use crate::EnclaveError;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::info;
+
+/// Signed record that an archive was taken down. The intent message
+/// timestamp is the deletion time.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchiveTombstone {
+    pub reference_id: String,
+    /// Bucket the screenshot was deleted from.
+    pub storage_bucket: String,
+    /// Storage keys deleted, path prefix included, one per screenshot format
+    /// as the record doesn't say which format the capture was taken in.
+    pub screenshot_storage_keys: Vec<String>,
+    /// Walrus blobs can't be deleted, copies stored there (the WACZ archive
+    /// and the screenshot blob) stay retrievable by blob id until they expire.
+    pub walrus_blobs_retained: bool,
+}
+
+pub type SignedArchiveTombstone = ProcessedDataResponse<IntentMessage<ArchiveTombstone>>;
+
+/// Delete the stored screenshot of an archive from `target`, the bucket and
+/// path prefix it was stored under, tombstone its attestation with the
+/// frontend and return the signed tombstone. Both steps succeed when already
+/// done, so a failed deletion can simply be retried.
+pub async fn delete_archive(
+    state: &AppState,
+    storage_endpoint: &str,
+    reference_id: &str,
+    target: &StorageTarget,
+) -> Result<SignedArchiveTombstone, EnclaveError> {
+    if !is_valid_reference_id(reference_id) {
+        return Err(EnclaveError::BadRequest(format!(
+            "invalid reference id {reference_id}"
+        )));
+    }
+    let config = &state.perma.config;
+
+    let keys: Vec<String> = ScreenshotFormat::ALL
+        .iter()
+        .map(|format| target.key(&screenshot_storage_key(reference_id, *format)))
+        .collect();
+    let credentials = StorageCredentials {
+        access_key_id: required(&config.storage_access_key_id, "STORAGE_ACCESS_KEY_ID")?,
+        secret_access_key: required(
+            &config.storage_secret_access_key,
+            "STORAGE_SECRET_ACCESS_KEY",
+        )?,
+    };
+    for key in &keys {
+        delete_object(
+            &state.http,
+            storage_endpoint,
+            &target.bucket,
+            key,
+            &credentials,
+        )
+        .await?;
+    }
+
+    let tombstone = to_signed_response(
+        &state.eph_kp,
+        ArchiveTombstone {
+            reference_id: reference_id.to_string(),
+            storage_bucket: target.bucket.clone(),
+            screenshot_storage_keys: keys,
+            walrus_blobs_retained: true,
+        },
+        now_ms(),
+        IntentScope::Tombstone,
+    );
+    notify_tombstone(&state.http, config, reference_id, &tombstone).await?;
+    info!("Deleted archive {}", reference_id);
+    Ok(tombstone)
+}
+
+/// POST the signed tombstone to the frontend so it stops serving the attestation.
+async fn notify_tombstone(
+    http: &reqwest::Client,
+    config: &PermaConfig,
+    reference_id: &str,
+    tombstone: &SignedArchiveTombstone,
+) -> Result<(), EnclaveError> {
+    let frontend_url = required(&config.frontend_url, "FRONTEND_URL")?;
+    let admin_secret = required(&config.admin_secret, "ADMIN_SECRET")?;
+
+    let tombstone_url = frontend_endpoint(frontend_url, "api/attestation/tombstone")?;
+    let response = http
+        .post(tombstone_url)
+        .json(&json!({
+            "admin_secret": admin_secret,
+            "reference_id": reference_id,
+            "tombstone": tombstone,
+        }))
+        .send()
+        .await
+        .map_err(|e| EnclaveError::from_reqwest("Failed to tombstone attestation", e))?;
+
+    if !response.status().is_success() {
+        return Err(EnclaveError::Upstream(format!(
+            "Frontend returned status {} tombstoning {reference_id}",
+            response.status()
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::PermaState;
+    use wiremock::matchers::{header_exists, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn state(server: &MockServer) -> AppState {
+        AppState {
+            perma: PermaState::with_config(PermaConfig {
+                frontend_url: Some(server.uri()),
+                admin_secret: Some("admin".to_string()),
+                storage_access_key_id: Some("key-id".to_string()),
+                storage_secret_access_key: Some("secret".to_string()),
+                ..Default::default()
+            }),
+            ..AppState::for_test()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delete_archive_deletes_and_tombstones() {
+        let server = MockServer::start().await;
+        for extension in ["png", "jpg", "webp", "pdf"] {
+            Mock::given(method("DELETE"))
+                .and(path(format!(
+                    "/perma-ws/ABCDEF-GH12/ABCDEF-GH12.{extension}"
+                )))
+                .and(header_exists("authorization"))
+                .respond_with(ResponseTemplate::new(204))
+                .expect(1)
+                .mount(&server)
+                .await;
+        }
+        Mock::given(method("POST"))
+            .and(path("/api/attestation/tombstone"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let tombstone = delete_archive(
+            &state(&server),
+            &server.uri(),
+            "ABCDEF-GH12",
+            &StorageTarget::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(tombstone.response.intent, IntentScope::Tombstone);
+        assert_eq!(tombstone.response.data.reference_id, "ABCDEF-GH12");
+        assert_eq!(tombstone.response.data.storage_bucket, "perma-ws");
+        assert!(tombstone.response.data.walrus_blobs_retained);
+        server.verify().await;
+    }
+
+    #[tokio::test]
+    async fn test_delete_archive_under_path_prefix() {
+        let server = MockServer::start().await;
+        for extension in ["png", "jpg", "webp", "pdf"] {
+            Mock::given(method("DELETE"))
+                .and(path(format!(
+                    "/tenant-bucket/tenant-a/ABCDEF-GH12/ABCDEF-GH12.{extension}"
+                )))
+                .respond_with(ResponseTemplate::new(204))
+                .expect(1)
+                .mount(&server)
+                .await;
+        }
+        Mock::given(method("POST"))
+            .and(path("/api/attestation/tombstone"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let target = StorageTarget {
+            bucket: "tenant-bucket".to_string(),
+            path_prefix: Some("tenant-a".to_string()),
+        };
+
+        let tombstone = delete_archive(&state(&server), &server.uri(), "ABCDEF-GH12", &target)
+            .await
+            .unwrap();
+        assert_eq!(tombstone.response.data.storage_bucket, "tenant-bucket");
+        assert_eq!(
+            tombstone.response.data.screenshot_storage_keys[0],
+            "tenant-a/ABCDEF-GH12/ABCDEF-GH12.png"
+        );
+        server.verify().await;
+    }
+
+    #[tokio::test]
+    async fn test_delete_archive_rejects_malformed_reference_id() {
+        let server = MockServer::start().await;
+        let err = delete_archive(
+            &state(&server),
+            &server.uri(),
+            "../etc",
+            &StorageTarget::default(),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, EnclaveError::BadRequest(_)));
+        assert!(server.received_requests().await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,104 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::target_http::TargetHttp;
+use crate::EnclaveError;
+use chrono::{DateTime, Utc};
+use std::time::Duration;
+use tracing::info;
+
+/// Fetch `url` and refuse it when its `Date` header is older than `max_age`,
+/// e.g. a cache replaying an old response. See [`check_freshness`].
+pub async fn ensure_fresh(
+    http: &TargetHttp,
+    url: &str,
+    max_age: Duration,
+) -> Result<(), EnclaveError> {
+    let response = http.get(url).await?;
+    let date = response
+        .headers()
+        .get(reqwest::header::DATE)
+        .and_then(|v| v.to_str().ok());
+    check_freshness(url, date, Utc::now(), max_age)
+}
+
+/// Refuse a response whose `Date` is more than `max_age` before `now`. A
+/// missing or unparsable `Date` skips the check. `Last-Modified` isn't used:
+/// it dates the content, and a live page may legitimately be old.
+pub fn check_freshness(
+    url: &str,
+    date: Option<&str>,
+    now: DateTime<Utc>,
+    max_age: Duration,
+) -> Result<(), EnclaveError> {
+    let Some(date) = date else {
+        info!("No Date header from {}, skipping the freshness check", url);
+        return Ok(());
+    };
+    let Ok(date) = DateTime::parse_from_rfc2822(date.trim()) else {
+        info!(
+            "Unparsable Date header {:?} from {}, skipping the freshness check",
+            date, url
+        );
+        return Ok(());
+    };
+    let age = now.signed_duration_since(date);
+    match age.to_std() {
+        Ok(age) if age > max_age => Err(EnclaveError::Stale(format!(
+            "{url} answered with a Date {}s old, over the {}s freshness window",
+            age.as_secs(),
+            max_age.as_secs()
+        ))),
+        // A Date in the future is clock skew, not staleness.
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    const MAX_AGE: Duration = Duration::from_secs(300);
+
+    #[tokio::test]
+    async fn test_fresh_page_proceeds() {
+        let server = MockServer::start().await;
+        let date = Utc::now().to_rfc2822();
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).insert_header("date", date.as_str()))
+            .mount(&server)
+            .await;
+        let http = TargetHttp::allowing(&[&server.address().to_string()]);
+        ensure_fresh(&http, &server.uri(), MAX_AGE).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stale_date_is_refused() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200).insert_header("date", "Tue, 15 Nov 1994 08:12:31 GMT"),
+            )
+            .mount(&server)
+            .await;
+        let http = TargetHttp::allowing(&[&server.address().to_string()]);
+        let result = ensure_fresh(&http, &server.uri(), MAX_AGE).await;
+        assert!(matches!(result, Err(EnclaveError::Stale(_))));
+    }
+
+    #[test]
+    fn test_check_freshness() {
+        let now = DateTime::parse_from_rfc2822("Wed, 01 Jan 2025 12:00:00 GMT")
+            .unwrap()
+            .with_timezone(&Utc);
+        let check = |date| check_freshness("https://example.com", date, now, MAX_AGE);
+        assert!(check(Some("Wed, 01 Jan 2025 11:56:00 GMT")).is_ok());
+        assert!(check(Some("Wed, 01 Jan 2025 11:54:00 GMT")).is_err());
+        // Skipped without a usable Date, and a Date ahead of the clock passes.
+        assert!(check(None).is_ok());
+        assert!(check(Some("yesterday")).is_ok());
+        assert!(check(Some("Wed, 01 Jan 2025 12:10:00 GMT")).is_ok());
+    }
+}
@@ -0,0 +1,214 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::config::PermaConfig;
+use crate::EnclaveError;
+use reqwest::Url;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::info;
+
+/// Token bucket per target host (`HOST_RATE_LIMIT_PER_MINUTE`,
+/// `HOST_RATE_BURST`), spacing out archives of the same origin so neither
+/// the enclave nor the screenshot provider hammers it. Archives over the rate
+/// wait for their turn, up to [`MAX_HOST_WAIT`], instead of failing.
+#[derive(Debug)]
+pub struct HostRateLimiter {
+    /// Tokens refilled per second, `None` disables the limiter.
+    rate: Option<f64>,
+    burst: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Negative when archives are waiting for tokens not yet refilled.
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Buckets kept before idle ones, back at full burst, are dropped.
+const MAX_TRACKED_HOSTS: usize = 1024;
+
+/// Longest an archive waits for its host's turn. Past it the archive is
+/// refused with 429 rather than queued, so a burst on one host can't hold
+/// requests open indefinitely.
+pub const MAX_HOST_WAIT: Duration = Duration::from_secs(30);
+
+impl HostRateLimiter {
+    pub fn new(config: &PermaConfig) -> Self {
+        Self {
+            rate: config
+                .host_rate_limit_per_minute
+                .filter(|rate| *rate > 0)
+                .map(|rate| f64::from(rate) / 60.0),
+            burst: f64::from(config.host_rate_burst.max(1)),
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// Wait until an archive of `url` may start. The token is reserved before
+    /// waiting, so concurrent archives of one host queue up in arrival order.
+    /// `RateLimited` when the turn is more than [`MAX_HOST_WAIT`] away.
+    pub async fn acquire(&self, url: &str) -> Result<(), EnclaveError> {
+        let Some(rate) = self.rate else {
+            return Ok(());
+        };
+        let host = normalized_host(url)?;
+        let wait = self.reserve(&host, rate, Instant::now()).map_err(|wait| {
+            EnclaveError::RateLimited {
+                reason: format!("too many archives of {host}"),
+                retry_after_secs: (wait - MAX_HOST_WAIT).as_secs_f64().ceil().max(1.0) as u64,
+            }
+        })?;
+        if !wait.is_zero() {
+            info!("Throttling archive of {} for {:?}", host, wait);
+            tokio::time::sleep(wait).await;
+        }
+        Ok(())
+    }
+
+    /// Take a token for `host` at `now` and return how long to wait for it,
+    /// or leave it and return the wait when it is over [`MAX_HOST_WAIT`].
+    fn reserve(&self, host: &str, rate: f64, now: Instant) -> Result<Duration, Duration> {
+        let mut buckets = self.buckets.lock().expect("lock poisoned");
+        if buckets.len() >= MAX_TRACKED_HOSTS {
+            let burst = self.burst;
+            buckets.retain(|_, b| {
+                b.tokens + now.duration_since(b.updated).as_secs_f64() * rate < burst
+            });
+        }
+        let bucket = buckets.entry(host.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let refilled = now.duration_since(bucket.updated).as_secs_f64() * rate;
+        bucket.tokens = (bucket.tokens + refilled).min(self.burst) - 1.0;
+        bucket.updated = now;
+        if bucket.tokens >= 0.0 {
+            return Ok(Duration::ZERO);
+        }
+        let wait = Duration::from_secs_f64(-bucket.tokens / rate);
+        if wait > MAX_HOST_WAIT {
+            bucket.tokens += 1.0;
+            return Err(wait);
+        }
+        Ok(wait)
+    }
+}
+
+/// Host the limiter keys on: lowercased, without a trailing dot or a
+/// leading `www.`, so `https://WWW.Example.com./a` and `http://example.com/b`
+/// share a bucket.
+pub fn normalized_host(url: &str) -> Result<String, EnclaveError> {
+    let parsed =
+        Url::parse(url).map_err(|e| EnclaveError::BadRequest(format!("invalid url {url}: {e}")))?;
+    let host = parsed
+        .host_str()
+        .ok_or_else(|| EnclaveError::BadRequest(format!("url {url} has no host")))?
+        .to_ascii_lowercase();
+    let host = host.trim_end_matches('.');
+    Ok(host.strip_prefix("www.").unwrap_or(host).to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn limiter(per_minute: u32) -> HostRateLimiter {
+        HostRateLimiter::new(&PermaConfig {
+            host_rate_limit_per_minute: Some(per_minute),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_normalized_host() {
+        for url in [
+            "https://example.com/a",
+            "http://WWW.Example.com./b?c",
+            "https://www.example.com:8443/",
+        ] {
+            assert_eq!(normalized_host(url).unwrap(), "example.com");
+        }
+        assert_eq!(
+            normalized_host("https://blog.example.com").unwrap(),
+            "blog.example.com"
+        );
+        assert!(normalized_host("not a url").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_same_host_archives_are_spaced() {
+        // 600 per minute is one every 100ms.
+        let limiter = limiter(600);
+        let start = Instant::now();
+        limiter.acquire("https://example.com/a").await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(50));
+        limiter.acquire("https://www.example.com/b").await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        // Another host has its own bucket.
+        let other = Instant::now();
+        limiter.acquire("https://example.org/").await.unwrap();
+        assert!(other.elapsed() < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_concurrent_reservations_queue_up() {
+        let limiter = limiter(60);
+        let now = Instant::now();
+        let waits: Vec<Duration> = (0..3)
+            .map(|_| limiter.reserve("example.com", 1.0, now).unwrap())
+            .collect();
+        assert_eq!(
+            waits,
+            vec![
+                Duration::ZERO,
+                Duration::from_secs(1),
+                Duration::from_secs(2)
+            ]
+        );
+        // Refilled after waiting out the queue.
+        assert_eq!(
+            limiter.reserve("example.com", 1.0, now + Duration::from_secs(4)),
+            Ok(Duration::ZERO)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wait_past_cap_is_refused() {
+        // One per minute: the second archive would wait 60s.
+        let limiter = limiter(1);
+        limiter.acquire("https://example.com/a").await.unwrap();
+        let err = limiter.acquire("https://example.com/b").await.unwrap_err();
+        assert!(
+            matches!(
+                err,
+                EnclaveError::RateLimited {
+                    retry_after_secs: 30,
+                    ..
+                }
+            ),
+            "{err:?}"
+        );
+        // The refused archive didn't take a token: the queue is unchanged.
+        let now = Instant::now();
+        assert_eq!(
+            limiter.reserve("example.com", 1.0 / 60.0, now + Duration::from_secs(60)),
+            Ok(Duration::ZERO)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disabled_by_default() {
+        let limiter = HostRateLimiter::new(&PermaConfig::default());
+        let start = Instant::now();
+        for _ in 0..10 {
+            limiter.acquire("https://example.com").await.unwrap();
+        }
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+}
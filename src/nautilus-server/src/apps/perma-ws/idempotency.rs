@@ -0,0 +1,229 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::attestation::SignedPermaResponse;
+use super::config::PermaConfig;
+use super::PermaRequest;
+use crate::EnclaveError;
+use fastcrypto::encoding::{Encoding, Hex};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::OnceCell;
+use tokio::time::Instant;
+use tracing::info;
+
+/// Longest idempotency key accepted.
+pub const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+/// Keys remembered at once. New keys over it are refused until the oldest
+/// expire, so clients can't grow the cache without bound.
+const MAX_TRACKED_KEYS: usize = 4096;
+
+/// Signed responses by `idempotency_key` for `IDEMPOTENCY_TTL_SECS`, so a
+/// client retrying after a dropped connection gets the archive it already
+/// started instead of a second scoop. Keys are per process: a restarted or
+/// different enclave archives again.
+#[derive(Debug)]
+pub struct IdempotencyCache {
+    /// `None` disables the cache.
+    ttl: Option<Duration>,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// Hex SHA-256 of the request the key was first used with.
+    request_hash: String,
+    created: Instant,
+    /// Set once the first archive with the key succeeds. Requests arriving
+    /// before then wait on it instead of archiving again.
+    response: Arc<OnceCell<SignedPermaResponse>>,
+}
+
+impl IdempotencyCache {
+    pub fn new(config: &PermaConfig) -> Self {
+        Self {
+            ttl: Some(Duration::from_secs(config.idempotency_ttl_secs))
+                .filter(|ttl| !ttl.is_zero()),
+            entries: Mutex::default(),
+        }
+    }
+
+    /// Run `archive` on `request` unless an archive with the same `key` ran
+    /// within the TTL, in which case its signed response is returned, or is
+    /// running, in which case it is awaited. Failed archives aren't cached, a
+    /// retry with the key archives again. Reusing a key for another request
+    /// is a 400.
+    pub async fn get_or_archive<F, Fut>(
+        &self,
+        key: Option<&str>,
+        request: PermaRequest,
+        archive: F,
+    ) -> Result<SignedPermaResponse, EnclaveError>
+    where
+        F: FnOnce(PermaRequest) -> Fut,
+        Fut: Future<Output = Result<SignedPermaResponse, EnclaveError>>,
+    {
+        let (Some(key), Some(ttl)) = (key, self.ttl) else {
+            return archive(request).await;
+        };
+        validate_key(key)?;
+        let response = self.entry(key, &request_hash(&request)?, ttl, Instant::now())?;
+        if response.initialized() {
+            info!("Returning the cached response for idempotency key {}", key);
+        }
+        response.get_or_try_init(|| archive(request)).await.cloned()
+    }
+
+    /// Response cell of `key` at `now`, created for `request_hash` when the
+    /// key is new or expired. `Unavailable` for a new key while
+    /// [`MAX_TRACKED_KEYS`] are remembered.
+    fn entry(
+        &self,
+        key: &str,
+        request_hash: &str,
+        ttl: Duration,
+        now: Instant,
+    ) -> Result<Arc<OnceCell<SignedPermaResponse>>, EnclaveError> {
+        let mut entries = self.entries.lock().expect("lock poisoned");
+        entries.retain(|_, e| now.duration_since(e.created) < ttl);
+        if entries.len() >= MAX_TRACKED_KEYS && !entries.contains_key(key) {
+            let oldest = entries.values().map(|e| e.created).min().unwrap_or(now);
+            let expires_in = ttl.saturating_sub(now.duration_since(oldest));
+            return Err(EnclaveError::Unavailable {
+                reason: "too many idempotency keys in use".to_string(),
+                retry_after_secs: expires_in.as_secs_f64().ceil().max(1.0) as u64,
+            });
+        }
+        let entry = entries.entry(key.to_string()).or_insert_with(|| Entry {
+            request_hash: request_hash.to_string(),
+            created: now,
+            response: Arc::default(),
+        });
+        if entry.request_hash != request_hash {
+            return Err(EnclaveError::BadRequest(format!(
+                "idempotency_key {key} was already used with a different request"
+            )));
+        }
+        Ok(entry.response.clone())
+    }
+}
+
+fn validate_key(key: &str) -> Result<(), EnclaveError> {
+    if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LEN {
+        return Err(EnclaveError::BadRequest(format!(
+            "idempotency_key must be 1 to {MAX_IDEMPOTENCY_KEY_LEN} bytes"
+        )));
+    }
+    Ok(())
+}
+
+fn request_hash(request: &PermaRequest) -> Result<String, EnclaveError> {
+    let json = serde_json::to_vec(request)
+        .map_err(|e| EnclaveError::Internal(format!("Failed to encode request: {e}")))?;
+    Ok(Hex::encode(Sha256::digest(json)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::attestation::test_signed_response;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn perma_request(url: &str) -> PermaRequest {
+        serde_json::from_value(serde_json::json!({ "url": url })).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_requests_archive_once() {
+        let cache = IdempotencyCache::new(&PermaConfig::default());
+        let request = || perma_request("https://example.com");
+        let archives = &AtomicUsize::new(0);
+        let archive = move |_: PermaRequest| async move {
+            archives.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok(test_signed_response("ABCDEF-GH12"))
+        };
+        let (first, second) = tokio::join!(
+            cache.get_or_archive(Some("retry-1"), request(), archive),
+            cache.get_or_archive(Some("retry-1"), request(), archive),
+        );
+        assert_eq!(archives.load(Ordering::SeqCst), 1);
+        assert_eq!(first.unwrap().signature, second.unwrap().signature);
+
+        let later = cache
+            .get_or_archive(Some("retry-1"), request(), archive)
+            .await
+            .unwrap();
+        assert_eq!(later.response.data.reference_id, "ABCDEF-GH12");
+        assert_eq!(archives.load(Ordering::SeqCst), 1);
+
+        cache
+            .get_or_archive(None, request(), archive)
+            .await
+            .unwrap();
+        assert_eq!(archives.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_failures_are_not_cached_and_keys_expire() {
+        let cache = IdempotencyCache::new(&PermaConfig::default());
+        let request = || perma_request("https://example.com");
+        let failed = cache
+            .get_or_archive(Some("retry-2"), request(), |_| async {
+                Err(EnclaveError::Upstream("scooper down".to_string()))
+            })
+            .await;
+        assert!(matches!(failed, Err(EnclaveError::Upstream(_))));
+        let retried = cache
+            .get_or_archive(Some("retry-2"), request(), |_| async {
+                Ok(test_signed_response("ABCDEF-GH12"))
+            })
+            .await;
+        assert!(retried.is_ok());
+
+        let other = perma_request("https://example.org");
+        let other = cache
+            .get_or_archive(Some("retry-2"), other, |_| async {
+                Ok(test_signed_response("ZYXWVU-TS98"))
+            })
+            .await;
+        assert!(matches!(other, Err(EnclaveError::BadRequest(_))));
+
+        let ttl = Duration::from_secs(PermaConfig::default().idempotency_ttl_secs);
+        let hash = request_hash(&request()).unwrap();
+        let now = Instant::now();
+        let cached = cache.entry("retry-2", &hash, ttl, now).unwrap();
+        assert!(cached.initialized());
+        let expired = cache.entry("retry-2", &hash, ttl, now + ttl).unwrap();
+        assert!(!expired.initialized());
+    }
+
+    #[test]
+    fn test_new_keys_over_capacity_are_refused() {
+        let cache = IdempotencyCache::new(&PermaConfig::default());
+        let ttl = Duration::from_secs(600);
+        let now = Instant::now();
+        for i in 0..MAX_TRACKED_KEYS {
+            cache.entry(&format!("key-{i}"), "hash", ttl, now).unwrap();
+        }
+        let later = now + Duration::from_secs(100);
+        let err = cache.entry("key-new", "hash", ttl, later).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                EnclaveError::Unavailable {
+                    retry_after_secs: 500,
+                    ..
+                }
+            ),
+            "{err:?}"
+        );
+        // Known keys still resolve, and new ones fit once the old expired.
+        assert!(cache.entry("key-0", "hash", ttl, later).is_ok());
+        assert!(cache.entry("key-new", "hash", ttl, now + ttl).is_ok());
+    }
+}
@@ -1,18 +1,77 @@
 // Copyright (c), Mysten Labs, Inc.
 // SPDX-License-Identifier: Apache-2.0
 
+pub mod admin;
+pub mod admission;
+pub mod attestation;
+pub mod attestation_store;
+pub mod batch;
+pub mod callback;
+pub mod canonical;
+pub mod capture;
+pub mod config;
+pub mod data_archive;
+pub mod dead_letter;
+pub mod deletion;
+pub mod freshness;
+pub mod host_limiter;
+pub mod idempotency;
+pub mod outbound_log;
+pub mod phash;
+pub mod pipeline;
+pub mod progress;
+pub mod provider;
+pub mod quota;
+pub mod recent_captures;
+pub mod redirect;
+pub mod reference_id;
+pub mod retry;
+pub mod scooper_auth;
+pub mod scooper_jobs;
+pub mod state;
+pub mod storage;
+pub mod stream;
+pub mod target_http;
+pub mod text;
+pub mod tls;
+pub mod upstream;
+pub mod validation;
+
+pub use admin::spawn_host_admin_server;
+pub use batch::process_batch;
+pub use capture::CaptureOptions;
+pub use config::PermaConfig;
+pub use state::PermaState;
+pub use stream::process_data_stream;
+pub use validation::validate_request;
+
 use crate::common::IntentMessage;
-use crate::common::{to_signed_response, IntentScope, ProcessDataRequest, ProcessedDataResponse};
+use crate::common::{archive_pipeline, ProcessDataRequest, ProcessedDataResponse, SigningOptions};
+use crate::common::{attach_platform_attestation, attestation_document};
+use crate::common::{probe_dependency, DependencyStatus};
+use crate::common::{Encoded, EncodingQuery, ResponseEncoding};
+use crate::tsa::TimestampOptions;
 use crate::AppState;
 use crate::EnclaveError;
-use axum::extract::State;
+use attestation::SignedPermaResponse;
+use axum::extract::{Path, Query, State};
+use axum::http::HeaderMap;
 use axum::Json;
+use callback::spawn_callback;
+use config::TSA_TIMEOUT;
+use dead_letter::record_dead_letter;
+use pipeline::{Base36ReferenceIds, PermaCapture, PermaProvider, PermaSink};
+use progress::Progress;
+use reference_id::reference_id_timestamp_ms;
+use scooper_jobs::{query_job_status, JobStatusResponse};
 use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
+use std::collections::BTreeMap;
 use std::sync::Arc;
-use tracing::info;
-use rand::Rng;
-use urlencoding;
+use std::time::{Duration, Instant};
+use tokio::sync::OwnedSemaphorePermit;
+use validation::{
+    first_error, validate_and_normalize_url, validate_perma_request, ValidatedRequest,
+};
 /// ====
 /// Core Nautilus server logic, replace it with your own
 /// relavant structs and process_data endpoint.
@@ -24,247 +83,794 @@ pub struct PermaResponse {
     pub reference_id: String,
     pub screenshot_blob_id: String,
     pub screenshot_byte_size: usize,
+    /// Effective full-page height ceiling the capture was taken with.
+    pub full_page_max_height: Option<u32>,
+    /// Screenshot provider that produced the capture.
+    pub screenshot_provider: String,
+    /// Hex SHA-256 of the target's leaf TLS certificate, when captured.
+    pub tls_cert_fingerprint: Option<String>,
+    /// Fingerprints of the presented certificate chain, leaf first.
+    pub tls_cert_chain: Vec<String>,
+    /// Page the requested URL redirected to, when the chain was recorded.
+    pub final_url: Option<String>,
+    /// Hex SHA-256 of the BCS encoded redirect chain, requested URL first.
+    pub redirect_chain_hash: Option<String>,
+    /// Extra captures taken at the requested `responsive_widths`, by
+    /// ascending width.
+    pub responsive_captures: Vec<ResponsiveCapture>,
+    /// Color scheme the page was rendered with (`light`, `dark` or
+    /// `no_preference`), when requested.
+    pub color_scheme: Option<String>,
+    /// Canonical URL declared by the requested page, archived in its place
+    /// when `follow_canonical` was set. `url` stays the requested URL.
+    pub canonical_url: Option<String>,
+    /// Perceptual hash of the screenshot (64 bit dHash, 16 hex chars) when
+    /// `compute_phash` was set. Archives of visually similar pages have
+    /// hashes a small Hamming distance apart. `None` for PDF captures.
+    pub phash: Option<String>,
+    /// Blob id of the page's extracted text when `extract_text` was set.
+    pub text_blob_id: Option<String>,
+    /// Hex SHA-256 of that text, so the attestation covers the exact text
+    /// a search index is built from.
+    pub text_sha256: Option<String>,
+    /// Media type of the target when it served data (e.g. JSON) and
+    /// `ALLOW_DATA_ARCHIVE` is set. The body is then stored as the screenshot
+    /// blob and `screenshot_provider` is `data`.
+    pub data_content_type: Option<String>,
+    /// Hex SHA-256 of the stored data, over its canonical form for JSON.
+    pub data_sha256: Option<String>,
+    /// Walrus blob id of the page's WACZ archive uploaded by scooper.
+    pub wacz_blob_id: String,
+    /// The request's `nonce`, so the client can check the attestation
+    /// answers its own request rather than replaying an older one.
+    pub nonce: Option<String>,
+}
+
+/// Payload `process_data` signs, checked by `/verify_signature`.
+pub type SignedPayload = PermaResponse;
+
+/// Capture of the page at one of the requested responsive widths.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ResponsiveCapture {
+    pub width: u32,
+    pub blob_id: String,
+    pub byte_size: usize,
 }
 
 /// Inner type T for ProcessDataRequest<T>
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct PermaRequest {
     pub url: String,
+    #[serde(flatten)]
+    pub capture: CaptureOptions,
+    /// Whether to save the attestation with the frontend, defaults to true.
+    /// Set to false to only get the signed response back.
+    #[serde(default)]
+    pub persist: Option<bool>,
+    /// Pin a configured screenshot provider, without falling back to others.
+    /// Uses the configured order with fallback when omitted.
+    #[serde(default)]
+    pub provider: Option<String>,
+    /// Fingerprint the target's TLS certificate chain at capture time.
+    /// Ignored for non-https urls.
+    #[serde(default)]
+    pub capture_tls_cert: Option<bool>,
+    /// Resolve the URL's redirects before capturing and sign the final URL
+    /// with a hash of the chain.
+    #[serde(default)]
+    pub record_redirect_chain: Option<bool>,
+    /// Archive and sign the page's `<link rel="canonical">` instead of the
+    /// URL, when it is same-origin and not a private host.
+    #[serde(default)]
+    pub follow_canonical: Option<bool>,
+    /// Download the screenshot after capture and sign its perceptual hash,
+    /// to compare archives visually. Skipped for PDF captures.
+    #[serde(default)]
+    pub compute_phash: Option<bool>,
+    /// Also store the page's text (at most
+    /// [`text::MAX_EXTRACTED_TEXT_BYTES`]) for full-text search, and sign
+    /// its hash.
+    #[serde(default)]
+    pub extract_text: Option<bool>,
+    /// Client bookkeeping (a job id, a user id) saved with the attestation.
+    /// NOT signed: it is stored next to the signed response, outside the BCS
+    /// payload, so nothing attests to it. At most
+    /// [`validation::MAX_CLIENT_METADATA_BYTES`] as JSON.
+    #[serde(default)]
+    pub client_metadata: Option<serde_json::Value>,
+    /// Return the enclave's attestation document (as from `/get_attestation`)
+    /// with the signed response, as `platform_attestation`.
+    #[serde(default)]
+    pub include_attestation: Option<bool>,
+    /// Store the captures in this bucket instead of perma-ws. Must be one of
+    /// `ALLOWED_STORAGE_BUCKETS`.
+    #[serde(default)]
+    pub bucket: Option<String>,
+    /// Store the captures under this key prefix, e.g. a tenant id. Must be
+    /// one of `ALLOWED_PATH_PREFIXES`.
+    #[serde(default)]
+    pub path_prefix: Option<String>,
+    /// Client chosen value, at most [`validation::MAX_NONCE_BYTES`], signed
+    /// back in the response as `nonce`.
+    #[serde(default)]
+    pub nonce: Option<String>,
+    /// Also POST the signed response here once the archive completed, scoop
+    /// included, signed with `CALLBACK_SECRET`, see [`callback`]. NOT signed
+    /// into the response.
+    #[serde(default)]
+    pub callback_url: Option<String>,
 }
 
-/// Encode a u64 number to base36 string (like JavaScript's toString(36))
-fn u64_to_base36(mut n: u64) -> String {
-    if n == 0 {
-        return "0".to_string();
+/// Scooper endpoint that archives the page as WACZ and uploads it to Walrus,
+/// unless overridden with `SCOOPER_URL`.
+pub const DEFAULT_SCOOPER_URL: &str = "https://scooper-production.up.railway.app/scoop-async";
+
+/// Archive the requested page and return the signed response, as JSON or,
+/// with `Accept: application/cbor` or `?encoding=cbor`, as CBOR. A request
+/// repeating a recent `idempotency_key` gets the first one's response, see
+/// [`IdempotencyCache`](idempotency::IdempotencyCache).
+pub async fn process_data(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<EncodingQuery>,
+    headers: HeaderMap,
+    Json(request): Json<ProcessDataRequest<PermaRequest>>,
+) -> Result<Encoded<ProcessedDataResponse<IntentMessage<PermaResponse>>>, EnclaveError> {
+    let encoding = ResponseEncoding::negotiate(&query, &headers);
+    let state = &state;
+    state
+        .perma
+        .idempotency
+        .get_or_archive(
+            request.idempotency_key.as_deref(),
+            request.payload,
+            |payload| async move {
+                // Held until the signed response is returned.
+                let _permit = admit(state, &payload).await?;
+                archive(state, payload, &Progress::default()).await
+            },
+        )
+        .await
+        .map(|signed| Encoded(encoding, signed))
+}
+
+/// Let `request` start archiving: wait for its host's turn, see
+/// [`HostRateLimiter`](host_limiter::HostRateLimiter), then take an archive
+/// permit, held until the returned guard is dropped. The host comes first so
+/// an archive throttled by its host doesn't sit on a permit meanwhile. An
+/// invalid url isn't throttled, [`archive`] reports it.
+pub async fn admit(
+    state: &AppState,
+    request: &PermaRequest,
+) -> Result<OwnedSemaphorePermit, EnclaveError> {
+    if let Ok(url) = validate_and_normalize_url(&request.url) {
+        state.perma.host_limiter.acquire(url.as_str()).await?;
+    }
+    state.perma.archive_limiter.acquire().await
+}
+
+/// Run the archive pipeline for one request: submit the page to scooper,
+/// capture a screenshot, then sign and persist the response. Stages are
+/// reported to `progress` as they complete.
+pub async fn archive(
+    state: &AppState,
+    request: PermaRequest,
+    progress: &Progress,
+) -> Result<SignedPermaResponse, EnclaveError> {
+    let config = &state.perma.config;
+    let ValidatedRequest {
+        url,
+        options,
+        providers,
+        storage,
+    } = validate_perma_request(&request, config).map_err(first_error)?;
+    let full_page_max_height = options.full_page_max_height;
+    let color_scheme = options.color_scheme.map(|s| s.as_str().to_string());
+    state.perma.daily_quota.try_consume(chrono::Utc::now())?;
+
+    let provider = PermaProvider {
+        config,
+        http: &state.http,
+        target_http: &state.perma.target_http,
+        scooper_jobs: &state.perma.scooper_jobs,
+        url: &url,
+        options,
+        providers,
+        storage,
+        capture_tls_cert: request.capture_tls_cert.unwrap_or(false),
+        record_redirect_chain: request.record_redirect_chain.unwrap_or(false),
+        follow_canonical: request.follow_canonical.unwrap_or(false),
+        compute_phash: request.compute_phash.unwrap_or(false),
+        extract_text: request.extract_text.unwrap_or(false),
+        progress,
+    };
+    let sink = PermaSink {
+        perma: &state.perma,
+        http: &state.http,
+        persist: request.persist.unwrap_or(true),
+        client_metadata: request.client_metadata.as_ref(),
+        progress,
+    };
+    let timestamp = config.tsa_url.as_ref().map(|tsa_url| TimestampOptions {
+        tsa_url: tsa_url.clone(),
+        http: state.http.clone(),
+        fail_open: config.tsa_fail_open,
+        timeout: TSA_TIMEOUT,
+    });
+    let signing = SigningOptions {
+        kp: &state.eph_kp,
+        intent: state.intent_scope,
+        hash_threshold: config.sign_hash_threshold_bytes,
+        timestamp: timestamp.as_ref(),
+    };
+
+    let result = archive_pipeline(
+        &Base36ReferenceIds {
+            epoch_secs: config.reference_id_epoch_secs,
+        },
+        &provider,
+        &sink,
+        &signing,
+        |reference_id, capture: PermaCapture| {
+            let tls_cert = capture.tls_cert;
+            let redirect_chain = capture.redirect_chain;
+            let text = capture.text;
+            let data = capture.data;
+            Ok(PermaResponse {
+                url: url.clone(),
+                reference_id: reference_id.to_string(),
+                screenshot_blob_id: capture.screenshot.blob_id,
+                screenshot_byte_size: capture.screenshot.byte_size,
+                full_page_max_height,
+                screenshot_provider: capture.screenshot_provider,
+                tls_cert_fingerprint: tls_cert.as_ref().map(|c| c.fingerprint.clone()),
+                tls_cert_chain: tls_cert.map(|c| c.chain).unwrap_or_default(),
+                final_url: redirect_chain.as_ref().map(|c| c.final_url().to_string()),
+                redirect_chain_hash: redirect_chain.as_ref().map(|c| c.hash()),
+                responsive_captures: capture.responsive_captures,
+                color_scheme,
+                canonical_url: capture.canonical_url,
+                phash: capture.phash,
+                text_blob_id: text.as_ref().map(|t| t.blob_id.clone()),
+                text_sha256: text.map(|t| t.sha256),
+                data_content_type: data.as_ref().map(|d| d.content_type.clone()),
+                data_sha256: data.map(|d| d.sha256),
+                wacz_blob_id: capture.wacz_blob_id,
+                nonce: request.nonce.clone(),
+            })
+        },
+    )
+    .await;
+    if let Err(e) = &result {
+        record_dead_letter(&state.http, config, &request, e).await;
     }
-    let base36_chars: Vec<char> = "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ".chars().collect();
-    let mut result = String::new();
-    while n > 0 {
-        result.push(base36_chars[(n % 36) as usize]);
-        n /= 36;
+    let mut signed = result?;
+    // Archives the client kept out of the frontend stay out of the list too.
+    if request.persist.unwrap_or(true) {
+        state.perma.recent_captures.record(&signed);
     }
-    result.chars().rev().collect()
+    if let (Some(callback_url), Some(secret)) = (&request.callback_url, &config.callback_secret) {
+        spawn_callback(
+            state.perma.target_http.clone(),
+            callback_url.trim().to_string(),
+            secret.clone(),
+            signed.clone(),
+        );
+    }
+    if request.include_attestation.unwrap_or(false) {
+        let document = attestation_document(&state.eph_kp.public_key_bytes())?;
+        attach_platform_attestation(&mut signed, &document);
+    }
+    Ok(signed)
 }
 
-/// Get ETag from a URL using a Range request (only downloads 1 byte)
-async fn get_etag(url: &str) -> Result<String, EnclaveError> {
-    let client = reqwest::Client::new();
-    let response = client
-        .get(url)
-        .header("Range", "bytes=0-0")
-        .send()
-        .await
-        .map_err(|e| EnclaveError::GenericError(format!("Failed to fetch URL: {}", e)))?;
-    
-    let etag = response
-        .headers()
-        .get("etag")
-        .ok_or_else(|| EnclaveError::GenericError("ETag header not found".to_string()))?
-        .to_str()
-        .map_err(|e| EnclaveError::GenericError(format!("Invalid ETag header: {}", e)))?
-        .to_string();
-    
-    Ok(etag)
+/// Prerequisites `/ready` waits for: the secrets archives need, see
+/// [`PermaConfig::missing_secrets`].
+pub async fn missing_prerequisites(state: &AppState) -> Vec<String> {
+    state.perma.config.missing_secrets()
 }
 
-/// Generate a reference ID by appending 2 random characters, capitalizing, and adding a hyphen before the last 4 characters
-fn generate_reference_id() -> Result<String, EnclaveError> {
-    // based on current timestamp, generate a referenceId from base36 encoding of current time in seconds since 01-01-2025
-    let epoch_2025 = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1735689600); // 2025-01-01 00:00:00 UTC
-    let current_timestamp_millis = std::time::SystemTime::now()
-        .duration_since(epoch_2025)
-        .map_err(|e| EnclaveError::GenericError(format!("Failed to get current timestamp: {}", e)))?
-        .as_millis() as u64;
-
-    let mut s = u64_to_base36(current_timestamp_millis);
-    
-    // Append 2 random alphanumeric characters
-    let base36_chars: Vec<char> = "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ".chars().collect();
-    let mut rng = rand::thread_rng();
-    for _ in 0..2 {
-        let random_idx = rng.gen_range(0..36);
-        s.push(base36_chars[random_idx]);
+/// How long the deep health check reuses the last probe of the
+/// dependencies. `/health_check` is public, and a probe writes to storage and
+/// calls ScreenshotOne, so this bounds what callers can make the enclave do.
+pub const DEPENDENCY_CHECK_TTL: Duration = Duration::from_secs(30);
+
+/// Dependencies the deep health check reports, probed at most once per
+/// [`DEPENDENCY_CHECK_TTL`], see [`probe_dependencies`].
+pub async fn dependency_checks(state: &AppState) -> BTreeMap<String, DependencyStatus> {
+    // Held while probing, so concurrent checks share one probe.
+    let mut last = state.perma.dependency_checks.lock().await;
+    if let Some((checked_at, statuses)) = last.as_ref() {
+        if checked_at.elapsed() < DEPENDENCY_CHECK_TTL {
+            return statuses.clone();
+        }
     }
-        
-    // Add hyphen before the last 4 characters (split after the 4th character from the back)
-    let split_point = s.len().saturating_sub(4);
-    Ok(format!("{}-{}", &s[..split_point], &s[split_point..]))
+    let statuses = probe_dependencies(state).await;
+    *last = Some((Instant::now(), statuses.clone()));
+    statuses
 }
 
-pub async fn process_data(
-    State(state): State<Arc<AppState>>,
-    Json(request): Json<ProcessDataRequest<PermaRequest>>,
-) -> Result<Json<ProcessedDataResponse<IntentMessage<PermaResponse>>>, EnclaveError> {
-    let reference_id = generate_reference_id()?;
-    let url = &request.payload.url;
-
-    let scooper_secret = std::env::var("SCOOPER_SECRET")
-        .map_err(|_| EnclaveError::GenericError("SCOOPER_SECRET not set".to_string()))?;
-
-    // Make a POST request to scooper - it will upload to Walrus the .wacz file
-    let scooper_url = "https://scooper-production.up.railway.app/scoop-async";
-        
-    // Build the JSON body for the scooper request matching the API structure
-    let scooper_request_body = json!({
-        "url": url,
-        "referenceId": reference_id,
-        "secret": scooper_secret
+/// Probe the dependencies at once: scooper answers at `SCOOPER_URL` (any
+/// status below 500, the probe is a HEAD), ScreenshotOne accepts
+/// `ACCESS_KEY` on its usage endpoint, and the storage credentials can
+/// write, see [`storage::check_storage_write`].
+async fn probe_dependencies(state: &AppState) -> BTreeMap<String, DependencyStatus> {
+    let config = &state.perma.config;
+    let scooper = probe_dependency(state.http.head(&config.scooper_url), |status| {
+        !status.is_server_error()
     });
-    
-    info!("Making POST request to scooper: {}", scooper_url);
-    info!("Request body: {}", serde_json::to_string_pretty(&scooper_request_body).unwrap_or_default());
-    
-    let scooper_response = reqwest::Client::new()
-        .post(scooper_url)
-        .header("Content-Type", "application/json")
-        .json(&scooper_request_body)
-        .send()
-        .await
-        .map_err(|e| EnclaveError::GenericError(format!("Failed to get scooper response: {}", e)))?;
-    
-    let status = scooper_response.status();
-    info!("Scooper response status: {}", status);
-    
-    // check job, if it is already running then abort this
-    if status != reqwest::StatusCode::ACCEPTED {
-        return Err(EnclaveError::GenericError(format!(
-            "Scooper returned status {} instead of 202, aborting",
-            status
+    let screenshotone = async {
+        match &config.access_key {
+            Some(access_key) => {
+                let request = state
+                    .http
+                    .get(capture::SCREENSHOTONE_USAGE_URL)
+                    .query(&[("access_key", access_key)]);
+                probe_dependency(request, |status| status.is_success()).await
+            }
+            None => DependencyStatus::down("ACCESS_KEY not set"),
+        }
+    };
+    let storage_write = storage::check_storage_write(&state.http, config);
+    let (scooper, screenshotone, storage_write) =
+        tokio::join!(scooper, screenshotone, storage_write);
+    BTreeMap::from([
+        ("scooper".to_string(), scooper),
+        ("screenshotone".to_string(), screenshotone),
+        ("storage_write".to_string(), storage_write),
+    ])
+}
+
+/// State of the scooper job that archives `reference_id` as WACZ. 404 when
+/// this enclave didn't start it (or has restarted since), a failed scoop
+/// comes back with scooper's error message.
+pub async fn job_status(
+    State(state): State<Arc<AppState>>,
+    Path(reference_id): Path<String>,
+) -> Result<Json<JobStatusResponse>, EnclaveError> {
+    let job_id = state
+        .perma
+        .scooper_jobs
+        .job_id(&reference_id)
+        .ok_or_else(|| {
+            EnclaveError::NotFound(format!("no scooper job for reference id {reference_id}"))
+        })?;
+    let status = query_job_status(
+        &state.http,
+        &state.perma.config.scooper_job_status_url()?,
+        &job_id,
+        state.perma.config.max_response_bytes,
+    )
+    .await?;
+    Ok(Json(JobStatusResponse {
+        reference_id,
+        job_id,
+        status,
+    }))
+}
+
+/// Response of `/decode_reference_id`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DecodedReferenceId {
+    pub reference_id: String,
+    /// When the id was generated, in Unix milliseconds.
+    pub timestamp_ms: u64,
+    /// The same time in RFC 3339, UTC, e.g. `2025-04-07T15:15:00.000Z`.
+    pub timestamp: String,
+}
+
+/// When `reference_id` was generated, read back from the base36 timestamp it
+/// starts with, see [`reference_id_timestamp_ms`], so support can check when
+/// a capture was taken without any upstream call. The id needn't come from
+/// this enclave, but must count from the same `REFERENCE_ID_EPOCH`. 400 for an
+/// id of another shape or dated in the future.
+pub async fn decode_reference_id(
+    State(state): State<Arc<AppState>>,
+    Path(reference_id): Path<String>,
+) -> Result<Json<DecodedReferenceId>, EnclaveError> {
+    let invalid =
+        || EnclaveError::BadRequest(format!("{reference_id} is not a generated reference id"));
+    let epoch_secs = state.perma.config.reference_id_epoch_secs;
+    let timestamp_ms = reference_id_timestamp_ms(&reference_id, epoch_secs).ok_or_else(invalid)?;
+    let timestamp = i64::try_from(timestamp_ms)
+        .ok()
+        .and_then(chrono::DateTime::from_timestamp_millis)
+        .ok_or_else(invalid)?;
+    if timestamp > chrono::Utc::now() {
+        return Err(EnclaveError::BadRequest(format!(
+            "{reference_id} is dated in the future"
         )));
     }
-    
-    let scooper_json = scooper_response.json::<Value>().await.map_err(|e| {
-        EnclaveError::GenericError(format!("Failed to parse scooper response: {}", e))
-    })?;
-    
-    info!("Scooper response body: {}", serde_json::to_string_pretty(&scooper_json).unwrap_or_default());
-
-    let access_key = std::env::var("ACCESS_KEY")
-        .map_err(|_| EnclaveError::GenericError("ACCESS_KEY not set".to_string()))?;
-    
-    let storage_access_key_id = std::env::var("STORAGE_ACCESS_KEY_ID")
-        .map_err(|_| EnclaveError::GenericError("STORAGE_ACCESS_KEY_ID not set".to_string()))?;
-
-    let storage_secret_access_key = std::env::var("STORAGE_SECRET_ACCESS_KEY")
-        .map_err(|_| EnclaveError::GenericError("STORAGE_SECRET_ACCESS_KEY not set".to_string()))?;
-    
-    let frontend_url = std::env::var("FRONTEND_URL")
-        .map_err(|_| EnclaveError::GenericError("FRONTEND_URL not set".to_string()))?;
-
-    let admin_secret = std::env::var("ADMIN_SECRET")
-        .map_err(|_| EnclaveError::GenericError("ADMIN_SECRET not set".to_string()))?;
-    
-    let storage_path = format!("{}%2F{}", reference_id, reference_id);
-
-    // call screenshotone for a screenshot then get blob_id
-    let screenshotone_url = format!(
-        "https://api.screenshotone.com/take?\
-        access_key={access_key}&\
-        url={}&\
-        format=png&\
-        block_ads=true&\
-        block_cookie_banners=true&\
-        block_banners_by_heuristics=true&\
-        block_trackers=true&\
-        block_chats=true&\
-        delay=0&\
-        timeout=60&\
-        storage_acl=public-read&\
-        store=true&\
-        storage_bucket=perma-ws&\
-        storage_path={storage_path}&\
-        storage_endpoint=https%3A%2F%2Fstorage.nami.cloud&\
-        storage_return_location=true&\
-        storage_access_key_id={storage_access_key_id}&\
-        storage_secret_access_key={storage_secret_access_key}&\
-        capture_beyond_viewport=true&\
-        response_type=json&\
-        full_page=true&\
-        full_page_scroll=true&\
-        full_page_scroll_delay=500&\
-        image_quality=80",
-        urlencoding::encode(url)
-    );
-    
-    info!("Calling ScreenshotOne API for: {}", url);
-    let screenshotone_response = reqwest::get(&screenshotone_url)
+    Ok(Json(DecodedReferenceId {
+        timestamp_ms,
+        timestamp: timestamp.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        reference_id,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::{to_signed_response, verify_signed_response, IntentScope};
+    use fastcrypto::encoding::{Encoding, Hex};
+    use reference_id::is_valid_reference_id;
+    use serde_json::json;
+    use wiremock::matchers::{header, method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    /// Walrus blob id served as the ETag of the stored screenshot.
+    const BLOB_ID: &str = "mC2ePrmW9VnmM_TRlN7zdh2Qn1o7ZH0ahR_q1nwyydE";
+
+    /// Stand-ins for scooper and ScreenshotOne, which also serves the stored
+    /// screenshot, so `process_data` runs without the paid APIs.
+    struct Upstreams {
+        scooper: MockServer,
+        screenshotone: MockServer,
+    }
+
+    impl Upstreams {
+        async fn start() -> Self {
+            Self {
+                scooper: MockServer::start().await,
+                screenshotone: MockServer::start().await,
+            }
+        }
+
+        /// App state calling the mocks, with every secret set and retries
+        /// off so failures surface at once.
+        fn state(&self) -> Arc<AppState> {
+            Arc::new(AppState {
+                perma: PermaState::with_config(PermaConfig {
+                    scooper_url: format!("{}/scoop-async", self.scooper.uri()),
+                    scooper_secret: Some("scooper".to_string()),
+                    screenshotone_url: format!("{}/take", self.screenshotone.uri()),
+                    access_key: Some("access".to_string()),
+                    storage_access_key_id: Some("id".to_string()),
+                    storage_secret_access_key: Some("secret".to_string()),
+                    upstream_max_attempts: 1,
+                    screenshotone_max_retries: 0,
+                    ..Default::default()
+                }),
+                ..AppState::for_test()
+            })
+        }
+
+        async fn mount_scooper(&self, response: ResponseTemplate) {
+            Mock::given(method("POST"))
+                .and(path("/scoop-async"))
+                .respond_with(response)
+                .mount(&self.scooper)
+                .await;
+        }
+
+        /// ScreenshotOne answering with the stored location of the capture.
+        async fn mount_screenshotone(&self) {
+            let location = format!("{}/perma-ws/capture.png", self.screenshotone.uri());
+            Mock::given(method("GET"))
+                .and(path("/take"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                    "store": { "location": location },
+                    "screenshot_url": location,
+                })))
+                .mount(&self.screenshotone)
+                .await;
+        }
+
+        /// The stored screenshot, with an ETag unless `etag` is false.
+        async fn mount_storage(&self, etag: bool) {
+            let mut response =
+                ResponseTemplate::new(206).insert_header("content-range", "bytes 0-0/1234");
+            if etag {
+                response = response.insert_header("etag", format!("\"{BLOB_ID}\""));
+            }
+            Mock::given(method("GET"))
+                .and(path("/perma-ws/capture.png"))
+                .and(header("range", "bytes=0-0"))
+                .respond_with(response)
+                .mount(&self.screenshotone)
+                .await;
+        }
+    }
+
+    async fn call_process_data(state: Arc<AppState>) -> Result<SignedPermaResponse, EnclaveError> {
+        call_process_data_with(
+            state,
+            json!({ "url": "https://example.com", "persist": false }),
+        )
         .await
-        .map_err(|e| EnclaveError::GenericError(format!("Failed to call ScreenshotOne: {}", e)))?;
-    
-    let screenshotone_json: Value = screenshotone_response.json().await
-        .map_err(|e| EnclaveError::GenericError(format!("Failed to parse ScreenshotOne response: {}", e)))?;
-    
-    info!("ScreenshotOne response: {}", serde_json::to_string_pretty(&screenshotone_json).unwrap_or_default());
-    
-    // Get the blob_id (ETag) from the screenshotone response URL
-    let screenshot_blob_url = screenshotone_json["store"]["location"]
-        .as_str()
-        .ok_or_else(|| EnclaveError::GenericError("store.location not found in ScreenshotOne response".to_string()))?;
-    let screenshot_blob_id = get_etag(screenshot_blob_url).await?;
-
-    // Get byte size of screenshot_url
-    let screenshot_url = screenshotone_json["screenshot_url"].as_str().unwrap_or("");
-    // Use Range request to get only headers (1 byte) instead of downloading the whole file
-    let client = reqwest::Client::new();
-    let screenshot_response = client
-        .get(screenshot_url)
-        .header("Range", "bytes=0-0")
-        .send()
+    }
+
+    async fn call_process_data_with(
+        state: Arc<AppState>,
+        payload: serde_json::Value,
+    ) -> Result<SignedPermaResponse, EnclaveError> {
+        let request = serde_json::from_value(json!({ "payload": payload })).unwrap();
+        process_data(
+            State(state),
+            Query(EncodingQuery::default()),
+            HeaderMap::new(),
+            Json(request),
+        )
         .await
-        .map_err(|e| EnclaveError::GenericError(format!("Failed to get screenshot: {}", e)))?;
-    
-    // Get content-length from headers to determine file size
-    let screenshot_byte_size = screenshot_response
-        .headers()
-        .get("content-range")
-        .and_then(|v| v.to_str().ok())
-        .and_then(|s| {
-            // Parse "bytes 0-0/44941" to get 44941
-            s.split('/').nth(1)?.parse::<usize>().ok()
-        })
-        .unwrap_or(0);
-    
-    // Get current timestamp in milliseconds for the response
-    let current_timestamp_ms = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .map_err(|e| EnclaveError::GenericError(format!("Failed to get current timestamp: {}", e)))?
-        .as_millis() as u64;
-    
-    let signed_response = to_signed_response(
-        &state.eph_kp,
-        PermaResponse {
-            url: url.to_string(),
-            reference_id: reference_id.clone(),
-            screenshot_blob_id,
-            screenshot_byte_size,
-        },
-        current_timestamp_ms,
-        IntentScope::ProcessData,
-    );
-
-    // save attestation - http://localhost:3001/api/attestation
-    let attestation_url = format!("{}{}", frontend_url, "/api/attestation");
-    let attestation_body = json!({
-        "admin_secret": admin_secret,
-        "reference_id": reference_id,
-        "attestation": signed_response
-    });
+        .map(|Encoded(_, signed)| signed)
+    }
+
+    #[tokio::test]
+    async fn test_process_data_with_mock_upstreams() {
+        let upstreams = Upstreams::start().await;
+        upstreams
+            .mount_scooper(ResponseTemplate::new(202).set_body_json(json!({
+                "jobId": "1",
+                "waczBlobId": "wacz-blob",
+            })))
+            .await;
+        upstreams.mount_screenshotone().await;
+        upstreams.mount_storage(true).await;
+        let state = upstreams.state();
+
+        let signed = call_process_data(state.clone()).await.unwrap();
+        let data = &signed.response.data;
+        assert_eq!(data.url, "https://example.com/");
+        assert!(
+            is_valid_reference_id(&data.reference_id),
+            "{}",
+            data.reference_id
+        );
+        assert_eq!(data.screenshot_blob_id, BLOB_ID);
+        assert_eq!(data.screenshot_byte_size, 1234);
+        assert_eq!(data.wacz_blob_id, "wacz-blob");
+        assert!(verify_signed_response(&state.eph_kp, &signed).is_ok());
+        // Requested with `persist: false`, so not listed in `/admin/captures`.
+        assert!(state.perma.recent_captures.latest(1).is_empty());
 
-    info!("Saving attestation to: {}", attestation_url);
+        // Scooper got the same reference id, signed with its secret.
+        let scooped = &upstreams.scooper.received_requests().await.unwrap()[0];
+        let body: serde_json::Value = serde_json::from_slice(&scooped.body).unwrap();
+        assert_eq!(body["referenceId"], data.reference_id.as_str());
+        assert!(scooped.headers.contains_key(scooper_auth::SIGNATURE_HEADER));
+    }
+
+    #[tokio::test]
+    async fn test_process_data_signs_responsive_captures() {
+        let upstreams = Upstreams::start().await;
+        upstreams
+            .mount_scooper(ResponseTemplate::new(202).set_body_json(json!({
+                "jobId": "1",
+                "waczBlobId": "wacz-blob",
+            })))
+            .await;
+        // Each width is stored at its own location, with its own blob id and size.
+        for width in [375, 1440] {
+            let location = format!(
+                "{}/perma-ws/capture-{width}w.png",
+                upstreams.screenshotone.uri()
+            );
+            Mock::given(method("GET"))
+                .and(path("/take"))
+                .and(query_param("viewport_width", width.to_string()))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                    "store": { "location": location },
+                    "screenshot_url": location,
+                })))
+                .with_priority(1)
+                .mount(&upstreams.screenshotone)
+                .await;
+            Mock::given(method("GET"))
+                .and(path(format!("/perma-ws/capture-{width}w.png")))
+                .respond_with(
+                    ResponseTemplate::new(206)
+                        .insert_header("content-range", format!("bytes 0-0/{width}"))
+                        .insert_header("etag", format!("\"blob-{width}w\"")),
+                )
+                .mount(&upstreams.screenshotone)
+                .await;
+        }
+        upstreams.mount_screenshotone().await;
+        upstreams.mount_storage(true).await;
+        let state = upstreams.state();
 
-    let attestation_res = reqwest::Client::new()
-        .post(attestation_url)
-        .json(&attestation_body)
-        .send()
+        let signed = call_process_data_with(
+            state.clone(),
+            json!({
+                "url": "https://example.com",
+                "persist": false,
+                "responsive_widths": [1440, 375],
+            }),
+        )
         .await
-        .map_err(|e| EnclaveError::GenericError(format!("Failed to save attestation: {}", e)))?;
+        .unwrap();
+        let data = &signed.response.data;
+        assert_eq!(data.screenshot_blob_id, BLOB_ID);
+        assert_eq!(
+            data.responsive_captures,
+            vec![
+                ResponsiveCapture {
+                    width: 375,
+                    blob_id: "blob-375w".to_string(),
+                    byte_size: 375,
+                },
+                ResponsiveCapture {
+                    width: 1440,
+                    blob_id: "blob-1440w".to_string(),
+                    byte_size: 1440,
+                },
+            ]
+        );
+        assert!(verify_signed_response(&state.eph_kp, &signed).is_ok());
+    }
 
-    if attestation_res.status() != reqwest::StatusCode::CREATED && attestation_res.status() != reqwest::StatusCode::OK {
-         return Err(EnclaveError::GenericError(format!(
-            "Failed to save attestation, status: {}",
-            attestation_res.status()
-        )));
+    #[tokio::test]
+    async fn test_decode_reference_id() {
+        let state = Arc::new(AppState::for_test());
+        let decode = |id: &str| decode_reference_id(State(state.clone()), Path(id.to_string()));
+
+        // 2025-04-07T15:15:00Z is 8349300000 ms after the reference id epoch.
+        let id = format!("{}-AB123", reference_id::u64_to_base36(8_349_300_000));
+        let Json(decoded) = decode(&id).await.unwrap();
+        assert_eq!(decoded.timestamp_ms, 1744038900000);
+        assert_eq!(decoded.timestamp, "2025-04-07T15:15:00.000Z");
+        assert_eq!(decoded.reference_id, id);
+
+        let generated =
+            reference_id::generate_reference_id(state.perma.config.reference_id_epoch_secs)
+                .unwrap();
+        assert!(decode(&generated).await.is_ok());
+        // Ids generated before the 5 character counter.
+        let Json(decoded) = decode("3U2YH-40XY").await.unwrap();
+        assert_eq!(decoded.timestamp_ms, 1744038900000);
+
+        for invalid in ["ABCDEF", "ABCDEF-GH1", "AB/CD-EFGH", "ZZZZZZZZ-0000"] {
+            let result = decode(invalid).await;
+            assert!(
+                matches!(result, Err(EnclaveError::BadRequest(_))),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_process_data_scooper_already_running() {
+        let upstreams = Upstreams::start().await;
+        upstreams
+            .mount_scooper(
+                ResponseTemplate::new(409).set_body_json(json!({ "referenceId": "ABCDEF-GH12" })),
+            )
+            .await;
+        upstreams.mount_screenshotone().await;
+        upstreams.mount_storage(true).await;
+
+        let result = call_process_data(upstreams.state()).await;
+        assert!(matches!(
+            result,
+            Err(EnclaveError::AlreadyRunning { ref reference_id }) if reference_id == "ABCDEF-GH12"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_process_data_scooper_plain_text_conflict() {
+        let upstreams = Upstreams::start().await;
+        upstreams
+            .mount_scooper(ResponseTemplate::new(409).set_body_raw("already running", "text/plain"))
+            .await;
+        upstreams.mount_screenshotone().await;
+        upstreams.mount_storage(true).await;
+
+        match call_process_data(upstreams.state()).await {
+            Err(EnclaveError::Upstream(msg)) => {
+                assert!(msg.contains("already archiving"));
+                assert!(!msg.contains("non-JSON"));
+            }
+            other => panic!("expected upstream error, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_process_data_screenshotone_error() {
+        let upstreams = Upstreams::start().await;
+        upstreams
+            .mount_scooper(ResponseTemplate::new(202).set_body_json(json!({
+                "jobId": "1",
+                "waczBlobId": "wacz-blob",
+            })))
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/take"))
+            .respond_with(ResponseTemplate::new(500).set_body_string("capture failed"))
+            .mount(&upstreams.screenshotone)
+            .await;
+
+        let state = upstreams.state();
+        let result = call_process_data(state.clone()).await;
+        assert!(matches!(result, Err(EnclaveError::Upstream(ref e)) if e.contains("500")));
+        assert!(state.perma.recent_captures.latest(1).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_process_data_missing_etag() {
+        let upstreams = Upstreams::start().await;
+        upstreams
+            .mount_scooper(ResponseTemplate::new(202).set_body_json(json!({
+                "jobId": "1",
+                "waczBlobId": "wacz-blob",
+            })))
+            .await;
+        upstreams.mount_screenshotone().await;
+        upstreams.mount_storage(false).await;
+
+        // Without an ETag on the Range request or a HEAD, the blob id falls
+        // back to the image checksum, which fails as the image can't be
+        // downloaded either.
+        let state = upstreams.state();
+        let result = call_process_data(state.clone()).await;
+        assert!(
+            matches!(result, Err(EnclaveError::Upstream(_))),
+            "{result:?}"
+        );
+        assert!(state.perma.recent_captures.latest(1).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_dependency_checks_are_reused() {
+        let scooper = MockServer::start().await;
+        Mock::given(method("HEAD"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&scooper)
+            .await;
+        // Without ScreenshotOne or storage credentials only scooper is called.
+        let state = AppState {
+            perma: PermaState::with_config(PermaConfig {
+                scooper_url: scooper.uri(),
+                ..Default::default()
+            }),
+            ..AppState::for_test()
+        };
+
+        let first = dependency_checks(&state).await;
+        assert!(first["scooper"].ok);
+        assert!(!first["storage_write"].ok);
+        assert_eq!(dependency_checks(&state).await, first);
+        scooper.verify().await;
+    }
+
+    #[test]
+    fn test_serde() {
+        // The nonce is the last field of the payload, after the intent byte
+        // and the little-endian timestamp.
+        let payload = PermaResponse {
+            url: "https://example.com".to_string(),
+            reference_id: "ABCDEF-GH12".to_string(),
+            screenshot_blob_id: "blob".to_string(),
+            screenshot_byte_size: 1,
+            full_page_max_height: None,
+            screenshot_provider: "screenshotone".to_string(),
+            tls_cert_fingerprint: None,
+            tls_cert_chain: vec![],
+            final_url: None,
+            redirect_chain_hash: None,
+            responsive_captures: vec![],
+            color_scheme: None,
+            canonical_url: None,
+            phash: None,
+            text_blob_id: None,
+            text_sha256: None,
+            data_content_type: None,
+            data_sha256: None,
+            wacz_blob_id: "wacz".to_string(),
+            nonce: Some("n0nce".to_string()),
+        };
+        let intent_msg =
+            IntentMessage::new(payload.clone(), 1744038900000, IntentScope::ProcessData);
+        let signing_payload = bcs::to_bytes(&intent_msg).expect("should not fail");
+
+        // The response carries the same bytes it signed.
+        let kp = AppState::for_test().eph_kp;
+        let signed = to_signed_response(&kp, payload, 1744038900000, IntentScope::ProcessData);
+        assert_eq!(signed.response_bcs, Some(Hex::encode(&signing_payload)));
+        assert_eq!(
+            Hex::encode(signing_payload),
+            "0020b1d110960100001368747470733a2f2f6578616d706c652e636f6d0b4142434445462d4748313204\
+             626c6f620100000000000000000d73637265656e73686f746f6e65000000000000000000000000047761\
+             637a01056e306e6365"
+        );
     }
-    
-    Ok(Json(signed_response))
 }
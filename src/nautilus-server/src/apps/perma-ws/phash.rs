@@ -0,0 +1,159 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::capture::{Screenshot, ScreenshotFormat};
+use crate::EnclaveError;
+use image::imageops::FilterType;
+use tracing::info;
+
+/// Largest screenshot downloaded to be hashed, larger ones are skipped.
+pub const MAX_PHASH_IMAGE_BYTES: usize = 20 * 1024 * 1024;
+
+/// Perceptual hash of a stored screenshot as 16 hex chars, see [`dhash`].
+/// `None` for PDF captures and screenshots over [`MAX_PHASH_IMAGE_BYTES`].
+/// The screenshot is downloaded from the provider's own URL, with the
+/// upstream client `http`, not as a URL the request named.
+pub async fn screenshot_phash(
+    http: &reqwest::Client,
+    screenshot: &Screenshot,
+    format: ScreenshotFormat,
+) -> Result<Option<String>, EnclaveError> {
+    if format == ScreenshotFormat::Pdf {
+        info!("Skipping the perceptual hash of a pdf capture");
+        return Ok(None);
+    }
+    if screenshot.byte_size > MAX_PHASH_IMAGE_BYTES {
+        info!(
+            "Skipping the perceptual hash of a {} byte screenshot",
+            screenshot.byte_size
+        );
+        return Ok(None);
+    }
+    let Some(image) = download_image(http, &screenshot.url).await? else {
+        return Ok(None);
+    };
+    let image = image::load_from_memory(&image)
+        .map_err(|e| EnclaveError::Upstream(format!("Failed to decode the screenshot: {e}")))?;
+    Ok(Some(format!("{:016x}", dhash(&image))))
+}
+
+/// Download `url`, giving up once it goes over [`MAX_PHASH_IMAGE_BYTES`]
+/// since the stored size may be stale.
+async fn download_image(
+    http: &reqwest::Client,
+    url: &str,
+) -> Result<Option<Vec<u8>>, EnclaveError> {
+    let mut response = http
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| EnclaveError::from_reqwest("Failed to download the screenshot", e))?;
+    let mut image = Vec::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|e| EnclaveError::from_reqwest("Failed to download the screenshot", e))?
+    {
+        if image.len() + chunk.len() > MAX_PHASH_IMAGE_BYTES {
+            info!("Screenshot {} is over the perceptual hash size cap", url);
+            return Ok(None);
+        }
+        image.extend_from_slice(&chunk);
+    }
+    Ok(Some(image))
+}
+
+/// 64 bit difference hash: the image shrunk to 9x8 grayscale, one bit per
+/// pixel set when it is brighter than its right neighbour. Robust to
+/// rescaling and recompression, and cheap enough to run on every capture.
+pub fn dhash(image: &image::DynamicImage) -> u64 {
+    let small = image.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            let left = small.get_pixel(x, y)[0];
+            let right = small.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | u64::from(left > right);
+        }
+    }
+    hash
+}
+
+/// Number of differing bits between two hashes, 0 for the same picture and
+/// up to 64. Near-identical screenshots are usually within 10.
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+    use std::io::Cursor;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    /// A page-like picture: a light background with dark text bars.
+    fn page(shift: u8) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(640, 480, |x, y| {
+            if (y / 40) % 2 == 1 && x > 40 && x < 40 + (y * 7) % 560 {
+                Rgb([20, 20, 20])
+            } else {
+                let v = 230 - (x / 16) as u8 + shift;
+                Rgb([v, v, v])
+            }
+        }))
+    }
+
+    fn png(image: &DynamicImage) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_near_identical_images_are_close() {
+        let original = dhash(&page(0));
+        let brighter = dhash(&page(8));
+        let rescaled = dhash(&page(0).resize_exact(320, 240, FilterType::Triangle));
+        assert!(hamming_distance(original, brighter) <= 4);
+        assert!(hamming_distance(original, rescaled) <= 4);
+
+        let different = dhash(&page(0).fliph());
+        let inverted = {
+            let mut image = page(0);
+            image.invert();
+            dhash(&image)
+        };
+        assert!(hamming_distance(original, different) >= 20);
+        assert!(hamming_distance(original, inverted) >= 20);
+    }
+
+    #[tokio::test]
+    async fn test_screenshot_phash() {
+        let server = MockServer::start().await;
+        let bytes = png(&page(0));
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(bytes.clone()))
+            .mount(&server)
+            .await;
+        let screenshot = Screenshot {
+            blob_id: "blob".to_string(),
+            byte_size: bytes.len(),
+            url: server.uri(),
+        };
+        let http = reqwest::Client::new();
+
+        let phash = screenshot_phash(&http, &screenshot, ScreenshotFormat::Png)
+            .await
+            .unwrap();
+        assert_eq!(phash, Some(format!("{:016x}", dhash(&page(0)))));
+        let pdf = screenshot_phash(&http, &screenshot, ScreenshotFormat::Pdf)
+            .await
+            .unwrap();
+        assert_eq!(pdf, None);
+    }
+}
@@ -0,0 +1,581 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::attestation::{persist_attestation, SignedPermaResponse};
+use super::canonical::resolve_canonical_url;
+use super::capture::{CaptureOptions, Screenshot, ScreenshotOneParams, STORAGE_ENDPOINT};
+use super::config::{required, PermaConfig};
+use super::data_archive::{
+    fetch_structured_data, store_data, ArchivedData, DataResponse, DATA_PROVIDER,
+};
+use super::freshness::ensure_fresh;
+use super::outbound_log::log_outbound;
+use super::phash::screenshot_phash;
+use super::progress::{ArchiveStage, Progress};
+use super::provider::capture_with_fallback;
+use super::redirect::{resolve_redirect_chain, RedirectChain};
+use super::reference_id::{
+    generate_reference_id, responsive_storage_key, scooper_reference_id, storage_key,
+};
+use super::retry::{send_with_retry, Stage};
+use super::scooper_auth::{scooper_signature, SIGNATURE_HEADER};
+use super::scooper_jobs::{
+    is_already_running, job_id_from_response, reference_id_from_response,
+    wacz_blob_id_from_response, wait_for_wacz_blob_id, ScooperJobs,
+};
+use super::state::PermaState;
+use super::storage::{StorageCredentials, StorageTarget};
+use super::target_http::TargetHttp;
+use super::text::{capture_page_text, ExtractedText};
+use super::tls::{capture_tls_certificate, TlsCertificate};
+use super::upstream::read_json_response;
+use super::{PermaResponse, ResponsiveCapture};
+use crate::common::{AttestationSink, ReferenceIdGenerator, ScreenshotProvider};
+use crate::EnclaveError;
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use std::future::Future;
+use std::time::Duration;
+use tracing::info;
+
+/// Time-based base36 reference ids counting from `epoch_secs`
+/// (`REFERENCE_ID_EPOCH`), see [`generate_reference_id`].
+pub struct Base36ReferenceIds {
+    pub epoch_secs: u64,
+}
+
+impl ReferenceIdGenerator for Base36ReferenceIds {
+    fn generate(&self) -> Result<String, EnclaveError> {
+        generate_reference_id(self.epoch_secs)
+    }
+}
+
+/// Everything captured for one archive.
+pub struct PermaCapture {
+    /// Canonical URL archived instead of the requested one, when followed.
+    pub canonical_url: Option<String>,
+    pub tls_cert: Option<TlsCertificate>,
+    pub redirect_chain: Option<RedirectChain>,
+    /// Screenshot provider that produced the screenshot.
+    pub screenshot_provider: String,
+    pub screenshot: Screenshot,
+    pub responsive_captures: Vec<ResponsiveCapture>,
+    /// Perceptual hash of the screenshot, when requested.
+    pub phash: Option<String>,
+    /// The page's stored text, when requested.
+    pub text: Option<ExtractedText>,
+    /// The target's body when it served data, stored instead of a screenshot.
+    pub data: Option<ArchivedData>,
+    /// Walrus blob id of the WACZ scooper uploaded.
+    pub wacz_blob_id: String,
+}
+
+/// Archives the page with scooper (WACZ) and a screenshot provider, after
+/// the optional TLS and redirect preflights.
+pub struct PermaProvider<'a> {
+    pub config: &'a PermaConfig,
+    pub http: &'a reqwest::Client,
+    /// Client for the target's own URLs, see [`TargetHttp`].
+    pub target_http: &'a TargetHttp,
+    /// Where the scooper job id of each archive is recorded.
+    pub scooper_jobs: &'a ScooperJobs,
+    pub url: &'a str,
+    /// Options already resolved against the configuration.
+    pub options: CaptureOptions,
+    /// Screenshot providers to try in order.
+    pub providers: Vec<String>,
+    /// Bucket and key prefix the captures are stored under.
+    pub storage: StorageTarget,
+    pub capture_tls_cert: bool,
+    pub record_redirect_chain: bool,
+    /// Archive the page's same-origin `<link rel="canonical">` instead of
+    /// the requested URL.
+    pub follow_canonical: bool,
+    /// Download the screenshot and compute its perceptual hash.
+    pub compute_phash: bool,
+    /// Store the page's text and sign its hash.
+    pub extract_text: bool,
+    pub progress: &'a Progress,
+}
+
+#[async_trait]
+impl ScreenshotProvider for PermaProvider<'_> {
+    type Capture = PermaCapture;
+
+    async fn capture(&self, reference_id: &str) -> Result<PermaCapture, EnclaveError> {
+        let config = self.config;
+
+        let canonical_url = if self.follow_canonical {
+            resolve_canonical_url(self.target_http, self.url)
+                .await?
+                .map(|url| url.to_string())
+        } else {
+            None
+        };
+        // Every step below archives the canonical URL when there is one.
+        let url = canonical_url.as_deref().unwrap_or(self.url);
+
+        // Refuse stale cached content before anything is archived.
+        if config.enforce_freshness {
+            let max_age = Duration::from_secs(config.freshness_max_age_secs);
+            ensure_fresh(self.target_http, url, max_age).await?;
+        }
+
+        // Fingerprint the certificate before archiving so a failed handshake
+        // aborts without starting a scooper job.
+        let tls_cert = if self.capture_tls_cert {
+            capture_tls_certificate(self.target_http, url).await?
+        } else {
+            None
+        };
+
+        let redirect_chain = if self.record_redirect_chain {
+            Some(resolve_redirect_chain(self.target_http, url).await?)
+        } else {
+            None
+        };
+
+        // A data response (e.g. a JSON feed) is stored as served instead of
+        // screenshotted.
+        let data = if config.allow_data_archive {
+            fetch_structured_data(self.target_http, url).await?
+        } else {
+            None
+        };
+
+        // The scoop and the screenshots are independent, so both run at once.
+        // The first leg to fail drops the other and fails the archive.
+        let scoop = async {
+            let submitted_wacz_blob_id = self.submit_to_scooper(reference_id, url).await?;
+            self.progress.report(ArchiveStage::ScooperSubmitted);
+            self.wacz_blob_id(reference_id, submitted_wacz_blob_id)
+                .await
+        };
+        let (wacz_blob_id, screenshots) =
+            tokio::try_join!(scoop, self.capture_screenshots(reference_id, url, data))?;
+
+        Ok(PermaCapture {
+            canonical_url,
+            tls_cert,
+            redirect_chain,
+            screenshot_provider: screenshots.provider,
+            screenshot: screenshots.screenshot,
+            responsive_captures: screenshots.responsive_captures,
+            phash: screenshots.phash,
+            text: screenshots.text,
+            data: screenshots.data,
+            wacz_blob_id,
+        })
+    }
+}
+
+/// What [`PermaProvider::capture_screenshots`] stored for an archive.
+struct Screenshots {
+    provider: String,
+    screenshot: Screenshot,
+    responsive_captures: Vec<ResponsiveCapture>,
+    phash: Option<String>,
+    text: Option<ExtractedText>,
+    data: Option<ArchivedData>,
+}
+
+impl PermaProvider<'_> {
+    /// Store the screenshot and its extras (phash, text, responsive widths)
+    /// of `url`, or `data` as served when the target is structured data.
+    async fn capture_screenshots(
+        &self,
+        reference_id: &str,
+        url: &str,
+        data: Option<DataResponse>,
+    ) -> Result<Screenshots, EnclaveError> {
+        let config = self.config;
+        let storage_access_key_id =
+            required(&config.storage_access_key_id, "STORAGE_ACCESS_KEY_ID")?;
+        let storage_secret_access_key = required(
+            &config.storage_secret_access_key,
+            "STORAGE_SECRET_ACCESS_KEY",
+        )?;
+        let credentials = StorageCredentials {
+            access_key_id: storage_access_key_id,
+            secret_access_key: storage_secret_access_key,
+        };
+
+        if let Some(data) = data {
+            let data = store_data(
+                self.http,
+                data,
+                reference_id,
+                &self.storage,
+                STORAGE_ENDPOINT,
+                &credentials,
+            )
+            .await?;
+            self.progress.report(ArchiveStage::ScreenshotDone);
+            self.progress.report(ArchiveStage::EtagFetched);
+            return Ok(Screenshots {
+                provider: DATA_PROVIDER.to_string(),
+                screenshot: Screenshot {
+                    blob_id: data.blob_id.clone(),
+                    byte_size: data.byte_size,
+                    url: String::new(),
+                },
+                responsive_captures: Vec::new(),
+                phash: None,
+                text: None,
+                data: Some(data),
+            });
+        }
+
+        let access_key = required(&config.access_key, "ACCESS_KEY")?;
+
+        let storage_path = self.storage.path_param(&storage_key(reference_id));
+
+        // call screenshot provider for a screenshot then get blob_id
+        let params = ScreenshotOneParams {
+            http: self.http,
+            api_url: &config.screenshotone_url,
+            access_key,
+            url,
+            storage_bucket: &self.storage.bucket,
+            storage_path: &storage_path,
+            storage_access_key_id,
+            storage_secret_access_key,
+            options: &self.options,
+            upstream_max_attempts: config.upstream_max_attempts,
+            screenshotone_max_retries: config.screenshotone_max_retries,
+            outbound_log_sample: config.outbound_log_sample,
+            max_response_bytes: config.max_response_bytes,
+        };
+        let (screenshot_provider, screenshot) =
+            capture_with_fallback(&self.providers, &params, self.progress).await?;
+
+        let phash = if self.compute_phash {
+            let format = self.options.format.unwrap_or_default();
+            screenshot_phash(self.http, &screenshot, format).await?
+        } else {
+            None
+        };
+
+        let text = if self.extract_text {
+            Some(
+                capture_page_text(
+                    self.http,
+                    self.target_http,
+                    url,
+                    reference_id,
+                    &self.storage,
+                    STORAGE_ENDPOINT,
+                    &credentials,
+                )
+                .await?,
+            )
+        } else {
+            None
+        };
+
+        let widths = self
+            .options
+            .responsive_widths
+            .as_deref()
+            .unwrap_or_default();
+        let responsive_captures = capture_responsive_widths(
+            reference_id,
+            &self.storage,
+            widths,
+            |width, storage_path| async move {
+                let options = CaptureOptions {
+                    viewport_width: Some(width),
+                    responsive_widths: None,
+                    ..self.options.clone()
+                };
+                let params = ScreenshotOneParams {
+                    http: self.http,
+                    api_url: &config.screenshotone_url,
+                    access_key,
+                    url,
+                    storage_bucket: &self.storage.bucket,
+                    storage_path: &storage_path,
+                    storage_access_key_id,
+                    storage_secret_access_key,
+                    options: &options,
+                    upstream_max_attempts: config.upstream_max_attempts,
+                    screenshotone_max_retries: config.screenshotone_max_retries,
+                    outbound_log_sample: config.outbound_log_sample,
+                    max_response_bytes: config.max_response_bytes,
+                };
+                capture_with_fallback(&self.providers, &params, self.progress)
+                    .await
+                    .map(|(_, screenshot)| screenshot)
+            },
+        )
+        .await?;
+
+        Ok(Screenshots {
+            provider: screenshot_provider,
+            screenshot,
+            responsive_captures,
+            phash,
+            text,
+            data: None,
+        })
+    }
+
+    /// Start the WACZ archive job of `url` with scooper, which uploads it to
+    /// Walrus, and record its job id for `/job_status`. Returns the WACZ blob
+    /// id when scooper already knows it at submission.
+    async fn submit_to_scooper(
+        &self,
+        reference_id: &str,
+        url: &str,
+    ) -> Result<Option<String>, EnclaveError> {
+        let scooper_secret = required(&self.config.scooper_secret, "SCOOPER_SECRET")?;
+
+        // Make a POST request to scooper - it will upload to Walrus the .wacz file
+        let scooper_url = self.config.scooper_url.as_str();
+
+        // Build the JSON body for the scooper request matching the API
+        // structure. The secret only keys the X-Signature HMAC.
+        let scooper_reference_id = scooper_reference_id(reference_id);
+        let timestamp_ms = chrono::Utc::now().timestamp_millis();
+        let signature = scooper_signature(scooper_secret, url, scooper_reference_id, timestamp_ms);
+        let scooper_request_body = json!({
+            "url": url,
+            "referenceId": scooper_reference_id,
+            "timestamp": timestamp_ms
+        });
+
+        info!("Making POST request to scooper: {}", scooper_url);
+        info!(
+            "Request body: {}",
+            serde_json::to_string_pretty(&scooper_request_body).unwrap_or_default()
+        );
+
+        // Only retried when the request never reached scooper, see [`Stage`].
+        let client = self.http;
+        let scooper_response = send_with_retry(
+            Stage::ScooperSubmit,
+            self.config.upstream_max_attempts,
+            || {
+                let send = client
+                    .post(scooper_url)
+                    .header("Content-Type", "application/json")
+                    .header(SIGNATURE_HEADER, &signature)
+                    .json(&scooper_request_body)
+                    .send();
+                log_outbound(
+                    self.config.outbound_log_sample,
+                    "scooper",
+                    "POST",
+                    scooper_url,
+                    send,
+                )
+            },
+        )
+        .await
+        .map_err(|e| EnclaveError::from_reqwest("Failed to get scooper response", e))?;
+
+        let status = scooper_response.status();
+        info!("Scooper response status: {}", status);
+
+        // check job, if it is already running then abort this
+        if status != reqwest::StatusCode::ACCEPTED {
+            let scooper_json = match read_json_response(
+                "Scooper",
+                scooper_response,
+                self.config.max_response_bytes,
+            )
+            .await
+            {
+                Ok(json) => json,
+                // A bare 409 still means scooper is already archiving the URL.
+                Err(_) if status == reqwest::StatusCode::CONFLICT => Value::Null,
+                Err(e) => return Err(e),
+            };
+            if is_already_running(status, &scooper_json) {
+                let Some(running) = reference_id_from_response(&scooper_json) else {
+                    return Err(EnclaveError::Upstream(format!(
+                        "Scooper is already archiving {url} but returned no reference id"
+                    )));
+                };
+                info!("Scooper is already archiving {} as {}", url, running);
+                // So `/job_status` of the running archive works when scooper says
+                // which job it is.
+                if let Some(job_id) = job_id_from_response(&scooper_json) {
+                    self.scooper_jobs.record(&running, &job_id);
+                }
+                return Err(EnclaveError::AlreadyRunning {
+                    reference_id: running,
+                });
+            }
+            return Err(EnclaveError::Upstream(format!(
+                "Scooper returned status {} instead of 202, aborting",
+                status
+            )));
+        }
+
+        // Proxies in front of scooper may answer with an HTML error page, surface
+        // that instead of a JSON parse error.
+        let scooper_json =
+            read_json_response("Scooper", scooper_response, self.config.max_response_bytes).await?;
+
+        info!(
+            "Scooper response body: {}",
+            serde_json::to_string_pretty(&scooper_json).unwrap_or_default()
+        );
+        match job_id_from_response(&scooper_json) {
+            Some(job_id) => self.scooper_jobs.record(reference_id, &job_id),
+            None => info!("Scooper returned no job id for {}", reference_id),
+        }
+        Ok(wacz_blob_id_from_response(&scooper_json))
+    }
+
+    /// Blob id of the WACZ of `reference_id`: the one scooper `submitted`
+    /// with its 202, else the one its job reports once completed, polled
+    /// every `SCOOPER_POLL_INTERVAL_MS` for up to `SCOOPER_POLL_TIMEOUT_SECS`.
+    async fn wacz_blob_id(
+        &self,
+        reference_id: &str,
+        submitted: Option<String>,
+    ) -> Result<String, EnclaveError> {
+        if let Some(blob_id) = submitted {
+            return Ok(blob_id);
+        }
+        let job_id = self.scooper_jobs.job_id(reference_id).ok_or_else(|| {
+            EnclaveError::Upstream(format!(
+                "Scooper returned neither a WACZ blob id nor a job id for {reference_id}"
+            ))
+        })?;
+        info!("Waiting for scooper job {} to upload the WACZ", job_id);
+        wait_for_wacz_blob_id(
+            self.http,
+            &self.config.scooper_job_status_url()?,
+            &job_id,
+            Duration::from_millis(self.config.scooper_poll_interval_ms),
+            Duration::from_secs(self.config.scooper_poll_timeout_secs),
+            self.config.max_response_bytes,
+        )
+        .await
+    }
+}
+
+/// Capture the page once per width, one after the other. `capture` gets the
+/// width and the percent-encoded storage path of that capture in `storage`,
+/// see [`responsive_storage_key`].
+pub async fn capture_responsive_widths<F, Fut>(
+    reference_id: &str,
+    storage: &StorageTarget,
+    widths: &[u32],
+    capture: F,
+) -> Result<Vec<ResponsiveCapture>, EnclaveError>
+where
+    F: Fn(u32, String) -> Fut,
+    Fut: Future<Output = Result<Screenshot, EnclaveError>>,
+{
+    let mut captures = Vec::with_capacity(widths.len());
+    for &width in widths {
+        let storage_path = storage.path_param(&responsive_storage_key(reference_id, width));
+        info!("Capturing {} at width {}", reference_id, width);
+        let screenshot = capture(width, storage_path).await?;
+        captures.push(ResponsiveCapture {
+            width,
+            blob_id: screenshot.blob_id,
+            byte_size: screenshot.byte_size,
+        });
+    }
+    Ok(captures)
+}
+
+/// Saves the signed response with the frontend, unless the client opted out.
+pub struct PermaSink<'a> {
+    pub perma: &'a PermaState,
+    pub http: &'a reqwest::Client,
+    pub persist: bool,
+    /// Unsigned client metadata saved with the attestation.
+    pub client_metadata: Option<&'a Value>,
+    pub progress: &'a Progress,
+}
+
+#[async_trait]
+impl AttestationSink<PermaResponse> for PermaSink<'_> {
+    async fn persist(
+        &self,
+        reference_id: &str,
+        signed: &SignedPermaResponse,
+    ) -> Result<bool, EnclaveError> {
+        self.progress.report(ArchiveStage::Signed);
+        let persisted = persist_attestation(
+            self.perma,
+            self.http,
+            self.persist,
+            reference_id,
+            signed,
+            self.client_metadata,
+        )
+        .await?;
+        if persisted {
+            self.progress.report(ArchiveStage::Persisted);
+        }
+        Ok(persisted)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn test_responsive_widths_are_captured() {
+        let storage_paths = Mutex::new(Vec::new());
+        let captures = capture_responsive_widths(
+            "ABCDEF-GH12",
+            &StorageTarget::default(),
+            &[375, 1440],
+            |width, path| {
+                storage_paths.lock().unwrap().push(path.clone());
+                async move {
+                    Ok(Screenshot {
+                        blob_id: format!("blob-{path}"),
+                        byte_size: width as usize * 10,
+                        url: String::new(),
+                    })
+                }
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            *storage_paths.lock().unwrap(),
+            vec![
+                "ABCDEF-GH12%2FABCDEF-GH12-375w",
+                "ABCDEF-GH12%2FABCDEF-GH12-1440w"
+            ]
+        );
+        assert_eq!(captures.len(), 2);
+        assert_eq!(captures[0].width, 375);
+        assert_eq!(captures[1].byte_size, 14400);
+        assert_ne!(captures[0].blob_id, captures[1].blob_id);
+    }
+
+    #[tokio::test]
+    async fn test_failed_width_fails_the_capture() {
+        let result = capture_responsive_widths(
+            "ABCDEF-GH12",
+            &StorageTarget::default(),
+            &[375, 1440],
+            |width, _| async move {
+                if width == 1440 {
+                    return Err(EnclaveError::Upstream("capture failed".to_string()));
+                }
+                Ok(Screenshot {
+                    blob_id: "blob".to_string(),
+                    byte_size: 1,
+                    url: String::new(),
+                })
+            },
+        )
+        .await;
+        assert!(matches!(result, Err(EnclaveError::Upstream(_))));
+    }
+}
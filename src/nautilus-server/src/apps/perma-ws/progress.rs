@@ -0,0 +1,47 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use serde::Serialize;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+/// Stages of the archive pipeline, reported as they complete.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveStage {
+    ScooperSubmitted,
+    ScreenshotDone,
+    EtagFetched,
+    Signed,
+    Persisted,
+}
+
+impl ArchiveStage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ArchiveStage::ScooperSubmitted => "scooper_submitted",
+            ArchiveStage::ScreenshotDone => "screenshot_done",
+            ArchiveStage::EtagFetched => "etag_fetched",
+            ArchiveStage::Signed => "signed",
+            ArchiveStage::Persisted => "persisted",
+        }
+    }
+}
+
+/// Where the pipeline reports its stages. The default discards them.
+#[derive(Debug, Clone, Default)]
+pub struct Progress(Option<UnboundedSender<ArchiveStage>>);
+
+impl Progress {
+    /// A progress reporter and the receiving end of its stages.
+    pub fn channel() -> (Self, UnboundedReceiver<ArchiveStage>) {
+        let (tx, rx) = unbounded_channel();
+        (Self(Some(tx)), rx)
+    }
+
+    pub fn report(&self, stage: ArchiveStage) {
+        if let Some(tx) = &self.0 {
+            // The receiver is gone when the client disconnected, nothing to do.
+            let _ = tx.send(stage);
+        }
+    }
+}
@@ -0,0 +1,124 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::capture::{capture_with_screenshotone, Screenshot, ScreenshotOneParams};
+use super::progress::Progress;
+use crate::EnclaveError;
+use tracing::info;
+
+/// Screenshot providers this build knows how to call.
+pub const SCREENSHOTONE: &str = "screenshotone";
+pub const KNOWN_PROVIDERS: &[&str] = &[SCREENSHOTONE];
+
+/// Parse the ordered, comma-separated provider list (`SCREENSHOT_PROVIDERS`).
+pub fn parse_providers(value: &str) -> Result<Vec<String>, EnclaveError> {
+    let providers: Vec<String> = value
+        .split(',')
+        .map(|p| p.trim().to_ascii_lowercase())
+        .filter(|p| !p.is_empty())
+        .collect();
+    if providers.is_empty() {
+        return Err(EnclaveError::GenericError(
+            "SCREENSHOT_PROVIDERS must name at least one provider".to_string(),
+        ));
+    }
+    if let Some(unknown) = providers
+        .iter()
+        .find(|p| !KNOWN_PROVIDERS.contains(&p.as_str()))
+    {
+        return Err(EnclaveError::GenericError(format!(
+            "Unknown screenshot provider {unknown}, expected one of {KNOWN_PROVIDERS:?}"
+        )));
+    }
+    Ok(providers)
+}
+
+/// Providers to try for a request, in order. A provider named by the client
+/// is used alone, without fallback, and must be one of the configured ones.
+pub fn select_providers(
+    requested: Option<&str>,
+    configured: &[String],
+) -> Result<Vec<String>, EnclaveError> {
+    match requested {
+        Some(name) => configured
+            .iter()
+            .find(|p| p.eq_ignore_ascii_case(name.trim()))
+            .map(|p| vec![p.clone()])
+            .ok_or_else(|| {
+                EnclaveError::BadRequest(format!(
+                    "Screenshot provider {name} is not configured, available: {}",
+                    configured.join(", ")
+                ))
+            }),
+        None => Ok(configured.to_vec()),
+    }
+}
+
+/// Capture with each provider in order until one succeeds. Returns the name
+/// of the provider used with its screenshot, or the last error.
+pub async fn capture_with_fallback(
+    providers: &[String],
+    params: &ScreenshotOneParams<'_>,
+    progress: &Progress,
+) -> Result<(String, Screenshot), EnclaveError> {
+    let mut last_error =
+        EnclaveError::GenericError("No screenshot provider configured".to_string());
+    for provider in providers {
+        match capture(provider, params, progress).await {
+            Ok(screenshot) => return Ok((provider.clone(), screenshot)),
+            Err(e) => {
+                info!("Screenshot provider {} failed: {}", provider, e);
+                last_error = e;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+async fn capture(
+    provider: &str,
+    params: &ScreenshotOneParams<'_>,
+    progress: &Progress,
+) -> Result<Screenshot, EnclaveError> {
+    match provider {
+        SCREENSHOTONE => capture_with_screenshotone(params, progress).await,
+        other => Err(EnclaveError::GenericError(format!(
+            "Unknown screenshot provider {other}"
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn configured() -> Vec<String> {
+        parse_providers("screenshotone").unwrap()
+    }
+
+    #[test]
+    fn test_select_named_provider() {
+        let selected = select_providers(Some("ScreenshotOne"), &configured()).unwrap();
+        assert_eq!(selected, vec![SCREENSHOTONE.to_string()]);
+    }
+
+    #[test]
+    fn test_select_unknown_provider_rejected() {
+        assert!(select_providers(Some("urlbox"), &configured()).is_err());
+    }
+
+    #[test]
+    fn test_select_default_providers() {
+        assert_eq!(select_providers(None, &configured()).unwrap(), configured());
+    }
+
+    #[test]
+    fn test_parse_providers() {
+        assert_eq!(
+            parse_providers(" screenshotone , ").unwrap(),
+            vec![SCREENSHOTONE.to_string()]
+        );
+        assert!(parse_providers("screenshotone,urlbox").is_err());
+        assert!(parse_providers(" , ").is_err());
+    }
+}
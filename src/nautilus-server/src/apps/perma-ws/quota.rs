@@ -0,0 +1,148 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::config::PermaConfig;
+use crate::EnclaveError;
+use chrono::{DateTime, Days, LocalResult, NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
+use std::sync::Mutex;
+use tracing::info;
+
+/// Daily archive limit (`DAILY_ARCHIVE_LIMIT`). The count resets at midnight
+/// in `QUOTA_RESET_TZ`, so a day can be 23 or 25 hours long around DST changes.
+#[derive(Debug)]
+pub struct DailyQuota {
+    limit: Option<u64>,
+    tz: Tz,
+    /// Local day being counted and the archives started on it.
+    used: Mutex<(NaiveDate, u64)>,
+}
+
+impl DailyQuota {
+    pub fn new(config: &PermaConfig) -> Self {
+        Self {
+            limit: config.daily_archive_limit,
+            tz: config.quota_reset_tz,
+            used: Mutex::new((NaiveDate::MIN, 0)),
+        }
+    }
+
+    /// Count one archive at `now`, or fail with the time left until the
+    /// next reset once the day's limit is used up.
+    pub fn try_consume(&self, now: DateTime<Utc>) -> Result<(), EnclaveError> {
+        let Some(limit) = self.limit else {
+            return Ok(());
+        };
+        let today = now.with_timezone(&self.tz).date_naive();
+        let mut used = self.used.lock().expect("lock poisoned");
+        if used.0 != today {
+            info!("Daily archive quota reset for {} ({})", today, self.tz);
+            *used = (today, 0);
+        }
+        if used.1 >= limit {
+            let retry_after_secs = (next_reset(now, self.tz) - now).num_seconds().max(1) as u64;
+            return Err(EnclaveError::RateLimited {
+                reason: format!("daily archive limit of {limit} reached"),
+                retry_after_secs,
+            });
+        }
+        used.1 += 1;
+        Ok(())
+    }
+}
+
+/// First instant of the local day after `now` in `tz`. When midnight doesn't
+/// exist because clocks spring forward at 00:00, the day starts at the first
+/// valid local time after it.
+pub fn next_reset(now: DateTime<Utc>, tz: Tz) -> DateTime<Utc> {
+    let tomorrow = now
+        .with_timezone(&tz)
+        .date_naive()
+        .checked_add_days(Days::new(1))
+        .expect("date in range");
+    let mut start = tomorrow.and_hms_opt(0, 0, 0).expect("valid time");
+    loop {
+        match tz.from_local_datetime(&start) {
+            LocalResult::Single(t) => return t.with_timezone(&Utc),
+            LocalResult::Ambiguous(earliest, _) => return earliest.with_timezone(&Utc),
+            LocalResult::None => start += chrono::Duration::minutes(15),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn quota(limit: u64, tz: &str) -> DailyQuota {
+        DailyQuota::new(&PermaConfig {
+            daily_archive_limit: Some(limit),
+            quota_reset_tz: tz.parse().unwrap(),
+            ..Default::default()
+        })
+    }
+
+    fn at(rfc3339: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(rfc3339)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_unlimited_by_default() {
+        let quota = DailyQuota::new(&PermaConfig::default());
+        for _ in 0..1000 {
+            assert!(quota.try_consume(Utc::now()).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_resets_at_utc_midnight() {
+        let quota = quota(1, "UTC");
+        assert!(quota.try_consume(at("2025-06-01T23:00:00Z")).is_ok());
+        let err = quota.try_consume(at("2025-06-01T23:59:00Z")).unwrap_err();
+        assert!(matches!(
+            err,
+            EnclaveError::RateLimited {
+                retry_after_secs: 60,
+                ..
+            }
+        ));
+        assert!(quota.try_consume(at("2025-06-02T00:00:00Z")).is_ok());
+    }
+
+    #[test]
+    fn test_resets_at_local_midnight() {
+        // Midnight in Tokyo (UTC+9) is 15:00 UTC the day before.
+        let quota = quota(1, "Asia/Tokyo");
+        assert!(quota.try_consume(at("2025-06-01T14:00:00Z")).is_ok());
+        assert!(quota.try_consume(at("2025-06-01T14:59:59Z")).is_err());
+        assert!(quota.try_consume(at("2025-06-01T15:00:00Z")).is_ok());
+        assert!(quota.try_consume(at("2025-06-01T23:00:00Z")).is_err());
+    }
+
+    #[test]
+    fn test_dst_day_boundaries() {
+        // New York springs forward on 2025-03-09: the day starts at 05:00 UTC
+        // (EST) and the next one at 04:00 UTC (EDT), 23 hours later.
+        let tz: Tz = "America/New_York".parse().unwrap();
+        assert_eq!(
+            next_reset(at("2025-03-08T12:00:00Z"), tz),
+            at("2025-03-09T05:00:00Z")
+        );
+        assert_eq!(
+            next_reset(at("2025-03-09T12:00:00Z"), tz),
+            at("2025-03-10T04:00:00Z")
+        );
+        // And falls back on 2025-11-02, a 25 hour day.
+        assert_eq!(
+            next_reset(at("2025-11-02T12:00:00Z"), tz),
+            at("2025-11-03T05:00:00Z")
+        );
+
+        let quota = quota(1, "America/New_York");
+        assert!(quota.try_consume(at("2025-03-09T05:30:00Z")).is_ok());
+        assert!(quota.try_consume(at("2025-03-10T03:59:59Z")).is_err());
+        assert!(quota.try_consume(at("2025-03-10T04:00:00Z")).is_ok());
+    }
+}
@@ -0,0 +1,143 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::attestation::SignedPermaResponse;
+use crate::AppState;
+use crate::EnclaveError;
+use axum::extract::{Query, State};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Captures `/admin/captures` returns unless `limit` says otherwise.
+pub const DEFAULT_CAPTURES_LIMIT: usize = 20;
+
+/// Most captures one `/admin/captures` call may ask for.
+pub const MAX_CAPTURES_LIMIT: usize = 100;
+
+/// Public fields of a signed capture, as listed by `/admin/captures`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecentCapture {
+    pub reference_id: String,
+    pub url: String,
+    pub screenshot_blob_id: String,
+    pub wacz_blob_id: String,
+    /// Signed timestamp of the attestation, in Unix milliseconds.
+    pub timestamp_ms: u64,
+}
+
+/// The latest persisted captures of this enclave, newest last, capped at
+/// `RECENT_CAPTURES_CAPACITY`. In memory only, so an operator can see what
+/// was processed without the frontend database. Lost on restart. Only served
+/// by the host-only admin server, since it lists every client's URLs.
+#[derive(Debug)]
+pub struct RecentCaptures {
+    capacity: usize,
+    entries: Mutex<VecDeque<RecentCapture>>,
+}
+
+impl RecentCaptures {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::default(),
+        }
+    }
+
+    /// Append a signed capture, evicting the oldest past capacity.
+    pub fn record(&self, signed: &SignedPermaResponse) {
+        let data = &signed.response.data;
+        let mut entries = self.entries.lock().expect("lock poisoned");
+        entries.push_back(RecentCapture {
+            reference_id: data.reference_id.clone(),
+            url: data.url.clone(),
+            screenshot_blob_id: data.screenshot_blob_id.clone(),
+            wacz_blob_id: data.wacz_blob_id.clone(),
+            timestamp_ms: signed.response.timestamp_ms,
+        });
+        while entries.len() > self.capacity {
+            entries.pop_front();
+        }
+    }
+
+    /// Up to `limit` captures, newest first.
+    pub fn latest(&self, limit: usize) -> Vec<RecentCapture> {
+        let entries = self.entries.lock().expect("lock poisoned");
+        entries.iter().rev().take(limit).cloned().collect()
+    }
+}
+
+/// Query parameters of `/admin/captures`.
+#[derive(Debug, Default, Deserialize)]
+pub struct CapturesQuery {
+    /// 1 to [`MAX_CAPTURES_LIMIT`], defaults to [`DEFAULT_CAPTURES_LIMIT`].
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+/// Response of `/admin/captures`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CapturesResponse {
+    pub captures: Vec<RecentCapture>,
+}
+
+/// The latest captures of this enclave, newest first, see
+/// [`RecentCaptures`]. Only fields of the signed responses are listed.
+pub async fn recent_captures(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<CapturesQuery>,
+) -> Result<Json<CapturesResponse>, EnclaveError> {
+    let limit = query.limit.unwrap_or(DEFAULT_CAPTURES_LIMIT);
+    if !(1..=MAX_CAPTURES_LIMIT).contains(&limit) {
+        return Err(EnclaveError::BadRequest(format!(
+            "limit must be 1 to {MAX_CAPTURES_LIMIT}, got {limit}"
+        )));
+    }
+    Ok(Json(CapturesResponse {
+        captures: state.perma.recent_captures.latest(limit),
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::attestation::test_signed_response;
+
+    #[tokio::test]
+    async fn test_recent_captures() {
+        let state = Arc::new(AppState::for_test());
+        let capacity = state.perma.config.recent_captures_capacity;
+        for i in 0..capacity + 2 {
+            let mut signed = test_signed_response(&format!("REF{i}"));
+            signed.response.timestamp_ms = i as u64;
+            state.perma.recent_captures.record(&signed);
+        }
+
+        let Json(response) = recent_captures(
+            State(state.clone()),
+            Query(CapturesQuery { limit: Some(2) }),
+        )
+        .await
+        .unwrap();
+        let newest = format!("REF{}", capacity + 1);
+        assert_eq!(response.captures.len(), 2);
+        assert_eq!(response.captures[0].reference_id, newest);
+        assert_eq!(response.captures[0].screenshot_blob_id, "blob");
+        assert_eq!(response.captures[0].timestamp_ms, capacity as u64 + 1);
+
+        // The oldest were evicted past capacity.
+        let all = state.perma.recent_captures.latest(usize::MAX);
+        assert_eq!(all.len(), capacity);
+        assert_eq!(all.last().unwrap().reference_id, "REF2");
+
+        for limit in [0, MAX_CAPTURES_LIMIT + 1] {
+            let result = recent_captures(
+                State(state.clone()),
+                Query(CapturesQuery { limit: Some(limit) }),
+            )
+            .await;
+            assert!(matches!(result, Err(EnclaveError::BadRequest(_))));
+        }
+    }
+}
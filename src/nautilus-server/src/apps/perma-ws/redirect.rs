@@ -0,0 +1,182 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::target_http::TargetHttp;
+use crate::EnclaveError;
+use fastcrypto::encoding::{Encoding, Hex};
+use fastcrypto::hash::{HashFunction, Sha256};
+use reqwest::Url;
+use tracing::info;
+
+/// Maximum number of redirects followed before giving up.
+pub const MAX_REDIRECTS: usize = 10;
+
+/// URLs visited resolving a target, starting with the requested URL and
+/// ending with the page that didn't redirect any further.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedirectChain {
+    pub urls: Vec<String>,
+}
+
+impl RedirectChain {
+    pub fn final_url(&self) -> &str {
+        self.urls.last().map(String::as_str).unwrap_or_default()
+    }
+
+    /// Hex SHA-256 of the BCS encoded list of URLs.
+    pub fn hash(&self) -> String {
+        let bytes = bcs::to_bytes(&self.urls).expect("should not fail");
+        Hex::encode(Sha256::digest(bytes).digest)
+    }
+}
+
+/// Follow the redirects of `url` one hop at a time with GET requests. Every
+/// hop must pass [`TargetHttp::check`]: an http(s) URL whose host neither is
+/// nor resolves to a private address, so none is signed into the chain. A
+/// URL seen twice is a loop.
+pub async fn resolve_redirect_chain(
+    http: &TargetHttp,
+    url: &str,
+) -> Result<RedirectChain, EnclaveError> {
+    let mut current =
+        Url::parse(url).map_err(|e| EnclaveError::BadRequest(format!("invalid url {url}: {e}")))?;
+    http.check(&current).await?;
+    let mut urls = vec![current.to_string()];
+    loop {
+        let response = http
+            .client()
+            .get(current.clone())
+            .send()
+            .await
+            .map_err(|e| {
+                EnclaveError::from_reqwest(&format!("Failed to resolve redirects of {current}"), e)
+            })?;
+        if !response.status().is_redirection() {
+            break;
+        }
+        let Some(location) = response
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|v| v.to_str().ok())
+        else {
+            break;
+        };
+        let next = current.join(location).map_err(|e| {
+            EnclaveError::Upstream(format!(
+                "{current} redirected to invalid location {location}: {e}"
+            ))
+        })?;
+        http.check(&next).await?;
+        if urls.contains(&next.to_string()) {
+            return Err(EnclaveError::BadRequest(format!(
+                "redirect loop resolving {url}: {} -> {next}",
+                urls.join(" -> ")
+            )));
+        }
+        if urls.len() > MAX_REDIRECTS {
+            return Err(EnclaveError::BadRequest(format!(
+                "{url} redirects more than {MAX_REDIRECTS} times"
+            )));
+        }
+        urls.push(next.to_string());
+        current = next;
+    }
+
+    info!("Redirect chain of {}: {}", url, urls.join(" -> "));
+    Ok(RedirectChain { urls })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    async fn redirect(server: &MockServer, from: &str, to: &str) {
+        Mock::given(method("GET"))
+            .and(path(from))
+            .respond_with(ResponseTemplate::new(302).insert_header("location", to))
+            .mount(server)
+            .await;
+    }
+
+    /// Client letting the loopback mock server through the private host
+    /// checks, but no other private host.
+    fn http(server: &MockServer) -> TargetHttp {
+        TargetHttp::allowing(&[&server.address().to_string()])
+    }
+
+    async fn page(server: &MockServer, at: &str) {
+        Mock::given(method("GET"))
+            .and(path(at))
+            .respond_with(ResponseTemplate::new(200).set_body_string("<html></html>"))
+            .mount(server)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_two_hop_chain() {
+        let server = MockServer::start().await;
+        redirect(&server, "/short", "/middle").await;
+        redirect(&server, "/middle", &format!("{}/final", server.uri())).await;
+        page(&server, "/final").await;
+
+        let chain = resolve_redirect_chain(&http(&server), &format!("{}/short", server.uri()))
+            .await
+            .unwrap();
+        let expected: Vec<String> = ["/short", "/middle", "/final"]
+            .iter()
+            .map(|p| format!("{}{p}", server.uri()))
+            .collect();
+        assert_eq!(chain.urls, expected);
+        assert_eq!(chain.final_url(), expected[2]);
+        assert_eq!(chain.hash().len(), 64);
+    }
+
+    #[tokio::test]
+    async fn test_no_redirect_is_single_url() {
+        let server = MockServer::start().await;
+        page(&server, "/page").await;
+        let url = format!("{}/page", server.uri());
+        let chain = resolve_redirect_chain(&http(&server), &url).await.unwrap();
+        assert_eq!(chain.urls, vec![url.clone()]);
+        assert_eq!(chain.final_url(), url);
+    }
+
+    #[tokio::test]
+    async fn test_redirect_loop_is_error() {
+        let server = MockServer::start().await;
+        redirect(&server, "/a", "/b").await;
+        redirect(&server, "/b", "/a").await;
+        let err = resolve_redirect_chain(&http(&server), &format!("{}/a", server.uri()))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, EnclaveError::BadRequest(ref msg) if msg.contains("loop")));
+    }
+
+    #[tokio::test]
+    async fn test_redirect_to_other_scheme_is_rejected() {
+        let server = MockServer::start().await;
+        redirect(&server, "/a", "file:///etc/passwd").await;
+        assert!(
+            resolve_redirect_chain(&http(&server), &format!("{}/a", server.uri()))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_redirect_to_private_host_is_rejected() {
+        let server = MockServer::start().await;
+        redirect(&server, "/a", "http://127.0.0.1:3001/admin/unpersisted").await;
+        let err = resolve_redirect_chain(&http(&server), &format!("{}/a", server.uri()))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, EnclaveError::BadRequest(ref msg) if msg.contains("private host")),
+            "{err}"
+        );
+        // The private hop is never requested.
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+    }
+}
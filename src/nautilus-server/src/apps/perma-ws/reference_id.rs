@@ -0,0 +1,299 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Reference ids and their encoding for scooper, storage keys and URL paths,
+//! kept in one place so all three agree.
+
+use crate::EnclaveError;
+use rand::Rng;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+/// Unix time, in seconds, reference id timestamps count from unless
+/// overridden with `REFERENCE_ID_EPOCH`: 2025-01-01 00:00:00 UTC.
+pub const DEFAULT_REFERENCE_ID_EPOCH_SECS: u64 = 1735689600;
+
+/// Encode a u64 number to base36 string (like JavaScript's toString(36)),
+/// upper case as scooper expects in reference ids. JavaScript emits lower
+/// case, so compare case-insensitively or decode with [`base36_decode`].
+pub fn u64_to_base36(mut n: u64) -> String {
+    if n == 0 {
+        return "0".to_string();
+    }
+    let base36_chars: Vec<char> = "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ".chars().collect();
+    let mut result = String::new();
+    while n > 0 {
+        result.push(base36_chars[(n % 36) as usize]);
+        n /= 36;
+    }
+    result.chars().rev().collect()
+}
+
+/// Decode a base36 string in either case, the inverse of [`u64_to_base36`]
+/// and of JavaScript's `toString(36)`. `None` if it is empty, has another
+/// character or overflows a u64.
+pub fn base36_decode(s: &str) -> Option<u64> {
+    if s.is_empty() {
+        return None;
+    }
+    s.chars().try_fold(0u64, |n, c| {
+        n.checked_mul(36)?.checked_add(u64::from(c.to_digit(36)?))
+    })
+}
+
+/// Characters after the hyphen, taken from [`next_sequence`]. Five, where
+/// ids generated before had 4 (the last 2 timestamp characters and 2 random
+/// ones), so [`reference_id_timestamp_ms`] can tell the two formats apart.
+const SEQUENCE_CHARS: u32 = 5;
+
+/// Random characters ending the ids generated before the counter, after the
+/// last 2 characters of their timestamp.
+const LEGACY_RANDOM_CHARS: usize = 2;
+
+/// Per-process counter behind the last characters of generated ids.
+static SEQUENCE: OnceLock<AtomicU64> = OnceLock::new();
+
+/// Next value of the process-local id counter. It starts at a random value
+/// so restarted or concurrent enclaves don't walk the same sequence.
+fn next_sequence() -> u64 {
+    SEQUENCE
+        .get_or_init(|| AtomicU64::new(rand::thread_rng().gen()))
+        .fetch_add(1, Ordering::Relaxed)
+}
+
+/// Generate a reference ID from the base36 milliseconds since `epoch_secs`
+/// (Unix seconds, 2025-01-01 by default), a hyphen and 5 characters of a
+/// process-local counter, upper case. Ids generated in the same millisecond
+/// differ in their counter, so they are unique within the process unless
+/// more than 36^5 (60M) are generated in one millisecond.
+pub fn generate_reference_id(epoch_secs: u64) -> Result<String, EnclaveError> {
+    // based on current timestamp, generate a referenceId from base36 encoding of current time in milliseconds since the epoch
+    let epoch = std::time::UNIX_EPOCH + std::time::Duration::from_secs(epoch_secs);
+    let current_timestamp_millis = std::time::SystemTime::now()
+        .duration_since(epoch)
+        .map_err(|e| EnclaveError::Internal(format!("Failed to get current timestamp: {}", e)))?
+        .as_millis() as u64;
+
+    // Append the counter as exactly 5 base36 characters, zero padded
+    let sequence = next_sequence() % 36u64.pow(SEQUENCE_CHARS);
+    Ok(format!(
+        "{}-{:0>width$}",
+        u64_to_base36(current_timestamp_millis),
+        u64_to_base36(sequence),
+        width = SEQUENCE_CHARS as usize
+    ))
+}
+
+/// Whether `id` has the shape of a generated reference id, as also enforced
+/// by scooper: 1-8 base36 characters, a hyphen and 4-5 more, upper case.
+pub fn is_valid_reference_id(id: &str) -> bool {
+    let base36 = |part: &str| {
+        part.bytes()
+            .all(|b| b.is_ascii_digit() || b.is_ascii_uppercase())
+    };
+    match id.split_once('-') {
+        Some((head, tail)) => {
+            (1..=8).contains(&head.len())
+                && (4..=5).contains(&tail.len())
+                && base36(head)
+                && base36(tail)
+        }
+        None => false,
+    }
+}
+
+/// Unix time in milliseconds embedded in a generated reference id, base36
+/// milliseconds since `epoch_secs`, which must be the epoch it was generated
+/// with. That is the part before the hyphen of ids with 5 characters after
+/// it, the counter, and for the older ids with 4 also the first 2 of these,
+/// the last 2 being random. Lower case ids, e.g. from JavaScript, are
+/// accepted. `None` unless `id` has the shape of a generated id.
+pub fn reference_id_timestamp_ms(id: &str, epoch_secs: u64) -> Option<u64> {
+    let id = id.to_ascii_uppercase();
+    if !is_valid_reference_id(&id) {
+        return None;
+    }
+    let (head, tail) = id.split_once('-')?;
+    let timestamp = if tail.len() == SEQUENCE_CHARS as usize {
+        head.to_string()
+    } else {
+        format!("{head}{}", &tail[..tail.len() - LEGACY_RANDOM_CHARS])
+    };
+    base36_decode(&timestamp)?.checked_add(epoch_secs.checked_mul(1000)?)
+}
+
+/// The id as sent to scooper in the `referenceId` JSON field. Scooper uses it
+/// verbatim as its job id and WACZ file name.
+pub fn scooper_reference_id(id: &str) -> &str {
+    id
+}
+
+/// The id as a URL path segment, e.g. `/scoop-status/{id}`.
+pub fn path_segment(id: &str) -> String {
+    urlencoding::encode(id).into_owned()
+}
+
+/// Storage key prefix of an archive's objects, `{id}/{id}`.
+pub fn storage_key(id: &str) -> String {
+    format!("{id}/{id}")
+}
+
+/// The storage key as the percent-encoded `storage_path` query parameter
+/// handed to ScreenshotOne.
+pub fn storage_path_param(id: &str) -> String {
+    urlencoding::encode(&storage_key(id)).into_owned()
+}
+
+/// Storage key prefix of the capture taken at a responsive width,
+/// `{id}/{id}-{width}w`.
+pub fn responsive_storage_key(id: &str, width: u32) -> String {
+    format!("{}-{width}w", storage_key(id))
+}
+
+/// Reference id from a storage key or its percent-encoded form. `None` unless
+/// both halves are the same valid id.
+pub fn decode_storage_path(path: &str) -> Option<String> {
+    let decoded = urlencoding::decode(path).ok()?;
+    let (dir, name) = decoded.split_once('/')?;
+    (dir == name && is_valid_reference_id(dir)).then(|| dir.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_reference_id_shape() {
+        let generated = generate_reference_id(DEFAULT_REFERENCE_ID_EPOCH_SECS).unwrap();
+        assert!(is_valid_reference_id(&generated), "{generated}");
+        assert!(is_valid_reference_id("A-BCDE"));
+        assert!(is_valid_reference_id("ABCDEFGH-12345"));
+        assert!(!is_valid_reference_id("ABCDEFGHI-1234"));
+        assert!(!is_valid_reference_id("abcdef-gh12"));
+        assert!(!is_valid_reference_id("ABCDEF-GH1"));
+        assert!(!is_valid_reference_id("ABCDEFGH12"));
+        assert!(!is_valid_reference_id("AB/CD-EFGH"));
+    }
+
+    #[test]
+    fn test_base36_round_trip() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(36);
+        for n in (0..10_000)
+            .map(|_| rng.gen::<u64>())
+            .chain([0, 1, 35, 36, u64::MAX])
+        {
+            let encoded = u64_to_base36(n);
+            assert_eq!(base36_decode(&encoded), Some(n), "{encoded}");
+            assert_eq!(base36_decode(&encoded.to_lowercase()), Some(n), "{encoded}");
+        }
+        assert_eq!(u64_to_base36(u64::MAX), "3W5E11264SGSF");
+        // 1295 as emitted by JavaScript's `(1295).toString(36)`.
+        assert_eq!(base36_decode("zz"), Some(1295));
+        assert_eq!(base36_decode("3W5E11264SGSG"), None);
+        assert_eq!(base36_decode(""), None);
+        assert_eq!(base36_decode("AB-C"), None);
+        assert_eq!(base36_decode(" 1"), None);
+    }
+
+    #[test]
+    fn test_reference_id_timestamp() {
+        let before = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let id = generate_reference_id(DEFAULT_REFERENCE_ID_EPOCH_SECS).unwrap();
+        let timestamp_ms = reference_id_timestamp_ms(&id, DEFAULT_REFERENCE_ID_EPOCH_SECS).unwrap();
+        assert!(
+            timestamp_ms >= before && timestamp_ms < before + 1000,
+            "{id}"
+        );
+        assert_eq!(
+            reference_id_timestamp_ms(&id.to_lowercase(), DEFAULT_REFERENCE_ID_EPOCH_SECS),
+            Some(timestamp_ms)
+        );
+
+        // Decoded against the epoch the id was generated with.
+        let epoch_secs = 1704067200; // 2024-01-01
+        let id = generate_reference_id(epoch_secs).unwrap();
+        let timestamp_ms = reference_id_timestamp_ms(&id, epoch_secs).unwrap();
+        assert!(timestamp_ms >= before, "{id}");
+
+        assert_eq!(
+            reference_id_timestamp_ms("0-00000", DEFAULT_REFERENCE_ID_EPOCH_SECS),
+            Some(DEFAULT_REFERENCE_ID_EPOCH_SECS * 1000)
+        );
+        assert_eq!(reference_id_timestamp_ms("ZZZZZZZZZ-12345", 0), None);
+        assert_eq!(reference_id_timestamp_ms("ABCDEF", 0), None);
+    }
+
+    #[test]
+    fn test_legacy_reference_id_timestamp() {
+        // 2025-04-07T15:15:00Z is 8349300000 ms, 3U2YH40 in base36, after the
+        // default epoch. Ids generated before the counter appended 2 random
+        // characters and put the hyphen before the last 4.
+        assert_eq!(u64_to_base36(8_349_300_000), "3U2YH40");
+        for id in ["3U2YH-40XY", "3u2yh-40xy"] {
+            assert_eq!(
+                reference_id_timestamp_ms(id, DEFAULT_REFERENCE_ID_EPOCH_SECS),
+                Some(1744038900000),
+                "{id}"
+            );
+        }
+        assert_eq!(
+            reference_id_timestamp_ms("3U2YH40-AB123", DEFAULT_REFERENCE_ID_EPOCH_SECS),
+            Some(1744038900000)
+        );
+    }
+
+    #[test]
+    fn test_concurrent_reference_ids_are_unique() {
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                std::thread::spawn(|| {
+                    (0..12_500)
+                        .map(|_| generate_reference_id(DEFAULT_REFERENCE_ID_EPOCH_SECS).unwrap())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let mut ids = HashSet::new();
+        for thread in threads {
+            for id in thread.join().unwrap() {
+                assert!(is_valid_reference_id(&id), "{id}");
+                assert!(ids.insert(id), "duplicate reference id");
+            }
+        }
+        assert_eq!(ids.len(), 100_000);
+    }
+
+    #[test]
+    fn test_encodings_agree() {
+        let id = "MH7K2QX-AB12";
+        assert_eq!(scooper_reference_id(id), id);
+        assert_eq!(path_segment(id), id);
+        assert_eq!(storage_key(id), "MH7K2QX-AB12/MH7K2QX-AB12");
+        assert_eq!(storage_path_param(id), "MH7K2QX-AB12%2FMH7K2QX-AB12");
+        assert_eq!(
+            urlencoding::decode(&storage_path_param(id)).unwrap(),
+            storage_key(id)
+        );
+        assert_eq!(
+            decode_storage_path(&storage_path_param(id)).as_deref(),
+            Some(id)
+        );
+        assert_eq!(decode_storage_path(&storage_key(id)).as_deref(), Some(id));
+
+        assert_eq!(decode_storage_path("MH7K2QX-AB12%2FOTHER-AB12"), None);
+        assert_eq!(decode_storage_path("mh7k2qx-ab12%2Fmh7k2qx-ab12"), None);
+        assert_eq!(decode_storage_path("MH7K2QX-AB12"), None);
+
+        assert_eq!(
+            responsive_storage_key(id, 375),
+            "MH7K2QX-AB12/MH7K2QX-AB12-375w"
+        );
+        assert_eq!(decode_storage_path(&responsive_storage_key(id, 375)), None);
+    }
+}
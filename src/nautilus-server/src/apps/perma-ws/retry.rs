@@ -0,0 +1,173 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::future::Future;
+use std::time::Duration;
+use tracing::info;
+
+/// Upstream calls of the archive pipeline, classified by whether repeating
+/// them is safe:
+/// - `ScooperSubmit` (POST scooper) is not idempotent, a repeat starts a
+///   second WACZ job.
+/// - `ScreenshotCapture` (ScreenshotOne `take`) is not idempotent, a repeat
+///   is billed and uploads again. It has its own retry policy instead, see
+///   [`super::capture::call_screenshotone_with_retry`].
+/// - `Etag` and `ByteSize` (ranged GET and HEAD of the stored capture) are
+///   idempotent.
+///
+/// Idempotent stages are retried on any transport error. Non-idempotent ones
+/// only when connecting failed, i.e. before the request was sent, so a
+/// timeout after sending never repeats the side effect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    ScooperSubmit,
+    ScreenshotCapture,
+    Etag,
+    ByteSize,
+}
+
+impl Stage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Stage::ScooperSubmit => "scooper_submit",
+            Stage::ScreenshotCapture => "screenshot_capture",
+            Stage::Etag => "etag",
+            Stage::ByteSize => "byte_size",
+        }
+    }
+
+    pub fn is_idempotent(&self) -> bool {
+        matches!(self, Stage::Etag | Stage::ByteSize)
+    }
+
+    /// Whether a call of this stage that failed with `error` may be repeated.
+    pub fn should_retry(&self, error: &reqwest::Error) -> bool {
+        if self.is_idempotent() {
+            error.is_connect() || error.is_timeout() || error.is_request()
+        } else {
+            error.is_connect()
+        }
+    }
+}
+
+/// Delay before the second attempt, doubled for each one after.
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(200);
+
+/// Exponential backoff before the attempt after `attempt` (1-based).
+pub fn backoff_delay(attempt: u32) -> Duration {
+    RETRY_BASE_DELAY * 2u32.pow(attempt.saturating_sub(1).min(16))
+}
+
+/// Send an upstream request for `stage`, making at most `max_attempts`
+/// attempts (`UPSTREAM_MAX_ATTEMPTS`) while the error allows a retry, see
+/// [`Stage`].
+pub async fn send_with_retry<T, F, Fut>(
+    stage: Stage,
+    max_attempts: u32,
+    mut send: F,
+) -> Result<T, reqwest::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, reqwest::Error>>,
+{
+    let mut attempt = 1;
+    loop {
+        match send().await {
+            Err(e) if attempt < max_attempts && stage.should_retry(&e) => {
+                let delay = backoff_delay(attempt);
+                info!(
+                    "Retrying {} after attempt {} failed in {:?}: {}",
+                    stage.as_str(),
+                    attempt,
+                    delay,
+                    e
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    /// URL nothing listens on, so connecting fails before anything is sent.
+    async fn closed_port_url() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        format!("http://{addr}")
+    }
+
+    #[tokio::test]
+    async fn test_scooper_timeout_after_send_is_not_retried() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(202).set_delay(Duration::from_secs(2)))
+            .mount(&server)
+            .await;
+        let client = reqwest::Client::new();
+        let result = send_with_retry(Stage::ScooperSubmit, 3, || {
+            client
+                .post(server.uri())
+                .timeout(Duration::from_millis(100))
+                .send()
+        })
+        .await;
+        assert!(result.unwrap_err().is_timeout());
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_scooper_connect_error_is_retried() {
+        let url = closed_port_url().await;
+        let client = reqwest::Client::new();
+        let attempts = AtomicU32::new(0);
+        let result = send_with_retry(Stage::ScooperSubmit, 3, || {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            client.post(&url).send()
+        })
+        .await;
+        assert!(result.unwrap_err().is_connect());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_idempotent_timeout_is_retried() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(206).set_delay(Duration::from_secs(2)))
+            .mount(&server)
+            .await;
+        let client = reqwest::Client::new();
+        let result = send_with_retry(Stage::Etag, 2, || {
+            client
+                .get(server.uri())
+                .timeout(Duration::from_millis(100))
+                .send()
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(server.received_requests().await.unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        assert_eq!(backoff_delay(1), RETRY_BASE_DELAY);
+        assert_eq!(backoff_delay(3), RETRY_BASE_DELAY * 4);
+    }
+
+    #[test]
+    fn test_classification() {
+        assert!(!Stage::ScooperSubmit.is_idempotent());
+        assert!(!Stage::ScreenshotCapture.is_idempotent());
+        assert!(Stage::Etag.is_idempotent());
+        assert!(Stage::ByteSize.is_idempotent());
+    }
+}
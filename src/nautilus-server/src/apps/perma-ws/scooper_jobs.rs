@@ -0,0 +1,406 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::upstream::read_json_response;
+use crate::EnclaveError;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::info;
+
+/// Scoop jobs remembered for `/job_status`, oldest evicted first.
+pub const MAX_TRACKED_JOBS: usize = 10_000;
+
+/// Scooper job ids of the archives started by this enclave, keyed by
+/// reference id. In memory only, so a restart forgets them.
+#[derive(Debug, Default)]
+pub struct ScooperJobs {
+    jobs: Mutex<TrackedJobs>,
+}
+
+#[derive(Debug, Default)]
+struct TrackedJobs {
+    job_ids: HashMap<String, String>,
+    order: VecDeque<String>,
+}
+
+impl ScooperJobs {
+    /// Remember the scooper job of `reference_id`, evicting the oldest job
+    /// past [`MAX_TRACKED_JOBS`].
+    pub fn record(&self, reference_id: &str, job_id: &str) {
+        let mut jobs = self.jobs.lock().expect("lock poisoned");
+        let previous = jobs
+            .job_ids
+            .insert(reference_id.to_string(), job_id.to_string());
+        if previous.is_none() {
+            jobs.order.push_back(reference_id.to_string());
+        }
+        while jobs.order.len() > MAX_TRACKED_JOBS {
+            if let Some(evicted) = jobs.order.pop_front() {
+                jobs.job_ids.remove(&evicted);
+            }
+        }
+    }
+
+    /// Scooper job id of `reference_id`, if this enclave started it.
+    pub fn job_id(&self, reference_id: &str) -> Option<String> {
+        let jobs = self.jobs.lock().expect("lock poisoned");
+        jobs.job_ids.get(reference_id).cloned()
+    }
+}
+
+/// Job id from the body of scooper's 202 answer.
+pub fn job_id_from_response(body: &Value) -> Option<String> {
+    ["jobId", "job_id", "id"]
+        .iter()
+        .find_map(|key| match &body[key] {
+            Value::String(id) if !id.is_empty() => Some(id.clone()),
+            Value::Number(id) => Some(id.to_string()),
+            _ => None,
+        })
+}
+
+/// Whether scooper refused the submission because a job for the URL is
+/// already running: a 409, or a 200 (rather than the 202 of a new job) whose
+/// `status` says so or which sets `alreadyRunning`.
+pub fn is_already_running(status: reqwest::StatusCode, body: &Value) -> bool {
+    if status == reqwest::StatusCode::CONFLICT {
+        return true;
+    }
+    status == reqwest::StatusCode::OK
+        && (["alreadyRunning", "already_running"]
+            .iter()
+            .any(|key| body[key] == Value::Bool(true))
+            || body["status"].as_str().is_some_and(|s| {
+                let s = s.trim().to_ascii_lowercase().replace([' ', '-'], "_");
+                s == "already_running" || s == "running"
+            }))
+}
+
+/// Reference id of the running job in scooper's already running answer,
+/// see [`is_already_running`].
+pub fn reference_id_from_response(body: &Value) -> Option<String> {
+    ["referenceId", "reference_id"]
+        .iter()
+        .find_map(|key| match body[key].as_str().map(str::trim) {
+            Some(id) if !id.is_empty() => Some(id.to_string()),
+            _ => None,
+        })
+}
+
+/// Walrus blob id of the uploaded WACZ in a scooper answer, the 202 or a
+/// completed job's status, also looked up in the job's `result` or
+/// `returnvalue`.
+pub fn wacz_blob_id_from_response(body: &Value) -> Option<String> {
+    [body, &body["result"], &body["returnvalue"]]
+        .into_iter()
+        .flat_map(|body| {
+            ["waczBlobId", "wacz_blob_id", "blobId", "blob_id"]
+                .into_iter()
+                .map(move |key| &body[key])
+        })
+        .find_map(|id| match id.as_str().map(str::trim) {
+            Some(id) if !id.is_empty() => Some(id.to_string()),
+            _ => None,
+        })
+}
+
+/// State of a scoop job, i.e. of the WACZ upload to Walrus.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Completed {
+        /// Walrus blob id of the WACZ, when scooper reported it.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        wacz_blob_id: Option<String>,
+    },
+    /// The scoop failed, with scooper's error message.
+    Failed {
+        error: String,
+    },
+}
+
+/// Response of `GET /job_status/:reference_id`.
+#[derive(Debug, Serialize)]
+pub struct JobStatusResponse {
+    pub reference_id: String,
+    pub job_id: String,
+    #[serde(flatten)]
+    pub status: JobStatus,
+}
+
+/// Map scooper's job state to a [`JobStatus`]. Accepts the synonyms queue
+/// workers commonly report, e.g. `queued` or `done`.
+pub fn parse_job_status(body: &Value) -> Result<JobStatus, EnclaveError> {
+    let state = body["status"]
+        .as_str()
+        .or_else(|| body["state"].as_str())
+        .ok_or_else(|| EnclaveError::Upstream("Scooper job status is missing".to_string()))?;
+    match state.to_ascii_lowercase().as_str() {
+        "pending" | "queued" | "waiting" | "delayed" => Ok(JobStatus::Pending),
+        "running" | "active" | "processing" => Ok(JobStatus::Running),
+        "completed" | "complete" | "done" | "succeeded" => Ok(JobStatus::Completed {
+            wacz_blob_id: wacz_blob_id_from_response(body),
+        }),
+        "failed" | "error" => {
+            let error = ["error", "failedReason", "message"]
+                .iter()
+                .find_map(|key| body[key].as_str())
+                .unwrap_or("scoop failed without an error message");
+            Ok(JobStatus::Failed {
+                error: error.to_string(),
+            })
+        }
+        other => Err(EnclaveError::Upstream(format!(
+            "Unknown scooper job status {other}"
+        ))),
+    }
+}
+
+/// Ask scooper for the state of `job_id` with `GET {status_url}/{job_id}`,
+/// reading at most `max_response_bytes` of the answer.
+pub async fn query_job_status(
+    http: &reqwest::Client,
+    status_url: &str,
+    job_id: &str,
+    max_response_bytes: usize,
+) -> Result<JobStatus, EnclaveError> {
+    let url = format!(
+        "{}/{}",
+        status_url.trim_end_matches('/'),
+        urlencoding::encode(job_id)
+    );
+    info!("Querying scooper job status: {}", url);
+    let response = http
+        .get(&url)
+        .send()
+        .await
+        .map_err(|e| EnclaveError::from_reqwest("Failed to query scooper job status", e))?;
+    let status = response.status();
+    if status == reqwest::StatusCode::NOT_FOUND {
+        return Err(EnclaveError::NotFound(format!(
+            "scooper has no job {job_id}"
+        )));
+    }
+    let body = read_json_response("Scooper", response, max_response_bytes).await?;
+    if !status.is_success() {
+        return Err(EnclaveError::Upstream(format!(
+            "Scooper returned status {status} for job {job_id}"
+        )));
+    }
+    parse_job_status(&body)
+}
+
+/// Poll the state of `job_id` every `interval` until the scoop completes and
+/// return the WACZ blob id, failing when the scoop failed, completed without
+/// a blob id, or didn't complete within `timeout`.
+pub async fn wait_for_wacz_blob_id(
+    http: &reqwest::Client,
+    status_url: &str,
+    job_id: &str,
+    interval: Duration,
+    timeout: Duration,
+    max_response_bytes: usize,
+) -> Result<String, EnclaveError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match query_job_status(http, status_url, job_id, max_response_bytes).await? {
+            JobStatus::Completed {
+                wacz_blob_id: Some(blob_id),
+            } => return Ok(blob_id),
+            JobStatus::Completed { wacz_blob_id: None } => {
+                return Err(EnclaveError::Upstream(format!(
+                    "Scooper job {job_id} completed without a WACZ blob id"
+                )))
+            }
+            JobStatus::Failed { error } => {
+                return Err(EnclaveError::Upstream(format!(
+                    "Scooper job {job_id} failed: {error}"
+                )))
+            }
+            JobStatus::Pending | JobStatus::Running => {}
+        }
+        if Instant::now() + interval > deadline {
+            return Err(EnclaveError::Timeout(format!(
+                "Scooper job {job_id} didn't complete within {}s",
+                timeout.as_secs()
+            )));
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::job_status;
+    use crate::common::DEFAULT_MAX_RESPONSE_BYTES;
+    use crate::AppState;
+    use axum::extract::{Path, State};
+    use serde_json::json;
+    use std::sync::Arc;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[test]
+    fn test_job_id_from_response() {
+        assert_eq!(
+            job_id_from_response(&json!({ "jobId": "42", "status": "queued" })),
+            Some("42".to_string())
+        );
+        assert_eq!(
+            job_id_from_response(&json!({ "id": 7 })),
+            Some("7".to_string())
+        );
+        assert_eq!(
+            job_id_from_response(&json!({ "message": "accepted" })),
+            None
+        );
+    }
+
+    #[test]
+    fn test_is_already_running() {
+        use reqwest::StatusCode;
+
+        let body = json!({ "referenceId": "ABCDEF-GH12" });
+        assert!(is_already_running(StatusCode::CONFLICT, &body));
+        assert!(is_already_running(
+            StatusCode::OK,
+            &json!({ "status": "Already running" })
+        ));
+        assert!(is_already_running(
+            StatusCode::OK,
+            &json!({ "alreadyRunning": true })
+        ));
+        assert!(!is_already_running(StatusCode::OK, &body));
+        assert!(!is_already_running(
+            StatusCode::ACCEPTED,
+            &json!({ "status": "running" })
+        ));
+        assert_eq!(
+            reference_id_from_response(&body),
+            Some("ABCDEF-GH12".to_string())
+        );
+        assert_eq!(
+            reference_id_from_response(&json!({ "reference_id": " " })),
+            None
+        );
+    }
+
+    #[test]
+    fn test_tracked_jobs_are_bounded() {
+        let jobs = ScooperJobs::default();
+        for i in 0..=MAX_TRACKED_JOBS {
+            jobs.record(&format!("REF-{i}"), &i.to_string());
+        }
+        assert_eq!(jobs.job_id("REF-0"), None);
+        assert_eq!(jobs.job_id("REF-1"), Some("1".to_string()));
+        assert_eq!(
+            jobs.job_id(&format!("REF-{MAX_TRACKED_JOBS}")),
+            Some(MAX_TRACKED_JOBS.to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unknown_reference_id_is_not_found() {
+        let state = Arc::new(AppState::for_test());
+        let result = job_status(State(state), Path("ABCDEF-GH12".to_string())).await;
+        assert!(matches!(result, Err(EnclaveError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn test_query_job_status() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/job-status/1"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "status": "active" })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/job-status/2"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({ "status": "failed", "error": "navigation timeout" })),
+            )
+            .mount(&server)
+            .await;
+
+        let http = reqwest::Client::new();
+        let status_url = format!("{}/job-status", server.uri());
+        let query = |job_id: &'static str| {
+            query_job_status(&http, &status_url, job_id, DEFAULT_MAX_RESPONSE_BYTES)
+        };
+        assert_eq!(query("1").await.unwrap(), JobStatus::Running);
+        let failed = query("2").await.unwrap();
+        assert_eq!(
+            failed,
+            JobStatus::Failed {
+                error: "navigation timeout".to_string()
+            }
+        );
+        assert_eq!(
+            serde_json::to_value(&failed).unwrap(),
+            json!({ "status": "failed", "error": "navigation timeout" })
+        );
+        assert!(matches!(query("3").await, Err(EnclaveError::NotFound(_))));
+    }
+
+    #[test]
+    fn test_wacz_blob_id_from_response() {
+        let blob_id = "REEUaw_h1caEWvEmulzmAD6nfWtMsE0UEU-GqSXF28o";
+        for body in [
+            json!({ "jobId": "1", "waczBlobId": blob_id }),
+            json!({ "status": "completed", "result": { "blobId": blob_id } }),
+            json!({ "state": "completed", "returnvalue": { "blob_id": blob_id } }),
+        ] {
+            assert_eq!(wacz_blob_id_from_response(&body).as_deref(), Some(blob_id));
+        }
+        assert_eq!(
+            wacz_blob_id_from_response(&json!({ "jobId": "1", "blobId": "" })),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_wacz_blob_id() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/job-status/1"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "status": "active" })))
+            .up_to_n_times(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/job-status/1"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(
+                json!({ "status": "completed", "result": { "waczBlobId": "wacz-blob" } }),
+            ))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/job-status/2"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "status": "queued" })))
+            .mount(&server)
+            .await;
+
+        let http = reqwest::Client::new();
+        let status_url = format!("{}/job-status", server.uri());
+        let wait = |job_id: &'static str| {
+            wait_for_wacz_blob_id(
+                &http,
+                &status_url,
+                job_id,
+                Duration::from_millis(10),
+                Duration::from_millis(200),
+                DEFAULT_MAX_RESPONSE_BYTES,
+            )
+        };
+        assert_eq!(wait("1").await.unwrap(), "wacz-blob");
+        assert!(matches!(wait("2").await, Err(EnclaveError::Timeout(_))));
+    }
+}
@@ -0,0 +1,107 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::admission::ArchiveLimiter;
+use super::attestation::UnpersistedAttestation;
+use super::attestation_store::{AttestationStore, InMemoryAttestationStore};
+use super::config::PermaConfig;
+use super::host_limiter::HostRateLimiter;
+use super::idempotency::IdempotencyCache;
+use super::quota::DailyQuota;
+use super::recent_captures::RecentCaptures;
+use super::scooper_jobs::ScooperJobs;
+use super::target_http::TargetHttp;
+use crate::common::DependencyStatus;
+use crate::EnclaveError;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Instant;
+use tracing::info;
+
+/// perma-ws specific part of AppState.
+pub struct PermaState {
+    pub config: PermaConfig,
+    /// Permits bounding the archives in flight.
+    pub archive_limiter: ArchiveLimiter,
+    /// Archives started today, against `config.daily_archive_limit`.
+    pub daily_quota: DailyQuota,
+    /// Spaces out archives of the same target host.
+    pub host_limiter: HostRateLimiter,
+    /// Signed responses by idempotency key, for retried requests.
+    pub idempotency: IdempotencyCache,
+    /// Signed responses whose attestation save failed, oldest first, capped
+    /// at `config.unpersisted_capacity`.
+    pub unpersisted: Mutex<VecDeque<UnpersistedAttestation>>,
+    /// Every persisted attestation by reference id, recorded before the
+    /// frontend save.
+    pub attestation_store: Box<dyn AttestationStore>,
+    /// Scooper job ids by reference id, for `/job_status`.
+    pub scooper_jobs: ScooperJobs,
+    /// The latest persisted captures, for `/admin/captures`.
+    pub recent_captures: RecentCaptures,
+    /// Client for the target's own URLs and callbacks, checking every hop.
+    pub target_http: TargetHttp,
+    /// Last probe of the dependencies and when it ran, reused by the deep
+    /// health check, see [`super::dependency_checks`].
+    pub dependency_checks:
+        tokio::sync::Mutex<Option<(Instant, BTreeMap<String, DependencyStatus>)>>,
+}
+
+impl Default for PermaState {
+    fn default() -> Self {
+        Self::with_config(PermaConfig::default())
+    }
+}
+
+impl PermaState {
+    pub fn from_env() -> Result<Self, EnclaveError> {
+        Ok(Self::with_config(PermaConfig::from_env()?))
+    }
+
+    pub fn with_config(config: PermaConfig) -> Self {
+        Self {
+            archive_limiter: ArchiveLimiter::new(&config),
+            daily_quota: DailyQuota::new(&config),
+            host_limiter: HostRateLimiter::new(&config),
+            idempotency: IdempotencyCache::new(&config),
+            unpersisted: Mutex::default(),
+            attestation_store: Box::new(InMemoryAttestationStore::new(
+                config.attestation_store_capacity,
+            )),
+            scooper_jobs: ScooperJobs::default(),
+            recent_captures: RecentCaptures::new(config.recent_captures_capacity),
+            target_http: TargetHttp::with_proxy(config.target_proxy_url.as_deref()),
+            dependency_checks: tokio::sync::Mutex::default(),
+            config,
+        }
+    }
+
+    /// Record attestations in `store` instead of the in-memory default.
+    pub fn with_attestation_store(mut self, store: impl AttestationStore + 'static) -> Self {
+        self.attestation_store = Box::new(store);
+        self
+    }
+
+    /// Remember an unpersisted attestation, evicting the oldest ones past capacity.
+    pub fn record_unpersisted(&self, attestation: UnpersistedAttestation) {
+        let mut unpersisted = self.unpersisted.lock().expect("lock poisoned");
+        unpersisted.push_back(attestation);
+        while unpersisted.len() > self.config.unpersisted_capacity {
+            if let Some(evicted) = unpersisted.pop_front() {
+                info!(
+                    "Evicting unpersisted attestation {} over capacity",
+                    evicted.reference_id
+                );
+            }
+        }
+    }
+
+    /// Take all unpersisted attestations, leaving the list empty.
+    pub fn take_unpersisted(&self) -> Vec<UnpersistedAttestation> {
+        self.unpersisted
+            .lock()
+            .expect("lock poisoned")
+            .drain(..)
+            .collect()
+    }
+}
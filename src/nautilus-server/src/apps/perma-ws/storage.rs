@@ -0,0 +1,512 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::capture::{STORAGE_BUCKET, STORAGE_ENDPOINT};
+use super::config::PermaConfig;
+use crate::common::DependencyStatus;
+use crate::http_util::normalize_etag;
+use crate::EnclaveError;
+use chrono::{DateTime, Utc};
+use fastcrypto::encoding::{Encoding, Hex};
+use hmac::{Hmac, Mac};
+use reqwest::header::HeaderValue;
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+use tracing::info;
+
+/// Region used for signing storage requests. The S3-compatible storage
+/// ignores it but SigV4 requires one.
+pub const STORAGE_REGION: &str = "us-east-1";
+
+/// Bucket and key prefix an archive's objects are written to: the perma-ws
+/// bucket at its root, unless the request picked a `bucket` or
+/// `path_prefix` the configuration allows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StorageTarget {
+    pub bucket: String,
+    /// Without leading or trailing slashes.
+    pub path_prefix: Option<String>,
+}
+
+impl Default for StorageTarget {
+    fn default() -> Self {
+        Self {
+            bucket: STORAGE_BUCKET.to_string(),
+            path_prefix: None,
+        }
+    }
+}
+
+impl StorageTarget {
+    /// `key` under the path prefix, e.g. `tenant-a/{id}/{id}.txt`.
+    pub fn key(&self, key: &str) -> String {
+        match &self.path_prefix {
+            Some(prefix) => format!("{prefix}/{key}"),
+            None => key.to_string(),
+        }
+    }
+
+    /// [`key`](Self::key) as the percent-encoded `storage_path` query
+    /// parameter handed to ScreenshotOne.
+    pub fn path_param(&self, key: &str) -> String {
+        urlencoding::encode(&self.key(key)).into_owned()
+    }
+}
+
+/// Credentials for the S3-compatible storage.
+pub struct StorageCredentials<'a> {
+    pub access_key_id: &'a str,
+    pub secret_access_key: &'a str,
+}
+
+/// Delete an object with a path-style S3 `DeleteObject` request. Deleting a
+/// missing object succeeds, so this is safe to repeat.
+pub async fn delete_object(
+    http: &reqwest::Client,
+    endpoint: &str,
+    bucket: &str,
+    key: &str,
+    credentials: &StorageCredentials<'_>,
+) -> Result<(), EnclaveError> {
+    let (url, host) = object_url(endpoint, bucket, key)?;
+    let signed = sign_request(
+        "DELETE",
+        &host,
+        url.path(),
+        EMPTY_PAYLOAD_SHA256,
+        credentials,
+        Utc::now(),
+    );
+    info!("Deleting storage object {}", url);
+    let response = http
+        .delete(url)
+        .header("x-amz-date", &signed.amz_date)
+        .header("x-amz-content-sha256", EMPTY_PAYLOAD_SHA256)
+        .header("authorization", &signed.authorization)
+        .send()
+        .await
+        .map_err(|e| EnclaveError::from_reqwest("Failed to delete storage object", e))?;
+
+    let status = response.status();
+    if !status.is_success() {
+        return Err(EnclaveError::Upstream(format!(
+            "Storage returned status {status} deleting {key}"
+        )));
+    }
+    Ok(())
+}
+
+/// Upload `body` with a path-style S3 `PutObject` request and return the
+/// status the storage answered with.
+pub async fn put_object(
+    http: &reqwest::Client,
+    endpoint: &str,
+    bucket: &str,
+    key: &str,
+    body: Vec<u8>,
+    credentials: &StorageCredentials<'_>,
+) -> Result<reqwest::StatusCode, EnclaveError> {
+    send_put_object(http, endpoint, bucket, key, body, credentials)
+        .await
+        .map(|response| response.status())
+}
+
+/// Characters of a Walrus blob id: 32 bytes in unpadded URL-safe base64.
+const WALRUS_BLOB_ID_LEN: usize = 43;
+
+/// Walrus blob id carried by a storage ETag, see [`normalize_etag`]. An ETag
+/// that isn't a blob id is an upstream error, so a malformed id never gets
+/// signed.
+pub fn blob_id_from_etag(etag: &HeaderValue) -> Result<String, EnclaveError> {
+    let id = normalize_etag(etag);
+    if is_walrus_blob_id(&id) {
+        Ok(id)
+    } else {
+        Err(EnclaveError::Upstream(format!(
+            "ETag {etag:?} is not a Walrus blob id"
+        )))
+    }
+}
+
+fn is_walrus_blob_id(id: &str) -> bool {
+    let base64_value = |c: u8| match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'-' => Some(62),
+        b'_' => Some(63),
+        _ => None,
+    };
+    let values: Option<Vec<u8>> = id.bytes().map(base64_value).collect();
+    // The last character only carries 4 bits of the 32 bytes.
+    values.is_some_and(|values| {
+        values.len() == WALRUS_BLOB_ID_LEN && values.last().is_some_and(|last| last & 0b11 == 0)
+    })
+}
+
+/// Upload `body` like [`put_object`], requiring success, and return the
+/// object's Walrus blob id, see [`blob_id_from_etag`].
+pub async fn upload_object(
+    http: &reqwest::Client,
+    endpoint: &str,
+    bucket: &str,
+    key: &str,
+    body: Vec<u8>,
+    credentials: &StorageCredentials<'_>,
+) -> Result<String, EnclaveError> {
+    let response = send_put_object(http, endpoint, bucket, key, body, credentials).await?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(EnclaveError::Upstream(format!(
+            "Storage returned status {status} writing {key}"
+        )));
+    }
+    let etag = response
+        .headers()
+        .get("etag")
+        .ok_or_else(|| EnclaveError::Upstream(format!("No ETag for storage object {key}")))?;
+    blob_id_from_etag(etag)
+}
+
+async fn send_put_object(
+    http: &reqwest::Client,
+    endpoint: &str,
+    bucket: &str,
+    key: &str,
+    body: Vec<u8>,
+    credentials: &StorageCredentials<'_>,
+) -> Result<reqwest::Response, EnclaveError> {
+    let (url, host) = object_url(endpoint, bucket, key)?;
+    let payload_sha256 = Hex::encode(Sha256::digest(&body));
+    let signed = sign_request(
+        "PUT",
+        &host,
+        url.path(),
+        &payload_sha256,
+        credentials,
+        Utc::now(),
+    );
+    info!("Writing storage object {}", url);
+    http.put(url)
+        .header("x-amz-date", &signed.amz_date)
+        .header("x-amz-content-sha256", &payload_sha256)
+        .header("authorization", &signed.authorization)
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| EnclaveError::from_reqwest("Failed to write storage object", e))
+}
+
+/// Outcome of [`probe_storage_write`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageWriteStatus {
+    Ok,
+    /// The storage rejected the credentials (401 or 403).
+    Denied,
+    /// The storage was unreachable, timed out or failed otherwise.
+    Error,
+}
+
+/// Prefix of the objects written by [`probe_storage_write`].
+pub const PROBE_KEY_PREFIX: &str = "health/probe-";
+
+/// Check the credentials can write to `bucket` by uploading a tiny object and
+/// deleting it again. The delete is attempted whatever the upload answered,
+/// in case it was stored before the upload timed out. Each request is given
+/// `timeout`.
+pub async fn probe_storage_write(
+    http: &reqwest::Client,
+    endpoint: &str,
+    bucket: &str,
+    credentials: &StorageCredentials<'_>,
+    timeout: Duration,
+) -> StorageWriteStatus {
+    let key = format!(
+        "{PROBE_KEY_PREFIX}{}",
+        Utc::now().timestamp_nanos_opt().unwrap_or_default()
+    );
+    let put = tokio::time::timeout(
+        timeout,
+        put_object(http, endpoint, bucket, &key, b"ok".to_vec(), credentials),
+    )
+    .await;
+    let status = match put {
+        Ok(Ok(status)) if status.is_success() => StorageWriteStatus::Ok,
+        Ok(Ok(status))
+            if status == reqwest::StatusCode::UNAUTHORIZED
+                || status == reqwest::StatusCode::FORBIDDEN =>
+        {
+            StorageWriteStatus::Denied
+        }
+        Ok(Ok(status)) => {
+            info!("Storage write probe got status {}", status);
+            StorageWriteStatus::Error
+        }
+        Ok(Err(e)) => {
+            info!("Storage write probe failed: {}", e);
+            StorageWriteStatus::Error
+        }
+        Err(_) => {
+            info!("Storage write probe timed out after {:?}", timeout);
+            StorageWriteStatus::Error
+        }
+    };
+
+    let delete = tokio::time::timeout(
+        timeout,
+        delete_object(http, endpoint, bucket, &key, credentials),
+    )
+    .await;
+    match delete {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => info!("Failed to delete storage probe object {}: {}", key, e),
+        Err(_) => info!("Deleting storage probe object {} timed out", key),
+    }
+    status
+}
+
+/// Timeout for each request of the deep health check's write probe.
+pub const STORAGE_PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Probe write access to the configured bucket with the configured
+/// credentials, as a dependency of the deep health check. Missing
+/// credentials are reported as denied.
+pub async fn check_storage_write(http: &reqwest::Client, config: &PermaConfig) -> DependencyStatus {
+    let (Some(access_key_id), Some(secret_access_key)) = (
+        config.storage_access_key_id.as_deref(),
+        config.storage_secret_access_key.as_deref(),
+    ) else {
+        info!("Storage credentials not configured, reporting storage write as denied");
+        return DependencyStatus::down("denied: storage credentials not set");
+    };
+    let credentials = StorageCredentials {
+        access_key_id,
+        secret_access_key,
+    };
+    let status = probe_storage_write(
+        http,
+        STORAGE_ENDPOINT,
+        STORAGE_BUCKET,
+        &credentials,
+        STORAGE_PROBE_TIMEOUT,
+    )
+    .await;
+    match status {
+        StorageWriteStatus::Ok => DependencyStatus::up(),
+        StorageWriteStatus::Denied => DependencyStatus::down("denied: credentials rejected"),
+        StorageWriteStatus::Error => DependencyStatus::down("error: write probe failed"),
+    }
+}
+
+/// Path-style URL of an object and the host header to sign for it.
+fn object_url(
+    endpoint: &str,
+    bucket: &str,
+    key: &str,
+) -> Result<(reqwest::Url, String), EnclaveError> {
+    let encoded_key = key
+        .split('/')
+        .map(|segment| urlencoding::encode(segment).into_owned())
+        .collect::<Vec<_>>()
+        .join("/");
+    let object_url = format!(
+        "{}/{}/{}",
+        endpoint.trim_end_matches('/'),
+        bucket,
+        encoded_key
+    );
+    let parsed = reqwest::Url::parse(&object_url).map_err(|e| {
+        EnclaveError::GenericError(format!("Invalid storage url {object_url}: {e}"))
+    })?;
+    let host = match (parsed.host_str(), parsed.port()) {
+        (Some(host), Some(port)) => format!("{host}:{port}"),
+        (Some(host), None) => host.to_string(),
+        _ => {
+            return Err(EnclaveError::GenericError(format!(
+                "Storage url {object_url} has no host"
+            )))
+        }
+    };
+    Ok((parsed, host))
+}
+
+/// SHA-256 of an empty request body.
+const EMPTY_PAYLOAD_SHA256: &str =
+    "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+/// Headers produced by [`sign_request`].
+#[derive(Debug, PartialEq, Eq)]
+pub struct SignedHeaders {
+    pub amz_date: String,
+    pub authorization: String,
+}
+
+/// AWS Signature Version 4 for a request without query parameters, signing
+/// the `host`, `x-amz-content-sha256` and `x-amz-date` headers.
+/// `payload_sha256` is the hex SHA-256 of the body.
+pub fn sign_request(
+    method: &str,
+    host: &str,
+    path: &str,
+    payload_sha256: &str,
+    credentials: &StorageCredentials<'_>,
+    now: DateTime<Utc>,
+) -> SignedHeaders {
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = now.format("%Y%m%d").to_string();
+    let scope = format!("{date}/{STORAGE_REGION}/s3/aws4_request");
+    let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+
+    let canonical_request = format!(
+        "{method}\n{path}\n\nhost:{host}\nx-amz-content-sha256:{payload_sha256}\nx-amz-date:{amz_date}\n\n{signed_headers}\n{payload_sha256}"
+    );
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+        Hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let secret = format!("AWS4{}", credentials.secret_access_key);
+    let mut key = hmac_sha256(secret.as_bytes(), date.as_bytes());
+    for part in [STORAGE_REGION, "s3", "aws4_request"] {
+        key = hmac_sha256(&key, part.as_bytes());
+    }
+    let signature = Hex::encode(hmac_sha256(&key, string_to_sign.as_bytes()));
+
+    SignedHeaders {
+        authorization: format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+            credentials.access_key_id
+        ),
+        amz_date,
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+    use wiremock::matchers::{method, path_regex};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    const CREDENTIALS: StorageCredentials<'static> = StorageCredentials {
+        access_key_id: "AKIDEXAMPLE",
+        secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+    };
+
+    #[test]
+    fn test_sign_request_is_deterministic() {
+        let now = Utc.with_ymd_and_hms(2025, 1, 2, 3, 4, 5).unwrap();
+        let a = sign_request(
+            "DELETE",
+            "storage.nami.cloud",
+            "/perma-ws/A/A.png",
+            EMPTY_PAYLOAD_SHA256,
+            &CREDENTIALS,
+            now,
+        );
+        let b = sign_request(
+            "DELETE",
+            "storage.nami.cloud",
+            "/perma-ws/A/A.png",
+            EMPTY_PAYLOAD_SHA256,
+            &CREDENTIALS,
+            now,
+        );
+        assert_eq!(a, b);
+        assert_eq!(a.amz_date, "20250102T030405Z");
+        assert!(a.authorization.starts_with(
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20250102/us-east-1/s3/aws4_request, \
+             SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature="
+        ));
+
+        let other = sign_request(
+            "DELETE",
+            "storage.nami.cloud",
+            "/perma-ws/B/B.png",
+            EMPTY_PAYLOAD_SHA256,
+            &CREDENTIALS,
+            now,
+        );
+        assert_ne!(a.authorization, other.authorization);
+    }
+
+    #[test]
+    fn test_storage_target_keys() {
+        let default = StorageTarget::default();
+        assert_eq!(default.bucket, "perma-ws");
+        assert_eq!(default.key("ID/ID.txt"), "ID/ID.txt");
+        let tenant = StorageTarget {
+            bucket: "tenant-a".to_string(),
+            path_prefix: Some("captures/2025".to_string()),
+        };
+        assert_eq!(tenant.key("ID/ID.txt"), "captures/2025/ID/ID.txt");
+        assert_eq!(tenant.path_param("ID/ID"), "captures%2F2025%2FID%2FID");
+    }
+
+    #[test]
+    fn test_blob_id_from_etag() {
+        let id = "REEUaw_h1caEWvEmulzmAD6nfWtMsE0UEU-GqSXF28o";
+        for etag in [id.to_string(), format!("\"{id}\""), format!("W/\"{id}\"")] {
+            let etag = HeaderValue::from_str(&etag).unwrap();
+            assert_eq!(blob_id_from_etag(&etag).unwrap(), id);
+        }
+        for etag in [
+            "",
+            "\"\"",
+            "\"d41d8cd98f00b204e9800998ecf8427e\"",
+            "REEUaw_h1caEWvEmulzmAD6nfWtMsE0UEU+GqSXF28o",
+            "REEUaw_h1caEWvEmulzmAD6nfWtMsE0UEU-GqSXF28p",
+            "REEUaw_h1caEWvEmulzmAD6nfWtMsE0UEU-GqSXF28o=",
+        ] {
+            assert!(matches!(
+                blob_id_from_etag(&HeaderValue::from_static(etag)),
+                Err(EnclaveError::Upstream(_))
+            ));
+        }
+    }
+
+    async fn probe_against(put_status: u16) -> (StorageWriteStatus, Vec<wiremock::Request>) {
+        let server = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .and(path_regex("^/perma-ws/health/probe-"))
+            .respond_with(ResponseTemplate::new(put_status))
+            .mount(&server)
+            .await;
+        Mock::given(method("DELETE"))
+            .and(path_regex("^/perma-ws/health/probe-"))
+            .respond_with(ResponseTemplate::new(204))
+            .mount(&server)
+            .await;
+        let status = probe_storage_write(
+            &reqwest::Client::new(),
+            &server.uri(),
+            "perma-ws",
+            &CREDENTIALS,
+            Duration::from_secs(2),
+        )
+        .await;
+        (status, server.received_requests().await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_probe_reports_ok_and_cleans_up() {
+        let (status, requests) = probe_against(200).await;
+        assert_eq!(status, StorageWriteStatus::Ok);
+        let methods: Vec<String> = requests.iter().map(|r| r.method.to_string()).collect();
+        assert_eq!(methods, vec!["PUT", "DELETE"]);
+        assert_eq!(requests[0].url.path(), requests[1].url.path());
+    }
+
+    #[tokio::test]
+    async fn test_probe_reports_denied_on_403() {
+        let (status, requests) = probe_against(403).await;
+        assert_eq!(status, StorageWriteStatus::Denied);
+        assert_eq!(requests.last().unwrap().method.to_string(), "DELETE");
+    }
+}
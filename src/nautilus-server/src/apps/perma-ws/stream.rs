@@ -0,0 +1,171 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::progress::{ArchiveStage, Progress};
+use super::{admit, archive, PermaRequest};
+use crate::common::ProcessDataRequest;
+use crate::AppState;
+use crate::EnclaveError;
+use axum::extract::State;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::Json;
+use futures_util::stream::{self, Stream};
+use serde::Serialize;
+use serde_json::json;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+
+/// Same as process_data, but answers with server-sent events: one event named
+/// after each pipeline stage as it completes, then a `result` event with the
+/// signed response or an `error` event. The archive permit is held by the
+/// pipeline task, which is aborted if the client disconnects.
+pub async fn process_data_stream(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<ProcessDataRequest<PermaRequest>>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, EnclaveError> {
+    let permit = admit(&state, &request.payload).await?;
+    let (progress, stages) = Progress::channel();
+    // Keeps the pipeline logs in the request's span.
+    let task = tokio::spawn(
+        async move {
+            let _permit = permit;
+            archive(&state, request.payload, &progress).await
+        }
+        .in_current_span(),
+    );
+    Ok(Sse::new(archive_events(stages, task)).keep_alive(KeepAlive::default()))
+}
+
+/// Aborts the pipeline task when the event stream is dropped.
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Stream the stages reported by `task`, then its outcome. The stage channel
+/// closes when the task finishes and drops its sender.
+fn archive_events<T: Serialize + Send + 'static>(
+    stages: UnboundedReceiver<ArchiveStage>,
+    task: JoinHandle<Result<T, EnclaveError>>,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    let running = Some((stages, AbortOnDrop(task)));
+    stream::unfold(running, |running| async move {
+        let (mut stages, mut task) = running?;
+        match stages.recv().await {
+            Some(stage) => {
+                let event = Event::default()
+                    .event(stage.as_str())
+                    .data(json!({ "stage": stage }).to_string());
+                Some((Ok(event), Some((stages, task))))
+            }
+            None => {
+                let event = match (&mut task.0).await {
+                    Ok(Ok(result)) => Event::default()
+                        .event("result")
+                        .json_data(&result)
+                        .unwrap_or_else(|e| error_event(&e)),
+                    Ok(Err(e)) => error_event(&e),
+                    Err(e) => error_event(&format!("archive task failed: {e}")),
+                };
+                Some((Ok(event), None))
+            }
+        }
+    })
+}
+
+fn error_event(error: &dyn std::fmt::Display) -> Event {
+    Event::default()
+        .event("error")
+        .data(json!({ "error": error.to_string() }).to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::admission::ArchiveLimiter;
+    use crate::app::PermaConfig;
+    use axum::response::IntoResponse;
+    use std::time::Duration;
+
+    async fn event_names(response: axum::response::Response) -> Vec<String> {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(body.to_vec())
+            .unwrap()
+            .lines()
+            .filter_map(|line| line.strip_prefix("event:"))
+            .map(|name| name.trim().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_stage_events_in_order() {
+        let (progress, stages) = Progress::channel();
+        let task = tokio::spawn(async move {
+            for stage in [
+                ArchiveStage::ScooperSubmitted,
+                ArchiveStage::ScreenshotDone,
+                ArchiveStage::EtagFetched,
+                ArchiveStage::Signed,
+                ArchiveStage::Persisted,
+            ] {
+                progress.report(stage);
+            }
+            Ok(json!({ "signature": "sig" }))
+        });
+        let response = Sse::new(archive_events(stages, task)).into_response();
+        assert_eq!(
+            event_names(response).await,
+            vec![
+                "scooper_submitted",
+                "screenshot_done",
+                "etag_fetched",
+                "signed",
+                "persisted",
+                "result"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failure_ends_with_error_event() {
+        let (progress, stages) = Progress::channel();
+        let task = tokio::spawn(async move {
+            progress.report(ArchiveStage::ScooperSubmitted);
+            Err::<(), _>(EnclaveError::Upstream("ScreenshotOne down".to_string()))
+        });
+        let response = Sse::new(archive_events(stages, task)).into_response();
+        assert_eq!(
+            event_names(response).await,
+            vec!["scooper_submitted", "error"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_releases_permit() {
+        let limiter = ArchiveLimiter::new(&PermaConfig {
+            max_concurrent_archives: 1,
+            ..Default::default()
+        });
+        let permit = limiter.acquire().await.unwrap();
+        let (progress, stages) = Progress::channel();
+        let task = tokio::spawn(async move {
+            let _permit = permit;
+            let _progress = progress;
+            std::future::pending::<Result<(), EnclaveError>>().await
+        });
+
+        let events = archive_events(stages, task);
+        assert!(limiter.acquire().await.is_err());
+        drop(events);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(limiter.acquire().await.is_ok());
+    }
+}
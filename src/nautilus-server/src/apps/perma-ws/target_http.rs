@@ -0,0 +1,412 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! HTTP client for the URLs a request names: the target page, fetched by the
+//! canonical, freshness, redirect chain, TLS certificate, text and data
+//! preflights, and the callback. They are validated on arrival, but a redirect or a public name
+//! resolving to a private address could still lead the enclave to its own
+//! services, e.g. the host-only admin server, so every hop is checked again.
+//!
+//! Inside a Nitro enclave the only egress is the hosts of
+//! `allowed_endpoints.yaml`, so these fetches go through the forward proxy of
+//! `TARGET_PROXY_URL` there, which resolves the names and must itself refuse
+//! private addresses.
+
+use super::canonical::{is_private_host, is_private_ip};
+use super::redirect::MAX_REDIRECTS;
+use crate::common::{HTTP_CONNECT_TIMEOUT, HTTP_TIMEOUT};
+use crate::EnclaveError;
+use fastcrypto::encoding::{Base64, Encoding};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::redirect::Policy;
+use reqwest::Url;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Largest answer of the proxy to a `CONNECT`, up to the blank line.
+const MAX_CONNECT_RESPONSE_BYTES: usize = 8 * 1024;
+
+/// Client for target and callback URLs. It never follows redirects by
+/// itself, see [`TargetHttp::get`], and refuses to connect to a name that
+/// resolves to a private address.
+#[derive(Clone)]
+pub struct TargetHttp {
+    client: reqwest::Client,
+    /// Forward proxy every request and connection goes through, see
+    /// [`TargetHttp::with_proxy`].
+    proxy: Option<Url>,
+    /// `host:port` let through the private host checks, only set by tests
+    /// against a local mock server.
+    exempt: Vec<String>,
+}
+
+impl Default for TargetHttp {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TargetHttp {
+    pub fn new() -> Self {
+        Self::with_proxy(None)
+    }
+
+    /// A client going through the http forward proxy at `proxy`
+    /// (`TARGET_PROXY_URL`, validated on boot) when set: https targets are
+    /// tunnelled with `CONNECT`, other requests are sent to it whole. Names
+    /// are then resolved by the proxy, so only private hosts named in the
+    /// URL are refused here.
+    pub fn with_proxy(proxy: Option<&str>) -> Self {
+        let proxy = proxy.map(|proxy| Url::parse(proxy).expect("TARGET_PROXY_URL is valid"));
+        let builder = reqwest::Client::builder()
+            .redirect(Policy::none())
+            .timeout(HTTP_TIMEOUT)
+            .connect_timeout(HTTP_CONNECT_TIMEOUT);
+        let builder = match &proxy {
+            Some(proxy) => builder
+                .proxy(reqwest::Proxy::all(proxy.clone()).expect("TARGET_PROXY_URL is valid")),
+            None => builder.dns_resolver(Arc::new(PublicResolver)),
+        };
+        Self {
+            client: builder.build().expect("HTTP client configuration is valid"),
+            proxy,
+            exempt: Vec::new(),
+        }
+    }
+
+    /// A client letting `hosts` (`host:port`, e.g. a mock server's address)
+    /// through the private host checks.
+    #[cfg(test)]
+    pub fn allowing(hosts: &[&str]) -> Self {
+        Self {
+            exempt: hosts.iter().map(|h| h.to_string()).collect(),
+            ..Self::new()
+        }
+    }
+
+    /// The client, which doesn't follow redirects. Check a URL with
+    /// [`TargetHttp::check`] before sending to it.
+    pub fn client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    /// Whether `url` is on a host let through the private host checks.
+    pub fn is_exempt(&self, url: &Url) -> bool {
+        let origin = format!(
+            "{}:{}",
+            url.host_str().unwrap_or_default(),
+            url.port_or_known_default().unwrap_or_default()
+        );
+        self.exempt.contains(&origin)
+    }
+
+    /// Refuse `url` unless it is http(s) with a host that neither is nor
+    /// resolves to a loopback, private or link-local address.
+    pub async fn check(&self, url: &Url) -> Result<(), EnclaveError> {
+        if !matches!(url.scheme(), "http" | "https") || url.host_str().is_none() {
+            return Err(EnclaveError::BadRequest(format!("unsupported url {url}")));
+        }
+        if self.is_exempt(url) {
+            return Ok(());
+        }
+        if is_private_host(url) {
+            return Err(EnclaveError::BadRequest(format!(
+                "url {url} points to a private host"
+            )));
+        }
+        if let (Some(domain), None) = (url.domain(), &self.proxy) {
+            public_addrs(domain, url.port_or_known_default().unwrap_or_default()).await?;
+        }
+        Ok(())
+    }
+
+    /// Open a TCP connection to the host and port of `url`, for what speaks
+    /// to the target below HTTP, e.g. the TLS certificate capture. The host
+    /// is checked as by [`TargetHttp::check`], and the connection goes to
+    /// one of the public addresses its name resolved to rather than
+    /// resolving it again, or is tunnelled through the proxy.
+    pub async fn connect(&self, url: &Url) -> Result<TcpStream, EnclaveError> {
+        let exempt = self.is_exempt(url);
+        if !exempt && is_private_host(url) {
+            return Err(EnclaveError::BadRequest(format!(
+                "url {url} points to a private host"
+            )));
+        }
+        let port = url.port_or_known_default().unwrap_or_default();
+        if let Some(proxy) = &self.proxy {
+            let authority = format!("{}:{port}", url.host_str().unwrap_or_default());
+            return tunnel(proxy, &authority).await;
+        }
+        let host = url
+            .host_str()
+            .ok_or_else(|| EnclaveError::BadRequest(format!("url {url} has no host")))?
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let addrs = match host.parse::<IpAddr>() {
+            Ok(ip) => vec![SocketAddr::new(ip, port)],
+            Err(_) if exempt => tokio::net::lookup_host((host, port))
+                .await
+                .map_err(|e| EnclaveError::Upstream(format!("Failed to resolve {host}: {e}")))?
+                .collect(),
+            Err(_) => public_addrs(host, port).await?,
+        };
+        if addrs.is_empty() {
+            return Err(EnclaveError::BadRequest(format!(
+                "{host} has no public address"
+            )));
+        }
+
+        let mut last_error = None;
+        for addr in addrs {
+            match tokio::time::timeout(HTTP_CONNECT_TIMEOUT, TcpStream::connect(addr)).await {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(e)) => last_error = Some(e.to_string()),
+                Err(_) => last_error = Some("connection timed out".to_string()),
+            }
+        }
+        Err(EnclaveError::Upstream(format!(
+            "Failed to connect to {host}:{port}: {}",
+            last_error.unwrap_or_default()
+        )))
+    }
+
+    /// GET `url`, following at most [`MAX_REDIRECTS`] redirects, with the
+    /// URL and every hop checked by [`TargetHttp::check`] before it is
+    /// requested.
+    pub async fn get(&self, url: &str) -> Result<reqwest::Response, EnclaveError> {
+        let mut current = Url::parse(url)
+            .map_err(|e| EnclaveError::BadRequest(format!("invalid url {url}: {e}")))?;
+        for _ in 0..=MAX_REDIRECTS {
+            self.check(&current).await?;
+            let response = self.client.get(current.clone()).send().await.map_err(|e| {
+                EnclaveError::from_reqwest(&format!("Failed to fetch {current}"), e)
+            })?;
+            let location = response
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .and_then(|v| v.to_str().ok());
+            match location {
+                Some(location) if response.status().is_redirection() => {
+                    current = current.join(location).map_err(|e| {
+                        EnclaveError::Upstream(format!(
+                            "{current} redirected to invalid location {location}: {e}"
+                        ))
+                    })?;
+                }
+                _ => return Ok(response),
+            }
+        }
+        Err(EnclaveError::BadRequest(format!(
+            "{url} redirects more than {MAX_REDIRECTS} times"
+        )))
+    }
+}
+
+/// A connection to `authority` (`host:port`) tunnelled through the http
+/// forward `proxy` with `CONNECT`, authenticated with the proxy URL's
+/// credentials when it has some.
+async fn tunnel(proxy: &Url, authority: &str) -> Result<TcpStream, EnclaveError> {
+    let failed = |e: &dyn std::fmt::Display| {
+        EnclaveError::Upstream(format!(
+            "Failed to tunnel to {authority} through the proxy: {e}"
+        ))
+    };
+    let proxy_addr = format!(
+        "{}:{}",
+        proxy.host_str().unwrap_or_default(),
+        proxy.port_or_known_default().unwrap_or_default()
+    );
+    let handshake = async {
+        let mut stream = TcpStream::connect(&proxy_addr)
+            .await
+            .map_err(|e| failed(&e))?;
+        let mut request = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n");
+        if !proxy.username().is_empty() {
+            let credentials = format!(
+                "{}:{}",
+                proxy.username(),
+                proxy.password().unwrap_or_default()
+            );
+            request.push_str(&format!(
+                "Proxy-Authorization: Basic {}\r\n",
+                Base64::encode(credentials)
+            ));
+        }
+        request.push_str("\r\n");
+        stream
+            .write_all(request.as_bytes())
+            .await
+            .map_err(|e| failed(&e))?;
+
+        // Read the answer byte by byte so nothing of the tunnelled stream is
+        // consumed with it.
+        let mut answer = Vec::new();
+        while !answer.ends_with(b"\r\n\r\n") {
+            if answer.len() >= MAX_CONNECT_RESPONSE_BYTES {
+                return Err(failed(&"the proxy answer is too large"));
+            }
+            let byte = stream.read_u8().await.map_err(|e| failed(&e))?;
+            answer.push(byte);
+        }
+        let answer = String::from_utf8_lossy(&answer);
+        let status_line = answer.lines().next().unwrap_or_default();
+        if status_line.split_whitespace().nth(1) != Some("200") {
+            return Err(failed(&format!("the proxy answered {status_line}")));
+        }
+        Ok(stream)
+    };
+    tokio::time::timeout(HTTP_CONNECT_TIMEOUT, handshake)
+        .await
+        .map_err(|_| failed(&"timed out"))?
+}
+
+/// The system resolver, failing for names with a private address so a name
+/// can't be pointed inside the enclave after [`TargetHttp::check`] passed.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs = public_addrs(name.as_str(), 0).await?;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Addresses of `host`, refused when any of them is private.
+async fn public_addrs(host: &str, port: u16) -> Result<Vec<SocketAddr>, EnclaveError> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| EnclaveError::Upstream(format!("Failed to resolve {host}: {e}")))?
+        .collect();
+    if let Some(private) = addrs.iter().find(|addr| is_private_ip(addr.ip())) {
+        return Err(EnclaveError::BadRequest(format!(
+            "{host} resolves to private address {}",
+            private.ip()
+        )));
+    }
+    Ok(addrs)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test]
+    async fn test_redirect_to_private_host_is_refused() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/public"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("ok"))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/admin"))
+            .respond_with(
+                ResponseTemplate::new(302)
+                    .insert_header("location", "http://127.0.0.1:3001/admin/unpersisted"),
+            )
+            .mount(&server)
+            .await;
+        let http = TargetHttp::allowing(&[&server.address().to_string()]);
+
+        let response = http.get(&format!("{}/public", server.uri())).await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "ok");
+
+        let result = http.get(&format!("{}/admin", server.uri())).await;
+        assert!(
+            matches!(result, Err(EnclaveError::BadRequest(ref msg)) if msg.contains("127.0.0.1:3001")),
+            "{result:?}"
+        );
+        // The mock server itself is refused without the exemption.
+        assert!(TargetHttp::new()
+            .get(&format!("{}/public", server.uri()))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_name_resolving_to_private_address_is_refused() {
+        assert!(matches!(
+            public_addrs("localhost", 80).await,
+            Err(EnclaveError::BadRequest(_))
+        ));
+        let http = TargetHttp::new();
+        for url in [
+            "http://169.254.169.254/latest",
+            "http://[::1]:3001/",
+            "ftp://example.com/",
+        ] {
+            assert!(
+                http.check(&Url::parse(url).unwrap()).await.is_err(),
+                "{url}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_requests_go_through_the_proxy() {
+        let proxy = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/page"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("proxied"))
+            .expect(1)
+            .mount(&proxy)
+            .await;
+        let http = TargetHttp::with_proxy(Some(&proxy.uri()));
+
+        let response = http.get("http://example.com/page").await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "proxied");
+        // Private hosts named in the URL are still refused before the proxy.
+        assert!(http.get("http://127.0.0.1:3001/admin").await.is_err());
+    }
+
+    /// Accept one `CONNECT` answering `status_line`, then echo the tunnel.
+    async fn spawn_connect_proxy(status_line: &'static str) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(stream.read_u8().await.unwrap());
+            }
+            assert!(request.starts_with(b"CONNECT example.com:443 HTTP/1.1\r\n"));
+            stream
+                .write_all(format!("{status_line}\r\n\r\n").as_bytes())
+                .await
+                .unwrap();
+            let mut buf = [0u8; 4];
+            if stream.read_exact(&mut buf).await.is_ok() {
+                stream.write_all(&buf).await.unwrap();
+            }
+        });
+        format!("http://{addr}")
+    }
+
+    #[tokio::test]
+    async fn test_connect_tunnels_through_the_proxy() {
+        let url = Url::parse("https://example.com/").unwrap();
+
+        let proxy = spawn_connect_proxy("HTTP/1.1 200 Connection established").await;
+        let mut stream = TargetHttp::with_proxy(Some(&proxy))
+            .connect(&url)
+            .await
+            .unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut echoed = [0u8; 4];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+
+        let proxy = spawn_connect_proxy("HTTP/1.1 403 Forbidden").await;
+        let result = TargetHttp::with_proxy(Some(&proxy)).connect(&url).await;
+        assert!(
+            matches!(result, Err(EnclaveError::Upstream(ref msg)) if msg.contains("403")),
+            "{result:?}"
+        );
+    }
+}
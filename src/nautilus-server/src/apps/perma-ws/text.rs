@@ -0,0 +1,272 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::reference_id::storage_key;
+use super::storage::{upload_object, StorageCredentials, StorageTarget};
+use super::target_http::TargetHttp;
+use crate::EnclaveError;
+use fastcrypto::encoding::{Encoding, Hex};
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+/// Bytes of HTML read to extract the text from.
+pub const MAX_TEXT_SOURCE_BYTES: usize = 5 * 1024 * 1024;
+
+/// Longest extracted text stored and signed, longer text is truncated.
+pub const MAX_EXTRACTED_TEXT_BYTES: usize = 1024 * 1024;
+
+/// Elements whose content is never rendered as text.
+const SKIPPED_ELEMENTS: &[&str] = &["script", "style", "noscript", "template", "svg", "head"];
+
+/// Storage key of an archive's extracted text, next to its screenshot.
+pub fn text_storage_key(reference_id: &str) -> String {
+    format!("{}.txt", storage_key(reference_id))
+}
+
+/// Text of a page as stored and signed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtractedText {
+    /// Storage ETag of the uploaded text, which is its Walrus blob id.
+    pub blob_id: String,
+    /// Hex SHA-256 of the stored UTF-8 text.
+    pub sha256: String,
+    pub byte_size: usize,
+}
+
+/// Fetch the page with `target_http`, extract its text (see
+/// [`extract_text`]), upload it next to the screenshot in `storage` and
+/// return its blob id and hash. The text comes from the served HTML, so
+/// content rendered by scripts is missing.
+pub async fn capture_page_text(
+    http: &reqwest::Client,
+    target_http: &TargetHttp,
+    url: &str,
+    reference_id: &str,
+    storage: &StorageTarget,
+    storage_endpoint: &str,
+    credentials: &StorageCredentials<'_>,
+) -> Result<ExtractedText, EnclaveError> {
+    let mut response = target_http
+        .get(url)
+        .await?
+        .error_for_status()
+        .map_err(|e| EnclaveError::from_reqwest("Failed to fetch the page text", e))?;
+    let mut html = Vec::new();
+    while html.len() < MAX_TEXT_SOURCE_BYTES {
+        match response.chunk().await {
+            Ok(Some(chunk)) => html.extend_from_slice(&chunk),
+            Ok(None) => break,
+            Err(e) => {
+                return Err(EnclaveError::from_reqwest(
+                    "Failed to read the page text",
+                    e,
+                ))
+            }
+        }
+    }
+
+    let text = extract_text(&String::from_utf8_lossy(&html));
+    let text = truncate_at_char_boundary(&text, MAX_EXTRACTED_TEXT_BYTES);
+    let sha256 = Hex::encode(Sha256::digest(text.as_bytes()));
+    let byte_size = text.len();
+    info!("Extracted {} bytes of text from {}", byte_size, url);
+
+    let blob_id = upload_object(
+        http,
+        storage_endpoint,
+        &storage.bucket,
+        &storage.key(&text_storage_key(reference_id)),
+        text.as_bytes().to_vec(),
+        credentials,
+    )
+    .await?;
+    Ok(ExtractedText {
+        blob_id,
+        sha256,
+        byte_size,
+    })
+}
+
+/// Visible text of an HTML document: tags dropped, the content of
+/// [`SKIPPED_ELEMENTS`] and comments left out, common entities decoded, and
+/// whitespace collapsed to single spaces.
+pub fn extract_text(html: &str) -> String {
+    let mut text = String::new();
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        text.push(' ');
+        rest = &rest[start..];
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+            continue;
+        }
+        let Some(end) = rest.find('>') else {
+            rest = "";
+            break;
+        };
+        let name = tag_name(&rest[1..end]);
+        rest = &rest[end + 1..];
+        if let Some(skipped) = SKIPPED_ELEMENTS.iter().find(|s| name == **s) {
+            let lower = rest.to_ascii_lowercase();
+            let close = format!("</{skipped}");
+            rest = lower.find(&close).map_or("", |i| &rest[i..]);
+        }
+    }
+    text.push_str(rest);
+    decode_entities(&text)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Lowercase name of an opening tag, empty for closing tags and doctypes.
+fn tag_name(tag: &str) -> String {
+    if tag.starts_with('/') || tag.starts_with('!') {
+        return String::new();
+    }
+    tag.split(|c: char| c.is_whitespace() || c == '/')
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+fn decode_entities(text: &str) -> String {
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// `text` cut to at most `max` bytes without splitting a character.
+fn truncate_at_char_boundary(text: &str, max: usize) -> &str {
+    if text.len() <= max {
+        return text;
+    }
+    let mut end = max;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::{PermaResponse, ResponsiveCapture};
+    use crate::common::{to_signed_response, IntentScope};
+    use crate::AppState;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    const PAGE: &str = r#"<!DOCTYPE html>
+<html><head><title>Ignored</title><style>p { color: red }</style></head>
+<body>
+  <!-- a comment -->
+  <h1>Hello,&nbsp;world</h1>
+  <script>var ignored = "<p>not text</p>";</script>
+  <p class="lead">Fish &amp; chips
+     are &lt;great&gt;.</p>
+</body></html>"#;
+
+    #[test]
+    fn test_extract_text() {
+        assert_eq!(extract_text(PAGE), "Hello, world Fish & chips are <great>.");
+        assert_eq!(extract_text("plain <b>bold</b>text"), "plain bold text");
+        assert_eq!(extract_text("<p>unclosed <script>x"), "unclosed");
+    }
+
+    #[test]
+    fn test_truncate_at_char_boundary() {
+        assert_eq!(truncate_at_char_boundary("héllo", 2), "h");
+        assert_eq!(truncate_at_char_boundary("héllo", 3), "hé");
+        assert_eq!(truncate_at_char_boundary("hi", 10), "hi");
+    }
+
+    #[tokio::test]
+    async fn test_page_text_is_stored_and_signed() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/page"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(PAGE))
+            .mount(&server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path("/perma-ws/ABCDEF-GH12/ABCDEF-GH12.txt"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("etag", "\"mC2ePrmW9VnmM_TRlN7zdh2Qn1o7ZH0ahR_q1nwyydE\""),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        let credentials = StorageCredentials {
+            access_key_id: "id",
+            secret_access_key: "secret",
+        };
+
+        let text = capture_page_text(
+            &reqwest::Client::new(),
+            &TargetHttp::allowing(&[&server.address().to_string()]),
+            &format!("{}/page", server.uri()),
+            "ABCDEF-GH12",
+            &StorageTarget::default(),
+            &server.uri(),
+            &credentials,
+        )
+        .await
+        .unwrap();
+        let expected = "Hello, world Fish & chips are <great>.";
+        assert_eq!(text.blob_id, "mC2ePrmW9VnmM_TRlN7zdh2Qn1o7ZH0ahR_q1nwyydE");
+        assert_eq!(text.byte_size, expected.len());
+        assert_eq!(
+            text.sha256,
+            Hex::encode(Sha256::digest(expected.as_bytes()))
+        );
+        let uploaded = &server.received_requests().await.unwrap()[1];
+        assert_eq!(uploaded.body, expected.as_bytes());
+
+        // The hash is part of the signed payload.
+        let response = |text_sha256: Option<String>| PermaResponse {
+            url: "https://example.com/".to_string(),
+            reference_id: "ABCDEF-GH12".to_string(),
+            screenshot_blob_id: "blob".to_string(),
+            screenshot_byte_size: 1,
+            full_page_max_height: None,
+            screenshot_provider: "screenshotone".to_string(),
+            tls_cert_fingerprint: None,
+            tls_cert_chain: vec![],
+            final_url: None,
+            redirect_chain_hash: None,
+            responsive_captures: Vec::<ResponsiveCapture>::new(),
+            color_scheme: None,
+            canonical_url: None,
+            phash: None,
+            text_blob_id: Some(text.blob_id.clone()),
+            text_sha256,
+            data_content_type: None,
+            data_sha256: None,
+            wacz_blob_id: "wacz".to_string(),
+            nonce: None,
+        };
+        let kp = AppState::for_test().eph_kp;
+        let signed = to_signed_response(
+            &kp,
+            response(Some(text.sha256.clone())),
+            1,
+            IntentScope::ProcessData,
+        );
+        let tampered = to_signed_response(
+            &kp,
+            response(Some("00".repeat(32))),
+            1,
+            IntentScope::ProcessData,
+        );
+        assert_eq!(signed.response.data.text_sha256, Some(text.sha256));
+        assert_ne!(signed.signature, tampered.signature);
+        server.verify().await;
+    }
+}
@@ -0,0 +1,194 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::target_http::TargetHttp;
+use crate::EnclaveError;
+use fastcrypto::encoding::{Encoding, Hex};
+use openssl::hash::MessageDigest;
+use openssl::ssl::{SslConnector, SslMethod};
+use openssl::x509::X509Ref;
+use std::net::TcpStream;
+use std::time::Duration;
+use tracing::info;
+
+/// Time allowed for completing the handshake once connected.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Certificates the target presented during the TLS handshake, identified by
+/// the hex SHA-256 of their DER encoding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsCertificate {
+    /// Fingerprint of the leaf certificate.
+    pub fingerprint: String,
+    /// Fingerprints of the chain as sent by the server, leaf first.
+    pub chain: Vec<String>,
+}
+
+/// Fingerprint the certificate chain of an https target, verified against the
+/// system trust store. Returns None for other schemes, which have no
+/// certificate to capture. The connection is opened by `target`, see
+/// [`TargetHttp::connect`], so a private host is refused like for every other
+/// fetch of the target.
+pub async fn capture_tls_certificate(
+    target: &TargetHttp,
+    url: &str,
+) -> Result<Option<TlsCertificate>, EnclaveError> {
+    let connector = SslConnector::builder(SslMethod::tls())
+        .map_err(|e| EnclaveError::Internal(format!("Failed to build TLS connector: {e}")))?
+        .build();
+    capture_tls_certificate_with(target, url, connector).await
+}
+
+pub async fn capture_tls_certificate_with(
+    target: &TargetHttp,
+    url: &str,
+    connector: SslConnector,
+) -> Result<Option<TlsCertificate>, EnclaveError> {
+    let parsed = reqwest::Url::parse(url)
+        .map_err(|e| EnclaveError::BadRequest(format!("invalid url {url}: {e}")))?;
+    if parsed.scheme() != "https" {
+        info!("Skipping TLS certificate capture for non-https url {}", url);
+        return Ok(None);
+    }
+    let host = parsed
+        .host_str()
+        .ok_or_else(|| EnclaveError::BadRequest(format!("url {url} has no host")))?
+        .to_string();
+    let port = parsed.port_or_known_default().unwrap_or(443);
+    let tcp = target
+        .connect(&parsed)
+        .await?
+        .into_std()
+        .and_then(|tcp| tcp.set_nonblocking(false).map(|_| tcp))
+        .map_err(|e| EnclaveError::Internal(format!("Failed to set up the TLS socket: {e}")))?;
+
+    tokio::task::spawn_blocking(move || handshake(&host, port, tcp, &connector))
+        .await
+        .map_err(|e| EnclaveError::Internal(format!("TLS capture task failed: {e}")))?
+        .map(Some)
+}
+
+/// Complete a handshake with host:port over `tcp` and fingerprint what was
+/// presented.
+fn handshake(
+    host: &str,
+    port: u16,
+    tcp: TcpStream,
+    connector: &SslConnector,
+) -> Result<TlsCertificate, EnclaveError> {
+    let failed = |e: &dyn std::fmt::Display| {
+        EnclaveError::Upstream(format!("TLS handshake with {host}:{port} failed: {e}"))
+    };
+
+    tcp.set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+        .and_then(|_| tcp.set_write_timeout(Some(HANDSHAKE_TIMEOUT)))
+        .map_err(|e| failed(&e))?;
+    let stream = connector.connect(host, tcp).map_err(|e| failed(&e))?;
+
+    let ssl = stream.ssl();
+    let leaf = ssl
+        .peer_certificate()
+        .ok_or_else(|| failed(&"server presented no certificate"))?;
+    let fingerprint = fingerprint(&leaf)?;
+    let mut chain = vec![fingerprint.clone()];
+    if let Some(stack) = ssl.peer_cert_chain() {
+        for cert in stack {
+            let fp = self::fingerprint(cert)?;
+            if !chain.contains(&fp) {
+                chain.push(fp);
+            }
+        }
+    }
+    Ok(TlsCertificate { fingerprint, chain })
+}
+
+fn fingerprint(cert: &X509Ref) -> Result<String, EnclaveError> {
+    cert.digest(MessageDigest::sha256())
+        .map(|digest| Hex::encode(&*digest))
+        .map_err(|e| EnclaveError::GenericError(format!("Failed to fingerprint certificate: {e}")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use openssl::pkey::PKey;
+    use openssl::ssl::{SslAcceptor, SslVerifyMode};
+    use openssl::x509::X509;
+    use std::net::TcpListener;
+
+    /// Serve one TLS handshake with a fresh self-signed localhost certificate,
+    /// returning the port and the certificate's expected fingerprint.
+    fn spawn_tls_server() -> (u16, String) {
+        let rcgen::CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let x509 = X509::from_pem(cert.pem().as_bytes()).unwrap();
+        let key = PKey::private_key_from_pem(key_pair.serialize_pem().as_bytes()).unwrap();
+        let expected = Hex::encode(&*x509.digest(MessageDigest::sha256()).unwrap());
+
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        acceptor.set_certificate(&x509).unwrap();
+        let acceptor = acceptor.build();
+
+        let listener = TcpListener::bind("localhost:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let (tcp, _) = listener.accept().unwrap();
+            let _ = acceptor.accept(tcp);
+        });
+        (port, expected)
+    }
+
+    fn insecure_connector() -> SslConnector {
+        let mut builder = SslConnector::builder(SslMethod::tls()).unwrap();
+        builder.set_verify(SslVerifyMode::NONE);
+        builder.build()
+    }
+
+    /// A client letting the local test server through the private host checks.
+    fn target(port: u16) -> TargetHttp {
+        TargetHttp::allowing(&[&format!("localhost:{port}")])
+    }
+
+    #[tokio::test]
+    async fn test_captures_leaf_fingerprint() {
+        let (port, expected) = spawn_tls_server();
+        let url = format!("https://localhost:{port}/page");
+        let cert = capture_tls_certificate_with(&target(port), &url, insecure_connector())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(cert.fingerprint, expected);
+        assert_eq!(cert.chain, vec![expected]);
+    }
+
+    #[tokio::test]
+    async fn test_untrusted_certificate_is_handshake_error() {
+        let (port, _) = spawn_tls_server();
+        let url = format!("https://localhost:{port}/");
+        let err = capture_tls_certificate(&target(port), &url)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, EnclaveError::Upstream(ref msg) if msg.contains("TLS handshake")));
+    }
+
+    #[tokio::test]
+    async fn test_loopback_host_is_refused() {
+        for url in ["https://localhost:8443/", "https://127.0.0.1:8443/"] {
+            let err = capture_tls_certificate_with(&TargetHttp::new(), url, insecure_connector())
+                .await
+                .unwrap_err();
+            assert!(matches!(err, EnclaveError::BadRequest(ref msg) if msg.contains("private")));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_skips_non_https() {
+        assert_eq!(
+            capture_tls_certificate(&TargetHttp::new(), "http://example.com")
+                .await
+                .unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,109 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::common::{read_body_limited, read_json_limited};
+use crate::EnclaveError;
+use serde_json::Value;
+
+/// Number of characters of a non-JSON upstream body quoted in errors.
+const BODY_PREVIEW_CHARS: usize = 200;
+
+/// Parse an upstream response body as JSON. A response that isn't declared as
+/// JSON (e.g. an HTML 502 page from a proxy in front of the upstream) is
+/// reported as an upstream error with its status and a preview of the body,
+/// rather than as a misleading parse failure. At most `max_bytes`
+/// (`MAX_RESPONSE_BYTES`) of the body are read, see [`read_body_limited`].
+pub async fn read_json_response(
+    upstream: &str,
+    response: reqwest::Response,
+    max_bytes: usize,
+) -> Result<Value, EnclaveError> {
+    let status = response.status();
+    let is_json = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_ascii_lowercase().contains("json"))
+        .unwrap_or(false);
+
+    if !is_json {
+        let body = read_body_limited(upstream, response, max_bytes)
+            .await
+            .unwrap_or_default();
+        let body = String::from_utf8_lossy(&body);
+        return Err(EnclaveError::Upstream(format!(
+            "{upstream} returned a non-JSON response with status {status}: {}",
+            body_preview(&body)
+        )));
+    }
+
+    read_json_limited(upstream, response, max_bytes).await
+}
+
+/// Truncate a body to a short single-line preview for error messages.
+fn body_preview(body: &str) -> String {
+    let collapsed = body.split_whitespace().collect::<Vec<_>>().join(" ");
+    if collapsed.chars().count() > BODY_PREVIEW_CHARS {
+        let truncated: String = collapsed.chars().take(BODY_PREVIEW_CHARS).collect();
+        format!("{truncated}...")
+    } else {
+        collapsed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::DEFAULT_MAX_RESPONSE_BYTES;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test]
+    async fn test_html_error_page_is_upstream_error() {
+        let server = MockServer::start().await;
+        let html = format!(
+            "<html><head><title>502 Bad Gateway</title></head><body>{}</body></html>",
+            "x".repeat(500)
+        );
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(502).set_body_raw(html, "text/html"))
+            .mount(&server)
+            .await;
+
+        let response = reqwest::Client::new()
+            .post(server.uri())
+            .send()
+            .await
+            .unwrap();
+        match read_json_response("Scooper", response, DEFAULT_MAX_RESPONSE_BYTES).await {
+            Err(EnclaveError::Upstream(msg)) => {
+                assert!(msg.contains("502"));
+                assert!(msg.contains("<title>502 Bad Gateway</title>"));
+                assert!(msg.len() < 400);
+            }
+            other => panic!("expected upstream error, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_json_response_is_parsed() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(202)
+                    .set_body_json(serde_json::json!({ "jobId": "ABC-1234" })),
+            )
+            .mount(&server)
+            .await;
+
+        let response = reqwest::Client::new()
+            .post(server.uri())
+            .send()
+            .await
+            .unwrap();
+        let json = read_json_response("Scooper", response, DEFAULT_MAX_RESPONSE_BYTES)
+            .await
+            .unwrap();
+        assert_eq!(json["jobId"], "ABC-1234");
+    }
+}
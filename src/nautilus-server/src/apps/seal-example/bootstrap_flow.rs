@@ -0,0 +1,139 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::EnclaveError;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, MutexGuard};
+
+/// How long an `init_parameter_load` blocks other bootstrap attempts when
+/// `complete_parameter_load` doesn't follow, matching the session
+/// certificate TTL. Override with `SEAL_BOOTSTRAP_TIMEOUT_SECS`.
+pub const DEFAULT_BOOTSTRAP_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Session certificate TTL in minutes unless `SEAL_SESSION_TTL_MIN` or the
+/// request's `ttl_min` says otherwise.
+pub const DEFAULT_SESSION_TTL_MIN: u16 = 10;
+
+/// Longest session certificate TTL Seal key servers accept.
+pub const MAX_SESSION_TTL_MIN: u16 = 30;
+
+lazy_static::lazy_static! {
+    /// Held for the duration of each bootstrap call, so calls never race on
+    /// the encryption keys and `SEAL_API_KEY`.
+    static ref BOOTSTRAP: Mutex<BootstrapFlow> = Mutex::new(BootstrapFlow::default());
+}
+
+/// The `init` -> `complete` flow in progress, if any.
+#[derive(Debug, Default)]
+pub struct BootstrapFlow {
+    started: Option<Instant>,
+}
+
+fn in_progress() -> EnclaveError {
+    EnclaveError::GenericError("bootstrap in progress".to_string())
+}
+
+/// Take the bootstrap lock, failing right away if another bootstrap call
+/// holds it.
+pub fn lock_bootstrap() -> Result<MutexGuard<'static, BootstrapFlow>, EnclaveError> {
+    BOOTSTRAP.try_lock().map_err(|_| in_progress())
+}
+
+/// Timeout after which a started flow no longer blocks a new one.
+pub fn bootstrap_timeout() -> Duration {
+    std::env::var("SEAL_BOOTSTRAP_TIMEOUT_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_BOOTSTRAP_TIMEOUT)
+}
+
+/// TTL of the session certificate: `requested`, else `SEAL_SESSION_TTL_MIN`,
+/// else [`DEFAULT_SESSION_TTL_MIN`]. A longer TTL gives a slow host more time
+/// to fetch the keys before `complete_parameter_load`.
+pub fn session_ttl_min(requested: Option<u16>) -> Result<u16, EnclaveError> {
+    let ttl_min = match requested {
+        Some(ttl_min) => ttl_min,
+        None => match std::env::var("SEAL_SESSION_TTL_MIN") {
+            Ok(v) if !v.trim().is_empty() => v.trim().parse().map_err(|e| {
+                EnclaveError::GenericError(format!("Invalid SEAL_SESSION_TTL_MIN: {e}"))
+            })?,
+            _ => DEFAULT_SESSION_TTL_MIN,
+        },
+    };
+    if !(1..=MAX_SESSION_TTL_MIN).contains(&ttl_min) {
+        return Err(EnclaveError::BadRequest(format!(
+            "ttl_min must be 1 to {MAX_SESSION_TTL_MIN} minutes, got {ttl_min}"
+        )));
+    }
+    Ok(ttl_min)
+}
+
+impl BootstrapFlow {
+    /// Fail if a flow started less than `timeout` ago is still waiting for
+    /// its `complete_parameter_load`.
+    pub fn ensure_idle(&self, now: Instant, timeout: Duration) -> Result<(), EnclaveError> {
+        match self.started {
+            Some(started) if now.duration_since(started) < timeout => Err(in_progress()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Record that `init_parameter_load` started a flow at `now`.
+    pub fn start(&mut self, now: Instant) {
+        self.started = Some(now);
+    }
+
+    /// Record that the flow completed.
+    pub fn finish(&mut self) {
+        self.started = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_concurrent_init_is_rejected() {
+        let timeout = Duration::from_secs(600);
+        let now = Instant::now();
+
+        // An init holds the lock while it runs.
+        let mut flow = lock_bootstrap().unwrap();
+        flow.ensure_idle(now, timeout).unwrap();
+        let concurrent = lock_bootstrap().unwrap_err();
+        assert_eq!(concurrent.to_string(), "bootstrap in progress");
+        flow.start(now);
+        drop(flow);
+
+        // Once it returned, a new init is still rejected until the flow
+        // completes or times out.
+        let mut flow = lock_bootstrap().unwrap();
+        assert!(flow
+            .ensure_idle(now + Duration::from_secs(1), timeout)
+            .is_err());
+        assert!(flow.ensure_idle(now + timeout, timeout).is_ok());
+        flow.finish();
+        assert!(flow
+            .ensure_idle(now + Duration::from_secs(1), timeout)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_session_ttl_is_bounded() {
+        assert_eq!(session_ttl_min(Some(25)).unwrap(), 25);
+        assert_eq!(
+            session_ttl_min(Some(MAX_SESSION_TTL_MIN)).unwrap(),
+            MAX_SESSION_TTL_MIN
+        );
+        assert!(matches!(
+            session_ttl_min(Some(0)),
+            Err(EnclaveError::BadRequest(_))
+        ));
+        assert!(matches!(
+            session_ttl_min(Some(MAX_SESSION_TTL_MIN + 1)),
+            Err(EnclaveError::BadRequest(_))
+        ));
+    }
+}
@@ -0,0 +1,275 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::EnclaveError;
+use fastcrypto::encoding::{Encoding, Hex};
+use hmac::{Hmac, Mac};
+use nsm_api::api::{Request as NsmRequest, Response as NsmResponse};
+use nsm_api::driver;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use rand::RngCore;
+use seal_sdk::types::{ElGamalPublicKey, ElgamalVerificationKey};
+use seal_sdk::ElGamalSecretKey;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// Encryption keypair the Seal responses are encrypted to.
+pub type EncryptionKeys = (ElGamalSecretKey, ElGamalPublicKey, ElgamalVerificationKey);
+
+/// Version prefix of the sealed blob, bumped if its layout changes.
+const SEALED_VERSION: u8 = 1;
+const IV_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// Where and with what the pending bootstrap is sealed. Persistence is off
+/// unless both `SEAL_BOOTSTRAP_STATE_PATH` and `SEAL_BOOTSTRAP_SEALING_KEY`
+/// (hex, 32 bytes) are set.
+pub struct SealedStateConfig {
+    pub path: PathBuf,
+    pub sealing_key: [u8; 32],
+}
+
+impl SealedStateConfig {
+    pub fn from_env() -> Result<Option<Self>, EnclaveError> {
+        let (Ok(path), Ok(key)) = (
+            std::env::var("SEAL_BOOTSTRAP_STATE_PATH"),
+            std::env::var("SEAL_BOOTSTRAP_SEALING_KEY"),
+        ) else {
+            return Ok(None);
+        };
+        let sealing_key = Hex::decode(&key)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| {
+                EnclaveError::GenericError(
+                    "SEAL_BOOTSTRAP_SEALING_KEY must be 32 hex encoded bytes".to_string(),
+                )
+            })?;
+        Ok(Some(Self {
+            path: path.into(),
+            sealing_key,
+        }))
+    }
+}
+
+/// Bootstrap state between `init_parameter_load` and
+/// `complete_parameter_load`: the encryption keys, the encoded
+/// `FetchKeyRequest` carrying the session certificate, and the nonce
+/// identifying the pending request.
+#[derive(Deserialize)]
+pub struct PendingBootstrap {
+    pub keys: EncryptionKeys,
+    pub encoded_request: String,
+    pub request_nonce: Vec<u8>,
+}
+
+/// Borrowed form of [`PendingBootstrap`] with the same BCS layout, as the
+/// secret key can't be cloned.
+#[derive(Serialize)]
+struct PendingBootstrapRef<'a> {
+    keys: &'a EncryptionKeys,
+    encoded_request: &'a str,
+    request_nonce: &'a [u8],
+}
+
+/// Measurement the sealed state is tied to: PCR0, PCR1 and PCR2 of the
+/// running enclave image.
+pub fn enclave_measurement() -> Result<Vec<u8>, EnclaveError> {
+    let fd = driver::nsm_init();
+    let mut measurement = Vec::new();
+    for index in 0..3 {
+        match driver::nsm_process_request(fd, NsmRequest::DescribePCR { index }) {
+            NsmResponse::DescribePCR { data, .. } => measurement.extend_from_slice(&data),
+            _ => {
+                driver::nsm_exit(fd);
+                return Err(EnclaveError::GenericError(format!(
+                    "Failed to read PCR{index}"
+                )));
+            }
+        }
+    }
+    driver::nsm_exit(fd);
+    Ok(measurement)
+}
+
+/// Sealing key for one measurement, so state sealed by one enclave image
+/// can't be opened by another.
+fn derive_key(sealing_key: &[u8; 32], measurement: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(sealing_key).expect("HMAC accepts any key length");
+    mac.update(b"NAUTILUS_SEAL_BOOTSTRAP");
+    mac.update(measurement);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Encrypt the pending bootstrap with AES-256-GCM under a key derived from
+/// the sealing key and the measurement, which is also authenticated as
+/// associated data. Layout: version || iv || tag || ciphertext.
+pub fn seal(
+    keys: &EncryptionKeys,
+    encoded_request: &str,
+    request_nonce: &[u8],
+    sealing_key: &[u8; 32],
+    measurement: &[u8],
+) -> Result<Vec<u8>, EnclaveError> {
+    let plaintext = bcs::to_bytes(&PendingBootstrapRef {
+        keys,
+        encoded_request,
+        request_nonce,
+    })
+    .expect("should not fail");
+    let mut iv = [0u8; IV_LEN];
+    rand::thread_rng().fill_bytes(&mut iv);
+    let mut tag = [0u8; TAG_LEN];
+    let ciphertext = encrypt_aead(
+        Cipher::aes_256_gcm(),
+        &derive_key(sealing_key, measurement),
+        Some(&iv),
+        measurement,
+        &plaintext,
+        &mut tag,
+    )
+    .map_err(|e| EnclaveError::GenericError(format!("Failed to seal bootstrap state: {e}")))?;
+
+    let mut sealed = vec![SEALED_VERSION];
+    sealed.extend_from_slice(&iv);
+    sealed.extend_from_slice(&tag);
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+/// Open a blob produced by [`seal`]. Fails if it was sealed with another
+/// key or by an enclave with another measurement.
+pub fn unseal(
+    sealed: &[u8],
+    sealing_key: &[u8; 32],
+    measurement: &[u8],
+) -> Result<PendingBootstrap, EnclaveError> {
+    let Some((&SEALED_VERSION, rest)) = sealed.split_first() else {
+        return Err(EnclaveError::GenericError(
+            "Unknown sealed bootstrap state version".to_string(),
+        ));
+    };
+    if rest.len() < IV_LEN + TAG_LEN {
+        return Err(EnclaveError::GenericError(
+            "Sealed bootstrap state is truncated".to_string(),
+        ));
+    }
+    let (iv, rest) = rest.split_at(IV_LEN);
+    let (tag, ciphertext) = rest.split_at(TAG_LEN);
+    let plaintext = decrypt_aead(
+        Cipher::aes_256_gcm(),
+        &derive_key(sealing_key, measurement),
+        Some(iv),
+        measurement,
+        ciphertext,
+        tag,
+    )
+    .map_err(|_| {
+        EnclaveError::GenericError(
+            "Failed to unseal bootstrap state, sealed by another key or enclave image".to_string(),
+        )
+    })?;
+    bcs::from_bytes(&plaintext)
+        .map_err(|e| EnclaveError::GenericError(format!("Invalid sealed bootstrap state: {e}")))
+}
+
+/// Seal the pending bootstrap to `path`, replacing any previous one.
+pub fn save_pending(
+    path: &Path,
+    keys: &EncryptionKeys,
+    encoded_request: &str,
+    request_nonce: &[u8],
+    sealing_key: &[u8; 32],
+    measurement: &[u8],
+) -> Result<(), EnclaveError> {
+    let sealed = seal(
+        keys,
+        encoded_request,
+        request_nonce,
+        sealing_key,
+        measurement,
+    )?;
+    std::fs::write(path, sealed).map_err(|e| {
+        EnclaveError::GenericError(format!("Failed to write {}: {e}", path.display()))
+    })?;
+    info!("Sealed pending bootstrap to {}", path.display());
+    Ok(())
+}
+
+/// Load the pending bootstrap sealed at `path`, `None` if there is none.
+pub fn load_pending(
+    path: &Path,
+    sealing_key: &[u8; 32],
+    measurement: &[u8],
+) -> Result<Option<PendingBootstrap>, EnclaveError> {
+    match std::fs::read(path) {
+        Ok(sealed) => unseal(&sealed, sealing_key, measurement).map(Some),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(EnclaveError::GenericError(format!(
+            "Failed to read {}: {e}",
+            path.display()
+        ))),
+    }
+}
+
+/// Remove the sealed state once the bootstrap completed, so the keys don't
+/// outlive it.
+pub fn clear_pending(path: &Path) -> Result<(), EnclaveError> {
+    match std::fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(EnclaveError::GenericError(format!(
+            "Failed to remove {}: {e}",
+            path.display()
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::thread_rng;
+    use seal_sdk::genkey;
+
+    const SEALING_KEY: [u8; 32] = [9; 32];
+    const MEASUREMENT: &[u8] = &[1; 144];
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("{name}-{}", uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    fn test_restart_resumes_bootstrap() {
+        let path = temp_path("seal-bootstrap");
+        let keys = genkey(&mut thread_rng());
+        save_pending(&path, &keys, "00aa", b"nonce", &SEALING_KEY, MEASUREMENT).unwrap();
+
+        // The process restarts: the in-memory keys are gone, the sealed file stays.
+        let expected = bcs::to_bytes(&keys).unwrap();
+        drop(keys);
+        let resumed = load_pending(&path, &SEALING_KEY, MEASUREMENT)
+            .unwrap()
+            .unwrap();
+        assert_eq!(bcs::to_bytes(&resumed.keys).unwrap(), expected);
+        assert_eq!(resumed.encoded_request, "00aa");
+        assert_eq!(resumed.request_nonce, b"nonce");
+
+        // Completing the bootstrap removes the sealed state.
+        clear_pending(&path).unwrap();
+        assert!(load_pending(&path, &SEALING_KEY, MEASUREMENT)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_other_measurement_cannot_unseal() {
+        let keys = genkey(&mut thread_rng());
+        let sealed = seal(&keys, "00aa", b"nonce", &SEALING_KEY, MEASUREMENT).unwrap();
+        assert!(unseal(&sealed, &SEALING_KEY, &[2; 144]).is_err());
+        assert!(unseal(&sealed, &[8; 32], MEASUREMENT).is_err());
+        assert!(unseal(&sealed[..10], &SEALING_KEY, MEASUREMENT).is_err());
+        assert!(unseal(&sealed, &SEALING_KEY, MEASUREMENT).is_ok());
+    }
+}
@@ -1,26 +1,32 @@
 // Copyright (c), Mysten Labs, Inc.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::collections::HashMap;
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 use axum::extract::State;
 use axum::Json;
 use fastcrypto::ed25519::Ed25519KeyPair;
 use fastcrypto::encoding::{Base64, Encoding, Hex};
 use fastcrypto::traits::{KeyPair, Signer};
-use rand::thread_rng;
+use rand::{thread_rng, RngCore};
 use seal_sdk::types::{FetchKeyRequest, KeyId};
-use seal_sdk::{
-    genkey, seal_decrypt_all_objects, signed_message, signed_request, Certificate, ElGamalSecretKey,
-};
+use seal_sdk::{genkey, seal_decrypt_all_objects, signed_message, signed_request, Certificate};
 use sui_sdk_types::{
     Address as ObjectID, Argument, Command, Identifier, Input, MoveCall, PersonalMessage,
     ProgrammableTransaction,
 };
 use tokio::sync::RwLock;
+use tracing::info;
 
+use super::bootstrap_flow::{bootstrap_timeout, lock_bootstrap, session_ttl_min};
+use super::bootstrap_state::{
+    clear_pending, enclave_measurement, load_pending, save_pending, EncryptionKeys,
+    SealedStateConfig,
+};
 use super::types::*;
+use crate::api_keys::ApiKeyPool;
 use crate::{AppState, EnclaveError};
 
 lazy_static::lazy_static! {
@@ -32,15 +38,15 @@ lazy_static::lazy_static! {
         serde_yaml::from_str(config_str)
             .expect("Failed to parse seal_config.yaml")
     };
-    /// Encryption secret key generated initialized on startup.
-    pub static ref ENCRYPTION_KEYS: (ElGamalSecretKey, seal_sdk::types::ElGamalPublicKey, seal_sdk::types::ElgamalVerificationKey) = {
-        genkey(&mut thread_rng())
-    };
+    /// Encryption secret key generated on startup, or resumed from the
+    /// sealed bootstrap state, see [`resume_pending_bootstrap`].
+    pub static ref ENCRYPTION_KEYS: RwLock<EncryptionKeys> = RwLock::new(genkey(&mut thread_rng()));
 
     /// Secret plaintext decrypted and set in enclave here when
-    /// /complete_parameter_load finishes. This is the weather
-    /// API key in this example, change it for your application.
-    pub static ref SEAL_API_KEY: Arc<RwLock<Option<String>>> = Arc::new(RwLock::new(None));
+    /// /complete_parameter_load finishes. These are the weather
+    /// API keys in this example (comma-separated in the secret,
+    /// rotated per request), change it for your application.
+    pub static ref SEAL_API_KEY: Arc<RwLock<Option<ApiKeyPool>>> = Arc::new(RwLock::new(None));
 }
 
 /// This endpoint takes an enclave obj id with initial shared version
@@ -48,7 +54,9 @@ lazy_static::lazy_static! {
 /// uses state's ephemeral key to sign the personal message. Returns
 /// a Hex encoded BCS serialized FetchKeyRequest containing the certificate
 /// and the desired ptb for seal_approve. This is the first step for
-/// the bootstrap phase.
+/// the bootstrap phase. Only one bootstrap runs at a time: a concurrent call,
+/// or an init while an earlier one awaits its complete_parameter_load, fails
+/// with "bootstrap in progress".
 pub async fn init_parameter_load(
     State(state): State<Arc<AppState>>,
     Json(request): Json<InitParameterLoadRequest>,
@@ -58,6 +66,10 @@ pub async fn init_parameter_load(
             "API key already set".to_string(),
         ));
     }
+    let ttl_min = session_ttl_min(request.ttl_min)?;
+    let mut flow = lock_bootstrap()?;
+    flow.ensure_idle(Instant::now(), bootstrap_timeout())?;
+
     // Generate the session and create certificate.
     let session = Ed25519KeyPair::generate(&mut thread_rng());
     let session_vk = session.public();
@@ -65,7 +77,6 @@ pub async fn init_parameter_load(
         .duration_since(UNIX_EPOCH)
         .map_err(|e| EnclaveError::GenericError(format!("Time error: {e}")))?
         .as_millis() as u64;
-    let ttl_min = 10;
     let message = signed_message(
         SEAL_CONFIG.package_id.to_string(),
         session_vk,
@@ -75,7 +86,10 @@ pub async fn init_parameter_load(
 
     // Convert fastcrypto keypair to sui-crypto for signing.
     let sui_private_key = {
-        let priv_key_bytes = state.eph_kp.as_ref();
+        let eph_kp = state.eph_kp.as_ed25519().ok_or_else(|| {
+            EnclaveError::GenericError("seal bootstrap requires an ed25519 KEY_SCHEME".to_string())
+        })?;
+        let priv_key_bytes = eph_kp.as_ref();
         let key_bytes: [u8; 32] = priv_key_bytes
             .try_into()
             .expect("Invalid private key length");
@@ -113,7 +127,8 @@ pub async fn init_parameter_load(
     .map_err(|e| EnclaveError::GenericError(format!("Failed to create PTB: {e}")))?;
 
     // Load the encryption public key and verification key.
-    let (_enc_secret, enc_key, enc_verification_key) = &*ENCRYPTION_KEYS;
+    let keys = ENCRYPTION_KEYS.read().await;
+    let (_enc_secret, enc_key, enc_verification_key) = &*keys;
 
     // Create the FetchKeyRequest.
     let request_message = signed_request(&ptb, enc_key, enc_verification_key);
@@ -126,19 +141,43 @@ pub async fn init_parameter_load(
         certificate,
     };
 
+    let encoded_request = Hex::encode(bcs::to_bytes(&request).expect("should not fail"));
+
+    // Seal the keys so a restart before complete_parameter_load can resume.
+    if let Some(config) = SealedStateConfig::from_env()? {
+        let mut request_nonce = [0u8; 16];
+        thread_rng().fill_bytes(&mut request_nonce);
+        save_pending(
+            &config.path,
+            &keys,
+            &encoded_request,
+            &request_nonce,
+            &config.sealing_key,
+            &enclave_measurement()?,
+        )?;
+    }
+
+    flow.start(Instant::now());
     Ok(Json(InitParameterLoadResponse {
-        encoded_request: Hex::encode(bcs::to_bytes(&request).expect("should not fail")),
+        encoded_request,
+        creation_time,
+        ttl_min,
+        expires_at: creation_time + u64::from(ttl_min) * 60_000,
     }))
 }
 
+/// Name of the manifest secret `SEAL_API_KEY` is loaded from.
+pub const API_KEY_SECRET: &str = "api_key";
+
 /// This endpoint accepts a list of encrypted objects and encoded seal responses,
 /// It parses the seal responses for all IDs and decrypt all encrypted objects
 /// with the encryption secret key. If all encrypted objects are decrypted, initialize
 /// the SEAL_API_KEY with the first secret and return the dummy secrets in the response.
-/// Remove dummy secrets for your app. This is done after the Seal responses are fetched
-/// and to complete the bootstrap phase.
+/// Remove dummy secrets for your app. With a `manifest`, every secret is instead stored
+/// in `AppState::secrets` under its name, and SEAL_API_KEY is the one named `api_key`.
+/// This is done after the Seal responses are fetched and to complete the bootstrap phase.
 pub async fn complete_parameter_load(
-    State(_state): State<Arc<AppState>>,
+    State(state): State<Arc<AppState>>,
     Json(request): Json<CompleteParameterLoadRequest>,
 ) -> Result<Json<CompleteParameterLoadResponse>, EnclaveError> {
     if SEAL_API_KEY.read().await.is_some() {
@@ -146,9 +185,11 @@ pub async fn complete_parameter_load(
             "API key already set".to_string(),
         ));
     }
+    let mut flow = lock_bootstrap()?;
 
     // Load the encryption secret key and try decrypting all encrypted objects.
-    let (enc_secret, _enc_key, _enc_verification_key) = &*ENCRYPTION_KEYS;
+    let keys = ENCRYPTION_KEYS.read().await;
+    let (enc_secret, _enc_key, _enc_verification_key) = &*keys;
     let decrypted_results = seal_decrypt_all_objects(
         enc_secret,
         &request.seal_responses,
@@ -156,25 +197,161 @@ pub async fn complete_parameter_load(
         &SEAL_CONFIG.server_pk_map,
     )
     .map_err(|e| EnclaveError::GenericError(format!("Failed to decrypt objects: {e}")))?;
+    check_all_decrypted(request.encrypted_objects.len(), &decrypted_results)?;
+
+    let response = if request.manifest.is_empty() {
+        // The first secret is the weather API key, store it.
+        if let Some(api_key_bytes) = decrypted_results.first() {
+            let api_key_str = String::from_utf8(api_key_bytes.clone())
+                .map_err(|e| EnclaveError::GenericError(format!("Invalid UTF-8 in secret: {e}")))?;
 
-    // The first secret is the weather API key, store it.
-    if let Some(api_key_bytes) = decrypted_results.first() {
-        let api_key_str = String::from_utf8(api_key_bytes.clone())
-            .map_err(|e| EnclaveError::GenericError(format!("Invalid UTF-8 in secret: {e}")))?;
+            let mut api_key_guard = (*SEAL_API_KEY).write().await;
+            *api_key_guard = Some(ApiKeyPool::parse(&api_key_str));
+        } else {
+            return Err(EnclaveError::GenericError(
+                "No secrets were decrypted".to_string(),
+            ));
+        }
 
-        let mut api_key_guard = (*SEAL_API_KEY).write().await;
-        *api_key_guard = Some(api_key_str.clone());
+        // Return the rest of decrypted secrets as an example,
+        // remove for your app as needed.
+        CompleteParameterLoadResponse {
+            dummy_secrets: decrypted_results[1..].to_vec(),
+            loaded: vec![],
+        }
     } else {
-        return Err(EnclaveError::GenericError(
-            "No secrets were decrypted".to_string(),
+        let ids: Vec<KeyId> = request
+            .encrypted_objects
+            .iter()
+            .map(|object| object.id.clone())
+            .collect();
+        let secrets = name_secrets(&ids, &decrypted_results, &request.manifest)?;
+        let mut loaded: Vec<String> = secrets.keys().cloned().collect();
+        loaded.sort();
+        *SEAL_API_KEY.write().await = Some(ApiKeyPool::parse(&secrets[API_KEY_SECRET]));
+        state.secrets.write().await.extend(secrets);
+        CompleteParameterLoadResponse {
+            dummy_secrets: vec![],
+            loaded,
+        }
+    };
+
+    // The bootstrap is done, the sealed keys are no longer needed.
+    if let Some(config) = SealedStateConfig::from_env()? {
+        clear_pending(&config.path)?;
+    }
+    flow.finish();
+    Ok(Json(response))
+}
+
+/// Fail unless there is a decrypted secret for each of the `expected`
+/// encrypted objects, naming the indices of those missing, so the enclave is
+/// never initialized with a partially loaded secret.
+fn check_all_decrypted(expected: usize, decrypted: &[Vec<u8>]) -> Result<(), EnclaveError> {
+    if decrypted.len() == expected {
+        return Ok(());
+    }
+    let missing: Vec<String> = (decrypted.len()..expected).map(|i| i.to_string()).collect();
+    Err(EnclaveError::GenericError(format!(
+        "Decrypted {} of {} objects, failed indices: [{}]",
+        decrypted.len(),
+        expected,
+        missing.join(", ")
+    )))
+}
+
+/// Pair each decrypted secret with its name in `manifest`, by the key id of
+/// the object it was decrypted from. Every object must be named, names must
+/// be unique and include [`API_KEY_SECRET`], and values must be UTF-8.
+fn name_secrets(
+    ids: &[KeyId],
+    decrypted: &[Vec<u8>],
+    manifest: &[NamedSecret],
+) -> Result<HashMap<String, String>, EnclaveError> {
+    let mut secrets = HashMap::new();
+    for (id, bytes) in ids.iter().zip(decrypted) {
+        let name = &manifest
+            .iter()
+            .find(|secret| &secret.id == id)
+            .ok_or_else(|| {
+                EnclaveError::BadRequest(format!("no manifest name for key id {}", Hex::encode(id)))
+            })?
+            .name;
+        let value = String::from_utf8(bytes.clone()).map_err(|e| {
+            EnclaveError::GenericError(format!("Invalid UTF-8 in secret {name}: {e}"))
+        })?;
+        if secrets.insert(name.clone(), value).is_some() {
+            return Err(EnclaveError::BadRequest(format!(
+                "manifest names more than one secret {name}"
+            )));
+        }
+    }
+    if !secrets.contains_key(API_KEY_SECRET) {
+        return Err(EnclaveError::BadRequest(format!(
+            "manifest must name an {API_KEY_SECRET} secret"
+        )));
+    }
+    Ok(secrets)
+}
+
+/// Clear `SEAL_API_KEY` so a rotated secret can be loaded with a fresh
+/// `init_parameter_load` and `complete_parameter_load`, without restarting the
+/// enclave and losing its attested key. Named secrets are cleared too. The encryption keys are regenerated,
+/// so key shares fetched for an earlier request no longer decrypt, and any
+/// pending bootstrap is dropped. Requires `confirmation_token` to equal
+/// `SEAL_RELOAD_TOKEN`, and is disabled when that is unset. The old secret is
+/// never returned.
+pub async fn reload_parameter(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<ReloadParameterRequest>,
+) -> Result<Json<ReloadParameterResponse>, EnclaveError> {
+    let expected = std::env::var("SEAL_RELOAD_TOKEN").ok();
+    check_reload_token(expected.as_deref(), &request.confirmation_token)?;
+    let mut flow = lock_bootstrap()?;
+
+    let mut secrets = state.secrets.write().await;
+    let cleared = SEAL_API_KEY.write().await.take().is_some() || !secrets.is_empty();
+    secrets.clear();
+    *ENCRYPTION_KEYS.write().await = genkey(&mut thread_rng());
+    if let Some(config) = SealedStateConfig::from_env()? {
+        clear_pending(&config.path)?;
+    }
+    flow.finish();
+    info!("Seal parameters cleared for reload");
+    Ok(Json(ReloadParameterResponse { cleared }))
+}
+
+/// Fail unless reloads are enabled and `provided` is the configured token.
+fn check_reload_token(expected: Option<&str>, provided: &str) -> Result<(), EnclaveError> {
+    let expected = expected.filter(|token| !token.is_empty()).ok_or_else(|| {
+        EnclaveError::Unauthorized("reload is disabled, set SEAL_RELOAD_TOKEN".to_string())
+    })?;
+    if provided != expected {
+        return Err(EnclaveError::Unauthorized(
+            "invalid confirmation_token".to_string(),
         ));
     }
+    Ok(())
+}
 
-    // Return the rest of decrypted secrets as an example,
-    // remove for your app as needed.
-    Ok(Json(CompleteParameterLoadResponse {
-        dummy_secrets: decrypted_results[1..].to_vec(),
-    }))
+/// Restore the encryption keys of a bootstrap sealed before a restart, so
+/// `complete_parameter_load` can decrypt the Seal responses fetched for the
+/// pending request. Does nothing unless sealed state is configured.
+pub async fn resume_pending_bootstrap() -> Result<(), EnclaveError> {
+    let Some(config) = SealedStateConfig::from_env()? else {
+        return Ok(());
+    };
+    if let Some(pending) = load_pending(&config.path, &config.sealing_key, &enclave_measurement()?)?
+    {
+        info!(
+            "Resuming pending bootstrap {}",
+            Hex::encode(&pending.request_nonce)
+        );
+        *ENCRYPTION_KEYS.write().await = pending.keys;
+        // Keep a new init from replacing the resumed keys.
+        lock_bootstrap()?.start(Instant::now());
+    }
+    Ok(())
 }
 
 /// Helper function that creates a PTB with multiple commands for
@@ -220,3 +397,69 @@ async fn create_ptb(
     }
     Ok(ProgrammableTransaction { inputs, commands })
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn named(id: &[u8], name: &str) -> NamedSecret {
+        NamedSecret {
+            id: id.to_vec(),
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_secrets_are_named_by_key_id() {
+        let ids = [vec![0, 0], vec![0, 1]];
+        let decrypted = [b"scooper".to_vec(), b"weather".to_vec()];
+        let manifest = [named(&[0, 1], "api_key"), named(&[0, 0], "scooper_secret")];
+        let secrets = name_secrets(&ids, &decrypted, &manifest).unwrap();
+        assert_eq!(secrets["api_key"], "weather");
+        assert_eq!(secrets["scooper_secret"], "scooper");
+
+        let unnamed = [named(&[0, 1], "api_key")];
+        assert!(matches!(
+            name_secrets(&ids, &decrypted, &unnamed),
+            Err(EnclaveError::BadRequest(_))
+        ));
+        let twice = [named(&[0, 0], "api_key"), named(&[0, 1], "api_key")];
+        assert!(matches!(
+            name_secrets(&ids, &decrypted, &twice),
+            Err(EnclaveError::BadRequest(_))
+        ));
+        let no_api_key = [named(&[0, 0], "a"), named(&[0, 1], "b")];
+        assert!(matches!(
+            name_secrets(&ids, &decrypted, &no_api_key),
+            Err(EnclaveError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn test_partial_decryption_is_rejected() {
+        let decrypted = [b"weather".to_vec()];
+        assert!(check_all_decrypted(1, &decrypted).is_ok());
+        assert_eq!(
+            check_all_decrypted(3, &decrypted).unwrap_err().to_string(),
+            "Decrypted 1 of 3 objects, failed indices: [1, 2]"
+        );
+        assert!(check_all_decrypted(1, &[]).is_err());
+    }
+
+    #[test]
+    fn test_reload_requires_the_token() {
+        assert!(check_reload_token(Some("rotate-2025"), "rotate-2025").is_ok());
+        assert!(matches!(
+            check_reload_token(Some("rotate-2025"), "rotate"),
+            Err(EnclaveError::Unauthorized(_))
+        ));
+        assert!(matches!(
+            check_reload_token(None, ""),
+            Err(EnclaveError::Unauthorized(_))
+        ));
+        assert!(matches!(
+            check_reload_token(Some(""), ""),
+            Err(EnclaveError::Unauthorized(_))
+        ));
+    }
+}
@@ -1,21 +1,28 @@
 // Copyright (c), Mysten Labs, Inc.
 // SPDX-License-Identifier: Apache-2.0
 
+pub mod bootstrap_flow;
+pub mod bootstrap_state;
 pub mod endpoints;
 pub mod types;
 
-pub use endpoints::{complete_parameter_load, init_parameter_load};
+pub use endpoints::{
+    complete_parameter_load, init_parameter_load, reload_parameter, resume_pending_bootstrap,
+};
 pub use types::*;
 
+use crate::api_keys::send_with_key_rotation;
 use crate::app::endpoints::SEAL_API_KEY;
+use crate::common::DependencyStatus;
 use crate::common::IntentMessage;
-use crate::common::{to_signed_response, IntentScope, ProcessDataRequest, ProcessedDataResponse};
+use crate::common::{read_json_limited, DEFAULT_MAX_RESPONSE_BYTES};
+use crate::common::{to_signed_response, ProcessDataRequest, ProcessedDataResponse};
 use crate::AppState;
 use crate::EnclaveError;
 use axum::extract::State;
 use axum::Json;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use std::collections::BTreeMap;
 use std::sync::Arc;
 use tracing::info;
 /// Inner type T for IntentMessage<T>
@@ -25,6 +32,30 @@ pub struct WeatherResponse {
     pub temperature: u64,
 }
 
+/// Prerequisites `/ready` waits for: the API key, loaded once the Seal
+/// bootstrap completed.
+pub async fn missing_prerequisites(_state: &AppState) -> Vec<String> {
+    if SEAL_API_KEY.read().await.is_none() {
+        vec!["SEAL_API_KEY".to_string()]
+    } else {
+        vec![]
+    }
+}
+
+/// Dependencies the deep health check reports: whether the Seal bootstrap
+/// loaded `SEAL_API_KEY`.
+pub async fn dependency_checks(_state: &AppState) -> BTreeMap<String, DependencyStatus> {
+    let seal_api_key = if SEAL_API_KEY.read().await.is_some() {
+        DependencyStatus::up()
+    } else {
+        DependencyStatus::down("complete the Seal bootstrap to load it")
+    };
+    BTreeMap::from([("seal_api_key".to_string(), seal_api_key)])
+}
+
+/// Payload `process_data` signs, checked by `/verify_signature`.
+pub type SignedPayload = WeatherResponse;
+
 /// Inner type T for ProcessDataRequest<T>
 #[derive(Debug, Serialize, Deserialize)]
 pub struct WeatherRequest {
@@ -37,34 +68,33 @@ pub async fn process_data(
 ) -> Result<Json<ProcessedDataResponse<IntentMessage<WeatherResponse>>>, EnclaveError> {
     // API key loaded from what was set during bootstrap.
     let api_key_guard = SEAL_API_KEY.read().await;
-    let api_key = api_key_guard.as_ref().ok_or_else(|| {
+    let api_keys = api_key_guard.as_ref().ok_or_else(|| {
         EnclaveError::GenericError(
             "API key not initialized. Please complete parameter load first.".to_string(),
         )
     })?;
 
-    let url = format!(
-        "https://api.weatherapi.com/v1/current.json?key={}&q={}",
-        api_key, request.payload.location
-    );
-    let response = reqwest::get(url.clone())
-        .await
-        .map_err(|e| EnclaveError::GenericError(format!("Failed to get weather response: {e}")))?;
-    let json = response.json::<Value>().await.map_err(|e| {
-        EnclaveError::GenericError(format!("Failed to parse weather response: {e}"))
-    })?;
+    let response = send_with_key_rotation(api_keys, "Failed to get weather response", |key| {
+        let url = format!(
+            "https://api.weatherapi.com/v1/current.json?key={}&q={}",
+            key, request.payload.location
+        );
+        state.http.get(url).send()
+    })
+    .await?;
+    let json = read_json_limited("weather", response, DEFAULT_MAX_RESPONSE_BYTES).await?;
     let location = json["location"]["name"].as_str().unwrap_or("Unknown");
     let temperature = json["current"]["temp_c"].as_f64().unwrap_or(0.0) as u64;
     let last_updated_epoch = json["current"]["last_updated_epoch"].as_u64().unwrap_or(0);
     let last_updated_timestamp_ms = last_updated_epoch * 1000_u64;
     let current_timestamp = std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
-        .map_err(|e| EnclaveError::GenericError(format!("Failed to get current timestamp: {e}")))?
+        .map_err(|e| EnclaveError::Internal(format!("Failed to get current timestamp: {e}")))?
         .as_millis() as u64;
 
     // 1 hour in milliseconds = 60 * 60 * 1000 = 3_600_000
     if last_updated_timestamp_ms + 3_600_000 < current_timestamp {
-        return Err(EnclaveError::GenericError(
+        return Err(EnclaveError::Upstream(
             "Weather API timestamp is too old".to_string(),
         ));
     }
@@ -76,15 +106,17 @@ pub async fn process_data(
             temperature,
         },
         last_updated_timestamp_ms,
-        IntentScope::ProcessData,
+        state.intent_scope,
     )))
 }
 
+use crate::common::{listen_addr, DEFAULT_HOST_INIT_PORT};
 /// Host-only init functionality
 use axum::{
     routing::{get, post},
     Router,
 };
+use std::net::SocketAddr;
 use tokio::net::TcpListener;
 
 /// Response for the ping endpoint
@@ -101,8 +133,12 @@ pub async fn ping() -> Json<PingResponse> {
     })
 }
 
-/// Spawn a separate server on localhost:3001 for host-only bootstrap access.
-pub async fn spawn_host_init_server(state: Arc<AppState>) -> Result<(), EnclaveError> {
+/// Spawn a separate server on port 3001 (or `HOST_INIT_PORT`, see
+/// [`listen_addr`]) for host-only bootstrap access. Returns the address the
+/// server is bound to.
+pub async fn spawn_host_init_server(state: Arc<AppState>) -> Result<SocketAddr, EnclaveError> {
+    resume_pending_bootstrap().await?;
+
     let host_app = Router::new()
         .route("/ping", get(ping))
         .route("/seal/init_parameter_load", post(init_parameter_load))
@@ -110,16 +146,17 @@ pub async fn spawn_host_init_server(state: Arc<AppState>) -> Result<(), EnclaveE
             "/seal/complete_parameter_load",
             post(complete_parameter_load),
         )
+        .route("/seal/reload_parameter", post(reload_parameter))
         .with_state(state);
 
-    let host_listener = TcpListener::bind("0.0.0.0:3001")
+    let host_listener = TcpListener::bind(listen_addr("HOST_INIT_PORT", DEFAULT_HOST_INIT_PORT)?)
         .await
         .map_err(|e| EnclaveError::GenericError(format!("Failed to bind host init server: {e}")))?;
 
-    info!(
-        "Host-only init server listening on {}",
-        host_listener.local_addr().unwrap()
-    );
+    let host_addr = host_listener
+        .local_addr()
+        .map_err(|e| EnclaveError::GenericError(format!("Failed to get host init address: {e}")))?;
+    info!("Host-only init server listening on {}", host_addr);
 
     tokio::spawn(async move {
         axum::serve(host_listener, host_app.into_make_service())
@@ -127,13 +164,13 @@ pub async fn spawn_host_init_server(state: Arc<AppState>) -> Result<(), EnclaveE
             .expect("Host init server failed");
     });
 
-    Ok(())
+    Ok(host_addr)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::common::IntentMessage;
+    use crate::common::{IntentMessage, IntentScope};
 
     #[test]
     fn test_serde() {
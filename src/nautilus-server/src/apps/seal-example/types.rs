@@ -22,6 +22,15 @@ where
         .collect()
 }
 
+/// Custom deserializer for hex string to Vec<u8>
+fn deserialize_hex<'de, D>(deserializer: D) -> Result<KeyId, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s: String = String::deserialize(deserializer)?;
+    Hex::decode(&s).map_err(serde::de::Error::custom)
+}
+
 /// Custom deserializer for hex string to ObjectID
 fn deserialize_object_id<'de, D>(deserializer: D) -> Result<ObjectID, D::Error>
 where
@@ -145,12 +154,31 @@ pub struct InitParameterLoadRequest {
     pub initial_shared_version: u64,
     #[serde(deserialize_with = "deserialize_hex_vec")]
     pub ids: Vec<KeyId>, // all ids for all encrypted objects (hex strings -> Vec<u8>)
+    /// Session certificate TTL in minutes, up to 30. Defaults to
+    /// `SEAL_SESSION_TTL_MIN`, or 10.
+    #[serde(default)]
+    pub ttl_min: Option<u16>,
 }
 
 /// Response for /init_parameter_load
 #[derive(Serialize, Deserialize)]
 pub struct InitParameterLoadResponse {
     pub encoded_request: String,
+    /// Unix time in milliseconds the session certificate was created at.
+    pub creation_time: u64,
+    pub ttl_min: u16,
+    /// Unix time in milliseconds after which key servers refuse the request,
+    /// so `complete_parameter_load` must get the responses fetched before it.
+    pub expires_at: u64,
+}
+
+/// Name a decrypted secret is stored under, by the key id it was encrypted
+/// with.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NamedSecret {
+    #[serde(deserialize_with = "deserialize_hex")]
+    pub id: KeyId,
+    pub name: String,
 }
 
 /// Request for /complete_parameter_load
@@ -160,6 +188,25 @@ pub struct CompleteParameterLoadRequest {
     pub encrypted_objects: Vec<EncryptedObject>,
     #[serde(deserialize_with = "deserialize_seal_responses")]
     pub seal_responses: Vec<(ObjectID, FetchKeyResponse)>,
+    /// Names of the secrets by key id. When set, every decrypted object is
+    /// stored under its name in `AppState::secrets`, instead of the first
+    /// being the API key and the rest dummy secrets.
+    #[serde(default)]
+    pub manifest: Vec<NamedSecret>,
+}
+
+/// Request for /reload_parameter
+#[derive(Serialize, Deserialize)]
+pub struct ReloadParameterRequest {
+    /// Must equal `SEAL_RELOAD_TOKEN`.
+    pub confirmation_token: String,
+}
+
+/// Response for /reload_parameter
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReloadParameterResponse {
+    /// Whether a secret was loaded before the reload.
+    pub cleared: bool,
 }
 
 /// Response for /complete_parameter_load, for demo on decrypting many secrets.
@@ -167,4 +214,7 @@ pub struct CompleteParameterLoadRequest {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CompleteParameterLoadResponse {
     pub dummy_secrets: Vec<Vec<u8>>,
+    /// Names of the secrets loaded from the manifest, never their values.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub loaded: Vec<String>,
 }
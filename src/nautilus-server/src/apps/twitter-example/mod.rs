@@ -1,8 +1,10 @@
 // Copyright (c), Mysten Labs, Inc.
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::common::DependencyStatus;
 use crate::common::IntentMessage;
-use crate::common::{to_signed_response, IntentScope, ProcessDataRequest, ProcessedDataResponse};
+use crate::common::{read_json_limited, DEFAULT_MAX_RESPONSE_BYTES};
+use crate::common::{to_signed_response, ProcessDataRequest, ProcessedDataResponse};
 use crate::AppState;
 use crate::EnclaveError;
 use axum::extract::State;
@@ -10,6 +12,7 @@ use axum::Json;
 use fastcrypto::encoding::{Encoding, Hex};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::sync::Arc;
 use tracing::info;
 /// ====
@@ -23,6 +26,42 @@ pub struct UserData {
     pub sui_address: Vec<u8>,
 }
 
+/// Prerequisites `/ready` waits for: an API key.
+pub async fn missing_prerequisites(state: &AppState) -> Vec<String> {
+    if state.api_keys.is_empty() {
+        vec!["API_KEY".to_string()]
+    } else {
+        vec![]
+    }
+}
+
+/// Dependencies the deep health check reports: whether the API keys are
+/// configured.
+pub async fn dependency_checks(state: &AppState) -> BTreeMap<String, DependencyStatus> {
+    let api_key = if state.api_keys.is_empty() {
+        DependencyStatus::down("API_KEY not set")
+    } else {
+        DependencyStatus::up()
+    };
+    BTreeMap::from([("api_key".to_string(), api_key)])
+}
+
+/// Payload `process_data` signs, checked by `/verify_signature`.
+pub type SignedPayload = UserData;
+
+/// Tag the Sui address must come before in the tweet or profile description,
+/// unless overridden with `TWITTER_HASHTAG` (e.g. `#WALRUS`).
+pub const DEFAULT_HASHTAG: &str = "#SUI";
+
+/// The configured `TWITTER_HASHTAG`, or [`DEFAULT_HASHTAG`] when unset.
+pub fn configured_hashtag() -> String {
+    std::env::var("TWITTER_HASHTAG")
+        .ok()
+        .map(|tag| tag.trim().to_string())
+        .filter(|tag| !tag.is_empty())
+        .unwrap_or_else(|| DEFAULT_HASHTAG.to_string())
+}
+
 /// Inner type for ProcessDataRequest<T>
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UserRequest {
@@ -38,10 +77,16 @@ pub async fn process_data(
 
     let current_timestamp = std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
-        .map_err(|e| EnclaveError::GenericError(format!("Failed to get current timestamp: {e}")))?
+        .map_err(|e| EnclaveError::Internal(format!("Failed to get current timestamp: {e}")))?
         .as_millis() as u64;
     // Fetch tweet content
-    let (twitter_name, sui_address) = fetch_tweet_content(&state.api_key, &user_url).await?;
+    let (twitter_name, sui_address) = fetch_tweet_content(
+        &state.http,
+        &state.api_keys.select()?,
+        &user_url,
+        &configured_hashtag(),
+    )
+    .await?;
     Ok(Json(to_signed_response(
         &state.eph_kp,
         UserData {
@@ -49,24 +94,18 @@ pub async fn process_data(
             sui_address: sui_address.clone(),
         },
         current_timestamp,
-        IntentScope::ProcessData,
+        state.intent_scope,
     )))
 }
 
 async fn fetch_tweet_content(
+    client: &reqwest::Client,
     api_key: &str,
     user_url: &str,
+    hashtag: &str,
 ) -> Result<(String, Vec<u8>), EnclaveError> {
-    let client = reqwest::Client::new();
     if user_url.contains("/status/") {
-        // Extract tweet ID from URL using regex
-        let re = Regex::new(r"x\.com/\w+/status/(\d+)")
-            .map_err(|_| EnclaveError::GenericError("Invalid tweet URL".to_string()))?;
-        let tweet_id = re
-            .captures(user_url)
-            .and_then(|cap| cap.get(1))
-            .map(|m| m.as_str())
-            .ok_or_else(|| EnclaveError::GenericError("Invalid tweet URL".to_string()))?;
+        let tweet_id = tweet_id(user_url)?;
 
         // Construct the Twitter API URL
         let url = format!(
@@ -79,58 +118,28 @@ async fn fetch_tweet_content(
             .header("Authorization", format!("Bearer {api_key}"))
             .send()
             .await
-            .map_err(|_| {
-                EnclaveError::GenericError("Failed to send request to Twitter API".to_string())
-            })?
-            .json::<serde_json::Value>()
-            .await
-            .map_err(|_| {
-                EnclaveError::GenericError("Failed to parse response from Twitter API".to_string())
-            })?;
+            .map_err(|e| EnclaveError::from_reqwest("Failed to send request to Twitter API", e))?;
+        let response =
+            read_json_limited("Twitter API", response, DEFAULT_MAX_RESPONSE_BYTES).await?;
 
         // Extract tweet text and author username
         let tweet_text = response["data"]["text"].as_str().ok_or_else(|| {
-            EnclaveError::GenericError(format!("Failed to extract tweet text {response}"))
+            EnclaveError::Upstream(format!("Failed to extract tweet text {response}"))
         })?;
 
         let twitter_name = response["includes"]["users"]
             .as_array()
             .and_then(|users| users.first())
             .and_then(|user| user["username"].as_str())
-            .ok_or_else(|| EnclaveError::GenericError("Failed to extract username".to_string()))?;
-
-        // Find the position of "#SUI" and extract address before it
-        let sui_tag_pos = tweet_text
-            .find("#SUI")
-            .ok_or_else(|| EnclaveError::GenericError("No #SUI tag found in tweet".to_string()))?;
-
-        let text_before_tag = &tweet_text[..sui_tag_pos];
-        let sui_address_re = Regex::new(r"0x[0-9a-fA-F]{64}")
-            .map_err(|_| EnclaveError::GenericError("Invalid Sui address regex".to_string()))?;
-
-        let sui_address = sui_address_re
-            .find(text_before_tag)
-            .map(|m| m.as_str())
-            .ok_or_else(|| {
-                EnclaveError::GenericError(
-                    "No valid Sui address found before #SUI in profile description".to_string(),
-                )
-            })?;
+            .ok_or_else(|| EnclaveError::Upstream("Failed to extract username".to_string()))?;
 
         Ok((
             twitter_name.to_string(),
-            Hex::decode(sui_address)
-                .map_err(|_| EnclaveError::GenericError("Invalid Sui address".to_string()))?,
+            sui_address_before_tag(tweet_text, hashtag, "tweet")?,
         ))
     } else {
         // Handle profile URL
-        let re = Regex::new(r"x\.com/(\w+)(?:/)?$")
-            .map_err(|_| EnclaveError::GenericError("Invalid profile URL".to_string()))?;
-        let username = re
-            .captures(user_url)
-            .and_then(|cap| cap.get(1))
-            .map(|m| m.as_str())
-            .ok_or_else(|| EnclaveError::GenericError("Invalid profile URL".to_string()))?;
+        let username = profile_username(user_url)?;
 
         // Fetch user profile
         let url = format!(
@@ -142,45 +151,88 @@ async fn fetch_tweet_content(
             .header("Authorization", format!("Bearer {api_key}"))
             .send()
             .await
-            .map_err(|_| {
-                EnclaveError::GenericError("Failed to send request to Twitter API".to_string())
-            })?
-            .json::<serde_json::Value>()
-            .await
-            .map_err(|_| {
-                EnclaveError::GenericError("Failed to parse response from Twitter API".to_string())
-            })?;
+            .map_err(|e| EnclaveError::from_reqwest("Failed to send request to Twitter API", e))?;
+        let response =
+            read_json_limited("Twitter API", response, DEFAULT_MAX_RESPONSE_BYTES).await?;
 
         // Extract user description
         let description = response["data"]["description"].as_str().ok_or_else(|| {
-            EnclaveError::GenericError("Failed to extract user description".to_string())
-        })?;
-
-        let sui_tag_pos = description.find("#SUI").ok_or_else(|| {
-            EnclaveError::GenericError("No #SUI tag found in profile description".to_string())
+            EnclaveError::Upstream("Failed to extract user description".to_string())
         })?;
 
-        let text_before_tag = &description[..sui_tag_pos];
-        let sui_address_re = Regex::new(r"0x[0-9a-fA-F]{64}")
-            .map_err(|_| EnclaveError::GenericError("Invalid Sui address regex".to_string()))?;
-
-        let sui_address = sui_address_re
-            .find(text_before_tag)
-            .map(|m| m.as_str())
-            .ok_or_else(|| {
-                EnclaveError::GenericError(
-                    "No valid Sui address found before #SUI in profile description".to_string(),
-                )
-            })?;
-
         Ok((
             username.to_string(),
-            Hex::decode(&sui_address[2..])
-                .map_err(|_| EnclaveError::GenericError("Invalid Sui address".to_string()))?,
+            sui_address_before_tag(description, hashtag, "profile description")?,
         ))
     }
 }
 
+/// Host part of the URL regexes: x.com or twitter.com, bare or under `www.`
+/// or `mobile.`, with or without a scheme.
+const TWITTER_HOST: &str = r"(?:^|/)(?:www\.|mobile\.)?(?:x|twitter)\.com";
+
+/// Tweet id of a `{host}/{user}/status/{id}` URL, see [`TWITTER_HOST`].
+fn tweet_id(user_url: &str) -> Result<&str, EnclaveError> {
+    // Extract tweet ID from URL using regex
+    let re = Regex::new(&format!(r"{TWITTER_HOST}/\w+/status/(\d+)"))
+        .map_err(|_| EnclaveError::GenericError("Invalid tweet URL".to_string()))?;
+    re.captures(user_url)
+        .and_then(|cap| cap.get(1))
+        .map(|m| m.as_str())
+        .ok_or_else(|| EnclaveError::BadRequest("Invalid tweet URL".to_string()))
+}
+
+/// Username of a `{host}/{user}` profile URL, see [`TWITTER_HOST`].
+fn profile_username(user_url: &str) -> Result<&str, EnclaveError> {
+    let re = Regex::new(&format!(r"{TWITTER_HOST}/(\w+)(?:/)?$"))
+        .map_err(|_| EnclaveError::GenericError("Invalid profile URL".to_string()))?;
+    re.captures(user_url)
+        .and_then(|cap| cap.get(1))
+        .map(|m| m.as_str())
+        .ok_or_else(|| EnclaveError::BadRequest("Invalid profile URL".to_string()))
+}
+
+/// Bytes of the Sui address found in `text` before the first `hashtag`.
+/// `source` names the text in errors, e.g. `tweet`.
+fn sui_address_before_tag(
+    text: &str,
+    hashtag: &str,
+    source: &str,
+) -> Result<Vec<u8>, EnclaveError> {
+    // Find the position of the tag and extract address before it
+    let tag_pos = text
+        .find(hashtag)
+        .ok_or_else(|| EnclaveError::BadRequest(format!("No {hashtag} tag found in {source}")))?;
+
+    let text_before_tag = &text[..tag_pos];
+    // Every whole 0x-prefixed hex token, so a longer hash isn't cut down to
+    // an address-sized prefix.
+    let hex_token_re = Regex::new(r"\b0x[0-9a-fA-F]+\b")
+        .map_err(|_| EnclaveError::Internal("Invalid Sui address regex".to_string()))?;
+
+    hex_token_re
+        .find_iter(text_before_tag)
+        .find_map(|m| parse_sui_address(m.as_str()))
+        .ok_or_else(|| {
+            EnclaveError::BadRequest(format!(
+                "No valid Sui address found before {hashtag} in {source}"
+            ))
+        })
+}
+
+/// Bytes of `token` when it is a Sui address: `0x` and exactly 64 hex
+/// digits. Sui addresses carry no checksum, so any casing is accepted.
+fn parse_sui_address(token: &str) -> Option<Vec<u8>> {
+    let hex = token.strip_prefix("0x")?;
+    if hex.len() != SUI_ADDRESS_LENGTH * 2 {
+        return None;
+    }
+    Hex::decode(hex).ok()
+}
+
+/// Length of a Sui address in bytes.
+const SUI_ADDRESS_LENGTH: usize = 32;
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -188,7 +240,7 @@ mod test {
     #[tokio::test]
     async fn test_serde() {
         // serialization should be consistent with move test see `fun test_serde` in `enclave.move`.
-        use crate::common::IntentMessage;
+        use crate::common::{IntentMessage, IntentScope};
         let intent_msg = IntentMessage::new(
             UserData {
                 twitter_name: "mystenintern".as_bytes().to_vec(),
@@ -202,5 +254,99 @@ mod test {
         );
         let signing_payload = bcs::to_bytes(&intent_msg).expect("should not fail");
         assert!(signing_payload == Hex::decode("003f41dd0d960100000c6d797374656e696e7465726e20101ce8865558e08408b83f60ee9e78843d03d547c850cbe12cb599e17833dd3e").unwrap());
+
+        // The response carries the same bytes it signed.
+        let kp = AppState::for_test().eph_kp;
+        let signed = to_signed_response(
+            &kp,
+            intent_msg.data,
+            intent_msg.timestamp_ms,
+            IntentScope::ProcessData,
+        );
+        assert_eq!(signed.response_bcs, Some(Hex::encode(&signing_payload)));
+    }
+
+    #[test]
+    fn test_twitter_and_x_urls() {
+        for host in [
+            "x.com",
+            "twitter.com",
+            "www.x.com",
+            "www.twitter.com",
+            "mobile.x.com",
+            "mobile.twitter.com",
+        ] {
+            for prefix in ["https://", "http://", ""] {
+                let status = format!("{prefix}{host}/mystenintern/status/1907806383463682178");
+                assert_eq!(
+                    tweet_id(&status).unwrap(),
+                    "1907806383463682178",
+                    "{status}"
+                );
+                let profile = format!("{prefix}{host}/mystenintern");
+                assert_eq!(
+                    profile_username(&profile).unwrap(),
+                    "mystenintern",
+                    "{profile}"
+                );
+                assert_eq!(
+                    profile_username(&format!("{profile}/")).unwrap(),
+                    "mystenintern"
+                );
+            }
+        }
+        for invalid in [
+            "https://notx.com/mystenintern/status/1",
+            "https://x.org/mystenintern/status/1",
+            "https://api.twitter.com/mystenintern/status/1",
+        ] {
+            assert!(tweet_id(invalid).is_err(), "{invalid}");
+        }
+        assert!(profile_username("https://notx.com/mystenintern").is_err());
+    }
+
+    #[test]
+    fn test_first_valid_sui_address_is_picked() {
+        let address = "0x101ce8865558e08408b83f60ee9e78843d03d547c850cbe12cb599e17833dd3e";
+        let text = format!(
+            "digest 0x{} short 0x1234 glued a0x{} {address} 0x{} #SUI",
+            "ab".repeat(64),
+            "ef".repeat(32),
+            "cd".repeat(32)
+        );
+        assert_eq!(
+            sui_address_before_tag(&text, DEFAULT_HASHTAG, "tweet").unwrap(),
+            Hex::decode(address).unwrap()
+        );
+        let upper = address.to_uppercase().replacen("0X", "0x", 1);
+        assert_eq!(
+            parse_sui_address(&upper),
+            Some(Hex::decode(address).unwrap())
+        );
+
+        let text = format!("0x{} and 0x1234 #SUI", "ab".repeat(64));
+        assert!(matches!(
+            sui_address_before_tag(&text, DEFAULT_HASHTAG, "tweet"),
+            Err(EnclaveError::BadRequest(msg))
+                if msg == "No valid Sui address found before #SUI in tweet"
+        ));
+    }
+
+    #[test]
+    fn test_custom_hashtag() {
+        let address = "0x101ce8865558e08408b83f60ee9e78843d03d547c850cbe12cb599e17833dd3e";
+        let text = format!("Verifying {address} #WALRUS");
+        assert_eq!(
+            sui_address_before_tag(&text, "#WALRUS", "tweet").unwrap(),
+            Hex::decode(address).unwrap()
+        );
+        assert!(matches!(
+            sui_address_before_tag(&text, DEFAULT_HASHTAG, "tweet"),
+            Err(EnclaveError::BadRequest(msg)) if msg == "No #SUI tag found in tweet"
+        ));
+        assert!(matches!(
+            sui_address_before_tag(&format!("#WALRUS {address}"), "#WALRUS", "tweet"),
+            Err(EnclaveError::BadRequest(_))
+        ));
     }
 }
@@ -1,14 +1,17 @@
 // Copyright (c), Mysten Labs, Inc.
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::api_keys::send_with_key_rotation;
+use crate::common::DependencyStatus;
 use crate::common::IntentMessage;
-use crate::common::{to_signed_response, IntentScope, ProcessDataRequest, ProcessedDataResponse};
+use crate::common::{read_json_limited, DEFAULT_MAX_RESPONSE_BYTES};
+use crate::common::{to_signed_response, ProcessDataRequest, ProcessedDataResponse};
 use crate::AppState;
 use crate::EnclaveError;
 use axum::extract::State;
 use axum::Json;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use std::collections::BTreeMap;
 use std::sync::Arc;
 /// ====
 /// Core Nautilus server logic, replace it with your own
@@ -21,6 +24,29 @@ pub struct WeatherResponse {
     pub temperature: u64,
 }
 
+/// Prerequisites `/ready` waits for: an API key.
+pub async fn missing_prerequisites(state: &AppState) -> Vec<String> {
+    if state.api_keys.is_empty() {
+        vec!["API_KEY".to_string()]
+    } else {
+        vec![]
+    }
+}
+
+/// Dependencies the deep health check reports: whether the API keys are
+/// configured.
+pub async fn dependency_checks(state: &AppState) -> BTreeMap<String, DependencyStatus> {
+    let api_key = if state.api_keys.is_empty() {
+        DependencyStatus::down("API_KEY not set")
+    } else {
+        DependencyStatus::up()
+    };
+    BTreeMap::from([("api_key".to_string(), api_key)])
+}
+
+/// Payload `process_data` signs, checked by `/verify_signature`.
+pub type SignedPayload = WeatherResponse;
+
 /// Inner type T for ProcessDataRequest<T>
 #[derive(Debug, Serialize, Deserialize)]
 pub struct WeatherRequest {
@@ -31,28 +57,28 @@ pub async fn process_data(
     State(state): State<Arc<AppState>>,
     Json(request): Json<ProcessDataRequest<WeatherRequest>>,
 ) -> Result<Json<ProcessedDataResponse<IntentMessage<WeatherResponse>>>, EnclaveError> {
-    let url = format!(
-        "https://api.weatherapi.com/v1/current.json?key={}&q={}",
-        state.api_key, request.payload.location
-    );
-    let response = reqwest::get(url.clone())
-        .await
-        .map_err(|e| EnclaveError::GenericError(format!("Failed to get weather response: {e}")))?;
-    let json = response.json::<Value>().await.map_err(|e| {
-        EnclaveError::GenericError(format!("Failed to parse weather response: {e}"))
-    })?;
+    let response =
+        send_with_key_rotation(&state.api_keys, "Failed to get weather response", |key| {
+            let url = format!(
+                "https://api.weatherapi.com/v1/current.json?key={}&q={}",
+                key, request.payload.location
+            );
+            state.http.get(url).send()
+        })
+        .await?;
+    let json = read_json_limited("weather", response, DEFAULT_MAX_RESPONSE_BYTES).await?;
     let location = json["location"]["name"].as_str().unwrap_or("Unknown");
     let temperature = json["current"]["temp_c"].as_f64().unwrap_or(0.0) as u64;
     let last_updated_epoch = json["current"]["last_updated_epoch"].as_u64().unwrap_or(0);
     let last_updated_timestamp_ms = last_updated_epoch * 1000_u64;
     let current_timestamp = std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
-        .map_err(|e| EnclaveError::GenericError(format!("Failed to get current timestamp: {e}")))?
+        .map_err(|e| EnclaveError::Internal(format!("Failed to get current timestamp: {e}")))?
         .as_millis() as u64;
 
     // 1 hour in milliseconds = 60 * 60 * 1000 = 3_600_000
     if last_updated_timestamp_ms + 3_600_000 < current_timestamp {
-        return Err(EnclaveError::GenericError(
+        return Err(EnclaveError::Upstream(
             "Weather API timestamp is too old".to_string(),
         ));
     }
@@ -64,22 +90,22 @@ pub async fn process_data(
             temperature,
         },
         last_updated_timestamp_ms,
-        IntentScope::ProcessData,
+        state.intent_scope,
     )))
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::common::IntentMessage;
+    use crate::api_keys::ApiKeyPool;
+    use crate::common::{IntentMessage, IntentScope};
     use axum::{extract::State, Json};
-    use fastcrypto::{ed25519::Ed25519KeyPair, traits::KeyPair};
 
     #[tokio::test]
     async fn test_process_data() {
         let state = Arc::new(AppState {
-            eph_kp: Ed25519KeyPair::generate(&mut rand::thread_rng()),
-            api_key: "045a27812dbe456392913223221306".to_string(),
+            api_keys: ApiKeyPool::parse("045a27812dbe456392913223221306"),
+            ..AppState::for_test()
         });
         let signed_weather_response = process_data(
             State(state),
@@ -87,6 +113,7 @@ mod test {
                 payload: WeatherRequest {
                     location: "San Francisco".to_string(),
                 },
+                idempotency_key: None,
             }),
         )
         .await
@@ -106,12 +133,17 @@ mod test {
             temperature: 13,
         };
         let timestamp = 1744038900000;
-        let intent_msg = IntentMessage::new(payload, timestamp, IntentScope::ProcessData);
+        let intent_msg = IntentMessage::new(payload.clone(), timestamp, IntentScope::ProcessData);
         let signing_payload = bcs::to_bytes(&intent_msg).expect("should not fail");
         assert!(
             signing_payload
                 == Hex::decode("0020b1d110960100000d53616e204672616e636973636f0d00000000000000")
                     .unwrap()
         );
+
+        // The response carries the same bytes it signed.
+        let kp = AppState::for_test().eph_kp;
+        let signed = to_signed_response(&kp, payload, timestamp, IntentScope::ProcessData);
+        assert_eq!(signed.response_bcs, Some(Hex::encode(&signing_payload)));
     }
 }
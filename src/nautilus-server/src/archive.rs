@@ -0,0 +1,248 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::common::{
+    to_signed_response_with_threshold, verify_signed_response, IntentMessage, IntentScope,
+    ProcessedDataResponse,
+};
+use crate::keys::EnclaveKeyPair;
+use crate::tsa::{attach_timestamp_token, TimestampOptions};
+use crate::EnclaveError;
+use async_trait::async_trait;
+use serde::Serialize;
+use std::fmt::Debug;
+
+/// ==== GENERIC ARCHIVE PIPELINE ====
+/// Shape shared by archive-style apps: allocate a reference id, capture the
+/// target, build the payload from the capture, sign it and hand it to a sink.
+/// Apps plug in their own implementations of each step.
+/// Allocates the id an archive is stored and looked up under.
+pub trait ReferenceIdGenerator: Send + Sync {
+    fn generate(&self) -> Result<String, EnclaveError>;
+}
+
+/// Captures the target and stores the result under the reference id.
+#[async_trait]
+pub trait ScreenshotProvider: Send + Sync {
+    type Capture: Send;
+
+    async fn capture(&self, reference_id: &str) -> Result<Self::Capture, EnclaveError>;
+}
+
+/// Receives the signed response, e.g. to save it with a frontend. Returns
+/// whether the response was persisted.
+#[async_trait]
+pub trait AttestationSink<T: Serialize + Send + Sync>: Send + Sync {
+    async fn persist(
+        &self,
+        reference_id: &str,
+        signed: &ProcessedDataResponse<IntentMessage<T>>,
+    ) -> Result<bool, EnclaveError>;
+}
+
+/// How the pipeline signs the payload.
+pub struct SigningOptions<'a> {
+    pub kp: &'a EnclaveKeyPair,
+    pub intent: IntentScope,
+    /// See [`to_signed_response_with_threshold`].
+    pub hash_threshold: Option<usize>,
+    /// Attach a TSA timestamp token to the signed response before it is
+    /// persisted, see [`attach_timestamp_token`].
+    pub timestamp: Option<&'a TimestampOptions>,
+}
+
+/// Check the enclave's own signature over `signed` before it leaves the
+/// enclave, so a signing bug or key mix-up fails the request instead of
+/// persisting an attestation no one can verify.
+pub fn check_own_signature<T: Serialize>(
+    kp: &EnclaveKeyPair,
+    signed: &ProcessedDataResponse<IntentMessage<T>>,
+) -> Result<(), EnclaveError> {
+    verify_signed_response(kp, signed).map_err(|e| {
+        EnclaveError::Internal(format!("Signed response failed self-verification: {e}"))
+    })
+}
+
+/// Run the archive pipeline and return the signed response. `build_payload`
+/// turns the reference id and capture into the payload to sign. Any failing
+/// step aborts the pipeline, nothing is signed or persisted after a failed
+/// capture, and a response whose signature doesn't verify is not persisted
+/// either.
+pub async fn archive_pipeline<C, T>(
+    reference_ids: &dyn ReferenceIdGenerator,
+    provider: &dyn ScreenshotProvider<Capture = C>,
+    sink: &dyn AttestationSink<T>,
+    signing: &SigningOptions<'_>,
+    build_payload: impl FnOnce(&str, C) -> Result<T, EnclaveError>,
+) -> Result<ProcessedDataResponse<IntentMessage<T>>, EnclaveError>
+where
+    C: Send,
+    T: Serialize + Clone + Debug + Send + Sync,
+{
+    let reference_id = reference_ids.generate()?;
+    let capture = provider.capture(&reference_id).await?;
+    let payload = build_payload(&reference_id, capture)?;
+
+    let timestamp_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_err(|e| EnclaveError::Internal(format!("Failed to get current timestamp: {}", e)))?
+        .as_millis() as u64;
+    let mut signed = to_signed_response_with_threshold(
+        signing.kp,
+        payload,
+        timestamp_ms,
+        signing.intent,
+        signing.hash_threshold,
+    );
+    check_own_signature(signing.kp, &signed)?;
+    if let Some(timestamp) = signing.timestamp {
+        attach_timestamp_token(&mut signed, timestamp).await?;
+    }
+
+    sink.persist(&reference_id, &signed).await?;
+    Ok(signed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::AppState;
+    use fastcrypto::encoding::{Encoding, Hex};
+    use serde::Deserialize;
+    use std::sync::Mutex;
+
+    struct FixedId;
+
+    impl ReferenceIdGenerator for FixedId {
+        fn generate(&self) -> Result<String, EnclaveError> {
+            Ok("ABCDEF-GH12".to_string())
+        }
+    }
+
+    struct FakeProvider {
+        fail: bool,
+    }
+
+    #[async_trait]
+    impl ScreenshotProvider for FakeProvider {
+        type Capture = String;
+
+        async fn capture(&self, reference_id: &str) -> Result<String, EnclaveError> {
+            if self.fail {
+                return Err(EnclaveError::Upstream("capture failed".to_string()));
+            }
+            Ok(format!("blob-{reference_id}"))
+        }
+    }
+
+    #[derive(Default)]
+    struct RecordingSink {
+        persisted: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl AttestationSink<Archived> for RecordingSink {
+        async fn persist(
+            &self,
+            reference_id: &str,
+            _signed: &ProcessedDataResponse<IntentMessage<Archived>>,
+        ) -> Result<bool, EnclaveError> {
+            self.persisted
+                .lock()
+                .unwrap()
+                .push(reference_id.to_string());
+            Ok(true)
+        }
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct Archived {
+        reference_id: String,
+        blob_id: String,
+    }
+
+    fn build(reference_id: &str, blob_id: String) -> Result<Archived, EnclaveError> {
+        Ok(Archived {
+            reference_id: reference_id.to_string(),
+            blob_id,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_signs_and_persists() {
+        let kp = AppState::for_test().eph_kp;
+        let sink = RecordingSink::default();
+        let signing = SigningOptions {
+            kp: &kp,
+            intent: IntentScope::ProcessData,
+            hash_threshold: None,
+            timestamp: None,
+        };
+
+        let signed = archive_pipeline(
+            &FixedId,
+            &FakeProvider { fail: false },
+            &sink,
+            &signing,
+            build,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(signed.response.data.reference_id, "ABCDEF-GH12");
+        assert_eq!(signed.response.data.blob_id, "blob-ABCDEF-GH12");
+        assert_eq!(*sink.persisted.lock().unwrap(), vec!["ABCDEF-GH12"]);
+
+        let sig = Hex::decode(&signed.signature).unwrap();
+        let message = bcs::to_bytes(&signed.response).unwrap();
+        assert!(kp.verify(&message, &sig).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_failed_capture_is_not_persisted() {
+        let kp = AppState::for_test().eph_kp;
+        let sink = RecordingSink::default();
+        let signing = SigningOptions {
+            kp: &kp,
+            intent: IntentScope::ProcessData,
+            hash_threshold: None,
+            timestamp: None,
+        };
+
+        let result = archive_pipeline(
+            &FixedId,
+            &FakeProvider { fail: true },
+            &sink,
+            &signing,
+            build,
+        )
+        .await;
+
+        assert!(matches!(result, Err(EnclaveError::Upstream(_))));
+        assert!(sink.persisted.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_tampered_response_fails_self_verification() {
+        let kp = AppState::for_test().eph_kp;
+        let payload = build("ABCDEF-GH12", "blob".to_string()).unwrap();
+        let signed =
+            to_signed_response_with_threshold(&kp, payload, 1, IntentScope::ProcessData, None);
+        assert!(check_own_signature(&kp, &signed).is_ok());
+
+        let mut tampered = signed.clone();
+        tampered.response.data.blob_id = "other-blob".to_string();
+        assert!(matches!(
+            check_own_signature(&kp, &tampered),
+            Err(EnclaveError::Internal(_))
+        ));
+
+        // Nor does it verify against another key.
+        let other =
+            crate::keys::EnclaveKeyPair::generate(Default::default(), &mut rand::thread_rng());
+        assert!(matches!(
+            check_own_signature(&other, &signed),
+            Err(EnclaveError::Internal(_))
+        ));
+    }
+}
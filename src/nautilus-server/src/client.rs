@@ -0,0 +1,203 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::common::{
+    http_client, IntentMessage, ProcessDataRequest, ProcessedDataResponse, PublicKeyResponse,
+    VerifySignatureResponse,
+};
+use crate::EnclaveError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+/// Typed client of the enclave's own API, for integration tests and sister
+/// services, so the wire format of the requests and responses lives in this
+/// crate only. Error answers are decoded back into the [`EnclaveError`] the
+/// enclave returned, `Retry-After` included.
+#[derive(Debug, Clone)]
+pub struct EnclaveClient {
+    http: reqwest::Client,
+    base_url: String,
+}
+
+impl EnclaveClient {
+    /// Client of the enclave at `base_url`, e.g. `http://<PUBLIC_IP>:3000`.
+    pub fn new(base_url: &str) -> Self {
+        Self::with_http(base_url, http_client())
+    }
+
+    /// Like [`EnclaveClient::new`], sending through an existing client.
+    pub fn with_http(base_url: &str, http: reqwest::Client) -> Self {
+        Self {
+            http,
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{path}", self.base_url)
+    }
+
+    /// Call `/process_data` with `payload` and return the signed response of
+    /// type `T`, the app's `SignedPayload`.
+    pub async fn process_data<P: Serialize, T: Serialize + DeserializeOwned>(
+        &self,
+        payload: P,
+    ) -> Result<ProcessedDataResponse<IntentMessage<T>>, EnclaveError> {
+        let request = ProcessDataRequest {
+            payload,
+            idempotency_key: None,
+        };
+        let response = self
+            .http
+            .post(self.url("/process_data"))
+            .json(&request)
+            .send()
+            .await?;
+        decode(response).await
+    }
+
+    /// Archive `url` with the default options and return the signed
+    /// response.
+    #[cfg(feature = "perma-ws")]
+    pub async fn process_perma(
+        &self,
+        url: &str,
+    ) -> Result<crate::app::attestation::SignedPermaResponse, EnclaveError> {
+        self.process_data(crate::app::PermaRequest {
+            url: url.to_string(),
+            ..Default::default()
+        })
+        .await
+    }
+
+    /// The enclave's current public key.
+    pub async fn public_key(&self) -> Result<PublicKeyResponse, EnclaveError> {
+        let response = self.http.get(self.url("/public_key")).send().await?;
+        decode(response).await
+    }
+
+    /// Whether the enclave's current key signed `signed`, as answered by
+    /// `/verify_signature`.
+    pub async fn verify<T: Serialize>(
+        &self,
+        signed: &ProcessedDataResponse<IntentMessage<T>>,
+    ) -> Result<bool, EnclaveError> {
+        let response = self
+            .http
+            .post(self.url("/verify_signature"))
+            .json(signed)
+            .send()
+            .await?;
+        let result: VerifySignatureResponse = decode(response).await?;
+        Ok(result.valid)
+    }
+}
+
+/// Body of a successful answer, or the error the enclave answered with.
+async fn decode<T: DeserializeOwned>(response: reqwest::Response) -> Result<T, EnclaveError> {
+    let status = response.status();
+    let retry_after_secs = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(1);
+    let body = response.bytes().await?;
+    if status.is_success() {
+        return serde_json::from_slice(&body).map_err(|e| {
+            EnclaveError::Upstream(format!("Failed to parse the enclave response: {e}"))
+        });
+    }
+
+    let error = serde_json::from_slice::<Value>(&body).unwrap_or_default();
+    let reason = error["error"]
+        .as_str()
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("enclave answered {status}"));
+    Err(match status.as_u16() {
+        400 => EnclaveError::BadRequest(reason),
+        401 => EnclaveError::Unauthorized(reason),
+        404 => EnclaveError::NotFound(reason),
+        409 => match error["reference_id"].as_str() {
+            Some(reference_id) => EnclaveError::AlreadyRunning {
+                reference_id: reference_id.to_string(),
+            },
+            None => EnclaveError::Upstream(reason),
+        },
+        422 => EnclaveError::Stale(reason),
+        429 => EnclaveError::RateLimited {
+            reason,
+            retry_after_secs,
+        },
+        503 => EnclaveError::Unavailable {
+            reason,
+            retry_after_secs,
+        },
+        504 => EnclaveError::Timeout(reason),
+        500 => EnclaveError::Internal(reason),
+        _ => EnclaveError::Upstream(reason),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::{to_signed_response, verify_signature, IntentScope};
+    use crate::AppState;
+    use axum::routing::post;
+    use std::sync::Arc;
+    use wiremock::matchers::path;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test]
+    async fn test_verify_against_enclave() {
+        let state = Arc::new(AppState::for_test());
+        let signed =
+            to_signed_response(&state.eph_kp, vec![1u8, 2, 3], 42, IntentScope::ProcessData);
+        let app = axum::Router::new()
+            .route("/verify_signature", post(verify_signature::<Vec<u8>>))
+            .with_state(state);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let client = EnclaveClient::new(&format!("http://{addr}/"));
+        assert!(client.verify(&signed).await.unwrap());
+        let mut tampered = signed;
+        tampered.response.data.push(4);
+        assert!(!client.verify(&tampered).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_errors_are_decoded() {
+        let server = MockServer::start().await;
+        Mock::given(path("/process_data"))
+            .respond_with(
+                ResponseTemplate::new(429)
+                    .insert_header("Retry-After", "30")
+                    .set_body_json(serde_json::json!({ "error": "too many requests" })),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(path("/public_key"))
+            .respond_with(ResponseTemplate::new(502).set_body_string("bad gateway"))
+            .mount(&server)
+            .await;
+
+        let client = EnclaveClient::new(&server.uri());
+        let err = client
+            .process_data::<_, Vec<u8>>(vec![1u8])
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            EnclaveError::RateLimited { ref reason, retry_after_secs: 30 } if reason == "too many requests"
+        ));
+        let err = client.public_key().await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "upstream error: enclave answered 502 Bad Gateway"
+        );
+    }
+}
@@ -1,30 +1,43 @@
 // Copyright (c), Mysten Labs, Inc.
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::keys::EnclaveKeyPair;
 use crate::AppState;
 use crate::EnclaveError;
-use axum::{extract::State, Json};
-use fastcrypto::traits::Signer;
-use fastcrypto::{encoding::Encoding, traits::ToFromBytes};
-use fastcrypto::{encoding::Hex, traits::KeyPair as FcKeyPair};
+use axum::body::Body;
+use axum::extract::{Request, State};
+use axum::http::header::{ACCEPT, CONTENT_LENGTH, CONTENT_TYPE};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use fastcrypto::encoding::{Encoding, Hex};
+use fastcrypto::hash::{Blake2b256, HashFunction};
 use nsm_api::api::{Request as NsmRequest, Response as NsmResponse};
 use nsm_api::driver;
 use reqwest::Client;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
+use serde_json::{json, Value};
 use serde_repr::Deserialize_repr;
 use serde_repr::Serialize_repr;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
-use tracing::info;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+use tracing::{info, info_span, Instrument};
 
-use fastcrypto::ed25519::Ed25519KeyPair;
+#[cfg(feature = "archive")]
+pub use crate::archive::{
+    archive_pipeline, AttestationSink, ReferenceIdGenerator, ScreenshotProvider, SigningOptions,
+};
 /// ==== COMMON TYPES ====
 /// Intent message wrapper struct containing the intent scope and timestamp.
 /// This standardizes the serialized payload for signing.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IntentMessage<T: Serialize> {
     pub intent: IntentScope,
     pub timestamp_ms: u64,
@@ -33,10 +46,36 @@ pub struct IntentMessage<T: Serialize> {
 
 /// Intent scope enum. Add new scope here if needed, each corresponds to a
 /// scope for signing. Replace in with your own intent per message type being signed by the enclave.
-#[derive(Serialize_repr, Deserialize_repr, Debug)]
+#[derive(Serialize_repr, Deserialize_repr, Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum IntentScope {
     ProcessData = 0,
+    /// Record that an archive was deleted.
+    Tombstone = 1,
+}
+
+impl IntentScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IntentScope::ProcessData => "process_data",
+            IntentScope::Tombstone => "tombstone",
+        }
+    }
+}
+
+/// Parse a scope from its name or its byte, e.g. `process_data` or `0`.
+impl std::str::FromStr for IntentScope {
+    type Err = EnclaveError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "0" | "process_data" => Ok(IntentScope::ProcessData),
+            "1" | "tombstone" => Ok(IntentScope::Tombstone),
+            other => Err(EnclaveError::GenericError(format!(
+                "unknown intent scope {other}, expected process_data (0) or tombstone (1)"
+            ))),
+        }
+    }
 }
 
 impl<T: Serialize + Debug> IntentMessage<T> {
@@ -50,24 +89,94 @@ impl<T: Serialize + Debug> IntentMessage<T> {
 }
 
 /// Wrapper struct containing the response (the intent message) and signature.
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProcessedDataResponse<T> {
     pub response: T,
     pub signature: String,
+    /// What the signature is over. Omitted for raw signatures, so responses
+    /// signed the default way serialize as before.
+    #[serde(default, skip_serializing_if = "SigningMode::is_raw")]
+    pub signing_mode: SigningMode,
+    /// Hex BCS bytes of `response` exactly as signed, so verifiers, e.g. on
+    /// chain, needn't re-encode the JSON and risk encoding it differently.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_bcs: Option<String>,
+    /// Base64 RFC 3161 timestamp token over the SHA-256 of the BCS encoded
+    /// response, from an external timestamp authority. Not covered by the
+    /// signature.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp_token: Option<String>,
+    /// Hex attestation document committed to the signing key, as returned by
+    /// `/get_attestation`, when the request asked for it. Not covered by the
+    /// signature.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform_attestation: Option<String>,
+}
+
+/// Message the enclave signs for an intent message.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SigningMode {
+    /// The BCS bytes of the intent message.
+    #[default]
+    Raw,
+    /// `blake2b256(HASHED_SIGNING_DOMAIN || bcs(intent message))`, so
+    /// verifiers only process 32 bytes however large the payload is.
+    Blake2b256,
+}
+
+impl SigningMode {
+    pub fn is_raw(&self) -> bool {
+        *self == SigningMode::Raw
+    }
+}
+
+/// Domain separator prepended to the BCS bytes before hashing, so a hashed
+/// signature can't be mistaken for a raw signature over some other message.
+pub const HASHED_SIGNING_DOMAIN: &[u8] = b"NAUTILUS_HASHED_INTENT_MESSAGE";
+
+/// Bytes signed for `bcs_bytes` under `mode`.
+pub fn signing_message(mode: SigningMode, bcs_bytes: &[u8]) -> Vec<u8> {
+    match mode {
+        SigningMode::Raw => bcs_bytes.to_vec(),
+        SigningMode::Blake2b256 => {
+            let mut hasher = Blake2b256::default();
+            hasher.update(HASHED_SIGNING_DOMAIN);
+            hasher.update(bcs_bytes);
+            hasher.finalize().digest.to_vec()
+        }
+    }
 }
 
 /// Wrapper struct containing the request payload.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ProcessDataRequest<T> {
     pub payload: T,
+    /// Client chosen key making retries of the request safe, for apps that
+    /// support it (perma-ws): a repeat within the TTL gets the first
+    /// request's response instead of being processed again.
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
 }
 
 /// Sign the bcs bytes of the the payload with keypair.
 pub fn to_signed_response<T: Serialize + Clone>(
-    kp: &Ed25519KeyPair,
+    kp: &EnclaveKeyPair,
+    payload: T,
+    timestamp_ms: u64,
+    intent: IntentScope,
+) -> ProcessedDataResponse<IntentMessage<T>> {
+    to_signed_response_with_threshold(kp, payload, timestamp_ms, intent, None)
+}
+
+/// Like [`to_signed_response`], but sign a domain-separated hash of the bcs
+/// bytes instead when they are longer than `hash_threshold` bytes.
+pub fn to_signed_response_with_threshold<T: Serialize + Clone>(
+    kp: &EnclaveKeyPair,
     payload: T,
     timestamp_ms: u64,
     intent: IntentScope,
+    hash_threshold: Option<usize>,
 ) -> ProcessedDataResponse<IntentMessage<T>> {
     let intent_msg = IntentMessage {
         intent,
@@ -76,10 +185,18 @@ pub fn to_signed_response<T: Serialize + Clone>(
     };
 
     let signing_payload = bcs::to_bytes(&intent_msg).expect("should not fail");
-    let sig = kp.sign(&signing_payload);
+    let signing_mode = match hash_threshold {
+        Some(threshold) if signing_payload.len() > threshold => SigningMode::Blake2b256,
+        _ => SigningMode::Raw,
+    };
+    let sig = kp.sign(&signing_message(signing_mode, &signing_payload));
     ProcessedDataResponse {
         response: intent_msg,
         signature: Hex::encode(sig),
+        signing_mode,
+        response_bcs: Some(Hex::encode(&signing_payload)),
+        timestamp_token: None,
+        platform_attestation: None,
     }
 }
 
@@ -98,33 +215,239 @@ pub async fn get_attestation(
 ) -> Result<Json<GetAttestationResponse>, EnclaveError> {
     info!("get attestation called");
 
-    let pk = state.eph_kp.public();
+    attestation_document(&state.eph_kp.public_key_bytes()).map(|document| {
+        Json(GetAttestationResponse {
+            attestation: Hex::encode(document),
+        })
+    })
+}
+
+/// Attestation document of the enclave committed to the public key `pk`,
+/// from the NSM.
+pub fn attestation_document(pk: &[u8]) -> Result<Vec<u8>, EnclaveError> {
+    let fd = driver::nsm_init();
+    let document = request_attestation(fd, pk);
+    driver::nsm_exit(fd);
+    document
+}
+
+/// Whether the server runs in a Nitro enclave, i.e. has an NSM device.
+pub fn in_enclave() -> bool {
     let fd = driver::nsm_init();
+    if fd < 0 {
+        return false;
+    }
+    driver::nsm_exit(fd);
+    true
+}
 
+/// Ask the NSM behind `fd` for an attestation document carrying `pk` both
+/// as its public key and as its user data.
+fn request_attestation(fd: i32, pk: &[u8]) -> Result<Vec<u8>, EnclaveError> {
     // Send attestation request to NSM driver with public key set.
     let request = NsmRequest::Attestation {
-        user_data: None,
+        user_data: Some(ByteBuf::from(pk.to_vec())),
         nonce: None,
-        public_key: Some(ByteBuf::from(pk.as_bytes().to_vec())),
+        public_key: Some(ByteBuf::from(pk.to_vec())),
     };
 
-    let response = driver::nsm_process_request(fd, request);
-    match response {
-        NsmResponse::Attestation { document } => {
-            driver::nsm_exit(fd);
-            Ok(Json(GetAttestationResponse {
-                attestation: Hex::encode(document),
-            }))
-        }
-        _ => {
-            driver::nsm_exit(fd);
-            Err(EnclaveError::GenericError(
-                "unexpected response".to_string(),
-            ))
+    match driver::nsm_process_request(fd, request) {
+        NsmResponse::Attestation { document } => Ok(document),
+        _ => Err(EnclaveError::GenericError(
+            "unexpected response".to_string(),
+        )),
+    }
+}
+
+/// PCRs reported by `/get_attestation_details`: the enclave image (0), the
+/// kernel and bootstrap (1) and the application (2).
+pub const ATTESTED_PCRS: [u16; 3] = [0, 1, 2];
+
+/// Size of a SHA-384 PCR, and of the zeroed PCRs of a mock attestation.
+const PCR_LEN: usize = 48;
+
+/// Prefix of the document of a mock attestation, followed by the public key.
+pub const MOCK_ATTESTATION_PREFIX: &[u8] = b"MOCK-ATTESTATION-NOT-FROM-AN-ENCLAVE:";
+
+/// Response of `/get_attestation_details`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AttestationDetailsResponse {
+    /// Attestation document serialized in Hex, as from `/get_attestation`.
+    pub attestation: String,
+    /// Hex public key the document commits to, as its public key and its
+    /// user data.
+    pub public_key: String,
+    /// Hex measurements of [`ATTESTED_PCRS`] by index.
+    pub pcrs: BTreeMap<u16, String>,
+    /// True outside a Nitro enclave: the document is
+    /// [`MOCK_ATTESTATION_PREFIX`] and the public key, and the PCRs are
+    /// zeroed. Nothing attests to such a key.
+    pub mock: bool,
+}
+
+/// Like `/get_attestation`, also returning the key the document binds and
+/// the PCRs it was measured with, to compare against the expected build
+/// without decoding the document. Outside an enclave (no NSM device) a mock
+/// labeled as such is returned instead of an error, for local development.
+pub async fn get_attestation_details(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<AttestationDetailsResponse>, EnclaveError> {
+    info!("get attestation details called");
+    attestation_details(&state.eph_kp.public_key_bytes()).map(Json)
+}
+
+fn attestation_details(pk: &[u8]) -> Result<AttestationDetailsResponse, EnclaveError> {
+    let fd = driver::nsm_init();
+    if fd < 0 {
+        info!("No NSM device, returning a mock attestation");
+        return Ok(mock_attestation_details(pk));
+    }
+    let details = request_attestation(fd, pk).and_then(|document| {
+        let pcrs = ATTESTED_PCRS
+            .iter()
+            .map(|&index| Ok((index, Hex::encode(describe_pcr(fd, index)?))))
+            .collect::<Result<_, EnclaveError>>()?;
+        Ok(AttestationDetailsResponse {
+            attestation: Hex::encode(document),
+            public_key: Hex::encode(pk),
+            pcrs,
+            mock: false,
+        })
+    });
+    driver::nsm_exit(fd);
+    details
+}
+
+fn describe_pcr(fd: i32, index: u16) -> Result<Vec<u8>, EnclaveError> {
+    match driver::nsm_process_request(fd, NsmRequest::DescribePCR { index }) {
+        NsmResponse::DescribePCR { data, .. } => Ok(data),
+        other => Err(EnclaveError::GenericError(format!(
+            "unexpected response describing PCR{index}: {other:?}"
+        ))),
+    }
+}
+
+fn mock_attestation_details(pk: &[u8]) -> AttestationDetailsResponse {
+    AttestationDetailsResponse {
+        attestation: Hex::encode([MOCK_ATTESTATION_PREFIX, pk].concat()),
+        public_key: Hex::encode(pk),
+        pcrs: ATTESTED_PCRS
+            .iter()
+            .map(|&index| (index, Hex::encode([0u8; PCR_LEN])))
+            .collect(),
+        mock: true,
+    }
+}
+
+/// Bundle the enclave's attestation `document` with a signed response, so a
+/// verifier gets what was signed and which enclave signed it in one call.
+pub fn attach_platform_attestation<T>(signed: &mut ProcessedDataResponse<T>, document: &[u8]) {
+    signed.platform_attestation = Some(Hex::encode(document));
+}
+
+/// ==== READINESS ====
+/// Response of `/ready`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReadinessResponse {
+    pub ready: bool,
+    /// Prerequisites the app is still waiting for, e.g. `ACCESS_KEY`.
+    pub missing: Vec<String>,
+}
+
+/// Readiness answer for the app's `missing` prerequisites: 200 when there
+/// are none, 503 listing them otherwise. Unlike [`health_report`], which only
+/// probes connectivity, it tells whether `process_data` can succeed.
+pub fn readiness(missing: Vec<String>) -> (StatusCode, Json<ReadinessResponse>) {
+    let ready = missing.is_empty();
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(ReadinessResponse { ready, missing }))
+}
+
+/// Response for the public key endpoint.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PublicKeyResponse {
+    /// Signature scheme of the key, `ed25519` unless overridden with
+    /// `KEY_SCHEME`, see [`KeyScheme`](crate::keys::KeyScheme).
+    pub scheme: String,
+    /// Hex encoded public key the enclave signs responses with.
+    pub public_key: String,
+}
+
+/// Endpoint that returns the enclave's ephemeral public key, so clients can
+/// cache it and verify signed responses locally. The key changes on restart,
+/// see [`KEY_FINGERPRINT_HEADER`].
+pub async fn public_key(State(state): State<Arc<AppState>>) -> Json<PublicKeyResponse> {
+    Json(PublicKeyResponse {
+        scheme: state.eph_kp.scheme().to_string(),
+        public_key: Hex::encode(state.eph_kp.public_key_bytes()),
+    })
+}
+
+/// Result of the verify signature endpoint.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VerifySignatureResponse {
+    /// Whether the enclave key signed the response as given.
+    pub valid: bool,
+    /// Hex encoded public key the signature was checked against.
+    pub public_key: String,
+    /// Sui address of that key, for comparing with the registered enclave.
+    pub signer_address: String,
+    /// Why the signature didn't verify.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Sui address of the enclave key: `0x` and the hex
+/// `blake2b256(flag || public key)`, the flag being that of the key's scheme
+/// (`KEY_SCHEME`), see [`KeyScheme::flag`](crate::keys::KeyScheme::flag).
+pub fn sui_address(kp: &EnclaveKeyPair) -> String {
+    kp.sui_address()
+}
+
+/// Check `signed` against the public key of `kp` over the same BCS encoding
+/// of the intent message and the same [`SigningMode`] as
+/// [`to_signed_response`]. A `response_bcs` given along must be that
+/// encoding too.
+pub fn verify_signed_response<T: Serialize>(
+    kp: &EnclaveKeyPair,
+    signed: &ProcessedDataResponse<IntentMessage<T>>,
+) -> Result<(), String> {
+    let signature =
+        Hex::decode(&signed.signature).map_err(|e| format!("signature is not hex: {e}"))?;
+    let bcs_bytes = bcs::to_bytes(&signed.response)
+        .map_err(|e| format!("failed to encode the response: {e}"))?;
+    kp.verify(
+        &signing_message(signed.signing_mode, &bcs_bytes),
+        &signature,
+    )?;
+    match &signed.response_bcs {
+        Some(response_bcs) if !response_bcs.eq_ignore_ascii_case(&Hex::encode(&bcs_bytes)) => {
+            Err("response_bcs does not match the response".to_string())
         }
+        _ => Ok(()),
     }
 }
 
+/// Endpoint that checks a signed response as returned by `process_data`
+/// against the enclave key, so clients don't have to reimplement the BCS
+/// encoding. `T` is the app's signed payload, see `app::SignedPayload`.
+pub async fn verify_signature<T: Serialize + DeserializeOwned>(
+    State(state): State<Arc<AppState>>,
+    Json(signed): Json<ProcessedDataResponse<IntentMessage<T>>>,
+) -> Json<VerifySignatureResponse> {
+    let error = verify_signed_response(&state.eph_kp, &signed).err();
+    Json(VerifySignatureResponse {
+        valid: error.is_none(),
+        public_key: Hex::encode(state.eph_kp.public_key_bytes()),
+        signer_address: sui_address(&state.eph_kp),
+        error,
+    })
+}
+
 /// Health check response.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct HealthCheckResponse {
@@ -132,20 +455,71 @@ pub struct HealthCheckResponse {
     pub pk: String,
     /// Status of endpoint connectivity checks
     pub endpoints_status: HashMap<String, bool>,
+    /// Status of the app's dependencies, only probed for `?deep=true`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dependencies: Option<BTreeMap<String, DependencyStatus>>,
 }
 
-/// Endpoint that health checks the enclave connectivity to all
-/// domains and returns the enclave's public key.
-pub async fn health_check(
-    State(state): State<Arc<AppState>>,
-) -> Result<Json<HealthCheckResponse>, EnclaveError> {
-    let pk = state.eph_kp.public();
+/// Query parameters of the health check.
+#[derive(Debug, Default, Deserialize)]
+pub struct HealthCheckQuery {
+    /// Also probe the app's dependencies, and run checks with side effects
+    /// like writing to storage.
+    #[serde(default)]
+    pub deep: bool,
+}
 
-    // Create HTTP client with timeout
-    let client = Client::builder()
-        .timeout(Duration::from_secs(5))
-        .build()
-        .map_err(|e| EnclaveError::GenericError(format!("Failed to create HTTP client: {e}")))?;
+/// Outcome of one dependency probe of the deep health check.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DependencyStatus {
+    pub ok: bool,
+    /// Why the dependency is down, or what was checked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl DependencyStatus {
+    pub fn up() -> Self {
+        Self {
+            ok: true,
+            detail: None,
+        }
+    }
+
+    pub fn down(detail: impl Into<String>) -> Self {
+        Self {
+            ok: false,
+            detail: Some(detail.into()),
+        }
+    }
+}
+
+/// Send a lightweight health probe, the dependency is up when it answers
+/// within [`HEALTH_CHECK_TIMEOUT`] with a status `healthy` accepts. The URL
+/// is left out of errors, as probes may carry a key in the query.
+pub async fn probe_dependency(
+    request: reqwest::RequestBuilder,
+    healthy: impl Fn(reqwest::StatusCode) -> bool,
+) -> DependencyStatus {
+    match request.timeout(HEALTH_CHECK_TIMEOUT).send().await {
+        Ok(response) if healthy(response.status()) => DependencyStatus::up(),
+        Ok(response) => DependencyStatus::down(format!("answered {}", response.status())),
+        Err(e) => DependencyStatus::down(e.without_url().to_string()),
+    }
+}
+
+/// Timeout of each endpoint probe of the health check.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Health check answer: the enclave connectivity to all domains and the
+/// enclave's public key. With `?deep=true` it also reports the app's
+/// `dependencies`, probed by the caller, answering 503 when any of them is
+/// down.
+pub async fn health_report(
+    state: &AppState,
+    dependencies: Option<BTreeMap<String, DependencyStatus>>,
+) -> (StatusCode, Json<HealthCheckResponse>) {
+    let pk = state.eph_kp.public_key_bytes();
 
     // Load allowed endpoints from YAML file
     let endpoints_status = match std::fs::read_to_string("allowed_endpoints.yaml") {
@@ -166,7 +540,13 @@ pub async fn health_check(
                                     format!("https://{endpoint_str}")
                                 };
 
-                                let is_reachable = match client.get(&url).send().await {
+                                let is_reachable = match state
+                                    .http
+                                    .get(&url)
+                                    .timeout(HEALTH_CHECK_TIMEOUT)
+                                    .send()
+                                    .await
+                                {
                                     Ok(response) => {
                                         if endpoint_str.contains(".amazonaws.com") {
                                             // For AWS endpoints, check if response body contains "healthy"
@@ -214,8 +594,1000 @@ pub async fn health_check(
         }
     };
 
-    Ok(Json(HealthCheckResponse {
-        pk: Hex::encode(pk.as_bytes()),
-        endpoints_status,
-    }))
+    let healthy = dependencies
+        .as_ref()
+        .is_none_or(|dependencies| dependencies.values().all(|status| status.ok));
+    let status = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(HealthCheckResponse {
+            pk: Hex::encode(pk),
+            endpoints_status,
+            dependencies,
+        }),
+    )
+}
+
+/// ==== RESPONSE ENCODING ====
+/// Content type of CBOR encoded responses.
+pub const CBOR_CONTENT_TYPE: &str = "application/cbor";
+
+/// Transport encoding of a response envelope. The signed bytes are BCS
+/// either way, this only changes how the envelope travels.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResponseEncoding {
+    #[default]
+    Json,
+    Cbor,
+}
+
+/// Query parameter selecting the response encoding, e.g. `?encoding=cbor`.
+#[derive(Debug, Default, Deserialize)]
+pub struct EncodingQuery {
+    #[serde(default)]
+    pub encoding: Option<ResponseEncoding>,
+}
+
+impl ResponseEncoding {
+    /// The encoding asked for by `?encoding=`, else by an `Accept` header
+    /// naming [`CBOR_CONTENT_TYPE`], else JSON.
+    pub fn negotiate(query: &EncodingQuery, headers: &HeaderMap) -> Self {
+        if let Some(encoding) = query.encoding {
+            return encoding;
+        }
+        let accepts_cbor = headers
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|v| {
+                v.split(';')
+                    .next()
+                    .is_some_and(|t| t.trim().eq_ignore_ascii_case(CBOR_CONTENT_TYPE))
+            });
+        if accepts_cbor {
+            ResponseEncoding::Cbor
+        } else {
+            ResponseEncoding::Json
+        }
+    }
+}
+
+/// Response body serialized in the negotiated [`ResponseEncoding`].
+pub struct Encoded<T>(pub ResponseEncoding, pub T);
+
+impl<T: Serialize> IntoResponse for Encoded<T> {
+    fn into_response(self) -> Response {
+        match self.0 {
+            ResponseEncoding::Json => Json(self.1).into_response(),
+            ResponseEncoding::Cbor => match serde_cbor::to_vec(&self.1) {
+                Ok(bytes) => ([(CONTENT_TYPE, CBOR_CONTENT_TYPE)], bytes).into_response(),
+                Err(e) => {
+                    EnclaveError::Internal(format!("Failed to encode CBOR: {e}")).into_response()
+                }
+            },
+        }
+    }
+}
+
+/// ==== KEY FINGERPRINT HEADER ====
+/// Response header carrying [`key_fingerprint`] of the key the enclave signs
+/// with. The key is ephemeral, so a changed fingerprint tells clients the
+/// enclave restarted and they should fetch the new public key.
+pub const KEY_FINGERPRINT_HEADER: &str = "x-enclave-key-fingerprint";
+
+/// Short fingerprint of the keypair's public key: the hex of the first 8
+/// bytes of its Blake2b256 hash.
+pub fn key_fingerprint(kp: &EnclaveKeyPair) -> String {
+    let digest = Blake2b256::digest(kp.public_key_bytes()).digest;
+    Hex::encode(&digest[..8])
+}
+
+/// Middleware adding [`KEY_FINGERPRINT_HEADER`] to responses of signing
+/// endpoints, errors included.
+pub async fn key_fingerprint_header(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mut response = next.run(request).await;
+    if let Ok(value) = HeaderValue::from_str(&key_fingerprint(&state.eph_kp)) {
+        response.headers_mut().insert(KEY_FINGERPRINT_HEADER, value);
+    }
+    response
+}
+
+/// ==== REQUEST ID ====
+/// Header correlating a request with its log lines. A client-chosen id is
+/// kept so it can be traced across services, otherwise one is generated.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest incoming request id kept, longer ones are replaced.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Middleware running the request in a `request` span carrying its id, so
+/// every log line of a request, across the pipeline steps, can be told apart
+/// from those of concurrent ones. The id is the incoming [`REQUEST_ID_HEADER`]
+/// when it is printable and at most `MAX_REQUEST_ID_LEN` bytes, else a new
+/// UUID, and is echoed in the response.
+pub async fn request_id(mut request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+        .and_then(|id| HeaderValue::from_str(id).ok())
+        .unwrap_or_else(|| {
+            HeaderValue::from_str(&uuid::Uuid::new_v4().to_string())
+                .expect("a UUID is a valid header value")
+        });
+    request.headers_mut().insert(REQUEST_ID_HEADER, id.clone());
+    let span = info_span!(
+        "request",
+        request_id = id.to_str().unwrap_or_default(),
+        method = %request.method(),
+        path = request.uri().path(),
+    );
+    let mut response = next.run(request).instrument(span).await;
+    response.headers_mut().insert(REQUEST_ID_HEADER, id);
+    response
+}
+
+/// ==== PRETTY JSON ====
+/// Dev middleware (`PRETTY_JSON=true`) re-serializing JSON response bodies
+/// with [`serde_json::to_string_pretty`] for manual inspection. Only the JSON
+/// envelope changes: signatures are over BCS bytes, and the hex and base64
+/// fields carrying them are passed through as is. Object keys come out
+/// sorted.
+pub async fn pretty_json(request: Request, next: Next) -> Response {
+    let response = next.run(request).await;
+    let is_json = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().starts_with(b"application/json"));
+    if !is_json {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, usize::MAX).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let pretty = serde_json::from_slice::<Value>(&bytes)
+        .ok()
+        .and_then(|value| serde_json::to_string_pretty(&value).ok());
+    let Some(pretty) = pretty else {
+        return Response::from_parts(parts, Body::from(bytes));
+    };
+    parts.headers.remove(CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(pretty))
+}
+
+/// ==== REQUEST BODY LIMIT ====
+/// Largest request body accepted unless `MAX_REQUEST_BODY_BYTES` is set.
+/// Requests only carry a URL and a few options, so it can be tight.
+pub const DEFAULT_MAX_REQUEST_BODY_BYTES: usize = 64 * 1024;
+
+/// Middleware refusing request bodies over `limit` bytes with 413 before a
+/// handler deserializes them. A body announcing a bigger `Content-Length` is
+/// refused unread, others are read up to the limit.
+pub async fn limit_request_body(
+    State(limit): State<usize>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let too_large = || EnclaveError::PayloadTooLarge { limit }.into_response();
+    let announced = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if announced.is_some_and(|length| length > limit as u64) {
+        return too_large();
+    }
+    let (parts, body) = request.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, limit).await else {
+        return too_large();
+    };
+    next.run(Request::from_parts(parts, Body::from(bytes)))
+        .await
+}
+
+/// ==== HTTP CLIENT ====
+/// Default timeout of outbound requests, so a hung upstream can't hold a
+/// task forever. Longer calls, like a ScreenshotOne capture that may take up
+/// to its own 60s `timeout`, set theirs with `RequestBuilder::timeout`, as do
+/// requests that must fail faster.
+pub const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time allowed to connect to an upstream.
+pub const HTTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Build the client stored in [`AppState::http`]. Cloning it is cheap and
+/// shares the connection pool. It follows redirects, so it is only for the
+/// configured upstreams; URLs named by a request go through perma-ws's
+/// `TargetHttp`, which checks every hop.
+pub fn http_client() -> Client {
+    Client::builder()
+        .timeout(HTTP_TIMEOUT)
+        .connect_timeout(HTTP_CONNECT_TIMEOUT)
+        .pool_idle_timeout(Duration::from_secs(90))
+        .pool_max_idle_per_host(8)
+        .build()
+        .expect("HTTP client configuration is valid")
+}
+
+/// ==== LISTEN ADDRESSES ====
+/// Port of the public server, overridden with `PORT`.
+pub const DEFAULT_PORT: u16 = 3000;
+
+/// Port of the host-only server (seal bootstrap or perma-ws admin),
+/// overridden with `HOST_INIT_PORT`.
+pub const DEFAULT_HOST_INIT_PORT: u16 = 3001;
+
+/// Address to bind from env var `name`: a bare port listens on all
+/// interfaces, a full socket address (e.g. `127.0.0.1:3100`) is used as is.
+/// Unset or empty falls back to `0.0.0.0:{default_port}`.
+pub fn listen_addr(name: &str, default_port: u16) -> Result<SocketAddr, EnclaveError> {
+    match std::env::var(name) {
+        Ok(value) if !value.trim().is_empty() => parse_listen_addr(name, &value),
+        _ => Ok(SocketAddr::from(([0, 0, 0, 0], default_port))),
+    }
+}
+
+fn parse_listen_addr(name: &str, value: &str) -> Result<SocketAddr, EnclaveError> {
+    let value = value.trim();
+    if let Ok(port) = value.parse::<u16>() {
+        return Ok(SocketAddr::from(([0, 0, 0, 0], port)));
+    }
+    value.parse::<SocketAddr>().map_err(|_| {
+        EnclaveError::GenericError(format!(
+            "Invalid {name} {value}: expected a port or a socket address like 0.0.0.0:{port}",
+            port = DEFAULT_PORT
+        ))
+    })
+}
+
+/// ==== CORS ====
+/// CORS policy for `CORS_ALLOWED_ORIGINS`, a comma-separated list of origins
+/// like `https://www.perma.ws`. Unset or empty allows any origin, for local
+/// development. Only GET and POST, the methods the routes use, are allowed.
+pub fn cors_layer(allowed_origins: Option<&str>) -> Result<CorsLayer, EnclaveError> {
+    let origins = allowed_origins
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|origin| !origin.is_empty())
+        .map(parse_origin)
+        .collect::<Result<Vec<_>, _>>()?;
+    let allow_origin = if origins.is_empty() {
+        AllowOrigin::from(Any)
+    } else {
+        AllowOrigin::list(origins)
+    };
+    Ok(CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([Method::GET, Method::POST])
+        .allow_headers(Any)
+        .expose_headers([
+            HeaderName::from_static(KEY_FINGERPRINT_HEADER),
+            HeaderName::from_static(REQUEST_ID_HEADER),
+        ]))
+}
+
+/// `origin` as browsers send it: scheme, host and non-default port, without
+/// a trailing slash.
+fn parse_origin(origin: &str) -> Result<HeaderValue, EnclaveError> {
+    let invalid = || {
+        EnclaveError::GenericError(format!(
+            "Invalid CORS_ALLOWED_ORIGINS entry {origin}: expected an origin like https://www.perma.ws"
+        ))
+    };
+    let url = reqwest::Url::parse(origin).map_err(|_| invalid())?;
+    if !matches!(url.scheme(), "http" | "https") || url.path() != "/" {
+        return Err(invalid());
+    }
+    HeaderValue::from_str(&url.origin().ascii_serialization()).map_err(|_| invalid())
+}
+
+/// ==== RESPONSE SIZE ====
+/// Largest upstream response body read into memory (`MAX_RESPONSE_BYTES`
+/// for perma-ws). Every JSON answer the apps parse is a few KiB.
+pub const DEFAULT_MAX_RESPONSE_BYTES: usize = 1024 * 1024;
+
+/// Read the body of `response`, failing with
+/// [`EnclaveError::ResponseTooLarge`] once it goes over `limit` bytes instead
+/// of buffering it whole. A declared Content-Length over the limit fails
+/// before anything is read.
+pub async fn read_body_limited(
+    upstream: &str,
+    mut response: reqwest::Response,
+    limit: usize,
+) -> Result<Vec<u8>, EnclaveError> {
+    let too_large = || EnclaveError::ResponseTooLarge {
+        upstream: upstream.to_string(),
+        limit,
+    };
+    if response
+        .content_length()
+        .is_some_and(|length| length > limit as u64)
+    {
+        return Err(too_large());
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(|e| {
+        EnclaveError::from_reqwest(&format!("Failed to read {upstream} response"), e)
+    })? {
+        if body.len() + chunk.len() > limit {
+            return Err(too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+/// Parse the body of `response` as JSON, reading at most `limit` bytes, see
+/// [`read_body_limited`].
+pub async fn read_json_limited(
+    upstream: &str,
+    response: reqwest::Response,
+    limit: usize,
+) -> Result<Value, EnclaveError> {
+    let body = read_body_limited(upstream, response, limit).await?;
+    serde_json::from_slice(&body)
+        .map_err(|e| EnclaveError::Upstream(format!("Failed to parse {upstream} response: {e}")))
+}
+
+/// ==== SHUTDOWN ====
+/// How long in-flight requests may run after a shutdown signal before the
+/// server exits anyway (`SHUTDOWN_DRAIN_TIMEOUT_SECS`). Covers a scoop with
+/// its ScreenshotOne retries.
+pub const DEFAULT_SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Resolve on Ctrl+C or SIGTERM, which the enclave gets on rolling deploys.
+/// The server then stops accepting connections and drains in-flight
+/// requests, up to [`DEFAULT_SHUTDOWN_DRAIN_TIMEOUT`], before exiting.
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            info!("Failed to listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                info!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("Shutdown signal received, draining in-flight requests");
+}
+
+/// ==== STARTUP BANNER ====
+/// Name of the app compiled into this binary.
+pub const ACTIVE_APP: &str = if cfg!(feature = "perma-ws") {
+    "perma-ws"
+} else if cfg!(feature = "seal-example") {
+    "seal-example"
+} else if cfg!(feature = "twitter-example") {
+    "twitter-example"
+} else if cfg!(feature = "weather-example") {
+    "weather-example"
+} else {
+    "none"
+};
+
+/// Redact a secret for logging, only revealing whether it is set.
+pub fn redact(secret: Option<&str>) -> String {
+    match secret {
+        Some(s) if !s.is_empty() => "<redacted>".to_string(),
+        _ => "<unset>".to_string(),
+    }
+}
+
+/// Structured summary of the effective runtime configuration, logged once on
+/// boot. `settings` must already be redacted by the caller, see [`redact`].
+pub fn startup_banner(bind_addrs: &[String], settings: &[(&str, String)]) -> Value {
+    let features: Vec<&str> = [
+        ("perma-ws", cfg!(feature = "perma-ws")),
+        ("archive", cfg!(feature = "archive")),
+        ("seal-example", cfg!(feature = "seal-example")),
+        ("twitter-example", cfg!(feature = "twitter-example")),
+        ("weather-example", cfg!(feature = "weather-example")),
+    ]
+    .into_iter()
+    .filter_map(|(name, enabled)| enabled.then_some(name))
+    .collect();
+    let config: serde_json::Map<String, Value> = settings
+        .iter()
+        .map(|(key, value)| (key.to_string(), Value::String(value.clone())))
+        .collect();
+
+    json!({
+        "app": ACTIVE_APP,
+        "version": env!("CARGO_PKG_VERSION"),
+        "bind_addrs": bind_addrs,
+        "features": features,
+        "config": config,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::keys::KeyScheme;
+
+    #[test]
+    fn test_startup_banner() {
+        let settings = [
+            ("api_key", redact(Some("045a27812dbe"))),
+            ("empty", redact(None)),
+        ];
+        let banner = startup_banner(&["0.0.0.0:3000".to_string()], &settings);
+        assert_eq!(banner["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(banner["bind_addrs"][0], "0.0.0.0:3000");
+        assert_eq!(banner["config"]["api_key"], "<redacted>");
+        assert_eq!(banner["config"]["empty"], "<unset>");
+        assert!(!banner.to_string().contains("045a27812dbe"));
+    }
+
+    #[tokio::test]
+    async fn test_hung_upstream_times_out() {
+        use wiremock::matchers::any;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        // Holds every request for an hour, i.e. never answers within the test.
+        let server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(3600)))
+            .mount(&server)
+            .await;
+
+        let error = http_client()
+            .get(server.uri())
+            .timeout(Duration::from_millis(200))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            EnclaveError::from_reqwest("Failed to call the upstream", error),
+            EnclaveError::Timeout(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_response_is_rejected() {
+        use wiremock::matchers::method;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        let body = format!("{{\"padding\":\"{}\"}}", "x".repeat(4096));
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(body, "application/json"))
+            .mount(&server)
+            .await;
+        let get = || async { reqwest::get(server.uri()).await.unwrap() };
+
+        let result = read_json_limited("Scooper", get().await, 1024).await;
+        assert!(matches!(
+            result,
+            Err(EnclaveError::ResponseTooLarge { limit: 1024, .. })
+        ));
+        let json = read_json_limited("Scooper", get().await, 8192)
+            .await
+            .unwrap();
+        assert_eq!(json["padding"].as_str().unwrap().len(), 4096);
+    }
+
+    #[test]
+    fn test_configured_intent_scope_is_signed() {
+        let kp = AppState::for_test().eph_kp;
+        for (value, byte) in [("process_data", 0u8), ("1", 1), (" Tombstone ", 1)] {
+            let scope: IntentScope = value.parse().unwrap();
+            let signed = to_signed_response(&kp, vec![7u8], 1, scope);
+            assert_eq!(bcs::to_bytes(&signed.response).unwrap()[0], byte);
+            assert!(verify(&kp, &signed));
+        }
+        assert!("2".parse::<IntentScope>().is_err());
+        assert!("archive".parse::<IntentScope>().is_err());
+    }
+
+    #[test]
+    fn test_parse_listen_addr() {
+        assert_eq!(
+            parse_listen_addr("PORT", "8080").unwrap(),
+            SocketAddr::from(([0, 0, 0, 0], 8080))
+        );
+        assert_eq!(
+            parse_listen_addr("PORT", " 127.0.0.1:3100 ").unwrap(),
+            SocketAddr::from(([127, 0, 0, 1], 3100))
+        );
+        assert_eq!(
+            parse_listen_addr("PORT", "[::1]:3000").unwrap().to_string(),
+            "[::1]:3000"
+        );
+        for invalid in ["70000", "localhost:3000", "0.0.0.0", "abc"] {
+            let err = parse_listen_addr("HOST_INIT_PORT", invalid).unwrap_err();
+            assert!(err.to_string().contains("Invalid HOST_INIT_PORT"), "{err}");
+        }
+    }
+
+    fn verify(kp: &EnclaveKeyPair, signed: &ProcessedDataResponse<IntentMessage<Vec<u8>>>) -> bool {
+        verify_signed_response(kp, signed).is_ok()
+    }
+
+    #[tokio::test]
+    async fn test_verify_signature() {
+        let state = Arc::new(AppState::for_test());
+        let signed =
+            to_signed_response(&state.eph_kp, vec![1u8, 2, 3], 42, IntentScope::ProcessData);
+        // Round trip through JSON like a client would.
+        let signed: ProcessedDataResponse<IntentMessage<Vec<u8>>> =
+            serde_json::from_value(serde_json::to_value(&signed).unwrap()).unwrap();
+
+        let Json(result) = verify_signature(State(state.clone()), Json(signed.clone())).await;
+        assert!(result.valid, "{:?}", result.error);
+        assert_eq!(
+            result.public_key,
+            Hex::encode(state.eph_kp.public_key_bytes())
+        );
+        assert_eq!(result.signer_address, sui_address(&state.eph_kp));
+        assert_eq!(result.signer_address.len(), 66);
+
+        let mut tampered = signed.clone();
+        tampered.response.timestamp_ms += 1;
+        let Json(result) = verify_signature(State(state.clone()), Json(tampered)).await;
+        assert!(!result.valid);
+        assert_eq!(
+            result.error.as_deref(),
+            Some("signature does not match the response")
+        );
+
+        let mut garbled = signed.clone();
+        garbled.signature = "zz".to_string();
+        let Json(result) = verify_signature(State(state.clone()), Json(garbled)).await;
+        assert!(!result.valid);
+
+        // The signed bytes are returned as is, and must match the response.
+        assert_eq!(
+            signed.response_bcs.as_deref(),
+            Some(Hex::encode(bcs::to_bytes(&signed.response).unwrap()).as_str())
+        );
+        let mut mismatched = signed;
+        mismatched.response_bcs = Some("00".to_string());
+        let Json(result) = verify_signature(State(state), Json(mismatched)).await;
+        assert_eq!(
+            result.error.as_deref(),
+            Some("response_bcs does not match the response")
+        );
+    }
+
+    #[test]
+    fn test_signing_modes() {
+        let kp = AppState::for_test().eph_kp;
+        let payload = vec![7u8; 64];
+
+        // The default and a threshold above the payload size sign the raw bytes.
+        let raw = to_signed_response(&kp, payload.clone(), 1, IntentScope::ProcessData);
+        let below = to_signed_response_with_threshold(
+            &kp,
+            payload.clone(),
+            1,
+            IntentScope::ProcessData,
+            Some(1024),
+        );
+        assert_eq!(raw.signing_mode, SigningMode::Raw);
+        assert_eq!(below.signature, raw.signature);
+        assert!(verify(&kp, &raw));
+        assert!(serde_json::to_value(&raw)
+            .unwrap()
+            .get("signing_mode")
+            .is_none());
+
+        let hashed =
+            to_signed_response_with_threshold(&kp, payload, 1, IntentScope::ProcessData, Some(16));
+        assert_eq!(hashed.signing_mode, SigningMode::Blake2b256);
+        assert_ne!(hashed.signature, raw.signature);
+        assert!(verify(&kp, &hashed));
+        assert_eq!(
+            serde_json::to_value(&hashed).unwrap()["signing_mode"],
+            "blake2b256"
+        );
+
+        // Hashed message is the 32 byte digest of the domain and the bcs bytes.
+        let bcs_bytes = bcs::to_bytes(&hashed.response).unwrap();
+        let message = signing_message(SigningMode::Blake2b256, &bcs_bytes);
+        assert_eq!(message.len(), 32);
+        let mut domain_separated = HASHED_SIGNING_DOMAIN.to_vec();
+        domain_separated.extend_from_slice(&bcs_bytes);
+        assert_eq!(
+            message,
+            Blake2b256::digest(&domain_separated).digest.to_vec()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_public_key() {
+        let state = AppState::for_test();
+        let expected = Hex::encode(state.eph_kp.public_key_bytes());
+        let Json(response) = public_key(State(Arc::new(state))).await;
+        assert_eq!(
+            serde_json::to_value(&response).unwrap(),
+            json!({ "scheme": "ed25519", "public_key": expected })
+        );
+
+        let state = AppState {
+            eph_kp: EnclaveKeyPair::generate(KeyScheme::Secp256k1, &mut rand::thread_rng()),
+            ..AppState::for_test()
+        };
+        let expected = Hex::encode(state.eph_kp.public_key_bytes());
+        let Json(response) = public_key(State(Arc::new(state))).await;
+        assert_eq!(response.scheme, "secp256k1");
+        assert_eq!(response.public_key, expected);
+    }
+
+    async fn fingerprint_header(state: AppState) -> String {
+        use axum::routing::post;
+        use tower::ServiceExt;
+
+        let state = Arc::new(state);
+        let app = axum::Router::new()
+            .route("/process_data", post(|| async { "signed" }))
+            .route_layer(axum::middleware::from_fn_with_state(
+                state.clone(),
+                key_fingerprint_header,
+            ))
+            .with_state(state);
+        let response = app
+            .oneshot(
+                axum::http::Request::post("/process_data")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        response.headers()[KEY_FINGERPRINT_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn test_attestation_details_are_mocked_outside_an_enclave() {
+        let state = AppState::for_test();
+        let pk = state.eph_kp.public_key_bytes();
+        let Json(details) = get_attestation_details(State(Arc::new(state)))
+            .await
+            .unwrap();
+        assert!(details.mock);
+        assert_eq!(details.public_key, Hex::encode(&pk));
+        let document = Hex::decode(&details.attestation).unwrap();
+        assert_eq!(document, [MOCK_ATTESTATION_PREFIX, pk.as_slice()].concat());
+        assert_eq!(
+            details.pcrs.keys().copied().collect::<Vec<_>>(),
+            ATTESTED_PCRS
+        );
+        assert!(details.pcrs.values().all(|pcr| *pcr == "00".repeat(48)));
+    }
+
+    #[tokio::test]
+    async fn test_cors_allow_list() {
+        use axum::http::header::{ACCESS_CONTROL_ALLOW_ORIGIN, ORIGIN};
+        use axum::routing::post;
+        use tower::ServiceExt;
+
+        let preflight = |cors: CorsLayer, origin: &'static str, method: &'static str| {
+            let app = axum::Router::new()
+                .route("/process_data", post(|| async { "signed" }))
+                .layer(cors);
+            app.oneshot(
+                axum::http::Request::builder()
+                    .method(Method::OPTIONS)
+                    .uri("/process_data")
+                    .header(ORIGIN, origin)
+                    .header("access-control-request-method", method)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+        let allowed_origin = |response: Response| {
+            response
+                .headers()
+                .get(ACCESS_CONTROL_ALLOW_ORIGIN)
+                .map(|v| v.to_str().unwrap().to_string())
+        };
+
+        let cors = || cors_layer(Some("https://www.perma.ws/, http://localhost:5173")).unwrap();
+        let response = preflight(cors(), "https://www.perma.ws", "POST")
+            .await
+            .unwrap();
+        assert_eq!(
+            allowed_origin(response).as_deref(),
+            Some("https://www.perma.ws")
+        );
+        let response = preflight(cors(), "https://evil.example", "POST")
+            .await
+            .unwrap();
+        assert_eq!(allowed_origin(response), None);
+        let response = preflight(cors(), "http://localhost:5173", "DELETE")
+            .await
+            .unwrap();
+        let methods = response.headers()["access-control-allow-methods"]
+            .to_str()
+            .unwrap();
+        assert!(!methods.contains("DELETE"));
+
+        let response = preflight(cors_layer(None).unwrap(), "https://evil.example", "POST")
+            .await
+            .unwrap();
+        assert_eq!(allowed_origin(response).as_deref(), Some("*"));
+
+        for invalid in ["www.perma.ws", "https://www.perma.ws/app", "ftp://perma.ws"] {
+            assert!(cors_layer(Some(invalid)).is_err(), "{invalid}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_oversized_request_body_is_rejected() {
+        use axum::routing::post;
+        use tower::ServiceExt;
+
+        let app = axum::Router::new()
+            .route(
+                "/process_data",
+                post(|Json(body): Json<Value>| async { Json(body) }),
+            )
+            .layer(axum::middleware::from_fn_with_state(64, limit_request_body));
+        let send = |body: String, announce_length: bool| {
+            let mut request =
+                axum::http::Request::post("/process_data").header(CONTENT_TYPE, "application/json");
+            if announce_length {
+                request = request.header(CONTENT_LENGTH, body.len());
+            }
+            app.clone().oneshot(request.body(Body::from(body)).unwrap())
+        };
+
+        let small = json!({ "payload": { "url": "https://example.com" } }).to_string();
+        assert_eq!(send(small, true).await.unwrap().status(), StatusCode::OK);
+
+        let large = json!({ "payload": { "url": "x".repeat(1024) } }).to_string();
+        for announce_length in [true, false] {
+            let response = send(large.clone(), announce_length).await.unwrap();
+            assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let error: Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(error["error"], "request body is over 64 bytes");
+        }
+    }
+
+    #[test]
+    fn test_readiness() {
+        let (status, Json(response)) = readiness(vec![]);
+        assert_eq!(status, StatusCode::OK);
+        assert!(response.ready);
+
+        let (status, Json(response)) = readiness(vec!["ACCESS_KEY".to_string()]);
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            serde_json::to_value(&response).unwrap(),
+            json!({ "ready": false, "missing": ["ACCESS_KEY"] })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_probe_dependency() {
+        use wiremock::matchers::path;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(path("/up"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+        Mock::given(path("/down"))
+            .respond_with(ResponseTemplate::new(502))
+            .mount(&server)
+            .await;
+        let client = http_client();
+        let is_success = |status: reqwest::StatusCode| status.is_success();
+
+        let up = probe_dependency(client.get(format!("{}/up", server.uri())), is_success).await;
+        assert_eq!(up, DependencyStatus::up());
+        let down = probe_dependency(client.get(format!("{}/down", server.uri())), is_success).await;
+        assert_eq!(down, DependencyStatus::down("answered 502 Bad Gateway"));
+        let unreachable = probe_dependency(client.get("http://127.0.0.1:1/"), is_success).await;
+        assert!(!unreachable.ok);
+        assert!(!unreachable.detail.unwrap().contains("127.0.0.1"));
+    }
+
+    #[test]
+    fn test_platform_attestation_is_bundled() {
+        let kp = AppState::for_test().eph_kp;
+        let mut signed = to_signed_response(&kp, vec![1u8, 2, 3], 1, IntentScope::ProcessData);
+        let unbundled = serde_json::to_value(&signed).unwrap();
+        assert!(unbundled.get("platform_attestation").is_none());
+
+        let document = b"\x84\x44\xa1\x01\x38\x22cose sign1 document";
+        attach_platform_attestation(&mut signed, document);
+        let json = serde_json::to_value(&signed).unwrap();
+        assert!(!json["signature"].as_str().unwrap().is_empty());
+        assert_eq!(json["platform_attestation"], Hex::encode(document));
+
+        // The document sits next to the signed payload, not inside it.
+        assert_eq!(json["signature"], unbundled["signature"]);
+        assert!(verify(&kp, &signed));
+    }
+
+    async fn encoded_body(
+        encoding: ResponseEncoding,
+        signed: ProcessedDataResponse<IntentMessage<Vec<u8>>>,
+    ) -> (String, axum::body::Bytes) {
+        let response = Encoded(encoding, signed).into_response();
+        let content_type = response.headers()[CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (content_type, bytes)
+    }
+
+    #[tokio::test]
+    async fn test_cbor_envelope_matches_json() {
+        let kp = AppState::for_test().eph_kp;
+        let signed = to_signed_response(
+            &kp,
+            vec![1u8, 2, 3],
+            1744038900000,
+            IntentScope::ProcessData,
+        );
+        let (content_type, cbor) = encoded_body(ResponseEncoding::Cbor, signed.clone()).await;
+        assert_eq!(content_type, CBOR_CONTENT_TYPE);
+        let (_, json) = encoded_body(ResponseEncoding::Json, signed).await;
+
+        let from_cbor: ProcessedDataResponse<IntentMessage<Vec<u8>>> =
+            serde_cbor::from_slice(&cbor).unwrap();
+        let from_json: ProcessedDataResponse<IntentMessage<Vec<u8>>> =
+            serde_json::from_slice(&json).unwrap();
+        assert_eq!(
+            serde_json::to_value(&from_cbor).unwrap(),
+            serde_json::to_value(&from_json).unwrap()
+        );
+        // The signature is still over the BCS bytes.
+        assert!(verify(&kp, &from_cbor));
+    }
+
+    #[test]
+    fn test_negotiate_encoding() {
+        let accept = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(ACCEPT, HeaderValue::from_str(value).unwrap());
+            headers
+        };
+        let none = EncodingQuery::default();
+        assert_eq!(
+            ResponseEncoding::negotiate(&none, &HeaderMap::new()),
+            ResponseEncoding::Json
+        );
+        assert_eq!(
+            ResponseEncoding::negotiate(&none, &accept("application/json, application/cbor;q=0.9")),
+            ResponseEncoding::Cbor
+        );
+        let json = EncodingQuery {
+            encoding: Some(ResponseEncoding::Json),
+        };
+        assert_eq!(
+            ResponseEncoding::negotiate(&json, &accept("application/cbor")),
+            ResponseEncoding::Json
+        );
+    }
+
+    async fn json_body(pretty: bool) -> String {
+        use axum::routing::get;
+        use tower::ServiceExt;
+
+        let app = axum::Router::new().route(
+            "/health_check",
+            get(|| async { Json(json!({ "pk": "ab", "endpoints_status": { "a": true } })) }),
+        );
+        let app = if pretty {
+            app.layer(axum::middleware::from_fn(pretty_json))
+        } else {
+            app
+        };
+        let response = app
+            .oneshot(
+                axum::http::Request::get("/health_check")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_pretty_json() {
+        let pretty = json_body(true).await;
+        assert!(pretty.contains("\n  \"pk\": \"ab\""));
+        let compact = json_body(false).await;
+        assert!(!compact.contains('\n'));
+        assert!(!compact.contains("  "));
+        let parsed: Value = serde_json::from_str(&pretty).unwrap();
+        assert_eq!(parsed, serde_json::from_str::<Value>(&compact).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_key_fingerprint_header_changes_with_key() {
+        let state = AppState::for_test();
+        let expected = key_fingerprint(&state.eph_kp);
+        assert_eq!(expected.len(), 16);
+        assert_eq!(fingerprint_header(state).await, expected);
+
+        // A restarted enclave boots with a new ephemeral key.
+        let rotated = AppState {
+            eph_kp: EnclaveKeyPair::generate(KeyScheme::Ed25519, &mut rand::thread_rng()),
+            ..AppState::for_test()
+        };
+        let rotated_fingerprint = fingerprint_header(rotated).await;
+        assert_eq!(rotated_fingerprint.len(), 16);
+        assert_ne!(rotated_fingerprint, expected);
+    }
+
+    #[tokio::test]
+    async fn test_request_id_is_echoed() {
+        use axum::routing::get;
+        use tower::ServiceExt;
+
+        let app = axum::Router::new()
+            .route(
+                "/health_check",
+                get(|headers: HeaderMap| async move {
+                    headers[REQUEST_ID_HEADER].to_str().unwrap().to_string()
+                }),
+            )
+            .layer(axum::middleware::from_fn(request_id));
+        let send = |id: Option<&str>| {
+            let mut request = axum::http::Request::get("/health_check");
+            if let Some(id) = id {
+                request = request.header(REQUEST_ID_HEADER, id);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        let response = send(Some("client-42")).await.unwrap();
+        assert_eq!(response.headers()[REQUEST_ID_HEADER], "client-42");
+
+        let response = send(None).await.unwrap();
+        let generated = response.headers()[REQUEST_ID_HEADER].to_str().unwrap();
+        assert!(uuid::Uuid::parse_str(generated).is_ok());
+        let seen = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(seen, generated.as_bytes());
+
+        let too_long = "a".repeat(MAX_REQUEST_ID_LEN + 1);
+        let response = send(Some(&too_long)).await.unwrap();
+        assert_ne!(response.headers()[REQUEST_ID_HEADER], too_long.as_str());
+    }
 }
@@ -0,0 +1,108 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use chrono::{DateTime, Utc};
+use reqwest::header::HeaderValue;
+use std::time::Duration;
+
+/// Total length from a Content-Range value, e.g. 44941 for `bytes 0-0/44941`
+/// or `bytes */44941`. Servers that leave out the space after the unit, as
+/// in `bytes=0-0/44941`, are accepted too. `None` if the total is unknown
+/// (`bytes 0-0/*`) or the value is malformed.
+pub fn parse_content_range_total(value: &HeaderValue) -> Option<usize> {
+    let value = value.to_str().ok()?.trim();
+    let unit = value.get(..5)?;
+    if !unit.eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    let range = value[5..].trim_start_matches([' ', '=']);
+    if !range.starts_with(|c: char| c.is_ascii_digit() || c == '*') {
+        return None;
+    }
+    let (_, total) = range.rsplit_once('/')?;
+    total.trim().parse::<usize>().ok()
+}
+
+/// Entity tag of an ETag value, without the weak `W/` prefix and the
+/// surrounding quotes, e.g. `abc` for `W/"abc"`.
+pub fn normalize_etag(value: &HeaderValue) -> String {
+    let etag = String::from_utf8_lossy(value.as_bytes());
+    let etag = etag.trim();
+    let etag = etag
+        .strip_prefix("W/")
+        .or_else(|| etag.strip_prefix("w/"))
+        .unwrap_or(etag);
+    etag.strip_prefix('"')
+        .and_then(|etag| etag.strip_suffix('"'))
+        .unwrap_or(etag)
+        .to_string()
+}
+
+/// Delay asked for by a Retry-After value, either seconds (`120`) or an
+/// HTTP-date (`Wed, 21 Oct 2015 07:28:00 GMT`) relative to `now`. A date in
+/// the past is no delay. `None` if the value is malformed.
+pub fn parse_retry_after(value: &HeaderValue, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = DateTime::parse_from_rfc2822(value).ok()?;
+    Some((at.with_timezone(&Utc) - now).to_std().unwrap_or_default())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn total(value: &str) -> Option<usize> {
+        parse_content_range_total(&HeaderValue::from_str(value).unwrap())
+    }
+
+    fn etag(value: &str) -> String {
+        normalize_etag(&HeaderValue::from_str(value).unwrap())
+    }
+
+    #[test]
+    fn test_parse_content_range_total() {
+        assert_eq!(total("bytes 0-0/44941"), Some(44941));
+        assert_eq!(total("Bytes 0-0/44941 "), Some(44941));
+        assert_eq!(total("bytes=0-0/44941"), Some(44941));
+        assert_eq!(total("bytes0-0/44941"), Some(44941));
+        assert_eq!(total("bytes */44941"), Some(44941));
+        assert_eq!(total("bytes 0-0/*"), None);
+        assert_eq!(total("bytes 0-0"), None);
+        assert_eq!(total("bytes 0-0/abc"), None);
+        assert_eq!(total("items 0-0/10"), None);
+        assert_eq!(total("bytesx 0-0/10"), None);
+        assert_eq!(total("byte"), None);
+        assert_eq!(total(""), None);
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = DateTime::parse_from_rfc2822("Wed, 01 Jan 2025 12:00:00 GMT")
+            .unwrap()
+            .with_timezone(&Utc);
+        let delay = |value: &str| parse_retry_after(&HeaderValue::from_str(value).unwrap(), now);
+        assert_eq!(delay("120"), Some(Duration::from_secs(120)));
+        assert_eq!(delay(" 0 "), Some(Duration::ZERO));
+        assert_eq!(
+            delay("Wed, 01 Jan 2025 12:00:30 GMT"),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(delay("Wed, 01 Jan 2025 11:00:00 GMT"), Some(Duration::ZERO));
+        assert_eq!(delay("-1"), None);
+        assert_eq!(delay("soon"), None);
+    }
+
+    #[test]
+    fn test_normalize_etag() {
+        assert_eq!(etag("abc"), "abc");
+        assert_eq!(etag("\"abc\""), "abc");
+        assert_eq!(etag("W/\"abc\""), "abc");
+        assert_eq!(etag(" w/\"abc\" "), "abc");
+        assert_eq!(etag("\"\""), "");
+        assert_eq!(etag("\"abc"), "\"abc");
+        assert_eq!(etag(""), "");
+    }
+}
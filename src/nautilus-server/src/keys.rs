@@ -0,0 +1,188 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use fastcrypto::ed25519::{Ed25519KeyPair, Ed25519Signature};
+use fastcrypto::encoding::{Encoding, Hex};
+use fastcrypto::hash::{Blake2b256, HashFunction};
+use fastcrypto::secp256k1::{Secp256k1KeyPair, Secp256k1Signature};
+use fastcrypto::secp256r1::{Secp256r1KeyPair, Secp256r1Signature};
+use fastcrypto::traits::{AllowedRng, KeyPair, Signer, ToFromBytes, VerifyingKey};
+use std::fmt;
+use std::str::FromStr;
+
+/// Signature scheme of the enclave key (`KEY_SCHEME`), `Ed25519` unless
+/// overridden.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeyScheme {
+    #[default]
+    Ed25519,
+    Secp256k1,
+    Secp256r1,
+}
+
+impl KeyScheme {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyScheme::Ed25519 => "ed25519",
+            KeyScheme::Secp256k1 => "secp256k1",
+            KeyScheme::Secp256r1 => "secp256r1",
+        }
+    }
+
+    /// Sui signature scheme flag, the first byte hashed into an address.
+    pub fn flag(&self) -> u8 {
+        match self {
+            KeyScheme::Ed25519 => 0x00,
+            KeyScheme::Secp256k1 => 0x01,
+            KeyScheme::Secp256r1 => 0x02,
+        }
+    }
+}
+
+impl fmt::Display for KeyScheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for KeyScheme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "ed25519" => Ok(KeyScheme::Ed25519),
+            "secp256k1" => Ok(KeyScheme::Secp256k1),
+            "secp256r1" => Ok(KeyScheme::Secp256r1),
+            other => Err(format!(
+                "unknown key scheme {other}, expected ed25519, secp256k1 or secp256r1"
+            )),
+        }
+    }
+}
+
+/// Keypair the enclave signs responses with, of any supported [`KeyScheme`].
+/// Ed25519 signs the message as is, the ECDSA schemes sign its SHA-256 hash
+/// like Sui's `ecdsa_k1`/`ecdsa_r1` verifiers with the SHA-256 hash flag.
+pub enum EnclaveKeyPair {
+    Ed25519(Ed25519KeyPair),
+    Secp256k1(Secp256k1KeyPair),
+    Secp256r1(Secp256r1KeyPair),
+}
+
+impl EnclaveKeyPair {
+    pub fn generate<R: AllowedRng>(scheme: KeyScheme, rng: &mut R) -> Self {
+        match scheme {
+            KeyScheme::Ed25519 => EnclaveKeyPair::Ed25519(Ed25519KeyPair::generate(rng)),
+            KeyScheme::Secp256k1 => EnclaveKeyPair::Secp256k1(Secp256k1KeyPair::generate(rng)),
+            KeyScheme::Secp256r1 => EnclaveKeyPair::Secp256r1(Secp256r1KeyPair::generate(rng)),
+        }
+    }
+
+    pub fn scheme(&self) -> KeyScheme {
+        match self {
+            EnclaveKeyPair::Ed25519(_) => KeyScheme::Ed25519,
+            EnclaveKeyPair::Secp256k1(_) => KeyScheme::Secp256k1,
+            EnclaveKeyPair::Secp256r1(_) => KeyScheme::Secp256r1,
+        }
+    }
+
+    /// Public key bytes, compressed for the ECDSA schemes.
+    pub fn public_key_bytes(&self) -> Vec<u8> {
+        match self {
+            EnclaveKeyPair::Ed25519(kp) => kp.public().as_bytes().to_vec(),
+            EnclaveKeyPair::Secp256k1(kp) => kp.public().as_bytes().to_vec(),
+            EnclaveKeyPair::Secp256r1(kp) => kp.public().as_bytes().to_vec(),
+        }
+    }
+
+    /// Signature bytes over `msg`.
+    pub fn sign(&self, msg: &[u8]) -> Vec<u8> {
+        match self {
+            EnclaveKeyPair::Ed25519(kp) => kp.sign(msg).as_ref().to_vec(),
+            EnclaveKeyPair::Secp256k1(kp) => kp.sign(msg).as_ref().to_vec(),
+            EnclaveKeyPair::Secp256r1(kp) => kp.sign(msg).as_ref().to_vec(),
+        }
+    }
+
+    /// Check that `signature` is this key's signature over `msg`.
+    pub fn verify(&self, msg: &[u8], signature: &[u8]) -> Result<(), String> {
+        let invalid = |e: fastcrypto::error::FastCryptoError| format!("invalid signature: {e}");
+        let mismatch = |_| "signature does not match the response".to_string();
+        match self {
+            EnclaveKeyPair::Ed25519(kp) => kp
+                .public()
+                .verify(
+                    msg,
+                    &Ed25519Signature::from_bytes(signature).map_err(invalid)?,
+                )
+                .map_err(mismatch),
+            EnclaveKeyPair::Secp256k1(kp) => kp
+                .public()
+                .verify(
+                    msg,
+                    &Secp256k1Signature::from_bytes(signature).map_err(invalid)?,
+                )
+                .map_err(mismatch),
+            EnclaveKeyPair::Secp256r1(kp) => kp
+                .public()
+                .verify(
+                    msg,
+                    &Secp256r1Signature::from_bytes(signature).map_err(invalid)?,
+                )
+                .map_err(mismatch),
+        }
+    }
+
+    /// The Ed25519 keypair, for callers that need its private key bytes.
+    pub fn as_ed25519(&self) -> Option<&Ed25519KeyPair> {
+        match self {
+            EnclaveKeyPair::Ed25519(kp) => Some(kp),
+            _ => None,
+        }
+    }
+
+    /// Sui address of the public key: `0x` and the hex
+    /// `blake2b256(flag || public key)`, see [`KeyScheme::flag`].
+    pub fn sui_address(&self) -> String {
+        let mut hasher = Blake2b256::default();
+        hasher.update([self.scheme().flag()]);
+        hasher.update(self.public_key_bytes());
+        format!("0x{}", Hex::encode(hasher.finalize().digest))
+    }
+}
+
+impl From<Ed25519KeyPair> for EnclaveKeyPair {
+    fn from(kp: Ed25519KeyPair) -> Self {
+        EnclaveKeyPair::Ed25519(kp)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn test_key_schemes_sign_and_verify() {
+        for scheme in [
+            KeyScheme::Ed25519,
+            KeyScheme::Secp256k1,
+            KeyScheme::Secp256r1,
+        ] {
+            let kp = EnclaveKeyPair::generate(scheme, &mut StdRng::from_seed([7; 32]));
+            assert_eq!(kp.scheme(), scheme);
+            assert_eq!(scheme.as_str().parse::<KeyScheme>().unwrap(), scheme);
+
+            let signature = kp.sign(b"perma");
+            assert!(kp.verify(b"perma", &signature).is_ok());
+            assert_eq!(
+                kp.verify(b"other", &signature).unwrap_err(),
+                "signature does not match the response"
+            );
+            assert!(kp.verify(b"perma", &[0u8; 3]).is_err());
+            assert_eq!(kp.sui_address().len(), 66);
+        }
+        assert_eq!(" SECP256K1 ".parse::<KeyScheme>(), Ok(KeyScheme::Secp256k1));
+        assert!("rsa".parse::<KeyScheme>().is_err());
+    }
+}
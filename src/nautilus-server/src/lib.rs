@@ -1,12 +1,13 @@
 // Copyright (c), Mysten Labs, Inc.
 // SPDX-License-Identifier: Apache-2.0
 
-use axum::http::StatusCode;
+use api_keys::ApiKeyPool;
+use axum::http::{header, StatusCode};
 use axum::response::IntoResponse;
 use axum::response::Response;
 use axum::Json;
-use fastcrypto::ed25519::Ed25519KeyPair;
 use serde_json::json;
+use std::collections::HashMap;
 use std::fmt;
 
 mod apps {
@@ -41,26 +42,135 @@ pub mod app {
     pub use crate::apps::perma_ws::*;
 }
 
+pub mod api_keys;
+pub mod client;
 pub mod common;
+pub mod http_util;
+pub mod keys;
+pub mod rate_limit;
+pub mod telemetry;
+
+#[cfg(feature = "archive")]
+pub mod archive;
+
+#[cfg(feature = "archive")]
+pub mod tsa;
+
+#[cfg(feature = "metrics")]
+pub mod metrics;
 
 /// App state, at minimum needs to maintain the ephemeral keypair.  
 pub struct AppState {
-    /// Ephemeral keypair on boot
-    pub eph_kp: Ed25519KeyPair,
-    /// API key when querying api.weatherapi.com
-    pub api_key: String,
+    /// Ephemeral keypair on boot, of the `KEY_SCHEME` scheme
+    pub eph_kp: keys::EnclaveKeyPair,
+    /// API keys when querying api.weatherapi.com, rotated per request
+    pub api_keys: ApiKeyPool,
+    /// HTTP client shared by all outbound requests, so connections are pooled
+    /// across requests instead of each one paying for a new TLS handshake.
+    pub http: reqwest::Client,
+    /// Intent scope `process_data` responses are signed with
+    /// (`INTENT_SCOPE`), `ProcessData` unless overridden.
+    pub intent_scope: common::IntentScope,
+    /// Secrets loaded at runtime by name, e.g. by the seal example's
+    /// `complete_parameter_load`, see [`AppState::secret`].
+    pub secrets: tokio::sync::RwLock<HashMap<String, String>>,
+    /// perma-ws configuration and runtime state
+    #[cfg(feature = "perma-ws")]
+    pub perma: app::PermaState,
+}
+
+#[cfg(test)]
+impl AppState {
+    /// Seed of the [`AppState::for_test`] keypair.
+    pub const TEST_SEED: [u8; 32] = [7; 32];
+
+    /// App state for tests, see [`AppState::new_for_test`], with the
+    /// [`AppState::TEST_SEED`] keypair.
+    pub fn for_test() -> Self {
+        Self::new_for_test(Self::TEST_SEED)
+    }
+
+    /// App state for tests: an Ed25519 keypair whose RFC 8032 private key is
+    /// `seed`, so signatures are reproducible byte for byte by any Ed25519
+    /// implementation, and default configuration for the compiled app. Only
+    /// compiled in tests, the server always signs with a random key.
+    pub fn new_for_test(seed: [u8; 32]) -> Self {
+        use fastcrypto::ed25519::{Ed25519KeyPair, Ed25519PrivateKey};
+        use fastcrypto::traits::ToFromBytes;
+
+        let private_key = Ed25519PrivateKey::from_bytes(&seed).expect("32 bytes are a valid key");
+        Self {
+            eph_kp: keys::EnclaveKeyPair::Ed25519(Ed25519KeyPair::from(private_key)),
+            api_keys: ApiKeyPool::parse("test-api-key"),
+            http: common::http_client(),
+            intent_scope: common::IntentScope::ProcessData,
+            secrets: Default::default(),
+            #[cfg(feature = "perma-ws")]
+            perma: app::PermaState::default(),
+        }
+    }
+}
+
+impl AppState {
+    /// Runtime secret loaded under `name`, if any.
+    pub async fn secret(&self, name: &str) -> Option<String> {
+        self.secrets.read().await.get(name).cloned()
+    }
 }
 
 /// Implement IntoResponse for EnclaveError.
 impl IntoResponse for EnclaveError {
     fn into_response(self) -> Response {
+        let mut retry_after = None;
+        let mut running_reference_id = None;
         let (status, error_message) = match self {
             EnclaveError::GenericError(e) => (StatusCode::BAD_REQUEST, e),
+            EnclaveError::BadRequest(e) => (StatusCode::BAD_REQUEST, e),
+            EnclaveError::Upstream(e) => (StatusCode::BAD_GATEWAY, e),
+            EnclaveError::Timeout(e) => (StatusCode::GATEWAY_TIMEOUT, e),
+            EnclaveError::Internal(e) => (StatusCode::INTERNAL_SERVER_ERROR, e),
+            EnclaveError::Unauthorized(e) => (StatusCode::UNAUTHORIZED, e),
+            EnclaveError::NotFound(e) => (StatusCode::NOT_FOUND, e),
+            EnclaveError::Stale(e) => (StatusCode::UNPROCESSABLE_ENTITY, e),
+            EnclaveError::AlreadyRunning { ref reference_id } => {
+                running_reference_id = Some(reference_id.clone());
+                (StatusCode::CONFLICT, self.to_string())
+            }
+            e @ EnclaveError::PayloadTooLarge { .. } => {
+                (StatusCode::PAYLOAD_TOO_LARGE, e.to_string())
+            }
+            e @ EnclaveError::ResponseTooLarge { .. } => (StatusCode::BAD_GATEWAY, e.to_string()),
+            EnclaveError::Unavailable {
+                reason,
+                retry_after_secs,
+            } => {
+                retry_after = Some(retry_after_secs);
+                (StatusCode::SERVICE_UNAVAILABLE, reason)
+            }
+            EnclaveError::RateLimited {
+                reason,
+                retry_after_secs,
+            } => {
+                retry_after = Some(retry_after_secs);
+                (StatusCode::TOO_MANY_REQUESTS, reason)
+            }
         };
-        let body = Json(json!({
-            "error": error_message,
-        }));
-        (status, body).into_response()
+        let body = match running_reference_id {
+            Some(reference_id) => Json(json!({
+                "error": error_message,
+                "reference_id": reference_id,
+            })),
+            None => Json(json!({
+                "error": error_message,
+            })),
+        };
+        let mut response = (status, body).into_response();
+        if let Some(secs) = retry_after {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, secs.into());
+        }
+        response
     }
 }
 
@@ -68,14 +178,203 @@ impl IntoResponse for EnclaveError {
 #[derive(Debug)]
 pub enum EnclaveError {
     GenericError(String),
+    /// The request failed validation.
+    BadRequest(String),
+    /// An upstream service returned an unusable response or couldn't be
+    /// reached.
+    Upstream(String),
+    /// An upstream service didn't answer in time.
+    Timeout(String),
+    /// The server is misconfigured or failed on its own, e.g. a missing
+    /// secret.
+    Internal(String),
+    /// The caller is not allowed to perform the request.
+    Unauthorized(String),
+    /// The requested resource doesn't exist.
+    NotFound(String),
+    /// The target answered with content older than the freshness window.
+    Stale(String),
+    /// Scooper is already archiving the URL, under `reference_id`, which the
+    /// client can poll instead of retrying.
+    AlreadyRunning {
+        reference_id: String,
+    },
+    /// The request body went over `MAX_REQUEST_BODY_BYTES`.
+    PayloadTooLarge {
+        limit: usize,
+    },
+    /// An upstream response body went over the configured size limit.
+    ResponseTooLarge {
+        upstream: String,
+        limit: usize,
+    },
+    /// The server is at capacity, the client should retry after the given delay.
+    Unavailable {
+        reason: String,
+        retry_after_secs: u64,
+    },
+    /// The caller used up its allowance, it may retry after the given delay.
+    RateLimited {
+        reason: String,
+        retry_after_secs: u64,
+    },
 }
 
 impl fmt::Display for EnclaveError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             EnclaveError::GenericError(e) => write!(f, "{e}"),
+            EnclaveError::BadRequest(e) => write!(f, "bad request: {e}"),
+            EnclaveError::Upstream(e) => write!(f, "upstream error: {e}"),
+            EnclaveError::Timeout(e) => write!(f, "timeout: {e}"),
+            EnclaveError::Internal(e) => write!(f, "internal error: {e}"),
+            EnclaveError::Unauthorized(e) => write!(f, "unauthorized: {e}"),
+            EnclaveError::NotFound(e) => write!(f, "not found: {e}"),
+            EnclaveError::Stale(e) => write!(f, "stale content: {e}"),
+            EnclaveError::AlreadyRunning { reference_id } => {
+                write!(
+                    f,
+                    "already running: the URL is being archived as {reference_id}"
+                )
+            }
+            EnclaveError::PayloadTooLarge { limit } => {
+                write!(f, "request body is over {limit} bytes")
+            }
+            EnclaveError::ResponseTooLarge { upstream, limit } => {
+                write!(
+                    f,
+                    "upstream error: {upstream} response is over {limit} bytes"
+                )
+            }
+            EnclaveError::Unavailable {
+                reason,
+                retry_after_secs,
+            } => write!(f, "unavailable: {reason}, retry after {retry_after_secs}s"),
+            EnclaveError::RateLimited {
+                reason,
+                retry_after_secs,
+            } => write!(f, "rate limited: {reason}, retry after {retry_after_secs}s"),
         }
     }
 }
 
 impl std::error::Error for EnclaveError {}
+
+impl EnclaveError {
+    /// Classify a failed upstream call: a timeout is [`EnclaveError::Timeout`],
+    /// anything else (connect, status, body) is [`EnclaveError::Upstream`].
+    /// `context` says which call failed.
+    pub fn from_reqwest(context: &str, e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            EnclaveError::Timeout(format!("{context}: {e}"))
+        } else {
+            EnclaveError::Upstream(format!("{context}: {e}"))
+        }
+    }
+}
+
+impl From<reqwest::Error> for EnclaveError {
+    fn from(e: reqwest::Error) -> Self {
+        let context = if e.is_connect() {
+            "Failed to connect"
+        } else {
+            "Upstream request failed"
+        };
+        EnclaveError::from_reqwest(context, e)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_for_test_is_deterministic() {
+        let a = AppState::for_test();
+        let b = AppState::for_test();
+        assert_eq!(a.eph_kp.public_key_bytes(), b.eph_kp.public_key_bytes());
+    }
+
+    #[test]
+    fn test_seeded_keypair_signature() {
+        use fastcrypto::encoding::{Encoding, Hex};
+
+        let kp = AppState::new_for_test(AppState::TEST_SEED).eph_kp;
+        assert_eq!(
+            Hex::encode(kp.public_key_bytes()),
+            "ea4a6c63e29c520abef5507b132ec5f9954776aebebe7b92421eea691446d22c"
+        );
+        let signed = common::to_signed_response(
+            &kp,
+            "hello".to_string(),
+            1744038900000,
+            common::IntentScope::ProcessData,
+        );
+        assert_eq!(
+            signed.response_bcs.as_deref(),
+            Some("0020b1d110960100000568656c6c6f")
+        );
+        assert_eq!(
+            signed.signature,
+            "6c1260e49edba95caeabf48749275c1e102c67e06f539978ec3bc35bf8a88743\
+             b058fd2068c6e9b545aabe7133d8ba60de7c1850c31b3ff9825e9fc00bc77507"
+        );
+        let signature = Hex::decode(&signed.signature).unwrap();
+        let bcs = Hex::decode(signed.response_bcs.as_deref().unwrap()).unwrap();
+        assert!(kp.verify(&bcs, &signature).is_ok());
+
+        let other = AppState::new_for_test([8; 32]).eph_kp;
+        assert_ne!(other.public_key_bytes(), kp.public_key_bytes());
+        assert!(other.verify(&bcs, &signature).is_err());
+    }
+
+    #[test]
+    fn test_error_status_codes() {
+        let status = |e: EnclaveError| e.into_response().status();
+        let message = || "x".to_string();
+        assert_eq!(status(EnclaveError::BadRequest(message())), 400);
+        assert_eq!(status(EnclaveError::Upstream(message())), 502);
+        assert_eq!(status(EnclaveError::Timeout(message())), 504);
+        assert_eq!(status(EnclaveError::NotFound(message())), 404);
+        assert_eq!(status(EnclaveError::Internal(message())), 500);
+        assert_eq!(status(EnclaveError::PayloadTooLarge { limit: 1 }), 413);
+        assert_eq!(
+            status(EnclaveError::AlreadyRunning {
+                reference_id: message()
+            }),
+            409
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reqwest_errors_are_classified() {
+        use std::time::Duration;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(wiremock::matchers::any())
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(2)))
+            .mount(&server)
+            .await;
+        let timeout = reqwest::Client::new()
+            .get(server.uri())
+            .timeout(Duration::from_millis(100))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            EnclaveError::from(timeout),
+            EnclaveError::Timeout(_)
+        ));
+
+        // Nothing listens on a port just released.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let refused = reqwest::get(format!("http://{addr}")).await.unwrap_err();
+        assert!(matches!(
+            EnclaveError::from(refused),
+            EnclaveError::Upstream(_)
+        ));
+    }
+}
@@ -2,57 +2,371 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use anyhow::Result;
-use axum::{routing::get, routing::post, Router};
-use fastcrypto::{ed25519::Ed25519KeyPair, traits::KeyPair};
-use nautilus_server::app::process_data;
-use nautilus_server::common::{get_attestation, health_check};
+use axum::extract::{Query, State};
+use axum::http::HeaderName;
+use axum::response::IntoResponse;
+use axum::routing::MethodRouter;
+use axum::{middleware, routing::get, routing::post, Router};
+use nautilus_server::api_keys::ApiKeyPool;
+use nautilus_server::app::{dependency_checks, missing_prerequisites, process_data, SignedPayload};
+use nautilus_server::common::{
+    cors_layer, get_attestation, get_attestation_details, health_report, http_client,
+    key_fingerprint_header, limit_request_body, listen_addr, pretty_json, public_key, readiness,
+    redact, request_id, shutdown_signal, startup_banner, verify_signature, HealthCheckQuery,
+    IntentScope, DEFAULT_MAX_REQUEST_BODY_BYTES, DEFAULT_PORT, DEFAULT_SHUTDOWN_DRAIN_TIMEOUT,
+};
+use nautilus_server::keys::{EnclaveKeyPair, KeyScheme};
+use nautilus_server::rate_limit::{
+    limit_client_rate, ClientRateLimiter, DEFAULT_CLIENT_RATE_BURST,
+};
+use nautilus_server::telemetry::init_tracing;
 use nautilus_server::AppState;
+use std::future::IntoFuture;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tower_http::cors::{Any, CorsLayer};
+use std::time::Duration;
+use tokio::sync::Notify;
 use tracing::info;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let eph_kp = Ed25519KeyPair::generate(&mut rand::thread_rng());
+    // Built with `otel`, set OTEL_EXPORTER_OTLP_ENDPOINT to also export spans.
+    let telemetry = init_tracing()?;
+
+    // Set KEY_SCHEME to `secp256k1` or `secp256r1` for integrations that
+    // expect ECDSA attestations. The Move verifier must check the same scheme.
+    let key_scheme = match std::env::var("KEY_SCHEME") {
+        Ok(v) if !v.trim().is_empty() => v
+            .parse::<KeyScheme>()
+            .map_err(|e| anyhow::anyhow!("Invalid KEY_SCHEME: {e}"))?,
+        _ => KeyScheme::default(),
+    };
+    let eph_kp = EnclaveKeyPair::generate(key_scheme, &mut rand::thread_rng());
 
     // This API_KEY value can be stored with secret-manager. To do that, follow the prompt `sh configure_enclave.sh`
     // Answer `y` to `Do you want to use a secret?` and finish. Otherwise, uncomment this code to use a hardcoded value.
     // let api_key = "045a27812dbe456392913223221306".to_string();
     #[cfg(not(feature = "seal-example"))]
-    let api_key = std::env::var("API_KEY").expect("API_KEY must be set");
+    // Several comma-separated keys are rotated per request, spreading the provider's rate limit.
+    let api_keys = ApiKeyPool::parse(&std::env::var("API_KEY").expect("API_KEY must be set"));
 
     // NOTE: if built with `seal-example` flag the `process_data` does not use this api_key from AppState, instead
     // it uses SEAL_API_KEY initialized with two phase bootstrap. Modify this as needed for your application.
     #[cfg(feature = "seal-example")]
-    let api_key = String::new();
+    let api_keys = ApiKeyPool::parse("");
+
+    // Set INTENT_SCOPE (e.g. `process_data` or `0`) to sign with another scope
+    // than the app's. It changes the signed bytes, so verifiers must expect it.
+    let intent_scope = match std::env::var("INTENT_SCOPE") {
+        Ok(v) if !v.trim().is_empty() => v
+            .parse::<IntentScope>()
+            .map_err(|e| anyhow::anyhow!("Invalid INTENT_SCOPE: {e}"))?,
+        _ => IntentScope::ProcessData,
+    };
+
+    let state = Arc::new(AppState {
+        eph_kp,
+        api_keys,
+        http: http_client(),
+        intent_scope,
+        secrets: Default::default(),
+        #[cfg(feature = "perma-ws")]
+        perma: nautilus_server::app::PermaState::from_env()?,
+    });
 
-    let state = Arc::new(AppState { eph_kp, api_key });
+    #[allow(unused_mut)]
+    let mut bind_addrs = Vec::new();
 
     // Spawn host-only init server if seal-example feature is enabled
     #[cfg(feature = "seal-example")]
     {
-        nautilus_server::app::spawn_host_init_server(state.clone()).await?;
+        let host_addr = nautilus_server::app::spawn_host_init_server(state.clone()).await?;
+        bind_addrs.push(host_addr.to_string());
     }
 
-    // Define your own restricted CORS policy here if needed.
-    let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods(Any)
-        .allow_headers(Any);
-    
-    let app = Router::new()
-        .route("/", get(ping))
+    // Spawn host-only admin server if perma-ws feature is enabled
+    #[cfg(feature = "perma-ws")]
+    {
+        let host_addr = nautilus_server::app::spawn_host_admin_server(state.clone()).await?;
+        bind_addrs.push(host_addr.to_string());
+
+        // Retry the attestations the frontend failed to save until it's back.
+        let retry_interval = state.perma.config.attestation_retry_interval_secs;
+        if retry_interval > 0 {
+            nautilus_server::app::attestation::spawn_attestation_retry(
+                state.clone(),
+                Duration::from_secs(retry_interval),
+            );
+        }
+    }
+
+    // Set KEY_FINGERPRINT_HEADER=false to leave out the key fingerprint header.
+    let key_fingerprint_header_enabled = std::env::var("KEY_FINGERPRINT_HEADER")
+        .map(|v| v != "false")
+        .unwrap_or(true);
+
+    // Set PRETTY_JSON=true to indent JSON responses while debugging.
+    let pretty_json_enabled = std::env::var("PRETTY_JSON")
+        .map(|v| v == "true")
+        .unwrap_or(false);
+
+    // Set SHUTDOWN_DRAIN_TIMEOUT_SECS to bound how long in-flight requests may
+    // run once a shutdown signal arrived.
+    let drain_timeout = match std::env::var("SHUTDOWN_DRAIN_TIMEOUT_SECS") {
+        Ok(v) if !v.trim().is_empty() => Duration::from_secs(
+            v.trim()
+                .parse()
+                .map_err(|e| anyhow::anyhow!("Invalid SHUTDOWN_DRAIN_TIMEOUT_SECS: {e}"))?,
+        ),
+        _ => DEFAULT_SHUTDOWN_DRAIN_TIMEOUT,
+    };
+
+    // Set MAX_REQUEST_BODY_BYTES to change the largest request body accepted.
+    let max_request_body_bytes = match std::env::var("MAX_REQUEST_BODY_BYTES") {
+        Ok(v) if !v.trim().is_empty() => v
+            .trim()
+            .parse()
+            .map_err(|e| anyhow::anyhow!("Invalid MAX_REQUEST_BODY_BYTES: {e}"))?,
+        _ => DEFAULT_MAX_REQUEST_BODY_BYTES,
+    };
+
+    let mut settings = app_settings(&state);
+    settings.push((
+        "key_fingerprint_header",
+        key_fingerprint_header_enabled.to_string(),
+    ));
+    settings.push(("pretty_json", pretty_json_enabled.to_string()));
+    settings.push((
+        "shutdown_drain_timeout_secs",
+        drain_timeout.as_secs().to_string(),
+    ));
+    settings.push(("max_request_body_bytes", max_request_body_bytes.to_string()));
+
+    // Set CORS_ALLOWED_ORIGINS (e.g. `https://www.perma.ws`) to only let
+    // those origins call the enclave from a browser. Unset allows any origin.
+    let cors_allowed_origins = std::env::var("CORS_ALLOWED_ORIGINS").ok();
+    let cors = cors_layer(cors_allowed_origins.as_deref())?;
+    settings.push((
+        "cors_allowed_origins",
+        cors_allowed_origins
+            .filter(|v| !v.trim().is_empty())
+            .unwrap_or_else(|| "*".to_string()),
+    ));
+
+    // Set CLIENT_RATE_LIMIT_PER_MINUTE to cap the requests one client IP may
+    // send to the routes that start work, CLIENT_RATE_BURST to let it burst
+    // above that, and CLIENT_IP_HEADER (e.g. `X-Forwarded-For`) to read the
+    // client IP from the proxy in front of the enclave. Without it, every
+    // request inside the enclave comes from the local forwarder, so the
+    // limit is shared by all clients.
+    let client_rate_limiter = match std::env::var("CLIENT_RATE_LIMIT_PER_MINUTE") {
+        Ok(v) if !v.trim().is_empty() => {
+            let per_minute = v
+                .trim()
+                .parse()
+                .map_err(|e| anyhow::anyhow!("Invalid CLIENT_RATE_LIMIT_PER_MINUTE: {e}"))?;
+            let burst = match std::env::var("CLIENT_RATE_BURST") {
+                Ok(v) if !v.trim().is_empty() => v
+                    .trim()
+                    .parse()
+                    .map_err(|e| anyhow::anyhow!("Invalid CLIENT_RATE_BURST: {e}"))?,
+                _ => DEFAULT_CLIENT_RATE_BURST,
+            };
+            let ip_header: Option<HeaderName> = match std::env::var("CLIENT_IP_HEADER") {
+                Ok(v) if !v.trim().is_empty() => Some(
+                    v.trim()
+                        .parse()
+                        .map_err(|e| anyhow::anyhow!("Invalid CLIENT_IP_HEADER: {e}"))?,
+                ),
+                _ => None,
+            };
+            if ip_header.is_none() {
+                info!(
+                    "CLIENT_IP_HEADER is unset, clients are rate limited by peer address, \
+                     which is the same for all of them behind the enclave's forwarder"
+                );
+            }
+            settings.push((
+                "client_rate_limit",
+                format!(
+                    "{per_minute}/min, burst {burst}, ip from {}",
+                    ip_header.as_ref().map_or("peer", HeaderName::as_str)
+                ),
+            ));
+            Some(Arc::new(ClientRateLimiter::new(
+                per_minute, burst, ip_header,
+            )))
+        }
+        _ => {
+            settings.push(("client_rate_limit", "off".to_string()));
+            None
+        }
+    };
+    let rate_limited = |route: MethodRouter<Arc<AppState>>| match &client_rate_limiter {
+        Some(limiter) => route.layer(middleware::from_fn_with_state(
+            limiter.clone(),
+            limit_client_rate,
+        )),
+        None => route,
+    };
+
+    let process_data_route = rate_limited(post(process_data));
+    // Count process_data responses by outcome for `/metrics`.
+    #[cfg(feature = "metrics")]
+    let process_data_route = process_data_route.layer(middleware::from_fn(
+        nautilus_server::metrics::track_process_data,
+    ));
+
+    // Routes that sign or return the enclave key.
+    let router = Router::new()
         .route("/get_attestation", get(get_attestation))
-        .route("/process_data", post(process_data))
+        .route("/get_attestation_details", get(get_attestation_details))
+        .route("/process_data", process_data_route)
         .route("/health_check", get(health_check))
+        .route("/public_key", get(public_key))
+        .route(
+            "/verify_signature",
+            rate_limited(post(verify_signature::<SignedPayload>)),
+        );
+
+    #[cfg(feature = "perma-ws")]
+    let router = router
+        .route(
+            "/process_data_stream",
+            rate_limited(post(nautilus_server::app::process_data_stream)),
+        )
+        .route(
+            "/process_batch",
+            rate_limited(post(nautilus_server::app::process_batch)),
+        );
+
+    let router = if key_fingerprint_header_enabled {
+        router.route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            key_fingerprint_header,
+        ))
+    } else {
+        router
+    };
+
+    #[cfg(feature = "perma-ws")]
+    let router = router
+        .route(
+            "/validate_request",
+            rate_limited(post(nautilus_server::app::validate_request)),
+        )
+        .route(
+            "/job_status/:reference_id",
+            get(nautilus_server::app::job_status),
+        )
+        .route(
+            "/decode_reference_id/:reference_id",
+            get(nautilus_server::app::decode_reference_id),
+        );
+
+    let router = router.route("/ready", get(ready));
+
+    #[cfg(feature = "metrics")]
+    let router = router.route("/metrics", get(nautilus_server::metrics::metrics));
+
+    // Kept to flush what's left once the server stopped.
+    #[cfg(feature = "perma-ws")]
+    let shutdown_state = state.clone();
+
+    let body_limit = middleware::from_fn_with_state(max_request_body_bytes, limit_request_body);
+    let router = router
+        .route("/", get(ping))
         .with_state(state)
-        .layer(cors);
+        .layer(body_limit);
+    let router = if pretty_json_enabled {
+        router.layer(middleware::from_fn(pretty_json))
+    } else {
+        router
+    };
+    // Tag every request's logs with its X-Request-Id.
+    let app = router.layer(middleware::from_fn(request_id)).layer(cors);
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await?;
+    // Set PORT (e.g. `3100` or `127.0.0.1:3100`) to run several enclaves on one host.
+    let listener = tokio::net::TcpListener::bind(listen_addr("PORT", DEFAULT_PORT)?).await?;
     info!("listening on {}", listener.local_addr().unwrap());
-    axum::serve(listener, app.into_make_service())
-        .await
-        .map_err(|e| anyhow::anyhow!("Server error: {e}"))
+    bind_addrs.push(listener.local_addr()?.to_string());
+    info!(
+        "starting nautilus-server: {}",
+        startup_banner(&bind_addrs, &settings)
+    );
+
+    // On SIGINT/SIGTERM stop accepting connections and let in-flight
+    // requests finish, giving up on them after the drain timeout.
+    let signalled = Arc::new(Notify::new());
+    // Connection info gives the rate limiter the peer IP.
+    let server = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown({
+        let signalled = signalled.clone();
+        async move {
+            shutdown_signal().await;
+            signalled.notify_one();
+        }
+    })
+    .into_future();
+    let drain_deadline = async {
+        signalled.notified().await;
+        tokio::time::sleep(drain_timeout).await;
+    };
+    tokio::select! {
+        result = server => result.map_err(|e| anyhow::anyhow!("Server error: {e}"))?,
+        _ = drain_deadline => info!(
+            "Requests still in flight after {:?}, shutting down anyway",
+            drain_timeout
+        ),
+    }
+
+    // In-flight archives have finished or timed out, save the attestations that failed
+    // to persist before they are lost with the process.
+    #[cfg(feature = "perma-ws")]
+    nautilus_server::app::attestation::flush_unpersisted(
+        &shutdown_state.perma,
+        &shutdown_state.http,
+        std::time::Duration::from_millis(shutdown_state.perma.config.shutdown_flush_timeout_ms),
+    )
+    .await;
+    telemetry.shutdown();
+    Ok(())
+}
+
+/// Redacted settings of the compiled app for the startup banner.
+fn app_settings(state: &AppState) -> Vec<(&'static str, String)> {
+    #[allow(unused_mut)]
+    let mut settings = vec![
+        ("api_key", redact(state.api_keys.first())),
+        ("api_key_count", state.api_keys.len().to_string()),
+        ("intent_scope", state.intent_scope.as_str().to_string()),
+        ("key_scheme", state.eph_kp.scheme().to_string()),
+    ];
+    #[cfg(feature = "perma-ws")]
+    settings.extend(state.perma.config.summary());
+    settings
+}
+
+/// Readiness probe: 503 until the app has what `process_data` needs, e.g.
+/// its secrets or a completed Seal bootstrap.
+async fn ready(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    readiness(missing_prerequisites(&state).await)
+}
+
+/// `/health_check`, probing the app's dependencies for `?deep=true`.
+async fn health_check(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<HealthCheckQuery>,
+) -> impl IntoResponse {
+    let dependencies = if query.deep {
+        Some(dependency_checks(&state).await)
+    } else {
+        None
+    };
+    health_report(&state, dependencies).await
 }
 
 async fn ping() -> &'static str {
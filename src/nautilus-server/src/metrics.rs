@@ -0,0 +1,148 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::EnclaveError;
+use axum::extract::Request;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use prometheus::core::Collector;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use std::time::Duration;
+
+/// App the server was built for, the `app` label of request counters.
+pub const APP: &str = if cfg!(feature = "perma-ws") {
+    "perma-ws"
+} else if cfg!(feature = "seal-example") {
+    "seal-example"
+} else if cfg!(feature = "twitter-example") {
+    "twitter-example"
+} else if cfg!(feature = "weather-example") {
+    "weather-example"
+} else {
+    "none"
+};
+
+/// Upstream latency buckets in seconds, up to ScreenshotOne's 60s capture
+/// timeout and beyond.
+const UPSTREAM_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+
+lazy_static::lazy_static! {
+    /// Registry served on `/metrics`.
+    static ref REGISTRY: Registry = Registry::new();
+
+    static ref PROCESS_DATA_REQUESTS: IntCounterVec = register(
+        IntCounterVec::new(
+            Opts::new(
+                "process_data_requests_total",
+                "process_data requests by app and outcome",
+            ),
+            &["app", "outcome"],
+        )
+        .expect("valid metric"),
+    );
+
+    static ref UPSTREAM_DURATION: HistogramVec = register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "upstream_request_duration_seconds",
+                "Duration of outbound calls, e.g. scooper, screenshotone or attestation",
+            )
+            .buckets(UPSTREAM_BUCKETS.to_vec()),
+            &["call"],
+        )
+        .expect("valid metric"),
+    );
+}
+
+fn register<M: Collector + Clone + 'static>(metric: M) -> M {
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("metric registered once");
+    metric
+}
+
+/// `outcome` label of a response: `success`, `client_error` or
+/// `server_error`.
+fn outcome(status: StatusCode) -> &'static str {
+    if status.is_server_error() {
+        "server_error"
+    } else if status.is_client_error() {
+        "client_error"
+    } else {
+        "success"
+    }
+}
+
+/// Count a `process_data` response by its status.
+pub fn record_process_data(status: StatusCode) {
+    PROCESS_DATA_REQUESTS
+        .with_label_values(&[APP, outcome(status)])
+        .inc();
+}
+
+/// Record how long the outbound `call` took, failed calls included.
+pub fn observe_upstream(call: &str, elapsed: Duration) {
+    UPSTREAM_DURATION
+        .with_label_values(&[call])
+        .observe(elapsed.as_secs_f64());
+}
+
+/// Middleware counting the responses of `/process_data`.
+pub async fn track_process_data(request: Request, next: Next) -> Response {
+    let response = next.run(request).await;
+    record_process_data(response.status());
+    response
+}
+
+/// Endpoint serving the metrics in the Prometheus text format.
+pub async fn metrics() -> Result<impl IntoResponse, EnclaveError> {
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    encoder
+        .encode(&REGISTRY.gather(), &mut body)
+        .map_err(|e| EnclaveError::Internal(format!("Failed to encode metrics: {e}")))?;
+    Ok(([(CONTENT_TYPE, encoder.format_type().to_string())], body))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn scrape() -> String {
+        let response = metrics().await.unwrap().into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_metrics_are_exposed() {
+        let errors = || {
+            PROCESS_DATA_REQUESTS
+                .with_label_values(&[APP, "server_error"])
+                .get()
+        };
+        let before = errors();
+        record_process_data(StatusCode::OK);
+        record_process_data(StatusCode::BAD_REQUEST);
+        record_process_data(StatusCode::BAD_GATEWAY);
+        assert_eq!(errors(), before + 1);
+        observe_upstream("screenshotone", Duration::from_millis(1500));
+
+        let text = scrape().await;
+        for outcome in ["success", "client_error", "server_error"] {
+            assert!(text.contains(&format!(
+                "process_data_requests_total{{app=\"{APP}\",outcome=\"{outcome}\"}}"
+            )));
+        }
+        assert!(text.contains(
+            "upstream_request_duration_seconds_bucket{call=\"screenshotone\",le=\"2.5\"}"
+        ));
+        assert!(text.contains("# TYPE upstream_request_duration_seconds histogram"));
+    }
+}
@@ -0,0 +1,204 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::EnclaveError;
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::{HeaderMap, HeaderName};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::info;
+
+/// Requests a client may burst before being held to the per-minute rate,
+/// unless overridden with `CLIENT_RATE_BURST`.
+pub const DEFAULT_CLIENT_RATE_BURST: u32 = 5;
+
+/// Buckets kept before idle ones, back at full burst, are dropped.
+const MAX_TRACKED_CLIENTS: usize = 4096;
+
+/// Token bucket per client IP (`CLIENT_RATE_LIMIT_PER_MINUTE`,
+/// `CLIENT_RATE_BURST`) for the routes that start work, so one client can't
+/// spend the upstream quotas of everyone. Requests over the rate are refused
+/// with 429 and a `Retry-After` rather than queued.
+#[derive(Debug)]
+pub struct ClientRateLimiter {
+    /// Tokens refilled per second.
+    rate: f64,
+    burst: f64,
+    /// Header holding the client IP when the enclave is reached through a
+    /// proxy (`CLIENT_IP_HEADER`, e.g. `X-Forwarded-For`), whose last address
+    /// is the one the proxy appended. The peer address is used otherwise,
+    /// which inside the enclave is always the local forwarder, so all
+    /// clients then share one bucket.
+    ip_header: Option<HeaderName>,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl ClientRateLimiter {
+    pub fn new(per_minute: u32, burst: u32, ip_header: Option<HeaderName>) -> Self {
+        Self {
+            rate: f64::from(per_minute.max(1)) / 60.0,
+            burst: f64::from(burst.max(1)),
+            ip_header,
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// Take a token for `ip` at `now`, or return how long until one is
+    /// available.
+    fn try_acquire(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().expect("lock poisoned");
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.retain(|_, b| {
+                b.tokens + now.duration_since(b.updated).as_secs_f64() * rate < burst
+            });
+        }
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let refilled = now.duration_since(bucket.updated).as_secs_f64() * self.rate;
+        bucket.tokens = (bucket.tokens + refilled).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    /// Client IP of a request: the last address of the configured header,
+    /// else the peer's. Earlier addresses are whatever the client sent, so
+    /// trusting them would give a client a fresh bucket per request.
+    fn client_ip(&self, headers: &HeaderMap, peer: Option<SocketAddr>) -> Option<IpAddr> {
+        self.ip_header
+            .as_ref()
+            .and_then(|name| headers.get(name))
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.rsplit(',').next())
+            .and_then(|ip| ip.trim().parse().ok())
+            .or(peer.map(|addr| addr.ip()))
+    }
+}
+
+/// Middleware refusing requests over the client's rate with 429, see
+/// [`ClientRateLimiter`]. Requests whose client IP is unknown pass.
+pub async fn limit_client_rate(
+    State(limiter): State<Arc<ClientRateLimiter>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(ip) = limiter.client_ip(request.headers(), peer.map(|ConnectInfo(addr)| addr)) else {
+        return next.run(request).await;
+    };
+    if let Err(wait) = limiter.try_acquire(ip, Instant::now()) {
+        info!("Rate limiting {} on {}", ip, request.uri().path());
+        return EnclaveError::RateLimited {
+            reason: format!("too many requests from {ip}"),
+            retry_after_secs: wait.as_secs_f64().ceil().max(1.0) as u64,
+        }
+        .into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::body::Body;
+    use axum::extract::connect_info::MockConnectInfo;
+    use axum::http::header::RETRY_AFTER;
+    use axum::http::StatusCode;
+    use axum::routing::{get, post};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_client_is_rate_limited() {
+        let limiter = Arc::new(ClientRateLimiter::new(
+            6,
+            3,
+            Some(HeaderName::from_static("x-forwarded-for")),
+        ));
+        let app = axum::Router::new()
+            .route(
+                "/process_data",
+                post(|| async { "signed" }).route_layer(axum::middleware::from_fn_with_state(
+                    limiter,
+                    limit_client_rate,
+                )),
+            )
+            .route("/health_check", get(|| async { "ok" }))
+            .layer(MockConnectInfo(SocketAddr::from(([10, 0, 0, 1], 4000))));
+        let send = |method: &str, uri: &str, forwarded_for: Option<&str>| {
+            let mut request = axum::http::Request::builder().method(method).uri(uri);
+            if let Some(ip) = forwarded_for {
+                request = request.header("x-forwarded-for", ip);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        for _ in 0..3 {
+            let response = send("POST", "/process_data", None).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = send("POST", "/process_data", None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "10");
+
+        // Other clients and unlimited routes are unaffected.
+        let response = send("POST", "/process_data", Some("10.0.0.1, 203.0.113.7"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        for _ in 0..10 {
+            let response = send("GET", "/health_check", None).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+    }
+
+    #[test]
+    fn test_client_ip_is_the_proxy_appended_address() {
+        let limiter =
+            ClientRateLimiter::new(6, 3, Some(HeaderName::from_static("x-forwarded-for")));
+        let peer = Some(SocketAddr::from(([127, 0, 0, 1], 4000)));
+        let client_ip = |forwarded_for: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-forwarded-for", forwarded_for.parse().unwrap());
+            limiter.client_ip(&headers, peer)
+        };
+        // Addresses the client made up before the proxy's are ignored.
+        let expected = Some(IpAddr::from([203, 0, 113, 7]));
+        assert_eq!(client_ip("203.0.113.7"), expected);
+        assert_eq!(client_ip("198.51.100.1, 203.0.113.7"), expected);
+        assert_eq!(client_ip("1.2.3.4,5.6.7.8, 203.0.113.7"), expected);
+        assert_eq!(
+            limiter.client_ip(&HeaderMap::new(), peer),
+            Some(IpAddr::from([127, 0, 0, 1]))
+        );
+    }
+
+    #[test]
+    fn test_tokens_refill() {
+        let limiter = ClientRateLimiter::new(60, 1, None);
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        let now = Instant::now();
+        assert!(limiter.try_acquire(ip, now).is_ok());
+        assert_eq!(limiter.try_acquire(ip, now), Err(Duration::from_secs(1)));
+        assert!(limiter
+            .try_acquire(ip, now + Duration::from_secs(1))
+            .is_ok());
+    }
+}
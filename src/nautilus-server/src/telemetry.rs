@@ -0,0 +1,75 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::Result;
+
+/// Service name of the exported spans.
+#[cfg(feature = "otel")]
+const SERVICE_NAME: &str = "nautilus-server";
+
+/// Keeps the span exporter of [`init_tracing`] alive. Call
+/// [`TelemetryGuard::shutdown`] before exiting to flush the last spans.
+#[derive(Debug, Default)]
+pub struct TelemetryGuard {
+    #[cfg(feature = "otel")]
+    provider: Option<opentelemetry_sdk::trace::TracerProvider>,
+}
+
+impl TelemetryGuard {
+    /// Export the spans still batched, if any.
+    pub fn shutdown(self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = self.provider {
+            if let Err(e) = provider.shutdown() {
+                tracing::info!("Failed to flush spans: {e}");
+            }
+        }
+    }
+}
+
+/// Install the global tracing subscriber, logging to stdout. With the `otel`
+/// feature and `OTEL_EXPORTER_OTLP_ENDPOINT` set (e.g.
+/// `http://collector:4317`), spans are also exported over OTLP/gRPC: each
+/// request is a root span, with a child span per outbound call. Without the
+/// feature this is the plain fmt subscriber.
+pub fn init_tracing() -> Result<TelemetryGuard> {
+    #[cfg(feature = "otel")]
+    if let Ok(endpoint) = std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+        if !endpoint.trim().is_empty() {
+            return init_otlp(endpoint.trim());
+        }
+    }
+    tracing_subscriber::fmt::init();
+    Ok(TelemetryGuard::default())
+}
+
+#[cfg(feature = "otel")]
+fn init_otlp(endpoint: &str) -> Result<TelemetryGuard> {
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{runtime, trace::TracerProvider, Resource};
+    use tracing_subscriber::filter::LevelFilter;
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::util::SubscriberInitExt;
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()
+        .map_err(|e| anyhow::anyhow!("Invalid OTEL_EXPORTER_OTLP_ENDPOINT: {e}"))?;
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new([KeyValue::new("service.name", SERVICE_NAME)]))
+        .build();
+    let tracer = provider.tracer(SERVICE_NAME);
+    tracing_subscriber::registry()
+        .with(LevelFilter::INFO)
+        .with(tracing_subscriber::fmt::layer())
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .try_init()?;
+    tracing::info!("Exporting spans to {endpoint}");
+    Ok(TelemetryGuard {
+        provider: Some(provider),
+    })
+}
@@ -0,0 +1,269 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::common::{IntentMessage, ProcessedDataResponse};
+use crate::EnclaveError;
+use fastcrypto::encoding::{Base64, Encoding};
+use rand::RngCore;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+use tracing::info;
+
+/// ==== RFC 3161 TIMESTAMPING ====
+/// Independent time attestation for signed responses: a timestamp authority
+/// (TSA) signs the SHA-256 of the BCS encoded intent message, and the token
+/// is attached to the response envelope, outside the enclave signature.
+/// Where and how to request timestamp tokens.
+#[derive(Debug, Clone)]
+pub struct TimestampOptions {
+    pub tsa_url: String,
+    /// Shared client of [`crate::AppState::http`].
+    pub http: reqwest::Client,
+    /// Keep the response without a token when the TSA fails, instead of
+    /// failing the request.
+    pub fail_open: bool,
+    pub timeout: Duration,
+}
+
+/// DER of the SHA-256 AlgorithmIdentifier, OID 2.16.840.1.101.3.4.2.1 with
+/// NULL parameters.
+const SHA256_ALGORITHM_ID: [u8; 15] = [
+    0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01, 0x05, 0x00,
+];
+
+/// DER TimeStampReq (RFC 3161 section 2.4.1) for a SHA-256 digest, with a
+/// nonce and asking for the TSA certificate in the token.
+pub fn timestamp_request(digest: &[u8; 32], nonce: [u8; 8]) -> Vec<u8> {
+    let mut message_imprint = SHA256_ALGORITHM_ID.to_vec();
+    message_imprint.extend_from_slice(&der(0x04, digest));
+
+    let mut body = der(0x02, &[1]); // version v1
+    body.extend(der(0x30, &message_imprint));
+    body.extend(der(0x02, &nonce));
+    body.extend(der(0x01, &[0xff])); // certReq TRUE
+    der(0x30, &body)
+}
+
+/// A positive nonce that encodes as a minimal 8 byte DER INTEGER.
+fn random_nonce() -> [u8; 8] {
+    let mut nonce = [0u8; 8];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    nonce[0] = (nonce[0] & 0x3f) | 0x40;
+    nonce
+}
+
+/// Extract the TimeStampToken from a DER TimeStampResp, failing unless the
+/// status is granted (0) or grantedWithMods (1).
+pub fn parse_timestamp_response(response: &[u8]) -> Result<Vec<u8>, EnclaveError> {
+    let invalid = |what: &str| EnclaveError::Upstream(format!("Invalid TSA response: {what}"));
+    let (tag, body, _) = read_tlv(response).ok_or_else(|| invalid("not DER"))?;
+    if tag != 0x30 {
+        return Err(invalid("not a sequence"));
+    }
+    let (tag, status_info, token) = read_tlv(body).ok_or_else(|| invalid("missing status"))?;
+    if tag != 0x30 {
+        return Err(invalid("status is not a sequence"));
+    }
+    let (tag, status, _) = read_tlv(status_info).ok_or_else(|| invalid("missing status"))?;
+    if tag != 0x02 || status.len() != 1 {
+        return Err(invalid("status is not a small integer"));
+    }
+    if status[0] > 1 {
+        return Err(EnclaveError::Upstream(format!(
+            "TSA rejected the request with status {}",
+            status[0]
+        )));
+    }
+    let (tag, _, _) = read_tlv(token).ok_or_else(|| invalid("missing token"))?;
+    if tag != 0x30 {
+        return Err(invalid("token is not a sequence"));
+    }
+    Ok(token.to_vec())
+}
+
+/// Request a timestamp token for `digest` from the TSA.
+pub async fn request_timestamp_token(
+    options: &TimestampOptions,
+    digest: &[u8; 32],
+) -> Result<Vec<u8>, EnclaveError> {
+    let response = options
+        .http
+        .post(&options.tsa_url)
+        .header("Content-Type", "application/timestamp-query")
+        .timeout(options.timeout)
+        .body(timestamp_request(digest, random_nonce()))
+        .send()
+        .await
+        .map_err(|e| EnclaveError::from_reqwest("Failed to call TSA", e))?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(EnclaveError::Upstream(format!(
+            "TSA returned status {status}"
+        )));
+    }
+    let body = response
+        .bytes()
+        .await
+        .map_err(|e| EnclaveError::Upstream(format!("Failed to read TSA response: {e}")))?;
+    parse_timestamp_response(&body)
+}
+
+/// Attach a base64 timestamp token over the SHA-256 of the BCS encoded
+/// intent message to `signed`. A TSA failure fails the call unless
+/// `fail_open` is set, in which case the response is left without a token.
+pub async fn attach_timestamp_token<T: Serialize>(
+    signed: &mut ProcessedDataResponse<IntentMessage<T>>,
+    options: &TimestampOptions,
+) -> Result<(), EnclaveError> {
+    let bcs_bytes = bcs::to_bytes(&signed.response).expect("should not fail");
+    let digest: [u8; 32] = Sha256::digest(&bcs_bytes).into();
+    match request_timestamp_token(options, &digest).await {
+        Ok(token) => {
+            signed.timestamp_token = Some(Base64::encode(token));
+            Ok(())
+        }
+        Err(e) if options.fail_open => {
+            info!(
+                "Timestamping failed, returning the response without a token: {}",
+                e
+            );
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// DER encode one element.
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|b| *b == 0)
+            .collect();
+        out.push(0x80 | bytes.len() as u8);
+        out.extend(bytes);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+/// Split the first DER element off `input`: its tag, content and the bytes
+/// after it.
+fn read_tlv(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, mut rest) = rest.split_first()?;
+    let len = if first < 0x80 {
+        first as usize
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+        let len = rest[..count]
+            .iter()
+            .fold(0usize, |acc, b| (acc << 8) | *b as usize);
+        rest = &rest[count..];
+        len
+    };
+    if rest.len() < len {
+        return None;
+    }
+    Some((tag, &rest[..len], &rest[len..]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::{to_signed_response, IntentScope};
+    use crate::AppState;
+    use wiremock::matchers::{header, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    /// Granted TimeStampResp carrying `token`.
+    fn granted(token: &[u8]) -> Vec<u8> {
+        let mut body = der(0x30, &der(0x02, &[0]));
+        body.extend_from_slice(token);
+        der(0x30, &body)
+    }
+
+    fn options(server: &MockServer, fail_open: bool) -> TimestampOptions {
+        TimestampOptions {
+            tsa_url: server.uri(),
+            http: reqwest::Client::new(),
+            fail_open,
+            timeout: Duration::from_secs(2),
+        }
+    }
+
+    #[test]
+    fn test_timestamp_request_encoding() {
+        let request = timestamp_request(&[0xab; 32], [0x41; 8]);
+        let (tag, body, rest) = read_tlv(&request).unwrap();
+        assert_eq!((tag, body.len(), rest.len()), (0x30, 67, 0));
+        assert!(request
+            .windows(34)
+            .any(|w| w[..2] == [0x04, 0x20] && w[2..] == [0xab; 32]));
+    }
+
+    #[test]
+    fn test_rejected_status() {
+        let rejection = der(0x30, &der(0x30, &der(0x02, &[2])));
+        assert!(parse_timestamp_response(&rejection).is_err());
+        assert!(parse_timestamp_response(&[0x30, 0x05]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_token_attached_to_response() {
+        let token = der(0x30, b"token");
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header("content-type", "application/timestamp-query"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(granted(&token)))
+            .mount(&server)
+            .await;
+
+        let kp = AppState::for_test().eph_kp;
+        let mut signed = to_signed_response(&kp, vec![1u8, 2, 3], 1, IntentScope::ProcessData);
+        let signature = signed.signature.clone();
+        attach_timestamp_token(&mut signed, &options(&server, false))
+            .await
+            .unwrap();
+
+        assert_eq!(signed.timestamp_token, Some(Base64::encode(&token)));
+        // The token sits next to the signature, which is unchanged.
+        assert_eq!(signed.signature, signature);
+        let json = serde_json::to_value(&signed).unwrap();
+        assert_eq!(json["timestamp_token"], Base64::encode(&token));
+    }
+
+    #[tokio::test]
+    async fn test_tsa_failure() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&server)
+            .await;
+        let kp = AppState::for_test().eph_kp;
+        let mut signed = to_signed_response(&kp, vec![1u8], 1, IntentScope::ProcessData);
+
+        assert!(
+            attach_timestamp_token(&mut signed, &options(&server, false))
+                .await
+                .is_err()
+        );
+        attach_timestamp_token(&mut signed, &options(&server, true))
+            .await
+            .unwrap();
+        assert!(signed.timestamp_token.is_none());
+        assert!(serde_json::to_value(&signed)
+            .unwrap()
+            .get("timestamp_token")
+            .is_none());
+    }
+}